@@ -0,0 +1,113 @@
+use amf::amf3::{Decoder, Value};
+use amf::Pair;
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use std::fs::File;
+use std::io::{Seek, SeekFrom, Write};
+
+fn encode(value: &Value) -> Vec<u8> {
+    let mut buf = Vec::new();
+    value.write_to(&mut buf).unwrap();
+    buf
+}
+
+// Decodes each input from a slice, from an unbuffered file,
+// where a read per element would be a system call, and from the file through `Decoder::buffered`
+fn bench_inputs(c: &mut Criterion, name: &str, inputs: &[(&str, Vec<u8>)]) {
+    let mut group = c.benchmark_group(name);
+    for (input_name, input) in inputs {
+        group.throughput(Throughput::Bytes(input.len() as u64));
+        group.bench_function(format!("{}/slice", input_name), |b| {
+            b.iter(|| Value::read_from(&mut &input[..]).unwrap())
+        });
+
+        let path = std::env::temp_dir().join(format!("amf-bench-{}-{}", name, input_name));
+        File::create(&path).unwrap().write_all(input).unwrap();
+        let mut file = File::open(&path).unwrap();
+        group.bench_function(format!("{}/file", input_name), |b| {
+            b.iter(|| {
+                file.seek(SeekFrom::Start(0)).unwrap();
+                Value::read_from(&mut file).unwrap()
+            })
+        });
+        group.bench_function(format!("{}/file-buffered", input_name), |b| {
+            b.iter(|| {
+                file.seek(SeekFrom::Start(0)).unwrap();
+                Decoder::buffered(&mut file).decode().unwrap()
+            })
+        });
+        let _ = std::fs::remove_file(&path);
+    }
+    group.finish();
+}
+
+fn vectors(c: &mut Criterion) {
+    let inputs = [
+        (
+            "int",
+            encode(&Value::IntVector {
+                is_fixed: false,
+                entries: (0..10_000).collect(),
+            }),
+        ),
+        (
+            "uint",
+            encode(&Value::UintVector {
+                is_fixed: false,
+                entries: (0..10_000).collect(),
+            }),
+        ),
+        (
+            "double",
+            encode(&Value::DoubleVector {
+                is_fixed: false,
+                entries: (0..10_000).map(|i| f64::from(i) / 3.0).collect(),
+            }),
+        ),
+    ];
+    bench_inputs(c, "vector", &inputs);
+}
+
+fn bytes(c: &mut Criterion) {
+    let inputs = [
+        ("byte-array", encode(&Value::ByteArray(vec![0xAB; 100_000]))),
+        ("string", encode(&Value::String("a".repeat(100_000)))),
+    ];
+    bench_inputs(c, "bytes", &inputs);
+}
+
+fn elements(c: &mut Criterion) {
+    let object = |i: i32| Value::Object {
+        class_name: None,
+        sealed_count: 2,
+        entries: vec![
+            Pair {
+                key: "id".to_string(),
+                value: Value::Integer(i),
+            },
+            Pair {
+                key: "name".to_string(),
+                value: Value::String(format!("item-{}", i)),
+            },
+        ],
+    };
+    let inputs = [
+        (
+            "objects",
+            encode(&Value::Array {
+                assoc_entries: Vec::new(),
+                dense_entries: (0..1_000).map(object).collect(),
+            }),
+        ),
+        (
+            "integers",
+            encode(&Value::Array {
+                assoc_entries: Vec::new(),
+                dense_entries: (0..10_000).map(|i| Value::Integer(i * 1_000)).collect(),
+            }),
+        ),
+    ];
+    bench_inputs(c, "elements", &inputs);
+}
+
+criterion_group!(benches, vectors, bytes, elements);
+criterion_main!(benches);
@@ -0,0 +1,302 @@
+use super::graph::{self, Graph, Node, NodeId};
+use super::{Decoder, Value};
+use crate::amf3;
+use crate::error::DecodeError;
+use crate::io;
+use crate::{DecodeResult, Pair};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::time;
+
+// An entry of the object reference table of `Decoder`
+#[derive(Debug)]
+pub(super) enum Slot {
+    // An object or an array in the graph, which may be still being decoded
+    Node(NodeId),
+}
+
+// A value which is not an object or an array
+#[derive(Clone)]
+pub(super) enum Scalar {
+    Number(f64),
+    Boolean(bool),
+    String(String),
+    Null,
+    Undefined,
+    Unsupported,
+    MovieClip,
+    RecordSet,
+    Date {
+        unix_time: time::Duration,
+        time_zone: i16,
+    },
+    XmlDocument(String),
+}
+
+// An object or an array whose entries have been built as `V`
+pub(super) enum Complex<V> {
+    Object {
+        class_name: Option<String>,
+        entries: Vec<Pair<String, V>>,
+    },
+    EcmaArray {
+        entries: Vec<Pair<String, V>>,
+    },
+    Array {
+        entries: Vec<V>,
+    },
+}
+
+// The representation which `Decoder` builds the decoded values in
+pub(super) trait Build {
+    type Value;
+
+    fn scalar(scalar: Scalar) -> Self::Value;
+
+    // Returns the object or the array at `index` of the reference table
+    fn refer<R>(&mut self, decoder: &mut Decoder<R>, index: usize) -> DecodeResult<Self::Value>;
+
+    // Adds an entry to the reference table, and builds the object or the array decoded by `f` in it
+    fn complex<R, F>(&mut self, decoder: &mut Decoder<R>, f: F) -> DecodeResult<Self::Value>
+    where
+        F: FnOnce(&mut Decoder<R>, &mut Self) -> DecodeResult<Complex<Self::Value>>;
+
+    // Decodes the AMF3 value of an `AvmPlus`
+    fn avmplus<R>(&mut self, decoder: &mut amf3::Decoder<R>) -> DecodeResult<Self::Value>
+    where
+        R: io::Read;
+}
+
+// `Decoder::decode_graph` adds the objects and arrays to the graph
+impl Build for Graph {
+    type Value = graph::Value;
+
+    fn scalar(scalar: Scalar) -> graph::Value {
+        match scalar {
+            Scalar::Number(x) => graph::Value::Number(x),
+            Scalar::Boolean(x) => graph::Value::Boolean(x),
+            Scalar::String(x) => graph::Value::String(x),
+            Scalar::Null => graph::Value::Null,
+            Scalar::Undefined => graph::Value::Undefined,
+            Scalar::Unsupported => graph::Value::Unsupported,
+            Scalar::MovieClip => graph::Value::MovieClip,
+            Scalar::RecordSet => graph::Value::RecordSet,
+            Scalar::Date {
+                unix_time,
+                time_zone,
+            } => graph::Value::Date {
+                unix_time,
+                time_zone,
+            },
+            Scalar::XmlDocument(x) => graph::Value::XmlDocument(x),
+        }
+    }
+    fn refer<R>(&mut self, decoder: &mut Decoder<R>, index: usize) -> DecodeResult<graph::Value> {
+        match decoder.complexes.get(index) {
+            Some(&Slot::Node(id)) => Ok(graph::Value::Node(id)),
+            None => Err(DecodeError::OutOfRangeReference { index }),
+        }
+    }
+    fn complex<R, F>(&mut self, decoder: &mut Decoder<R>, f: F) -> DecodeResult<graph::Value>
+    where
+        F: FnOnce(&mut Decoder<R>, &mut Self) -> DecodeResult<Complex<graph::Value>>,
+    {
+        let id = self.reserve();
+        decoder.complexes.push(Slot::Node(id));
+        match f(decoder, self) {
+            Ok(complex) => {
+                self.fill(id, complex.into());
+                Ok(graph::Value::Node(id))
+            }
+            Err(e) => {
+                // Drops the reserved node and the nodes decoded under it
+                self.truncate(id);
+                Err(e)
+            }
+        }
+    }
+    fn avmplus<R>(&mut self, decoder: &mut amf3::Decoder<R>) -> DecodeResult<graph::Value>
+    where
+        R: io::Read,
+    {
+        decoder
+            .decode_value(self.avmplus_mut())
+            .map(graph::Value::AvmPlus)
+    }
+}
+
+// `Decoder::decode` builds the objects and arrays as trees.
+//
+// Each object and array is also added to `graph` once, and the references copy it out of `graph`
+// (as well as the values decoded as a graph by `Decoder::decode_graph`).
+pub(super) struct Tree<'a> {
+    pub(super) graph: &'a mut Graph,
+    // The nodes of `graph` which have been copied
+    pub(super) copied: &'a mut Vec<bool>,
+    // The nodes of `graph.avmplus()` which have been copied
+    pub(super) avmplus_copied: &'a mut Vec<bool>,
+}
+
+// A value built by `Tree`, and its counterpart in the graph
+pub(super) struct Built {
+    pub(super) tree: Value,
+    node: graph::Value,
+}
+
+impl Build for Tree<'_> {
+    type Value = Built;
+
+    fn scalar(scalar: Scalar) -> Built {
+        let tree = match scalar.clone() {
+            Scalar::Number(x) => Value::Number(x),
+            Scalar::Boolean(x) => Value::Boolean(x),
+            Scalar::String(x) => Value::String(x),
+            Scalar::Null => Value::Null,
+            Scalar::Undefined => Value::Undefined,
+            Scalar::Unsupported => Value::Unsupported,
+            Scalar::MovieClip => Value::MovieClip,
+            Scalar::RecordSet => Value::RecordSet,
+            Scalar::Date {
+                unix_time,
+                time_zone,
+            } => Value::Date {
+                unix_time,
+                time_zone,
+            },
+            Scalar::XmlDocument(x) => Value::XmlDocument(x),
+        };
+        Built {
+            tree,
+            node: Graph::scalar(scalar),
+        }
+    }
+    fn refer<R>(&mut self, decoder: &mut Decoder<R>, index: usize) -> DecodeResult<Built> {
+        match decoder.complexes.get(index) {
+            Some(&Slot::Node(id)) if self.graph.decoded_node(id).is_ok() => {
+                let node = graph::Value::Node(id);
+                let tree = self.graph.to_tree_with(
+                    &node,
+                    self.copied,
+                    self.avmplus_copied,
+                    &mut decoder.budget,
+                )?;
+                Ok(Built { tree, node })
+            }
+            Some(_) => Err(DecodeError::CircularReference { index }),
+            None => Err(DecodeError::OutOfRangeReference { index }),
+        }
+    }
+    fn complex<R, F>(&mut self, decoder: &mut Decoder<R>, f: F) -> DecodeResult<Built>
+    where
+        F: FnOnce(&mut Decoder<R>, &mut Self) -> DecodeResult<Complex<Built>>,
+    {
+        let id = self.graph.reserve();
+        decoder.complexes.push(Slot::Node(id));
+        match f(decoder, self) {
+            Ok(complex) => {
+                let (tree, node) = complex.split();
+                self.graph.fill(id, node);
+                // The tree is the first copy of the node
+                if self.copied.len() <= id.index() {
+                    self.copied.resize(id.index() + 1, false);
+                }
+                self.copied[id.index()] = true;
+                Ok(Built {
+                    tree,
+                    node: graph::Value::Node(id),
+                })
+            }
+            Err(e) => {
+                // Drops the reserved node and the nodes decoded under it
+                self.graph.truncate(id);
+                self.copied.truncate(id.index());
+                Err(e)
+            }
+        }
+    }
+    fn avmplus<R>(&mut self, decoder: &mut amf3::Decoder<R>) -> DecodeResult<Built>
+    where
+        R: io::Read,
+    {
+        let mut tree = amf3::build::Tree {
+            graph: self.graph.avmplus_mut(),
+            copied: self.avmplus_copied,
+        };
+        let built = decoder.decode_value(&mut tree)?;
+        Ok(Built {
+            tree: Value::AvmPlus(built.tree),
+            node: graph::Value::AvmPlus(built.node),
+        })
+    }
+}
+
+impl From<Complex<graph::Value>> for Node {
+    fn from(complex: Complex<graph::Value>) -> Self {
+        match complex {
+            Complex::Object {
+                class_name,
+                entries,
+            } => Node::Object {
+                class_name,
+                entries,
+            },
+            Complex::EcmaArray { entries } => Node::EcmaArray { entries },
+            Complex::Array { entries } => Node::Array { entries },
+        }
+    }
+}
+impl Complex<Built> {
+    // Splits this into the tree and the node in the graph
+    fn split(self) -> (Value, Node) {
+        match self {
+            Complex::Object {
+                class_name,
+                entries,
+            } => {
+                let (tree_entries, entries) = split_pairs(entries);
+                let tree = Value::Object {
+                    class_name: class_name.clone(),
+                    entries: tree_entries,
+                };
+                let node = Node::Object {
+                    class_name,
+                    entries,
+                };
+                (tree, node)
+            }
+            Complex::EcmaArray { entries } => {
+                let (tree_entries, entries) = split_pairs(entries);
+                let tree = Value::EcmaArray {
+                    entries: tree_entries,
+                };
+                (tree, Node::EcmaArray { entries })
+            }
+            Complex::Array { entries } => {
+                let (tree_entries, entries) = entries.into_iter().map(|v| (v.tree, v.node)).unzip();
+                let tree = Value::Array {
+                    entries: tree_entries,
+                };
+                (tree, Node::Array { entries })
+            }
+        }
+    }
+}
+
+type Pairs<V> = Vec<Pair<String, V>>;
+
+fn split_pairs(pairs: Pairs<Built>) -> (Pairs<Value>, Pairs<graph::Value>) {
+    pairs
+        .into_iter()
+        .map(|p| {
+            let tree = Pair {
+                key: p.key.clone(),
+                value: p.value.tree,
+            };
+            let node = Pair {
+                key: p.key,
+                value: p.value.node,
+            };
+            (tree, node)
+        })
+        .unzip()
+}
@@ -1,260 +1,201 @@
+use super::build::{Build, Complex, Scalar, Slot, Tree};
+use super::graph::{self, Graph};
 use super::marker;
+use super::shared;
 use super::Value;
-use crate::DecodePart;
-use crate::amf3;
-use crate::error::DecodeError;
-use crate::{DecodeResult, Pair};
-use byteorder::{BigEndian, ReadBytesExt};
-use std::error::Error;
-use std::io;
-use std::io::Cursor;
-use std::io::Read;
-use std::time;
+use crate::amf3::{self, ExternalizableRegistry};
+use crate::error::{DecodeError, Location, PathSegment, Warning, WarningKind};
+use crate::io::{self, ReadBytesExt};
+use crate::limits::Budget;
+use crate::util::Position;
+use crate::wire::{Tape, WireDetail};
+use crate::{DecodeResult, DecoderLimits, Pair, WireFormat};
+use alloc::string::String;
+use alloc::vec::Vec;
+use byteorder::BigEndian;
+use core::mem;
+use core::time;
+
+/// AMF0 value decoded by `Decoder::decode_entire`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecodedValue {
+    /// The decoded value.
+    pub value: Value,
+
+    /// The byte offset of the value.
+    pub offset: usize,
+
+    /// The number of the bytes of the value.
+    pub len: usize,
+}
+
+/// AMF0 value decoded by `Decoder::decode_lenient`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LenientValue {
+    /// The decoded value.
+    pub value: Value,
+
+    /// The problems in the input which have been worked around.
+    pub warnings: Vec<Warning>,
+}
+
+/// AMF0 value decoded by `Decoder::decode_exact`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExactValue {
+    /// The decoded value.
+    pub value: Value,
+
+    /// The wire details of the value, which `Encoder::encode_exact` reproduces.
+    pub wire: WireFormat,
+}
 
 /// AMF0 decoder.
+///
+/// Strings are read in bulk, but markers and other primitives are read one by one,
+/// so make the decoder of an unbuffered reader such as `TcpStream` by `Decoder::buffered`.
 #[derive(Debug)]
 pub struct Decoder<R> {
-    inner: R,
-    complexes: Vec<Value>,
-    reader: u32,
-    innervec: Vec<u8>
+    inner: Position<R>,
+    pub(super) complexes: Vec<Slot>,
+    graph: Graph,
+    shared: shared::Nodes,
+    // The nodes which have been copied by `decode`
+    copied: Vec<bool>,
+    avmplus_copied: Vec<bool>,
+    pub(super) budget: Budget,
+    // `Some` while decoding in the lenient mode
+    warnings: Option<Vec<Warning>>,
+    // `Some` while decoding in the exact mode
+    wire: Option<Tape>,
+    // The AMF3 decoder is taken out while it decodes an `AvmPlus` value
+    amf3: Option<amf3::Decoder<io::Empty>>,
+    // The location of the last error detected in the input
+    location: Option<Location>,
 }
 impl<R> Decoder<R> {
     /// Unwraps this `Decoder`, returning the underlying reader.
+    ///
+    /// Note that the bytes in `buffer` are lost.
     pub fn into_inner(self) -> R {
-        self.inner
+        self.inner.inner
     }
 
     /// Get the reference to the underlying reader.
     pub fn inner(&self) -> &R {
-        &self.inner
+        &self.inner.inner
     }
 
     /// Get the mutable reference to the underlying reader.
+    ///
+    /// Note that the bytes read through the reference are not counted
+    /// in the offsets of the errors.
     pub fn inner_mut(&mut self) -> &mut R {
-        &mut self.inner
+        &mut self.inner.inner
     }
-}
 
-impl<R> Decoder<R>
-where
-    R: AsRef<[u8]> + io::Read + Copy
-{
-    pub fn new_from_array(inner: R) -> Self {
-        let b = inner;
-        Decoder {
-            inner: b,
-            complexes: Vec::new(),
-            reader: 0,
-            innervec: inner.as_ref().to_vec()
-        }
+    /// Returns the bytes which a buffered decoder has read from the underlying reader
+    /// but not decoded yet.
+    ///
+    /// This is empty unless the decoder is made by `buffered`.
+    pub fn buffer(&self) -> &[u8] {
+        self.inner.buffer()
     }
 
-    pub fn decode_entire(&mut self) -> DecodeResult<Vec<Value>> {
-    self.decode_entire_array()
+    /// Get the object graph which holds the objects and arrays decoded by `decode_graph`.
+    ///
+    /// `decode` also adds each object and array to the graph once, where the later references copy it from.
+    /// The graph is reset by `clear_reference_table`.
+    pub fn graph(&self) -> &Graph {
+        &self.graph
     }
 
-    fn decode_once(&mut self) -> DecodeResult<Value> { // TODO SELF READER INCREASING
-        let marker = Cursor::new(&self.innervec[self.reader as usize..]).read_u8()?;
-        let mut a: Vec<Value> = Vec::new();
-        self.reader += 1;
-        println!("MARKER : {}",marker);
-        println!("REMAINING VEC :  {:?}",&self.innervec[self.reader as usize..]);
-        match marker {
-            marker::NUMBER => {a.push(self.decode_number_from_vec()?); self.reader += 8},
-            marker::BOOLEAN => {a.push(self.decode_boolean_from_vec()?); self.reader += 1 },
-            marker::STRING => {
-                let temp = self.decode_string_from_vec()?;
-                self.reader += temp.1;
-                a.push(temp.0);
-            }
-            marker::OBJECT => {a.push(self.decode_object_from_vec()?);}
-            marker::MOVIECLIP => return Err(DecodeError::Unsupported { marker }),
-            marker::NULL => {a.push(Value::Null);},
-            marker::UNDEFINED => {a.push(Value::Undefined);},
-            marker::REFERENCE => {self.decode_reference_from_vec();}
-            marker::ECMA_ARRAY => {self.decode_ecma_array_from_vec();}
-            marker::OBJECT_END_MARKER => return Err(DecodeError::UnexpectedObjectEnd),
-            marker::STRICT_ARRAY => {self.decode_strict_array_from_vec();}
-            marker::DATE => {self.decode_date_from_vec();}
-            marker::LONG_STRING => {let decoded = self.decode_long_string_from_vec()?; a.push(decoded.0); self.reader += decoded.1;}
-            marker::UNSUPPORTED => return Err(DecodeError::Unsupported { marker }),
-            marker::RECORDSET => return Err(DecodeError::Unsupported { marker }),
-            marker::XML_DOCUMENT => {self.decode_xml_document_from_vec();}
-            marker::TYPED_OBJECT => {self.decode_typed_object_from_vec();}
-            marker::AVMPLUS_OBJECT => {self.decode_avmplus_from_vec();}
-            _ => return Err(DecodeError::Unknown { marker }),
-        }
-        Ok(a.get(0).unwrap().to_owned())
-    }
-    fn decode_entire_array(&mut self) -> DecodeResult<Vec<Value>> {
-        let mut a = Vec::new();
-        while self.reader != self.innervec.len() as u32 {
-            let marker = Cursor::new(&self.innervec[self.reader as usize..]).read_u8()?;
-            self.reader += 1;
-            println!("MARKER ENTIRE : {}",marker);
-            match marker {
-                marker::NUMBER => {a.push(self.decode_number_from_vec()?); self.reader += 8},
-                marker::BOOLEAN => {a.push(self.decode_boolean_from_vec()?); self.reader += 1 },
-                marker::STRING => {
-                    let temp = self.decode_string_from_vec()?;
-                    self.reader += temp.1;
-                    a.push(temp.0);
-                }
-                marker::OBJECT => {a.push(self.decode_object_from_vec()?);}
-                marker::MOVIECLIP => return Err(DecodeError::Unsupported { marker }),
-                marker::NULL => {a.push(Value::Null);},
-                marker::UNDEFINED => {a.push(Value::Undefined);},
-                marker::REFERENCE => {a.push(self.decode_reference_from_vec()?); self.reader += 2}
-                marker::ECMA_ARRAY => {a.push(self.decode_ecma_array_from_vec()?);} // TODO
-                marker::OBJECT_END_MARKER => return Err(DecodeError::UnexpectedObjectEnd),
-                marker::STRICT_ARRAY => {a.push(self.decode_strict_array_from_vec()?);}
-                marker::DATE => {a.push(self.decode_date_from_vec()?);}
-                marker::LONG_STRING => {let decoded = self.decode_long_string_from_vec()?; a.push(decoded.0); self.reader += decoded.1;},
-                marker::UNSUPPORTED => return Err(DecodeError::Unsupported { marker }),
-                marker::RECORDSET => return Err(DecodeError::Unsupported { marker }),
-                marker::XML_DOCUMENT => {let decoded = self.decode_xml_document_from_vec()?; a.push(decoded.0); self.reader += decoded.1},
-                marker::TYPED_OBJECT => {a.push(self.decode_typed_object_from_vec()?);}
-                marker::AVMPLUS_OBJECT => return Err(DecodeError::Unsupported { marker }), //{self.decode_avmplus_from_vec();}
-                _ => return Err(DecodeError::Unknown { marker }),
-            }
-            println!("{:?}",a);
-            println!("{:?}",a.get(0).unwrap());
-        }
-        Ok(a)
-    }
-
-    fn decode_number_from_vec(&mut self) -> DecodeResult<Value> {
-        let mut n = Cursor::new(&self.innervec[self.reader as usize..]);
-        println!("N :  {:?}",n);
-        let a = n.read_f64::<BigEndian>()?;
-        Ok(Value::Number(a))
-    }
-    fn decode_boolean_from_vec(&mut self) -> DecodeResult<Value> {
-        let b = Cursor::new(&self.innervec[self.reader as usize..]).read_u8()? != 0;
-        Ok(Value::Boolean(b))
-    }
-    fn decode_string_from_vec(&mut self) -> DecodePart<Value> {
-        let len = Cursor::new(&self.innervec[self.reader as usize..]).read_u16::<BigEndian>()? as usize; self.reader += 2;
-        println!("{}",len);
-        println!("L : {:?}",&self.innervec[self.reader as usize..self.reader as usize + len]);
-        let a = self.read_utf8_from_vec(len).map(Value::String)?;
-        Ok((a, len as u32))
-    }
-    fn read_utf8_from_vec(&mut self, len: usize) -> DecodeResult<String> {
-        let mut buf = vec![0; len];
-        Cursor::new(&self.innervec[self.reader as usize..]).read_exact(&mut buf)?;
-        println!("buf : {:?}",buf);
-        let utf8 = String::from_utf8(buf)?;
-        println!("utf : {:?}",utf8);
-        Ok(utf8)
-    }
-
-    fn decode_object_from_vec(&mut self) -> DecodeResult<Value> {
-        self.decode_complex_type_from_vec(|this| {
-            let entries = this.decode_pairs_from_vec()?;
-            Ok(Value::Object {
-                class_name: None,
-                entries,
-            })
-        })
+    /// Get the mutable reference to the registry of the externalizable class handlers
+    /// used to decode the AMF3 values embedded in `AvmPlus`.
+    pub fn externalizables_mut(&mut self) -> &mut ExternalizableRegistry {
+        self.amf3_mut().externalizables_mut()
     }
-    fn decode_complex_type_from_vec<F>(&mut self, f: F) -> DecodeResult<Value>
-    where
-        F: FnOnce(&mut Self) -> DecodeResult<Value>,
-    {
-        let index = self.complexes.len();
-        self.complexes.push(Value::Null);
-        let value = f(self)?;
-        self.complexes[index] = value.clone();
-        Ok(value)
+    fn amf3_mut(&mut self) -> &mut amf3::Decoder<io::Empty> {
+        self.amf3.as_mut().expect("the AMF3 decoder is put back")
     }
-    fn decode_pairs_from_vec(&mut self) -> DecodeResult<Vec<Pair<String, Value>>> {
-        let mut entries = Vec::new();
-        loop {
-            println!("REMAINING VEC FROM DECODE PAIRS FROM VEC FUNCTION : {:?}", &self.innervec[self.reader as usize..]);
-            let len = Cursor::new(&self.innervec[self.reader as usize..]).read_u16::<BigEndian>()? as usize;
-            println!("len : {}",len);
-            self.reader += 2;
-            let key = self.read_utf8_from_vec(len)?;
-            self.reader += len as u32;
-            match self.decode_once() {
-                Ok(value) => {
-                    entries.push(Pair { key, value });
-                }
-                Err(DecodeError::UnexpectedObjectEnd) if key.is_empty() => break,
-                Err(e) => {println!("NOOOO : {}",e);return Err(e)},
-            }
-            println!("ENTRIES : {:?}",entries);
-        }
-        Ok(entries)
+
+    /// Get the limits of this decoder.
+    pub fn limits(&self) -> &DecoderLimits {
+        &self.budget.limits
     }
-    fn decode_reference_from_vec(&mut self) -> DecodeResult<Value> {
-        let index = Cursor::new(&self.innervec[self.reader as usize..]).read_u16::<BigEndian>()? as usize;
-        self.complexes
-            .get(index)
-            .ok_or(DecodeError::OutOfRangeReference { index })
-            .and_then(|v| {
-                if *v == Value::Null {
-                    Err(DecodeError::CircularReference { index })
-                } else {
-                    Ok(v.clone())
-                }
-            })
+
+    /// Get the mutable reference to the limits of this decoder.
+    pub fn limits_mut(&mut self) -> &mut DecoderLimits {
+        &mut self.budget.limits
     }
-    fn decode_ecma_array_from_vec(&mut self) -> DecodeResult<Value> {
-        self.decode_complex_type_from_vec(|this: &mut Decoder<R>| {
-            let _count = Cursor::new(&this.innervec[this.reader as usize..]).read_u32::<BigEndian>()? as usize;
-            let entries = this.decode_pairs_from_vec()?; // TODO TODO TODO TODO TODO TODO TODO TODO 
-            Ok(Value::EcmaArray { entries })
-        })
+
+    /// Get the location of the value which failed to decode in the last call of a decoding method.
+    ///
+    /// This is `None` if the last call succeeded, or failed after decoding the value
+    /// (e.g., `decode_shared` finds a circular reference in the decoded graph).
+    /// The location of an error in the AMF3 values embedded in `AvmPlus` is also reported.
+    pub fn error_location(&self) -> Option<&Location> {
+        self.location.as_ref()
     }
-    fn decode_strict_array_from_vec(&mut self) -> DecodeResult<Value> {
-        self.decode_complex_type_from_vec(|this: &mut Decoder<R>| {
-            let count = Cursor::new(&this.innervec[this.reader as usize..]).read_u32::<BigEndian>()? as usize; this.reader += 4;
-            let entries = (0..count)
-                .map(|_| this.decode_once())
-                .collect::<DecodeResult<_>>()?;
-            Ok(Value::Array { entries })
-        })
+}
+
+impl<R> Decoder<R>
+where
+    R: AsRef<[u8]> + io::Read,
+{
+    /// Makes a new instance which decodes values from an in-memory byte array (e.g., `&[u8]`).
+    pub fn new_from_array(inner: R) -> Self {
+        Self::new(inner)
     }
-    fn decode_date_from_vec(&mut self) -> DecodeResult<Value> {
-        let millis = Cursor::new(&self.innervec[self.reader as usize..]).read_f64::<BigEndian>()?; self.reader += 8;
-        let time_zone = Cursor::new(&self.innervec[self.reader as usize..]).read_i16::<BigEndian>()?; self.reader += 2;
-        if !(millis.is_finite() && millis.is_sign_positive()) {
-            Err(DecodeError::InvalidDate { millis })
-        } else {
-            Ok(Value::Date {
-                unix_time: time::Duration::from_millis(millis as u64),
-                time_zone,
-            })
+
+    /// Decodes all AMF0 values remaining in the byte array.
+    ///
+    /// This is useful to decode a sequence of concatenated values
+    /// such as the payload of an RTMP command message.
+    ///
+    /// The offset of each value is relative to the position of the byte array
+    /// at the time this method is called.
+    /// The reference tables are shared by the values, as they are in a message.
+    /// The path of an error starts with the index of the value which failed to decode.
+    ///
+    /// # Examples
+    /// ```
+    /// use amf::amf0::{DecodedValue, Decoder, Value};
+    ///
+    /// // "connect", 1.0, null
+    /// let input = b"\x02\x00\x07connect\x00\x3f\xf0\x00\x00\x00\x00\x00\x00\x05";
+    /// let values = Decoder::new_from_array(&input[..]).decode_entire().unwrap();
+    /// assert_eq!(
+    ///     values,
+    ///     [
+    ///         DecodedValue { value: Value::String("connect".to_string()), offset: 0, len: 10 },
+    ///         DecodedValue { value: Value::Number(1.0), offset: 10, len: 9 },
+    ///         DecodedValue { value: Value::Null, offset: 19, len: 1 },
+    ///     ]
+    /// );
+    /// ```
+    pub fn decode_entire(&mut self) -> DecodeResult<Vec<DecodedValue>> {
+        let total = self.inner.inner.as_ref().len();
+        let mut values = Vec::new();
+        let mut offset = 0;
+        while offset < total {
+            let index = values.len();
+            let value = match self.decode() {
+                Ok(value) => value,
+                Err(e) => {
+                    Location::prepend(&mut self.location, PathSegment::Index(index));
+                    return Err(e);
+                }
+            };
+            let next = total - self.inner.inner.as_ref().len();
+            values.push(DecodedValue {
+                value,
+                offset,
+                len: next - offset,
+            });
+            offset = next;
         }
+        Ok(values)
     }
-    fn decode_long_string_from_vec(&mut self) -> DecodePart<Value> {
-        let len = Cursor::new(&self.innervec[self.reader as usize..]).read_u32::<BigEndian>()? as usize; self.reader += 4;
-        let string = self.read_utf8_from_vec(len).map(Value::String)?;
-        Ok((string, len as u32))
-    }
-    fn decode_xml_document_from_vec(&mut self) -> DecodePart<Value> {
-        let len = Cursor::new(&self.innervec[self.reader as usize..]).read_u32::<BigEndian>()? as usize;
-        Ok((self.read_utf8_from_vec(len).map(Value::XmlDocument)?, len as u32))
-    }
-    fn decode_typed_object_from_vec(&mut self) -> DecodeResult<Value> {
-        self.decode_complex_type(|this| {
-            let len = Cursor::new(&this.innervec[this.reader as usize..]).read_u16::<BigEndian>()? as usize;
-            let class_name = this.read_utf8_from_vec(len)?;
-            let entries = this.decode_pairs_from_vec()?;
-            Ok(Value::Object {
-                class_name: Some(class_name),
-                entries,
-            })
-        })
-    }
-    fn decode_avmplus_from_vec(&mut self) -> DecodeResult<Value> {
-        let value = amf3::Decoder::new(&mut self.inner).decode()?;
-        Ok(Value::AvmPlus(value))
-    }
-    
 }
 
 impl<R> Decoder<R>
@@ -263,185 +204,453 @@ where
 {
     /// Makes a new instance.
     pub fn new(inner: R) -> Self {
+        Self::with_limits(inner, DecoderLimits::default())
+    }
+
+    /// Makes a new instance with the specified limits.
+    ///
+    /// The limits also apply to the AMF3 values embedded in `AvmPlus`.
+    pub fn with_limits(inner: R, limits: DecoderLimits) -> Self {
         Decoder {
-            inner,
+            inner: Position::new(inner),
             complexes: Vec::new(),
-            reader: 0,
-            innervec: Vec::new()
+            graph: Graph::new(),
+            shared: shared::Nodes::default(),
+            copied: Vec::new(),
+            avmplus_copied: Vec::new(),
+            budget: Budget::new(limits),
+            warnings: None,
+            wire: None,
+            amf3: Some(amf3::Decoder::new(io::empty())),
+            location: None,
         }
     }
 
+    /// Makes a new instance which reads ahead from `inner` through an internal buffer.
+    ///
+    /// This saves the read calls to an unbuffered `inner` (e.g., `File` or `TcpStream`),
+    /// which are otherwise made for each marker and each byte of the variable length integers.
+    /// The bytes read ahead but not decoded yet are returned by `buffer`.
+    ///
+    /// # Examples
+    /// ```
+    /// use amf::amf0::{Decoder, Value};
+    ///
+    /// let input = b"\x01\x01\x05rest";
+    /// let mut decoder = Decoder::buffered(&input[..]);
+    /// assert_eq!(decoder.decode(), Ok(Value::Boolean(true)));
+    /// assert_eq!(decoder.decode(), Ok(Value::Null));
+    ///
+    /// // The rest of the input has been read ahead
+    /// assert_eq!(decoder.buffer(), b"rest");
+    /// ```
+    pub fn buffered(inner: R) -> Self {
+        let mut decoder = Self::new(inner);
+        decoder.inner.enable_read_ahead();
+        decoder
+    }
+
     /// Decodes a AMF0 value.
+    ///
+    /// Note that reference objects are copied in the decoding phase
+    /// for the sake of simplicity of the resulting value representation.
+    /// Circular references are rejected because the tree `Value` cannot represent those.
+    /// The copies are limited by `DecoderLimits::max_reference_expansions`.
+    /// Use `decode_shared` to share the referred values instead of copying them,
+    /// or [`decode_graph`](Self::decode_graph) to preserve shared and circular references.
     pub fn decode(&mut self) -> DecodeResult<Value> {
-        self.decode_value()
+        self.budget.start_value();
+        self.location = None;
+        let mut graph = mem::take(&mut self.graph);
+        let mut copied = mem::take(&mut self.copied);
+        let mut avmplus_copied = mem::take(&mut self.avmplus_copied);
+        let result = self.decode_value(&mut Tree {
+            graph: &mut graph,
+            copied: &mut copied,
+            avmplus_copied: &mut avmplus_copied,
+        });
+        self.graph = graph;
+        self.copied = copied;
+        self.avmplus_copied = avmplus_copied;
+        result.map(|built| built.tree)
+    }
+
+    /// Decodes a AMF0 value leniently, salvaging what can be decoded from damaged input.
+    ///
+    /// Unlike `decode`, this works around the following problems and reports them as warnings:
+    ///
+    /// - invalid UTF-8 strings are decoded with replacement characters,
+    /// - the markers which cannot start a value are skipped,
+    /// - the declared length of an ECMA array which differs from its entries is ignored,
+    /// - truncated strings, objects and arrays keep the contents decoded before the end of the input.
+    ///
+    /// The strings of the AMF3 values embedded in `AvmPlus` are also decoded with replacement characters,
+    /// but the other problems in those values are errors as in `decode`,
+    /// except that a truncated AMF3 value is dropped from the enclosing AMF0 object or array.
+    ///
+    /// # Examples
+    /// ```
+    /// use amf::amf0::{Decoder, Value};
+    /// use amf::error::{Warning, WarningKind};
+    ///
+    /// // A stray byte 0xFF, and a string which contains an invalid byte 0xC0
+    /// let input = b"\xFF\x02\x00\x03a\xC0b";
+    /// let lenient = Decoder::new(&input[..]).decode_lenient().unwrap();
+    /// assert_eq!(lenient.value, Value::String("a\u{FFFD}b".to_string()));
+    /// assert_eq!(
+    ///     lenient.warnings,
+    ///     [
+    ///         Warning { offset: 0, kind: WarningKind::SkippedMarker { marker: 0xFF } },
+    ///         Warning { offset: 4, kind: WarningKind::InvalidUtf8 },
+    ///     ]
+    /// );
+    /// ```
+    pub fn decode_lenient(&mut self) -> DecodeResult<LenientValue> {
+        self.warnings = Some(Vec::new());
+        let result = self.decode();
+        let warnings = self.warnings.take().unwrap_or_default();
+        result.map(|value| LenientValue { value, warnings })
+    }
+
+    /// Decodes a AMF0 value together with its wire details.
+    ///
+    /// `Encoder::encode_exact` reproduces the input byte for byte from the result,
+    /// which `Encoder::encode` does not necessarily do (see `WireFormat`).
+    /// The wire details of the AMF3 values embedded in `AvmPlus` are also recorded.
+    pub fn decode_exact(&mut self) -> DecodeResult<ExactValue> {
+        self.wire = Some(Tape::default());
+        let result = self.decode();
+        let wire = self.wire.take().unwrap_or_default().into_wire();
+        result.map(|value| ExactValue { value, wire })
+    }
+
+    /// Decodes a AMF0 value as an object graph.
+    ///
+    /// The objects and arrays are added to the graph returned by `graph`.
+    /// Unlike `decode`, shared and circular references are preserved.
+    pub fn decode_graph(&mut self) -> DecodeResult<graph::Value> {
+        self.budget.start_value();
+        self.location = None;
+        let mut graph = mem::take(&mut self.graph);
+        let result = self.decode_value(&mut graph);
+        self.graph = graph;
+        result
+    }
+
+    /// Decodes a AMF0 value whose objects and arrays are shared by reference counting.
+    ///
+    /// An object or an array is decoded only once even if it is referred multiple times,
+    /// including the references from the values decoded by the previous calls.
+    /// Circular references are treated as errors.
+    pub fn decode_shared(&mut self) -> DecodeResult<shared::Value> {
+        let value = self.decode_graph()?;
+        self.shared.share(&self.graph, &value)
     }
 
     /// Clear the reference table of this decoder.
     ///
+    /// The reference tables of the AMF3 values embedded in `AvmPlus` are also cleared.
+    ///
     /// > Note that object reference indices are local to each message body.
     /// > Serializers and deserializers must reset reference indices to 0 each time a new message is processed.
     /// >
     /// > [AMF 0 Specification: 4.1.3 AMF Message](http://download.macromedia.com/pub/labs/amf/amf0_spec_121207.pdf)
     pub fn clear_reference_table(&mut self) {
         self.complexes.clear();
+        self.graph.clear();
+        self.shared.clear();
+        self.copied.clear();
+        self.avmplus_copied.clear();
+        self.budget.clear_expansions();
+        self.amf3_mut().clear_reference_table();
     }
 
-    fn decode_value(&mut self) -> DecodeResult<Value> {
-        let marker = self.inner.read_u8()?;
+    fn decode_value<B: Build>(&mut self, b: &mut B) -> DecodeResult<B::Value> {
+        let (offset, marker) = self.read_marker()?;
+        self.decode_value_with_marker(b, offset, marker)
+    }
+    fn decode_value_with_marker<B: Build>(
+        &mut self,
+        b: &mut B,
+        mut offset: u64,
+        mut marker: u8,
+    ) -> DecodeResult<B::Value> {
+        while (marker == marker::OBJECT_END_MARKER || marker > marker::AVMPLUS_OBJECT)
+            && self.warn(offset, WarningKind::SkippedMarker { marker })
+        {
+            (offset, marker) = self.read_marker()?;
+        }
+        let result = self.decode_marked_value(b, marker);
+        if result.is_err() {
+            Location::record(&mut self.location, offset);
+        }
+        result
+    }
+    fn decode_marked_value<B: Build>(&mut self, b: &mut B, marker: u8) -> DecodeResult<B::Value> {
+        if let Some(wire) = &mut self.wire {
+            wire.next_site();
+        }
         match marker {
-            marker::NUMBER => self.decode_number(),
-            marker::BOOLEAN => self.decode_boolean(),
-            marker::STRING => self.decode_string(),
-            marker::OBJECT => self.decode_object(),
-            marker::MOVIECLIP => Err(DecodeError::Unsupported { marker }),
-            marker::NULL => Ok(Value::Null),
-            marker::UNDEFINED => Ok(Value::Undefined),
-            marker::REFERENCE => self.decode_reference(),
-            marker::ECMA_ARRAY => self.decode_ecma_array(),
+            marker::NUMBER => self.decode_number().map(B::scalar),
+            marker::BOOLEAN => self.decode_boolean().map(B::scalar),
+            marker::STRING => self.decode_string().map(B::scalar),
+            marker::OBJECT => self.decode_object(b),
+            marker::MOVIECLIP => Ok(B::scalar(Scalar::MovieClip)),
+            marker::NULL => Ok(B::scalar(Scalar::Null)),
+            marker::UNDEFINED => Ok(B::scalar(Scalar::Undefined)),
+            marker::REFERENCE => self.decode_reference(b),
+            marker::ECMA_ARRAY => self.decode_ecma_array(b),
             marker::OBJECT_END_MARKER => Err(DecodeError::UnexpectedObjectEnd),
-            marker::STRICT_ARRAY => self.decode_strict_array(),
-            marker::DATE => self.decode_date(),
-            marker::LONG_STRING => self.decode_long_string(),
-            marker::UNSUPPORTED => Err(DecodeError::Unsupported { marker }),
-            marker::RECORDSET => Err(DecodeError::Unsupported { marker }),
-            marker::XML_DOCUMENT => self.decode_xml_document(),
-            marker::TYPED_OBJECT => self.decode_typed_object(),
-            marker::AVMPLUS_OBJECT => self.decode_avmplus(),
+            marker::STRICT_ARRAY => self.decode_strict_array(b),
+            marker::DATE => self.decode_date().map(B::scalar),
+            marker::LONG_STRING => self.decode_long_string().map(B::scalar),
+            marker::UNSUPPORTED => Ok(B::scalar(Scalar::Unsupported)),
+            marker::RECORDSET => Ok(B::scalar(Scalar::RecordSet)),
+            marker::XML_DOCUMENT => self.decode_xml_document().map(B::scalar),
+            marker::TYPED_OBJECT => self.decode_typed_object(b),
+            marker::AVMPLUS_OBJECT => self.decode_avmplus(b),
             _ => Err(DecodeError::Unknown { marker }),
         }
     }
-    fn decode_number(&mut self) -> DecodeResult<Value> {
+    fn decode_number(&mut self) -> DecodeResult<Scalar> {
         let n = self.inner.read_f64::<BigEndian>()?;
-        Ok(Value::Number(n))
+        Ok(Scalar::Number(n))
     }
-    fn decode_boolean(&mut self) -> DecodeResult<Value> {
+    fn decode_boolean(&mut self) -> DecodeResult<Scalar> {
         let b = self.inner.read_u8()? != 0;
-        Ok(Value::Boolean(b))
+        Ok(Scalar::Boolean(b))
     }
-    fn decode_string(&mut self) -> DecodeResult<Value> {
+    fn decode_string(&mut self) -> DecodeResult<Scalar> {
         let len = self.inner.read_u16::<BigEndian>()? as usize;
-        self.read_utf8(len).map(Value::String)
+        self.read_utf8(len).map(Scalar::String)
     }
-    fn decode_object(&mut self) -> DecodeResult<Value> {
-        self.decode_complex_type(|this| {
-            let entries = this.decode_pairs()?;
-            Ok(Value::Object {
+    fn decode_object<B: Build>(&mut self, b: &mut B) -> DecodeResult<B::Value> {
+        self.decode_complex_type(b, |this, b| {
+            let entries = this.decode_pairs(b)?;
+            Ok(Complex::Object {
                 class_name: None,
                 entries,
             })
         })
     }
-    fn decode_reference(&mut self) -> DecodeResult<Value> {
+    fn decode_reference<B: Build>(&mut self, b: &mut B) -> DecodeResult<B::Value> {
         let index = self.inner.read_u16::<BigEndian>()? as usize;
-        self.complexes
-            .get(index)
-            .ok_or(DecodeError::OutOfRangeReference { index })
-            .and_then(|v| {
-                if *v == Value::Null {
-                    Err(DecodeError::CircularReference { index })
-                } else {
-                    Ok(v.clone())
-                }
-            })
+        self.record(self.site(), WireDetail::Reference(index));
+        b.refer(self, index)
     }
-    fn decode_ecma_array(&mut self) -> DecodeResult<Value> {
-        self.decode_complex_type(|this| {
-            let _count = this.inner.read_u32::<BigEndian>()? as usize;
-            let entries = this.decode_pairs()?;
-            Ok(Value::EcmaArray { entries })
+    fn decode_ecma_array<B: Build>(&mut self, b: &mut B) -> DecodeResult<B::Value> {
+        let site = self.site();
+        self.decode_complex_type(b, |this, b| {
+            let offset = this.inner.offset;
+            let count = this.inner.read_u32::<BigEndian>()? as usize;
+            let entries = this.decode_pairs(b)?;
+            if entries.len() != count {
+                this.record(site, WireDetail::EcmaArrayLength(count as u32));
+                let kind = WarningKind::EcmaArrayLength {
+                    declared: count,
+                    actual: entries.len(),
+                };
+                this.warn(offset, kind);
+            }
+            Ok(Complex::EcmaArray { entries })
         })
     }
-    fn decode_strict_array(&mut self) -> DecodeResult<Value> {
-        self.decode_complex_type(|this| {
+    fn decode_strict_array<B: Build>(&mut self, b: &mut B) -> DecodeResult<B::Value> {
+        self.decode_complex_type(b, |this, b| {
             let count = this.inner.read_u32::<BigEndian>()? as usize;
-            let entries = (0..count)
-                .map(|_| this.decode_value())
-                .collect::<DecodeResult<_>>()?;
-            Ok(Value::Array { entries })
+            this.budget.collection::<B::Value>(count)?;
+            // The entries grow as they are decoded, so a bogus count does not allocate a large buffer
+            let mut entries = Vec::new();
+            for i in 0..count {
+                match this.decode_value(b) {
+                    Ok(value) => entries.push(value),
+                    Err(ref e) if this.truncates(e) => break,
+                    Err(e) => {
+                        Location::prepend(&mut this.location, PathSegment::Index(i));
+                        return Err(e);
+                    }
+                }
+            }
+            Ok(Complex::Array { entries })
         })
     }
-    fn decode_date(&mut self) -> DecodeResult<Value> {
+    fn decode_date(&mut self) -> DecodeResult<Scalar> {
         let millis = self.inner.read_f64::<BigEndian>()?;
         let time_zone = self.inner.read_i16::<BigEndian>()?;
         if !(millis.is_finite() && millis.is_sign_positive()) {
             Err(DecodeError::InvalidDate { millis })
         } else {
-            Ok(Value::Date {
+            Ok(Scalar::Date {
                 unix_time: time::Duration::from_millis(millis as u64),
                 time_zone,
             })
         }
     }
-    fn decode_long_string(&mut self) -> DecodeResult<Value> {
+    fn decode_long_string(&mut self) -> DecodeResult<Scalar> {
         let len = self.inner.read_u32::<BigEndian>()? as usize;
-        self.read_utf8(len).map(Value::String)
+        if len <= 0xFFFF {
+            self.record(self.site(), WireDetail::LongString);
+        }
+        self.read_utf8(len).map(Scalar::String)
     }
-    fn decode_xml_document(&mut self) -> DecodeResult<Value> {
+    fn decode_xml_document(&mut self) -> DecodeResult<Scalar> {
         let len = self.inner.read_u32::<BigEndian>()? as usize;
-        self.read_utf8(len).map(Value::XmlDocument)
+        self.read_utf8(len).map(Scalar::XmlDocument)
     }
-    fn decode_typed_object(&mut self) -> DecodeResult<Value> {
-        self.decode_complex_type(|this| {
+    fn decode_typed_object<B: Build>(&mut self, b: &mut B) -> DecodeResult<B::Value> {
+        self.decode_complex_type(b, |this, b| {
             let len = this.inner.read_u16::<BigEndian>()? as usize;
             let class_name = this.read_utf8(len)?;
-            let entries = this.decode_pairs()?;
-            Ok(Value::Object {
+            let entries = this.decode_pairs(b)?;
+            Ok(Complex::Object {
                 class_name: Some(class_name),
                 entries,
             })
         })
     }
-    fn decode_avmplus(&mut self) -> DecodeResult<Value> {
-        let value = amf3::Decoder::new(&mut self.inner).decode()?;
-        Ok(Value::AvmPlus(value))
+    fn decode_avmplus<B: Build>(&mut self, b: &mut B) -> DecodeResult<B::Value> {
+        // The AMF3 reference tables are shared by all `AvmPlus` values in a message
+        let amf3 = self.amf3.take().expect("the AMF3 decoder is put back");
+        let offset = self.inner.offset;
+        let (mut decoder, empty) = amf3.with_inner(&mut self.inner);
+        decoder.set_offset(offset);
+        decoder.budget = self.budget;
+        decoder.warnings = self.warnings.take();
+        decoder.wire = self.wire.take();
+        let result = b.avmplus(&mut decoder);
+        self.location = decoder.location.take();
+        self.budget = decoder.budget;
+        self.warnings = decoder.warnings.take();
+        self.wire = decoder.wire.take();
+        self.amf3 = Some(decoder.with_inner(empty).0);
+        result
     }
 
+    // The current site of the wire details in the exact mode
+    fn site(&self) -> u64 {
+        self.wire.as_ref().map_or(0, Tape::site)
+    }
+    fn record(&mut self, site: u64, detail: WireDetail) {
+        if let Some(wire) = &mut self.wire {
+            wire.record(site, detail);
+        }
+    }
+    fn read_marker(&mut self) -> DecodeResult<(u64, u8)> {
+        let offset = self.inner.offset;
+        match self.inner.read_u8() {
+            Ok(marker) => Ok((offset, marker)),
+            Err(e) => {
+                Location::record(&mut self.location, offset);
+                Err(e.into())
+            }
+        }
+    }
     fn read_utf8(&mut self, len: usize) -> DecodeResult<String> {
-        let mut buf = vec![0; len];
-        self.inner.read_exact(&mut buf)?;
-        println!("buf : {:?}",buf);
-        let utf8 = String::from_utf8(buf)?;
-        println!("utf : {:?}",utf8);
-        Ok(utf8)
-    }
-    fn decode_pairs(&mut self) -> DecodeResult<Vec<Pair<String, Value>>> {
+        self.budget.string(len)?;
+        let offset = self.inner.offset;
+        let buf = self.inner.read_up_to(len)?;
+        if buf.len() < len && !self.warn(self.inner.offset, WarningKind::Truncated) {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        match String::from_utf8(buf) {
+            Ok(utf8) => Ok(utf8),
+            Err(e) if self.warn(offset, WarningKind::InvalidUtf8) => {
+                Ok(String::from_utf8_lossy(e.as_bytes()).into_owned())
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+    fn decode_pairs<B: Build>(&mut self, b: &mut B) -> DecodeResult<Vec<Pair<String, B::Value>>> {
         let mut entries = Vec::new();
         loop {
-            let len = self.inner.read_u16::<BigEndian>()? as usize;
-            let key = self.read_utf8(len)?;
-            match self.decode_value() {
-                Ok(value) => {
-                    entries.push(Pair { key, value });
+            match self.decode_pair(b) {
+                Ok(Some(pair)) => {
+                    self.budget
+                        .entry::<Pair<String, B::Value>>(entries.len() + 1)?;
+                    entries.push(pair);
                 }
-                Err(DecodeError::UnexpectedObjectEnd) if key.is_empty() => break,
+                Ok(None) => break,
+                Err(ref e) if self.truncates(e) => break,
                 Err(e) => return Err(e),
             }
         }
         Ok(entries)
     }
-    fn decode_complex_type<F>(&mut self, f: F) -> DecodeResult<Value>
+    // Decodes a pair, or the object end marker as `None`
+    fn decode_pair<B: Build>(&mut self, b: &mut B) -> DecodeResult<Option<Pair<String, B::Value>>> {
+        let len = self.inner.read_u16::<BigEndian>()? as usize;
+        let key = self.read_utf8(len)?;
+        let (offset, marker) = self.read_marker()?;
+        if key.is_empty() && marker == marker::OBJECT_END_MARKER {
+            return Ok(None);
+        }
+        match self.decode_value_with_marker(b, offset, marker) {
+            Ok(value) => Ok(Some(Pair { key, value })),
+            Err(e) => {
+                Location::prepend(&mut self.location, PathSegment::Key(key));
+                Err(e)
+            }
+        }
+    }
+    // Records a warning in the lenient mode, returning `false` in the strict mode
+    fn warn(&mut self, offset: u64, kind: WarningKind) -> bool {
+        match self.warnings {
+            Some(ref mut warnings) => {
+                warnings.push(Warning { offset, kind });
+                true
+            }
+            None => false,
+        }
+    }
+    // Returns `true` if the contents of a container decoded so far should be kept
+    // because the input ends in the lenient mode
+    fn truncates(&mut self, e: &DecodeError) -> bool {
+        let is_eof = match *e {
+            DecodeError::Io(ref e) => e.kind() == io::ErrorKind::UnexpectedEof,
+            _ => false,
+        };
+        // The enclosing containers of a truncated value also end there, which is warned only once
+        let is_warned = self
+            .warnings
+            .iter()
+            .flatten()
+            .any(|w| w.kind == WarningKind::Truncated);
+        let truncates =
+            is_eof && (is_warned || self.warn(self.inner.offset, WarningKind::Truncated));
+        if truncates {
+            // The error is worked around, so it has no location
+            self.location = None;
+        }
+        truncates
+    }
+    fn decode_complex_type<B, F>(&mut self, b: &mut B, f: F) -> DecodeResult<B::Value>
     where
-        F: FnOnce(&mut Self) -> DecodeResult<Value>,
+        B: Build,
+        F: FnOnce(&mut Self, &mut B) -> DecodeResult<Complex<B::Value>>,
     {
+        self.budget.enter()?;
         let index = self.complexes.len();
-        self.complexes.push(Value::Null);
-        let value = f(self)?;
-        self.complexes[index] = value.clone();
-        Ok(value)
+        let result = b.complex(self, f);
+        self.budget.leave();
+        if result.is_err() {
+            // Drops the entries of the value and those decoded under it
+            self.complexes.truncate(index);
+        }
+        result
     }
 }
 
 #[cfg(test)]
 mod tests {
     #![allow(clippy::approx_constant)]
-    use super::super::marker;
-    use super::super::Value;
+    use super::super::{graph, shared};
+    use super::super::{Decoder, Encoder, Reader, SliceDecoder, Value, ValueRef};
     use crate::amf3;
-    use crate::error::DecodeError;
-    use crate::Pair;
+    use crate::error::{DecodeError, Limit, Location, Path, PathSegment, Warning, WarningKind};
+    use crate::io;
+    use crate::{DecodeResult, DecoderLimits, Pair};
     use std::f64;
-    use std::io;
-    use std::iter;
+    use std::sync::Arc;
     use std::time;
 
     macro_rules! decode {
@@ -525,10 +734,7 @@ mod tests {
     }
     #[test]
     fn decodes_long_string() {
-        decode_eq!(
-            "amf0-long-string.bin",
-            Value::String(iter::repeat('a').take(0x10013).collect())
-        );
+        decode_eq!("amf0-long-string.bin", Value::String("a".repeat(0x10013)));
         decode_unexpected_eof!("amf0-long-string-partial.bin");
     }
     #[test]
@@ -571,36 +777,24 @@ mod tests {
     }
     #[test]
     fn decodes_unsupported() {
+        decode_eq!("amf0-movieclip.bin", Value::MovieClip);
+        decode_eq!("amf0-recordset.bin", Value::RecordSet);
+        decode_eq!("amf0-unsupported.bin", Value::Unsupported);
+
+        let input = include_bytes!("../testdata/amf0-unsupported.bin");
         assert_eq!(
-            decode!("amf0-movieclip.bin"),
-            Err(DecodeError::Unsupported {
-                marker: marker::MOVIECLIP
-            })
-        );
-        assert_eq!(
-            decode!("amf0-recordset.bin"),
-            Err(DecodeError::Unsupported {
-                marker: marker::RECORDSET
-            })
-        );
-        assert_eq!(
-            decode!("amf0-unsupported.bin"),
-            Err(DecodeError::Unsupported {
-                marker: marker::UNSUPPORTED
-            })
+            SliceDecoder::new(&input[..]).decode().unwrap(),
+            ValueRef::Unsupported
         );
     }
     #[test]
     fn decodes_ecma_array() {
         let entries = es(&[("0", s("a")), ("1", s("b")), ("2", s("c")), ("3", s("d"))][..]);
-        decode_eq!(
-            "amf0-ecma-ordinal-array.bin",
-            Value::EcmaArray { entries: entries }
-        );
+        decode_eq!("amf0-ecma-ordinal-array.bin", Value::EcmaArray { entries });
         decode_unexpected_eof!("amf0-ecma-array-partial.bin");
 
         let entries = es(&[("c", s("d")), ("a", s("b"))][..]);
-        decode_eq!("amf0-hash.bin", Value::EcmaArray { entries: entries });
+        decode_eq!("amf0-hash.bin", Value::EcmaArray { entries });
     }
     #[test]
     fn decodes_strict_array() {
@@ -611,6 +805,20 @@ mod tests {
             }
         );
         decode_unexpected_eof!("amf0-strict-array-partial.bin");
+
+        // A bogus count does not allocate a buffer for the entries in advance
+        let input = [0x0a, 0xff, 0xff, 0xff, 0xff];
+        match Decoder::with_limits(&input[..], DecoderLimits::unlimited()).decode() {
+            Err(DecodeError::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof),
+            other => panic!("{:?}", other),
+        }
+        // and the default limits reject it before reading the entries
+        assert_eq!(
+            Value::read_from(&mut &input[..]),
+            Err(DecodeError::LimitExceeded {
+                limit: Limit::Allocation
+            })
+        );
     }
     #[test]
     fn decodes_reference() {
@@ -629,6 +837,222 @@ mod tests {
         );
     }
     #[test]
+    fn decodes_entire() {
+        let files: [&[u8]; 6] = [
+            include_bytes!("../testdata/amf0-string.bin"),
+            include_bytes!("../testdata/amf0-ecma-ordinal-array.bin"),
+            include_bytes!("../testdata/amf0-date.bin"),
+            include_bytes!("../testdata/amf0-xml-doc.bin"),
+            include_bytes!("../testdata/amf0-long-string.bin"),
+            include_bytes!("../testdata/amf0-avmplus-object.bin"),
+        ];
+        let input = files.concat();
+        let values = Decoder::new_from_array(&input[..]).decode_entire().unwrap();
+        assert_eq!(values.len(), files.len());
+        let mut offset = 0;
+        for (value, file) in values.iter().zip(files.iter()) {
+            assert_eq!(value.value, Value::read_from(&mut &file[..]).unwrap());
+            assert_eq!(value.offset, offset);
+            assert_eq!(value.len, file.len());
+            offset += file.len();
+        }
+
+        // References are shared by the values
+        let object = Value::Object {
+            class_name: None,
+            entries: vec![Pair {
+                key: "app".to_string(),
+                value: Value::String("live".to_string()),
+            }],
+        };
+        let mut input = Vec::new();
+        let mut encoder = Encoder::new(&mut input);
+        encoder.set_object_references(true);
+        encoder.encode(&object).unwrap();
+        encoder.encode(&object).unwrap();
+        let values = Decoder::new_from_array(&input[..]).decode_entire().unwrap();
+        assert_eq!(values[1].value, object);
+        assert_eq!((values[1].offset, values[1].len), (input.len() - 3, 3));
+
+        // Truncated input
+        let input = [files[0], &files[2][..5]].concat();
+        let mut decoder = Decoder::new_from_array(&input[..]);
+        let result = decoder.decode_entire();
+        assert!(
+            matches!(result, Err(DecodeError::Io(ref e)) if e.kind() == io::ErrorKind::UnexpectedEof)
+        );
+        let location = decoder.error_location().unwrap();
+        assert_eq!(location.offset, files[0].len() as u64);
+        assert_eq!(location.path.to_string(), "$[1]");
+
+        assert_eq!(
+            Decoder::new_from_array(&[][..]).decode_entire().unwrap(),
+            []
+        );
+    }
+    #[test]
+    fn decodes_graph() {
+        let input = include_bytes!("../testdata/amf0-circular-reference.bin");
+        let mut decoder = Decoder::new(&input[..]);
+        let root = decoder.decode_graph().unwrap();
+        let id = match root {
+            graph::Value::Node(id) => id,
+            _ => panic!(),
+        };
+        assert_eq!(
+            *decoder.graph().node(id),
+            graph::Node::Array {
+                entries: vec![graph::Value::Node(id)]
+            }
+        );
+
+        let mut buf = Vec::new();
+        Encoder::new(&mut buf)
+            .encode_graph(decoder.graph(), &root)
+            .unwrap();
+        assert_eq!(buf, &input[..]);
+
+        // Shared objects are decoded as the same node
+        let input = include_bytes!("../testdata/amf0-ref-test.bin");
+        let mut decoder = Decoder::new(&input[..]);
+        let root = decoder.decode_graph().unwrap();
+        let g = decoder.graph();
+        assert_eq!(g.len(), 2);
+        match root {
+            graph::Value::Node(id) => match *g.node(id) {
+                graph::Node::Object { ref entries, .. } => {
+                    assert_eq!(entries[0].value, entries[1].value)
+                }
+                _ => panic!(),
+            },
+            _ => panic!(),
+        }
+    }
+    #[test]
+    fn decodes_shared() {
+        let input = include_bytes!("../testdata/amf0-ref-test.bin");
+        let mut decoder = Decoder::new(&input[..]);
+        let value = decoder.decode_shared().unwrap();
+        match value {
+            shared::Value::Node(ref x) => match **x {
+                shared::Node::Object { ref entries, .. } => {
+                    match (&entries[0].value, &entries[1].value) {
+                        (shared::Value::Node(x), shared::Value::Node(y)) => {
+                            assert!(Arc::ptr_eq(x, y))
+                        }
+                        _ => panic!(),
+                    }
+                }
+                _ => panic!(),
+            },
+            _ => panic!(),
+        }
+        assert_eq!(value.into_owned(), decode!("amf0-ref-test.bin").unwrap());
+
+        let input = include_bytes!("../testdata/amf0-circular-reference.bin");
+        assert_eq!(
+            Decoder::new(&input[..]).decode_shared(),
+            Err(DecodeError::CircularReference { index: 0 })
+        );
+    }
+    #[test]
+    fn decodes_with_limits() {
+        // Checks the decoders, and returns the result of `Reader`
+        fn check(input: &[u8], limits: DecoderLimits, limit: Limit) -> DecodeResult<()> {
+            let expected = Err(DecodeError::LimitExceeded { limit });
+            assert_eq!(Decoder::with_limits(input, limits).decode(), expected);
+            assert_eq!(
+                SliceDecoder::with_limits(input, limits)
+                    .decode()
+                    .map(ValueRef::into_owned),
+                expected
+            );
+            Reader::with_limits(input, limits)
+                .collect::<DecodeResult<Vec<_>>>()
+                .map(|_| ())
+        }
+        fn exceeded(limit: Limit) -> DecodeResult<()> {
+            Err(DecodeError::LimitExceeded { limit })
+        }
+        let unlimited = DecoderLimits::unlimited();
+
+        // The depth includes the AMF3 values embedded in `AvmPlus`
+        let input = b"\x03\x00\x01a\x11\x09\x01\x01\x00\x00\x09";
+        let limits = DecoderLimits {
+            max_depth: 1,
+            ..unlimited
+        };
+        assert_eq!(check(input, limits, Limit::Depth), exceeded(Limit::Depth));
+        let limits = DecoderLimits {
+            max_depth: 2,
+            ..unlimited
+        };
+        assert!(Decoder::with_limits(&input[..], limits).decode().is_ok());
+
+        let limits = DecoderLimits {
+            max_string_len: 3,
+            ..unlimited
+        };
+        assert_eq!(
+            check(b"\x02\x00\x04abcd", limits, Limit::StringLength),
+            exceeded(Limit::StringLength)
+        );
+
+        let limits = DecoderLimits {
+            max_collection_len: 3,
+            ..unlimited
+        };
+        assert_eq!(
+            check(b"\x0a\x00\x00\x00\x04", limits, Limit::CollectionLength),
+            exceeded(Limit::CollectionLength)
+        );
+
+        let limits = DecoderLimits {
+            max_allocation: 7,
+            ..unlimited
+        };
+        assert_eq!(
+            check(b"\x0c\x00\x00\x00\x08abcdefgh", limits, Limit::Allocation),
+            exceeded(Limit::Allocation)
+        );
+
+        // A strict array which contains a long string in 100 nested arrays and `n` references to them
+        let copies = |n: u8| {
+            let mut input = vec![0x0a, 0x00, 0x00, 0x00, n + 1];
+            input.extend_from_slice(&b"\x0a\x00\x00\x00\x01".repeat(100));
+            input.extend_from_slice(b"\x0c\x00\x00\x03\xe8");
+            input.extend_from_slice(&[b'a'; 1000]);
+            input.extend_from_slice(&b"\x07\x00\x01".repeat(n as usize));
+            input
+        };
+        let limits = DecoderLimits {
+            max_allocation: 32 * 1024,
+            ..DecoderLimits::default()
+        };
+        assert!(Decoder::with_limits(&copies(1)[..], limits)
+            .decode()
+            .is_ok());
+        // The copies are counted in the allocation
+        assert_eq!(check(&copies(8), limits, Limit::Allocation), Ok(()));
+
+        // A strict array which contains the same empty object twice
+        let input = b"\x0a\x00\x00\x00\x02\x03\x00\x00\x09\x07\x00\x01";
+        let limits = DecoderLimits {
+            max_reference_expansions: 0,
+            ..unlimited
+        };
+        // `Reader` does not copy the referred values
+        assert_eq!(check(input, limits, Limit::ReferenceExpansions), Ok(()));
+        assert!(Decoder::with_limits(&input[..], limits)
+            .decode_shared()
+            .is_ok());
+        let limits = DecoderLimits {
+            max_reference_expansions: 1,
+            ..unlimited
+        };
+        assert!(Decoder::with_limits(&input[..], limits).decode().is_ok());
+    }
+    #[test]
     fn decodes_date() {
         decode_eq!(
             "amf0-date.bin",
@@ -662,7 +1086,212 @@ mod tests {
             assoc_entries: vec![],
             dense_entries: (1..4).map(amf3::Value::Integer).collect(),
         };
-        decode_eq!("amf0-avmplus-object.bin", Value::AvmPlus(expected));
+        decode_eq!("amf0-avmplus-object.bin", Value::AvmPlus(expected.clone()));
+
+        // The embedded AMF3 values are read through the buffer of the AMF0 decoder
+        let mut input = include_bytes!("../testdata/amf0-avmplus-object.bin").to_vec();
+        input.push(0x05);
+        let mut decoder = Decoder::buffered(&input[..]);
+        assert_eq!(decoder.decode(), Ok(Value::AvmPlus(expected)));
+        assert_eq!(decoder.buffer(), b"\x05");
+        assert_eq!(decoder.decode(), Ok(Value::Null));
+
+        // The AMF3 reference tables are shared by the sibling values
+        let input = b"\x0a\x00\x00\x00\x02\x11\x06\x07foo\x11\x06\x00";
+        let foo = Value::AvmPlus(amf3::Value::String("foo".to_string()));
+        assert_eq!(
+            Value::read_from(&mut &input[..]).unwrap(),
+            Value::Array {
+                entries: vec![foo.clone(), foo]
+            }
+        );
+    }
+    #[test]
+    fn locates_errors() {
+        // [1.0, {price: <unknown marker>}]
+        let input =
+            b"\x0a\x00\x00\x00\x02\x00\x3f\xf0\x00\x00\x00\x00\x00\x00\x03\x00\x05price\x20";
+        let expected = Location {
+            offset: 22,
+            path: Path {
+                segments: vec![PathSegment::Index(1), PathSegment::Key("price".to_string())],
+            },
+        };
+        let mut decoder = Decoder::new(&input[..]);
+        assert_eq!(decoder.decode(), Err(DecodeError::Unknown { marker: 32 }));
+        assert_eq!(decoder.error_location(), Some(&expected));
+        assert_eq!(expected.to_string(), "offset=22, path=$[1].price");
+        let mut decoder = SliceDecoder::new(&input[..]);
+        assert_eq!(decoder.decode(), Err(DecodeError::Unknown { marker: 32 }));
+        assert_eq!(decoder.error_location(), Some(&expected));
+
+        // The offsets of the AMF3 values embedded in `AvmPlus` are counted from the AMF0 input
+        let input = b"\x03\x00\x08the keys\x11\x09\x03\x01\x7b";
+        let expected = Location {
+            offset: 15,
+            path: Path {
+                segments: vec![
+                    PathSegment::Key("the keys".to_string()),
+                    PathSegment::Index(0),
+                ],
+            },
+        };
+        let mut decoder = Decoder::new(&input[..]);
+        assert_eq!(decoder.decode(), Err(DecodeError::Unknown { marker: 123 }));
+        assert_eq!(decoder.error_location(), Some(&expected));
+        assert_eq!(expected.path.to_string(), "$[\"the keys\"][0]");
+        let mut decoder = SliceDecoder::new(&input[..]);
+        assert_eq!(decoder.decode(), Err(DecodeError::Unknown { marker: 123 }));
+        assert_eq!(decoder.error_location(), Some(&expected));
+
+        // A successful call clears the location
+        let input = b"\x20\x05";
+        let mut decoder = Decoder::new(&input[..]);
+        assert!(decoder.decode().is_err());
+        assert_eq!(decoder.error_location().map(|l| l.offset), Some(0));
+        assert_eq!(decoder.decode(), Ok(Value::Null));
+        assert_eq!(decoder.error_location(), None);
+    }
+    #[test]
+    fn decodes_leniently() {
+        let warning = |offset, kind| Warning { offset, kind };
+
+        // {"a\xC0": "xyz"} in an ECMA array which declares 5 entries
+        let input = b"\x08\x00\x00\x00\x05\x00\x02a\xc0\x02\x00\x03xyz\x00\x00\x09";
+        let lenient = Decoder::new(&input[..]).decode_lenient().unwrap();
+        assert_eq!(
+            lenient.value,
+            Value::EcmaArray {
+                entries: es(&[("a\u{FFFD}", s("xyz"))])
+            }
+        );
+        assert_eq!(
+            lenient.warnings,
+            [
+                warning(7, WarningKind::InvalidUtf8),
+                warning(
+                    1,
+                    WarningKind::EcmaArrayLength {
+                        declared: 5,
+                        actual: 1
+                    }
+                ),
+            ]
+        );
+
+        // {}, stray bytes after the object end marker, and null
+        let input = b"\x03\x00\x00\x09\xff\x09\x05";
+        let mut decoder = Decoder::new(&input[..]);
+        let lenient = decoder.decode_lenient().unwrap();
+        assert_eq!(lenient.value, obj(None, &[]));
+        assert_eq!(lenient.warnings, []);
+        let lenient = decoder.decode_lenient().unwrap();
+        assert_eq!(lenient.value, Value::Null);
+        assert_eq!(
+            lenient.warnings,
+            [
+                warning(4, WarningKind::SkippedMarker { marker: 0xff }),
+                warning(5, WarningKind::SkippedMarker { marker: 0x09 }),
+            ]
+        );
+
+        // {a: "xyz", b: [1.0, <truncated number>]}
+        let input = b"\x03\x00\x01a\x02\x00\x03xyz\x00\x01b\x0a\x00\x00\x00\x02\x00\x3f\xf0\x00\x00\x00\x00\x00\x00\x00\x40";
+        let lenient = Decoder::new(&input[..]).decode_lenient().unwrap();
+        assert_eq!(
+            lenient.value,
+            obj(
+                None,
+                &[
+                    ("a", s("xyz")),
+                    (
+                        "b",
+                        Value::Array {
+                            entries: vec![n(1.0)]
+                        }
+                    )
+                ]
+            )
+        );
+        assert_eq!(lenient.warnings, [warning(29, WarningKind::Truncated)]);
+
+        // A truncated string keeps the bytes before the end
+        let input = b"\x02\x00\x05abc";
+        let lenient = Decoder::new(&input[..]).decode_lenient().unwrap();
+        assert_eq!(lenient.value, s("abc"));
+        assert_eq!(lenient.warnings, [warning(6, WarningKind::Truncated)]);
+
+        // The strings of the AMF3 values are also decoded with replacement characters
+        let input = b"\x11\x06\x07a\xc0b";
+        let lenient = Decoder::new(&input[..]).decode_lenient().unwrap();
+        assert_eq!(
+            lenient.value,
+            Value::AvmPlus(amf3::Value::String("a\u{FFFD}b".to_string()))
+        );
+        assert_eq!(lenient.warnings, [warning(3, WarningKind::InvalidUtf8)]);
+
+        // A truncated top-level number cannot be salvaged
+        let input = b"\x00\x3f\xf0";
+        let error = Decoder::new(&input[..]).decode_lenient().unwrap_err();
+        match error {
+            DecodeError::Io(e) => assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof),
+            e => panic!("{:?}", e),
+        }
+
+        // The strict mode is unaffected
+        let input = b"\xff\x05";
+        assert_eq!(
+            Decoder::new(&input[..]).decode(),
+            Err(DecodeError::Unknown { marker: 0xff })
+        );
+    }
+    #[test]
+    fn drops_failed_nodes() {
+        // An empty strict array and a truncated one in a strict array
+        let input = b"\x0a\x00\x00\x00\x02\x0a\x00\x00\x00\x00\x0a\x00";
+        let mut decoder = Decoder::new(&input[..]);
+        assert!(decoder.decode_graph().is_err());
+        assert!(decoder.graph().is_empty());
+
+        // The failed arrays are not in the reference table
+        *decoder.inner_mut() = &b"\x07\x00\x00"[..];
+        assert_eq!(
+            decoder.decode_graph(),
+            Err(DecodeError::OutOfRangeReference { index: 0 })
+        );
+    }
+    #[test]
+    fn decodes_trees_with_one_node_per_value() {
+        // An empty object and three references to it
+        let input = b"\x03\x00\x00\x09\x07\x00\x00\x07\x00\x00\x07\x00\x00";
+        let empty = Value::Object {
+            class_name: None,
+            entries: Vec::new(),
+        };
+        let mut decoder = Decoder::new(&input[..]);
+        assert_eq!(decoder.decode(), Ok(empty.clone()));
+        assert_eq!(decoder.decode(), Ok(empty));
+        assert_eq!(decoder.graph().len(), 1);
+
+        // `decode_graph` shares the node with `decode`
+        let node = decoder.decode_graph().unwrap();
+        assert_eq!(decoder.decode_graph(), Ok(node));
+        assert_eq!(decoder.graph().len(), 1);
+
+        // A string in 100 nested arrays is stored once, not once per level
+        let mut input = b"\x0a\x00\x00\x00\x01".repeat(100);
+        input.extend_from_slice(b"\x02\x00\x32");
+        input.extend_from_slice(&[b'a'; 50]);
+        let mut decoder = Decoder::new(&input[..]);
+        let mut value = decoder.decode().unwrap();
+        for _ in 0..100 {
+            match value {
+                Value::Array { mut entries } => value = entries.pop().unwrap(),
+                _ => panic!(),
+            }
+        }
+        assert_eq!(value, Value::String("a".repeat(50)));
+        assert_eq!(decoder.graph().len(), 100);
     }
     #[test]
     fn other_errors() {
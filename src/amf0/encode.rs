@@ -1,21 +1,83 @@
+use super::graph::{self, Graph, Node, NodeId};
 use super::marker;
 use super::Value;
-use crate::amf3;
-use crate::Pair;
-use byteorder::{BigEndian, WriteBytesExt};
-use std::io;
-use std::time;
+use crate::amf3::{self, ExternalizableRegistry};
+use crate::error::EncodeError;
+use crate::io::{self, WriteBytesExt};
+use crate::util::{unix_millis, ByteCounter, Shapes};
+use crate::wire::{Tape, WireDetail};
+use crate::{ArrayPolicy, EncodeResult, EncoderOptions, LongStringPolicy, Map, Pair, WireFormat};
+use alloc::string::String;
+use alloc::string::ToString;
+use alloc::vec::Vec;
+use byteorder::BigEndian;
+use core::mem;
+use core::time;
 
 /// AMF0 encoder.
 #[derive(Debug)]
 pub struct Encoder<W> {
-    inner: W,
+    pub(super) inner: W,
+    // The indices of the objects and arrays by the identifiers of their structures
+    complexes: Map<usize, usize>,
+    shapes: Shapes,
+    // `true` for an encoder which writes the key of an object or an array in `shapes`
+    writes_key: bool,
+    nodes: Map<NodeId, usize>,
+    pub(super) complex_count: usize,
+    object_references: bool,
+    // `Some` while encoding in the exact mode
+    wire: Option<Tape>,
+    // The indices of the objects and arrays referred by the wire details being replayed,
+    // mapped to the keys of those values once encoded
+    referenced: Map<usize, Option<usize>>,
+    // The AMF3 encoder is taken out while it encodes an `AvmPlus` value
+    amf3: Option<amf3::Encoder<io::Sink>>,
+    // The keys added to `complexes` while computing `encoded_len`, which are removed afterwards
+    journal: Option<Vec<usize>>,
 }
 impl<W> Encoder<W> {
     /// Unwraps this `Encoder`, returning the underlying writer.
     pub fn into_inner(self) -> W {
         self.inner
     }
+
+    /// Returns the number of the bytes which `encode` writes for `value` in the current state.
+    ///
+    /// Unlike `encode`, this neither writes the value nor updates the reference tables,
+    /// so the length can be written before the value (e.g., as a length prefix).
+    /// The entries added to the reference tables while counting,
+    /// including those of the AMF3 values, are removed afterwards.
+    pub fn encoded_len(&mut self, value: &Value) -> EncodeResult<usize> {
+        let mut counter = Encoder {
+            inner: ByteCounter::default(),
+            complexes: mem::take(&mut self.complexes),
+            shapes: mem::take(&mut self.shapes),
+            writes_key: false,
+            nodes: Map::new(),
+            complex_count: self.complex_count,
+            object_references: self.object_references,
+            wire: None,
+            referenced: Map::new(),
+            amf3: Some(self.amf3_mut().start_counting(io::sink())),
+            journal: Some(Vec::new()),
+        };
+        let result = counter.encode(value);
+        for key in counter.journal.take().unwrap_or_default() {
+            counter.complexes.remove(&key);
+        }
+        self.complexes = counter.complexes;
+        self.shapes = mem::take(&mut counter.shapes);
+        let amf3 = counter.amf3.take().expect("the AMF3 encoder is put back");
+        self.amf3_mut().finish_counting(amf3);
+        result.map(|()| counter.inner.count)
+    }
+    fn amf3(&self) -> &amf3::Encoder<io::Sink> {
+        self.amf3.as_ref().expect("the AMF3 encoder is put back")
+    }
+    fn amf3_mut(&mut self) -> &mut amf3::Encoder<io::Sink> {
+        self.amf3.as_mut().expect("the AMF3 encoder is put back")
+    }
 }
 impl<W> Encoder<W>
 where
@@ -23,22 +85,131 @@ where
 {
     /// Makes a new instance.
     pub fn new(inner: W) -> Self {
-        Encoder { inner }
+        Self::with_options(inner, EncoderOptions::default())
+    }
+
+    /// Makes a new instance with the specified options.
+    ///
+    /// The same options are applied to the AMF3 values embedded in `AvmPlus`.
+    pub fn with_options(inner: W, options: EncoderOptions) -> Self {
+        Encoder {
+            inner,
+            complexes: Map::new(),
+            shapes: Shapes::default(),
+            writes_key: false,
+            nodes: Map::new(),
+            complex_count: 0,
+            object_references: false,
+            wire: None,
+            referenced: Map::new(),
+            amf3: Some(amf3::Encoder::with_options(io::sink(), options)),
+            journal: None,
+        }
+    }
+
+    /// Enables or disables the emission of object references.
+    ///
+    /// If enabled, an object or an array which is structurally equal to
+    /// a previously encoded one is written as a reference to it.
+    /// The same setting is applied to the AMF3 values embedded in `AvmPlus`.
+    ///
+    /// This is disabled by default because finding the repeated values
+    /// requires extra computation and memory proportional to the size of the encoded values.
+    pub fn set_object_references(&mut self, enabled: bool) {
+        self.object_references = enabled;
+        self.amf3_mut().set_object_references(enabled);
+    }
+
+    /// Returns a mutable reference to the registry of the externalizable class handlers
+    /// used to encode the AMF3 values embedded in `AvmPlus`.
+    pub fn externalizables_mut(&mut self) -> &mut ExternalizableRegistry {
+        self.amf3_mut().externalizables_mut()
+    }
+
+    /// Returns an immutable reference to the options of this encoder.
+    pub fn options(&self) -> &EncoderOptions {
+        self.amf3().options()
+    }
+
+    /// Returns a mutable reference to the options of this encoder.
+    pub fn options_mut(&mut self) -> &mut EncoderOptions {
+        self.amf3_mut().options_mut()
     }
+
     /// Encodes a AMF0 value.
-    pub fn encode(&mut self, value: &Value) -> io::Result<()> {
+    pub fn encode(&mut self, value: &Value) -> EncodeResult<()> {
+        let result = self.encode_value(value);
+        self.shapes.forget_addresses();
+        result
+    }
+    fn encode_value(&mut self, value: &Value) -> EncodeResult<()> {
+        if self.writes_key && self.complex_count > 0 && is_complex(value) {
+            if let Some(id) = self.shapes.get(value) {
+                self.inner.write_u8(Shapes::MEMBER)?;
+                self.inner.write_all(&(id as u64).to_be_bytes())?;
+                return Ok(());
+            }
+        }
+        let detail = self.wire.as_mut().and_then(|wire| {
+            wire.next_site();
+            wire.find(Some)
+        });
+        if self.wire.is_some() && is_complex(value) {
+            if let Some(WireDetail::Reference(index)) = detail {
+                if self.is_replayed_reference(index, value)? {
+                    self.inner.write_u8(marker::REFERENCE)?;
+                    self.inner.write_u16::<BigEndian>(index as u16)?;
+                    return Ok(());
+                }
+            }
+            if self.referenced.contains_key(&self.complex_count) {
+                let key = self.reference_key(value)?;
+                self.referenced.insert(self.complex_count, Some(key));
+            }
+        } else if self.object_references && is_complex(value) {
+            let key = self.reference_key(value)?;
+            match self.complexes.get(&key) {
+                Some(&index) if index <= 0xFFFF => {
+                    self.inner.write_u8(marker::REFERENCE)?;
+                    self.inner.write_u16::<BigEndian>(index as u16)?;
+                    return Ok(());
+                }
+                Some(_) => {}
+                None => {
+                    if let Some(ref mut journal) = self.journal {
+                        journal.push(key);
+                    }
+                    self.complexes.insert(key, self.complex_count);
+                }
+            }
+        }
+        if is_complex(value) {
+            self.complex_count += 1;
+        }
         match *value {
             Value::Number(x) => self.encode_number(x),
             Value::Boolean(x) => self.encode_boolean(x),
+            Value::String(ref x) if detail == Some(WireDetail::LongString) => {
+                self.encode_long_string(x)
+            }
             Value::String(ref x) => self.encode_string(x),
             Value::Object {
                 ref class_name,
                 ref entries,
-            } => self.encode_object(class_name, entries),
+            } => self.encode_object(class_name, entries, Self::encode_value),
             Value::Null => self.encode_null(),
             Value::Undefined => self.encode_undefined(),
-            Value::EcmaArray { ref entries } => self.encode_ecma_array(entries),
-            Value::Array { ref entries } => self.encode_strict_array(entries),
+            Value::Unsupported => self.encode_marker(marker::UNSUPPORTED),
+            Value::MovieClip => self.encode_marker(marker::MOVIECLIP),
+            Value::RecordSet => self.encode_marker(marker::RECORDSET),
+            Value::EcmaArray { ref entries } => {
+                let declared = match detail {
+                    Some(WireDetail::EcmaArrayLength(len)) => Some(len),
+                    _ => None,
+                };
+                self.encode_ecma_array(entries, declared, Self::encode_value)
+            }
+            Value::Array { ref entries } => self.encode_strict_array(entries, Self::encode_value),
             Value::Date {
                 unix_time,
                 time_zone,
@@ -48,100 +219,329 @@ where
         }
     }
 
-    fn encode_number(&mut self, n: f64) -> io::Result<()> {
+    /// Encodes a AMF0 value reproducing its wire details recorded by `Decoder::decode_exact`.
+    ///
+    /// The options of this encoder and `set_object_references` are not applied.
+    /// The AMF3 values embedded in `AvmPlus` are also encoded in this way.
+    /// See `WireFormat` for the details which are reproduced.
+    pub fn encode_exact(&mut self, value: &Value, wire: &WireFormat) -> EncodeResult<()> {
+        let tape = Tape::replay(wire);
+        self.referenced = tape.references(|d| match d {
+            WireDetail::Reference(index) => Some(index),
+            _ => None,
+        });
+        self.amf3_mut().referenced = tape.references(|d| match d {
+            WireDetail::ObjectReference(index) => Some(index),
+            _ => None,
+        });
+        self.wire = Some(tape);
+        let options = mem::take(self.options_mut());
+        let result = self.encode(value);
+        *self.options_mut() = options;
+        self.wire = None;
+        self.referenced.clear();
+        self.amf3_mut().referenced.clear();
+        result
+    }
+
+    /// Encodes a AMF0 value in the canonical form.
+    ///
+    /// The value is normalized by `Value::canonicalize`, and written without the options of this encoder
+    /// and `set_object_references`, so that equal values are written in identical bytes.
+    /// The AMF3 values embedded in `AvmPlus` are written as `amf3::Encoder::encode_canonical` does,
+    /// thus this holds as long as the reference tables are in the same state
+    /// (e.g., the values are the first ones after `clear_reference_table`).
+    pub fn encode_canonical(&mut self, value: &Value) -> EncodeResult<()> {
+        let mut value = value.clone();
+        value.canonicalize();
+        let options = mem::take(self.options_mut());
+        let object_references = self.object_references;
+        self.set_object_references(false);
+        let result = self.encode(&value);
+        *self.options_mut() = options;
+        self.set_object_references(object_references);
+        result
+    }
+
+    /// Encodes a AMF0 value in an object graph.
+    ///
+    /// A node which has already been written by this encoder is encoded as a reference,
+    /// so shared and circular references are preserved.
+    /// If the index of such a reference does not fit in 16 bits,
+    /// `EncodeError::OutOfRangeReference` will be returned.
+    pub fn encode_graph(&mut self, graph: &Graph, value: &graph::Value) -> EncodeResult<()> {
+        let id = match *value {
+            graph::Value::Number(x) => return self.encode_number(x),
+            graph::Value::Boolean(x) => return self.encode_boolean(x),
+            graph::Value::String(ref x) => return self.encode_string(x),
+            graph::Value::Null => return self.encode_null(),
+            graph::Value::Undefined => return self.encode_undefined(),
+            graph::Value::Unsupported => return self.encode_marker(marker::UNSUPPORTED),
+            graph::Value::MovieClip => return self.encode_marker(marker::MOVIECLIP),
+            graph::Value::RecordSet => return self.encode_marker(marker::RECORDSET),
+            graph::Value::Date {
+                unix_time,
+                time_zone,
+            } => return self.encode_date(unix_time, time_zone),
+            graph::Value::XmlDocument(ref x) => return self.encode_xml_document(x),
+            graph::Value::AvmPlus(ref x) => {
+                self.inner.write_u8(marker::AVMPLUS_OBJECT)?;
+                return self.with_amf3(|encoder| encoder.encode_graph(graph.avmplus(), x));
+            }
+            graph::Value::Node(id) => id,
+        };
+        if let Some(&index) = self.nodes.get(&id) {
+            if index > 0xFFFF {
+                return Err(EncodeError::OutOfRangeReference { index });
+            }
+            self.inner.write_u8(marker::REFERENCE)?;
+            self.inner.write_u16::<BigEndian>(index as u16)?;
+            return Ok(());
+        }
+        self.nodes.insert(id, self.complex_count);
+        self.complex_count += 1;
+
+        let f = |this: &mut Self, v: &graph::Value| this.encode_graph(graph, v);
+        match *graph.node(id) {
+            Node::Object {
+                ref class_name,
+                ref entries,
+            } => self.encode_object(class_name, entries, f),
+            Node::EcmaArray { ref entries } => self.encode_ecma_array(entries, None, f),
+            Node::Array { ref entries } => self.encode_strict_array(entries, f),
+        }
+    }
+
+    /// Clear the reference table of this encoder.
+    ///
+    /// The reference tables of the AMF3 values embedded in `AvmPlus` are also cleared.
+    ///
+    /// > Note that object reference indices are local to each message body.
+    /// > Serializers and deserializers must reset reference indices to 0 each time a new message is processed.
+    /// >
+    /// > [AMF 0 Specification: 4.1.3 AMF Message](http://download.macromedia.com/pub/labs/amf/amf0_spec_121207.pdf)
+    pub fn clear_reference_table(&mut self) {
+        self.complexes.clear();
+        self.shapes.clear();
+        self.nodes.clear();
+        self.complex_count = 0;
+        self.amf3_mut().clear_reference_table();
+    }
+
+    pub(super) fn encode_number(&mut self, n: f64) -> EncodeResult<()> {
         self.inner.write_u8(marker::NUMBER)?;
         self.inner.write_f64::<BigEndian>(n)?;
         Ok(())
     }
-    fn encode_boolean(&mut self, b: bool) -> io::Result<()> {
+    pub(super) fn encode_boolean(&mut self, b: bool) -> EncodeResult<()> {
         self.inner.write_u8(marker::BOOLEAN)?;
         self.inner.write_u8(b as u8)?;
         Ok(())
     }
-    fn encode_string(&mut self, s: &str) -> io::Result<()> {
-        if s.len() <= 0xFFFF {
+    pub(super) fn encode_string(&mut self, s: &str) -> EncodeResult<()> {
+        let is_long = self.options().long_strings == LongStringPolicy::Always;
+        if s.len() <= 0xFFFF && !is_long {
             self.inner.write_u8(marker::STRING)?;
             self.write_str_u16(s)?;
+            Ok(())
         } else {
-            self.inner.write_u8(marker::LONG_STRING)?;
-            self.write_str_u32(s)?;
+            self.encode_long_string(s)
+        }
+    }
+    fn encode_long_string(&mut self, s: &str) -> EncodeResult<()> {
+        if s.len() > 0xFFFF_FFFF {
+            return Err(EncodeError::StringTooLong { len: s.len() });
         }
+        self.inner.write_u8(marker::LONG_STRING)?;
+        self.write_str_u32(s)?;
         Ok(())
     }
-    fn encode_object(
+    fn encode_object<T, F>(
         &mut self,
         class_name: &Option<String>,
-        entries: &[Pair<String, Value>],
-    ) -> io::Result<()> {
-        assert!(entries.len() <= 0xFFFF_FFFF);
+        entries: &[Pair<String, T>],
+        mut f: F,
+    ) -> EncodeResult<()>
+    where
+        F: FnMut(&mut Self, &T) -> EncodeResult<()>,
+    {
         if let Some(class_name) = class_name.as_ref() {
             self.inner.write_u8(marker::TYPED_OBJECT)?;
             self.write_str_u16(class_name)?;
         } else {
             self.inner.write_u8(marker::OBJECT)?;
         }
-        self.encode_pairs(entries)?;
+        self.encode_pairs(entries, &mut f)?;
         Ok(())
     }
-    fn encode_null(&mut self) -> io::Result<()> {
+    pub(super) fn encode_null(&mut self) -> EncodeResult<()> {
         self.inner.write_u8(marker::NULL)?;
         Ok(())
     }
-    fn encode_undefined(&mut self) -> io::Result<()> {
+    pub(super) fn encode_undefined(&mut self) -> EncodeResult<()> {
         self.inner.write_u8(marker::UNDEFINED)?;
         Ok(())
     }
-    fn encode_ecma_array(&mut self, entries: &[Pair<String, Value>]) -> io::Result<()> {
-        assert!(entries.len() <= 0xFFFF_FFFF);
+    // Encodes a value which consists of only its marker
+    pub(super) fn encode_marker(&mut self, marker: u8) -> EncodeResult<()> {
+        self.inner.write_u8(marker)?;
+        Ok(())
+    }
+    // `declared` is the length written instead of the number of the entries
+    fn encode_ecma_array<T, F>(
+        &mut self,
+        entries: &[Pair<String, T>],
+        declared: Option<u32>,
+        mut f: F,
+    ) -> EncodeResult<()>
+    where
+        F: FnMut(&mut Self, &T) -> EncodeResult<()>,
+    {
+        let len = collection_len(entries.len())?;
+        let is_dense = entries
+            .iter()
+            .enumerate()
+            .all(|(i, e)| e.key == i.to_string());
+        if self.options().arrays == ArrayPolicy::Strict && is_dense && declared.is_none() {
+            self.inner.write_u8(marker::STRICT_ARRAY)?;
+            self.inner.write_u32::<BigEndian>(len)?;
+            for e in entries {
+                f(self, &e.value)?;
+            }
+            return Ok(());
+        }
         self.inner.write_u8(marker::ECMA_ARRAY)?;
-        self.inner.write_u32::<BigEndian>(entries.len() as u32)?;
-        self.encode_pairs(entries)?;
+        self.inner.write_u32::<BigEndian>(declared.unwrap_or(len))?;
+        self.encode_pairs(entries, &mut f)?;
         Ok(())
     }
-    fn encode_strict_array(&mut self, entries: &[Value]) -> io::Result<()> {
-        assert!(entries.len() <= 0xFFFF_FFFF);
+    fn encode_strict_array<T, F>(&mut self, entries: &[T], mut f: F) -> EncodeResult<()>
+    where
+        F: FnMut(&mut Self, &T) -> EncodeResult<()>,
+    {
+        let len = collection_len(entries.len())?;
+        if self.options().arrays == ArrayPolicy::Ecma {
+            self.inner.write_u8(marker::ECMA_ARRAY)?;
+            self.inner.write_u32::<BigEndian>(len)?;
+            for (i, e) in entries.iter().enumerate() {
+                self.write_str_u16(&i.to_string())?;
+                f(self, e)?;
+            }
+            self.inner.write_u16::<BigEndian>(0)?;
+            self.inner.write_u8(marker::OBJECT_END_MARKER)?;
+            return Ok(());
+        }
         self.inner.write_u8(marker::STRICT_ARRAY)?;
-        self.inner.write_u32::<BigEndian>(entries.len() as u32)?;
+        self.inner.write_u32::<BigEndian>(len)?;
         for e in entries {
-            self.encode(e)?;
+            f(self, e)?;
         }
         Ok(())
     }
-    fn encode_date(&mut self, unix_time: time::Duration, time_zone: i16) -> io::Result<()> {
-        let millis = unix_time.as_secs() * 1000 + (unix_time.subsec_nanos() as u64) / 1_000_000;
+    pub(super) fn encode_date(
+        &mut self,
+        unix_time: time::Duration,
+        time_zone: i16,
+    ) -> EncodeResult<()> {
+        let millis = unix_millis(unix_time)?;
 
         self.inner.write_u8(marker::DATE)?;
         self.inner.write_f64::<BigEndian>(millis as f64)?;
         self.inner.write_i16::<BigEndian>(time_zone)?;
         Ok(())
     }
-    fn encode_xml_document(&mut self, xml: &str) -> io::Result<()> {
+    pub(super) fn encode_xml_document(&mut self, xml: &str) -> EncodeResult<()> {
+        if xml.len() > 0xFFFF_FFFF {
+            return Err(EncodeError::StringTooLong { len: xml.len() });
+        }
         self.inner.write_u8(marker::XML_DOCUMENT)?;
         self.write_str_u32(xml)?;
         Ok(())
     }
-    fn encode_avmplus(&mut self, value: &amf3::Value) -> io::Result<()> {
+    fn encode_avmplus(&mut self, value: &amf3::Value) -> EncodeResult<()> {
         self.inner.write_u8(marker::AVMPLUS_OBJECT)?;
-        amf3::Encoder::new(&mut self.inner).encode(value)?;
-        Ok(())
+        self.with_amf3(|encoder| encoder.encode(value))
+    }
+    fn with_amf3<F>(&mut self, f: F) -> EncodeResult<()>
+    where
+        F: FnOnce(&mut amf3::Encoder<&mut W>) -> EncodeResult<()>,
+    {
+        // The AMF3 reference tables are shared by all `AvmPlus` values in a message
+        let amf3 = self.amf3.take().expect("the AMF3 encoder is put back");
+        let (mut encoder, sink) = amf3.with_inner(&mut self.inner);
+        encoder.wire = self.wire.take();
+        let result = f(&mut encoder);
+        self.wire = encoder.wire.take();
+        self.amf3 = Some(encoder.with_inner(sink).0);
+        result
     }
 
-    fn write_str_u32(&mut self, s: &str) -> io::Result<()> {
-        assert!(s.len() <= 0xFFFF_FFFF);
+    // Checks if a reference in the wire details refers to a value which is structurally equal to `value`
+    fn is_replayed_reference(&mut self, index: usize, value: &Value) -> EncodeResult<bool> {
+        match self.referenced.get(&index) {
+            Some(&Some(key)) => Ok(key == self.reference_key(value)?),
+            _ => Ok(false),
+        }
+    }
+    // Returns the identifier of the structure of an object or an array to find structurally equal values
+    //
+    // The key of the value is written by a new encoder after the identifiers of its members are found.
+    fn reference_key(&mut self, value: &Value) -> EncodeResult<usize> {
+        if let Some(id) = self.shapes.get(value) {
+            return Ok(id);
+        }
+        match *value {
+            Value::Object { ref entries, .. } | Value::EcmaArray { ref entries } => {
+                self.member_keys(entries.iter().map(|p| &p.value))?;
+            }
+            Value::Array { ref entries } => self.member_keys(entries)?,
+            _ => {}
+        }
+
+        let mut key = Vec::new();
+        let mut encoder = Encoder::with_options(&mut key, *self.options());
+        *encoder.externalizables_mut() = self.amf3().externalizables.clone();
+        encoder.writes_key = true;
+        encoder.shapes = mem::take(&mut self.shapes);
+        let result = encoder.encode_value(value);
+        self.shapes = mem::take(&mut encoder.shapes);
+        result?;
+        Ok(self.shapes.add(value, key))
+    }
+    fn member_keys<'a, I>(&mut self, members: I) -> EncodeResult<()>
+    where
+        I: IntoIterator<Item = &'a Value>,
+    {
+        for member in members {
+            if is_complex(member) {
+                self.reference_key(member)?;
+            }
+        }
+        Ok(())
+    }
+    fn write_str_u32(&mut self, s: &str) -> EncodeResult<()> {
+        if s.len() > 0xFFFF_FFFF {
+            return Err(EncodeError::StringTooLong { len: s.len() });
+        }
         self.inner.write_u32::<BigEndian>(s.len() as u32)?;
         self.inner.write_all(s.as_bytes())?;
         Ok(())
     }
-    fn write_str_u16(&mut self, s: &str) -> io::Result<()> {
-        assert!(s.len() <= 0xFFFF);
+    pub(super) fn write_str_u16(&mut self, s: &str) -> EncodeResult<()> {
+        if s.len() > 0xFFFF {
+            return Err(EncodeError::StringTooLong { len: s.len() });
+        }
         self.inner.write_u16::<BigEndian>(s.len() as u16)?;
         self.inner.write_all(s.as_bytes())?;
         Ok(())
     }
-    fn encode_pairs(&mut self, pairs: &[Pair<String, Value>]) -> io::Result<()> {
+    fn encode_pairs<T, F>(&mut self, pairs: &[Pair<String, T>], f: &mut F) -> EncodeResult<()>
+    where
+        F: FnMut(&mut Self, &T) -> EncodeResult<()>,
+    {
         for p in pairs {
             self.write_str_u16(&p.key)?;
-            self.encode(&p.value)?;
+            f(self, &p.value)?;
         }
         self.inner.write_u16::<BigEndian>(0)?;
         self.inner.write_u8(marker::OBJECT_END_MARKER)?;
@@ -149,13 +549,28 @@ where
     }
 }
 
+pub(super) fn collection_len(len: usize) -> EncodeResult<u32> {
+    if len > 0xFFFF_FFFF {
+        Err(EncodeError::CollectionTooLarge { len })
+    } else {
+        Ok(len as u32)
+    }
+}
+
+fn is_complex(value: &Value) -> bool {
+    matches!(
+        *value,
+        Value::Object { .. } | Value::EcmaArray { .. } | Value::Array { .. }
+    )
+}
+
 #[cfg(test)]
 mod tests {
     #![allow(clippy::approx_constant)]
-    use super::super::Value;
+    use super::super::{Decoder, Encoder, Value};
     use crate::amf3;
-    use crate::Pair;
-    use std::iter;
+    use crate::error::EncodeError;
+    use crate::{ArrayPolicy, EncoderOptions, LongStringPolicy, Pair};
     use std::time;
 
     macro_rules! encode_eq {
@@ -196,10 +611,7 @@ mod tests {
     }
     #[test]
     fn encodes_long_string() {
-        encode_eq!(
-            Value::String(iter::repeat('a').take(0x10013).collect()),
-            "amf0-long-string.bin"
-        );
+        encode_eq!(Value::String("a".repeat(0x10013)), "amf0-long-string.bin");
     }
     #[test]
     fn encodes_object() {
@@ -224,12 +636,15 @@ mod tests {
         encode_eq!(Value::Undefined, "amf0-undefined.bin");
     }
     #[test]
+    fn encodes_unsupported() {
+        encode_eq!(Value::Unsupported, "amf0-unsupported.bin");
+        encode_eq!(Value::MovieClip, "amf0-movieclip.bin");
+        encode_eq!(Value::RecordSet, "amf0-recordset.bin");
+    }
+    #[test]
     fn encodes_ecma_array() {
         let entries = es(&[("0", s("a")), ("1", s("b")), ("2", s("c")), ("3", s("d"))][..]);
-        encode_eq!(
-            Value::EcmaArray { entries: entries },
-            "amf0-ecma-ordinal-array.bin"
-        );
+        encode_eq!(Value::EcmaArray { entries }, "amf0-ecma-ordinal-array.bin");
     }
     #[test]
     fn encodes_string_array() {
@@ -275,12 +690,278 @@ mod tests {
         );
     }
     #[test]
+    fn encodes_reference() {
+        let object = obj(None, &[("foo", s("baz")), ("bar", n(3.14))][..]);
+        let value = obj(None, &[("0", object.clone()), ("1", object)][..]);
+        let mut encoder = Encoder::new(Vec::new());
+        encoder.set_object_references(true);
+        encoder.encode(&value).unwrap();
+        assert_eq!(
+            encoder.into_inner(),
+            &include_bytes!("../testdata/amf0-ref-test.bin")[..]
+        );
+
+        // Deeply nested values which differ only in their innermost members
+        let nested = |leaf| (0..100).fold(leaf, |v, _| Value::Array { entries: vec![v] });
+        let value = Value::Array {
+            entries: vec![nested(n(1.0)), nested(n(2.0)), nested(n(1.0))],
+        };
+        let mut encoder = Encoder::new(Vec::new());
+        encoder.set_object_references(true);
+        encoder.encode(&value).unwrap();
+        let buf = encoder.into_inner();
+        assert!(buf.ends_with(b"\x07\x00\x01"));
+        assert_eq!(Value::read_from(&mut &buf[..]).unwrap(), value);
+
+        // Object references are disabled by default
+        let mut buf = Vec::new();
+        value.write_to(&mut buf).unwrap();
+        assert_eq!(Value::read_from(&mut &buf[..]).unwrap(), value);
+        assert!(buf.len() > include_bytes!("../testdata/amf0-ref-test.bin").len());
+    }
+    #[test]
     fn encodes_avmplus() {
         let value = amf3::Value::Array {
             assoc_entries: vec![],
             dense_entries: (1..4).map(amf3::Value::Integer).collect(),
         };
         encode_eq!(Value::AvmPlus(value), "amf0-avmplus-object.bin");
+
+        // The AMF3 reference tables are shared by the sibling values
+        let foo = Value::AvmPlus(amf3::Value::String("foo".to_string()));
+        let mut encoder = Encoder::new(Vec::new());
+        encoder.encode(&foo).unwrap();
+        encoder.encode(&foo).unwrap();
+        encoder.clear_reference_table();
+        encoder.encode(&foo).unwrap();
+        assert_eq!(
+            encoder.into_inner(),
+            b"\x11\x06\x07foo\x11\x06\x00\x11\x06\x07foo"
+        );
+    }
+    #[test]
+    fn encodes_with_options() {
+        macro_rules! options_eq {
+            ($options:expr, $value:expr, $expected:expr) => {{
+                let mut encoder = Encoder::with_options(Vec::new(), $options);
+                encoder.encode(&$value).unwrap();
+                assert_eq!(encoder.into_inner(), &$expected[..]);
+            }};
+        }
+        let long = EncoderOptions {
+            long_strings: LongStringPolicy::Always,
+            ..EncoderOptions::default()
+        };
+        options_eq!(long, s("foo"), b"\x0c\x00\x00\x00\x03foo");
+        options_eq!(
+            long,
+            obj(None, &[("a", Value::Null)]),
+            b"\x03\x00\x01a\x05\x00\x00\x09"
+        );
+
+        let ecma = EncoderOptions {
+            arrays: ArrayPolicy::Ecma,
+            ..EncoderOptions::default()
+        };
+        let value = Value::Array {
+            entries: vec![Value::Null, Value::Undefined],
+        };
+        options_eq!(
+            ecma,
+            value,
+            b"\x08\x00\x00\x00\x02\x00\x010\x05\x00\x011\x06\x00\x00\x09"
+        );
+
+        let strict = EncoderOptions {
+            arrays: ArrayPolicy::Strict,
+            ..EncoderOptions::default()
+        };
+        let value = Value::EcmaArray {
+            entries: es(&[("0", Value::Null), ("1", Value::Undefined)]),
+        };
+        options_eq!(strict, value, b"\x0a\x00\x00\x00\x02\x05\x06");
+        let value = Value::EcmaArray {
+            entries: es(&[("1", Value::Null)]),
+        };
+        options_eq!(
+            strict,
+            value,
+            b"\x08\x00\x00\x00\x01\x00\x011\x05\x00\x00\x09"
+        );
+
+        // The options are also applied to the AMF3 values
+        let promote = EncoderOptions {
+            promote_integers: true,
+            ..EncoderOptions::default()
+        };
+        options_eq!(
+            promote,
+            Value::AvmPlus(amf3::Value::Integer(1 << 28)),
+            b"\x11\x05\x41\xb0\x00\x00\x00\x00\x00\x00"
+        );
+    }
+    #[test]
+    fn encodes_exactly() {
+        macro_rules! exact_eq {
+            ($input:expr) => {{
+                let input = &$input[..];
+                let exact = Decoder::new(input).decode_exact().unwrap();
+                let mut encoder = Encoder::new(Vec::new());
+                encoder.encode_exact(&exact.value, &exact.wire).unwrap();
+                assert_eq!(encoder.into_inner(), input);
+            }};
+        }
+        exact_eq!(include_bytes!("../testdata/amf0-ref-test.bin"));
+        exact_eq!(include_bytes!("../testdata/amf0-hash.bin"));
+        exact_eq!(include_bytes!("../testdata/amf0-avmplus-object.bin"));
+        exact_eq!(b"\x0c\x00\x00\x00\x03foo");
+        exact_eq!(b"\x08\x00\x00\x00\x05\x00\x01a\x05\x00\x00\x09");
+        exact_eq!(b"\x11\x09\x05\x01\x04\x80\x01\x06\x80\x07foo");
+
+        // The options are not applied
+        let options = EncoderOptions {
+            long_strings: LongStringPolicy::Always,
+            ..EncoderOptions::default()
+        };
+        let mut encoder = Encoder::with_options(Vec::new(), options);
+        encoder
+            .encode_exact(&s("foo"), &Default::default())
+            .unwrap();
+        assert_eq!(encoder.into_inner(), b"\x02\x00\x03foo");
+
+        // A reference to an object which is no longer equal to the referring one is not reproduced
+        let input = include_bytes!("../testdata/amf0-ref-test.bin");
+        let mut exact = Decoder::new(&input[..]).decode_exact().unwrap();
+        let object = obj(None, &[("foo", s("qux")), ("bar", n(3.14))][..]);
+        match exact.value {
+            Value::Object {
+                ref mut entries, ..
+            } => entries[1].value = object.clone(),
+            _ => panic!(),
+        }
+        let mut encoder = Encoder::new(Vec::new());
+        encoder.encode_exact(&exact.value, &exact.wire).unwrap();
+        let buf = encoder.into_inner();
+        assert_eq!(Value::read_from(&mut &buf[..]).unwrap(), exact.value);
+        assert!(buf.len() > input.len());
+    }
+    #[test]
+    fn encodes_canonically() {
+        macro_rules! canonical_eq {
+            ($value:expr, $expected:expr) => {{
+                let mut encoder = Encoder::new(Vec::new());
+                encoder.encode_canonical(&$value).unwrap();
+                assert_eq!(encoder.into_inner(), &$expected[..]);
+            }};
+        }
+        canonical_eq!(n(-0.0), b"\x00\x00\x00\x00\x00\x00\x00\x00\x00");
+        canonical_eq!(
+            n(f64::from_bits(0x7ff8_0000_0000_0001)),
+            b"\x00\x7f\xf8\x00\x00\x00\x00\x00\x00"
+        );
+
+        let expected = b"\x03\x00\x01a\x05\x00\x01b\x06\x00\x00\x09";
+        canonical_eq!(
+            obj(None, &[("a", Value::Null), ("b", Value::Undefined)]),
+            expected
+        );
+        canonical_eq!(
+            obj(None, &[("b", Value::Undefined), ("a", Value::Null)]),
+            expected
+        );
+
+        // ECMA arrays keyed by the indices are written as strict arrays
+        let expected = b"\x0a\x00\x00\x00\x02\x05\x06";
+        let value = Value::EcmaArray {
+            entries: es(&[("1", Value::Undefined), ("0", Value::Null)]),
+        };
+        canonical_eq!(value, expected);
+        let value = Value::Array {
+            entries: vec![Value::Null, Value::Undefined],
+        };
+        canonical_eq!(value, expected);
+        let value = Value::EcmaArray {
+            entries: es(&[("1", Value::Undefined), ("01", Value::Null)]),
+        };
+        canonical_eq!(
+            value,
+            b"\x08\x00\x00\x00\x02\x00\x0201\x05\x00\x011\x06\x00\x00\x09"
+        );
+
+        canonical_eq!(Value::AvmPlus(amf3::Value::Double(1.0)), b"\x11\x04\x01");
+
+        // The options are not applied
+        let options = EncoderOptions {
+            long_strings: LongStringPolicy::Always,
+            arrays: ArrayPolicy::Ecma,
+            ..EncoderOptions::default()
+        };
+        let mut encoder = Encoder::with_options(Vec::new(), options);
+        encoder.encode_canonical(&s("foo")).unwrap();
+        assert_eq!(encoder.into_inner(), b"\x02\x00\x03foo");
+    }
+    #[test]
+    fn computes_encoded_len() {
+        let files: [&[u8]; 6] = [
+            include_bytes!("../testdata/amf0-string.bin"),
+            include_bytes!("../testdata/amf0-long-string.bin"),
+            include_bytes!("../testdata/amf0-typed-object.bin"),
+            include_bytes!("../testdata/amf0-ecma-ordinal-array.bin"),
+            include_bytes!("../testdata/amf0-date.bin"),
+            include_bytes!("../testdata/amf0-avmplus-object.bin"),
+        ];
+        for input in files {
+            let value = Value::read_from(input).unwrap();
+            assert_eq!(value.encoded_len(), Ok(input.len()));
+        }
+
+        // The length depends on the reference tables, which are not updated
+        let object = obj(None, &[("foo", s("baz"))]);
+        let mut encoder = Encoder::new(Vec::new());
+        encoder.set_object_references(true);
+        assert_eq!(encoder.encoded_len(&object), Ok(15));
+        assert_eq!(encoder.encoded_len(&object), Ok(15));
+        encoder.encode(&object).unwrap();
+        assert_eq!(encoder.encoded_len(&object), Ok(3));
+
+        let foo = Value::AvmPlus(amf3::Value::String("foo".to_string()));
+        assert_eq!(encoder.encoded_len(&foo), Ok(6));
+        encoder.encode(&foo).unwrap();
+        assert_eq!(encoder.encoded_len(&foo), Ok(3));
+        assert_eq!(encoder.into_inner().len(), 15 + 6);
+
+        let value = obj(None, &[(&"a".repeat(0x10000), Value::Null)]);
+        assert_eq!(
+            value.encoded_len(),
+            Err(EncodeError::StringTooLong { len: 0x10000 })
+        );
+    }
+    #[test]
+    fn encode_errors() {
+        let key = "a".repeat(0x10000);
+        let value = obj(None, &[(&key, Value::Null)]);
+        assert_eq!(
+            value.write_to(&mut Vec::new()),
+            Err(EncodeError::StringTooLong { len: 0x10000 })
+        );
+
+        let value = Value::Object {
+            class_name: Some(key),
+            entries: Vec::new(),
+        };
+        assert_eq!(
+            value.write_to(&mut Vec::new()),
+            Err(EncodeError::StringTooLong { len: 0x10000 })
+        );
+
+        let value = Value::Date {
+            unix_time: time::Duration::MAX,
+            time_zone: 0,
+        };
+        assert_eq!(
+            value.write_to(&mut Vec::new()),
+            Err(EncodeError::IntegerOutOfRange { value: i64::MAX })
+        );
     }
 
     fn s(s: &str) -> Value {
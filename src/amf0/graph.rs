@@ -0,0 +1,439 @@
+//! Object graph representation of AMF0 values.
+//!
+//! Unlike the tree [`Value`](super::Value), objects and arrays are stored in a [`Graph`]
+//! and addressed by [`NodeId`]s, so shared and circular references are preserved.
+//!
+//! # Examples
+//! ```
+//! use amf::amf0::{self, graph};
+//! use amf::Pair;
+//!
+//! // Builds an object which refers to itself
+//! let mut g = graph::Graph::new();
+//! let id = g.add(graph::Node::Object {
+//!     class_name: None,
+//!     entries: Vec::new(),
+//! });
+//! if let graph::Node::Object { entries, .. } = g.node_mut(id) {
+//!     entries.push(Pair { key: "self".to_string(), value: graph::Value::Node(id) });
+//! }
+//!
+//! // Encodes and decodes the graph
+//! let mut buf = Vec::new();
+//! amf0::Encoder::new(&mut buf).encode_graph(&g, &graph::Value::Node(id)).unwrap();
+//! let mut decoder = amf0::Decoder::new(&buf[..]);
+//! let root = decoder.decode_graph().unwrap();
+//! assert_eq!(decoder.graph().node(id), g.node(id));
+//! assert_eq!(root, graph::Value::Node(id));
+//! ```
+use super::shared;
+use super::Value as TreeValue;
+use crate::amf3;
+use crate::error::DecodeError;
+use crate::limits::Budget;
+use crate::{DecodeResult, Pair};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::mem;
+use core::time;
+
+/// Identifier of a node in a [`Graph`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct NodeId(usize);
+impl NodeId {
+    /// Returns the index of the node in the graph.
+    ///
+    /// For a graph decoded by [`Decoder`](super::Decoder),
+    /// this is equal to the index in the object reference table.
+    pub fn index(self) -> usize {
+        self.0
+    }
+}
+
+/// AMF0 value in an object graph.
+///
+/// Objects and arrays are represented as references to the nodes of a [`Graph`].
+#[derive(Debug, Clone, PartialEq, PartialOrd)]
+pub enum Value {
+    /// See [`Value::Number`](super::Value::Number).
+    Number(f64),
+
+    /// See [`Value::Boolean`](super::Value::Boolean).
+    Boolean(bool),
+
+    /// See [`Value::String`](super::Value::String).
+    String(String),
+
+    /// See [`Value::Null`](super::Value::Null).
+    Null,
+
+    /// See [`Value::Undefined`](super::Value::Undefined).
+    Undefined,
+
+    /// See [`Value::Unsupported`](super::Value::Unsupported).
+    Unsupported,
+
+    /// See [`Value::MovieClip`](super::Value::MovieClip).
+    MovieClip,
+
+    /// See [`Value::RecordSet`](super::Value::RecordSet).
+    RecordSet,
+
+    /// See [`Value::Date`](super::Value::Date).
+    Date {
+        /// Unix timestamp with milliseconds precision.
+        unix_time: time::Duration,
+
+        /// Time zone offset.
+        ///
+        /// Note that this is a reserved field and the value should be zero.
+        time_zone: i16,
+    },
+
+    /// See [`Value::XmlDocument`](super::Value::XmlDocument).
+    XmlDocument(String),
+
+    /// See [`Value::AvmPlus`](super::Value::AvmPlus).
+    ///
+    /// The complex AMF3 values are stored in [`Graph::avmplus`].
+    AvmPlus(amf3::graph::Value),
+
+    /// An object or an array stored in a graph.
+    Node(NodeId),
+}
+
+/// Object or array in an object graph.
+#[derive(Debug, Clone, PartialEq, PartialOrd)]
+pub enum Node {
+    /// See [`Value::Object`](super::Value::Object).
+    Object {
+        /// The class name of the object.
+        /// `None` means it is an anonymous object.
+        class_name: Option<String>,
+
+        /// Properties of the object.
+        entries: Vec<Pair<String, Value>>,
+    },
+
+    /// See [`Value::EcmaArray`](super::Value::EcmaArray).
+    EcmaArray {
+        /// Entries of the associative array.
+        entries: Vec<Pair<String, Value>>,
+    },
+
+    /// See [`Value::Array`](super::Value::Array).
+    Array {
+        /// Entries of the array.
+        entries: Vec<Value>,
+    },
+}
+
+/// An arena of the objects and arrays in an object graph.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Graph {
+    // `None` denotes a node reserved for a container which is being decoded
+    nodes: Vec<Option<Node>>,
+    avmplus: amf3::graph::Graph,
+}
+impl Graph {
+    /// Makes a new empty graph.
+    pub fn new() -> Self {
+        Graph::default()
+    }
+
+    /// Adds `node` to this graph, returning its identifier.
+    pub fn add(&mut self, node: Node) -> NodeId {
+        self.nodes.push(Some(node));
+        NodeId(self.nodes.len() - 1)
+    }
+
+    /// Returns a reference to the node identified by `id`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `id` does not belong to this graph,
+    /// or refers to a container which a decoder is still decoding.
+    pub fn node(&self, id: NodeId) -> &Node {
+        self.nodes[id.0]
+            .as_ref()
+            .expect("the node is being decoded")
+    }
+
+    /// Returns a mutable reference to the node identified by `id`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `id` does not belong to this graph,
+    /// or refers to a container which a decoder is still decoding.
+    pub fn node_mut(&mut self, id: NodeId) -> &mut Node {
+        self.nodes[id.0]
+            .as_mut()
+            .expect("the node is being decoded")
+    }
+
+    /// Returns the number of the nodes in this graph.
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Returns `true` if this graph has no nodes.
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// Returns a reference to the graph which holds the complex AMF3 values of `AvmPlus`.
+    pub fn avmplus(&self) -> &amf3::graph::Graph {
+        &self.avmplus
+    }
+
+    /// Returns a mutable reference to the graph which holds the complex AMF3 values of `AvmPlus`.
+    pub fn avmplus_mut(&mut self) -> &mut amf3::graph::Graph {
+        &mut self.avmplus
+    }
+
+    /// Removes all the nodes of this graph.
+    pub fn clear(&mut self) {
+        self.nodes.clear();
+        self.avmplus.clear();
+    }
+
+    /// Adds the objects and arrays contained in the tree `value` to this graph.
+    ///
+    /// Each object or array becomes a distinct node.
+    pub fn add_tree(&mut self, value: &TreeValue) -> Value {
+        let node = match *value {
+            TreeValue::Number(x) => return Value::Number(x),
+            TreeValue::Boolean(x) => return Value::Boolean(x),
+            TreeValue::String(ref x) => return Value::String(x.clone()),
+            TreeValue::Null => return Value::Null,
+            TreeValue::Undefined => return Value::Undefined,
+            TreeValue::Unsupported => return Value::Unsupported,
+            TreeValue::MovieClip => return Value::MovieClip,
+            TreeValue::RecordSet => return Value::RecordSet,
+            TreeValue::Date {
+                unix_time,
+                time_zone,
+            } => {
+                return Value::Date {
+                    unix_time,
+                    time_zone,
+                }
+            }
+            TreeValue::XmlDocument(ref x) => return Value::XmlDocument(x.clone()),
+            TreeValue::AvmPlus(ref x) => return Value::AvmPlus(self.avmplus.add_tree(x)),
+            TreeValue::Object {
+                ref class_name,
+                ref entries,
+            } => Node::Object {
+                class_name: class_name.clone(),
+                entries: self.add_tree_pairs(entries),
+            },
+            TreeValue::EcmaArray { ref entries } => Node::EcmaArray {
+                entries: self.add_tree_pairs(entries),
+            },
+            TreeValue::Array { ref entries } => Node::Array {
+                entries: entries.iter().map(|v| self.add_tree(v)).collect(),
+            },
+        };
+        Value::Node(self.add(node))
+    }
+
+    /// Converts `value` to the tree representation.
+    ///
+    /// Shared nodes are copied for each occurrence,
+    /// and the copies are limited by `DecoderLimits::default().max_reference_expansions`
+    /// and `DecoderLimits::default().max_allocation`.
+    /// If `value` contains circular references, `DecodeError::CircularReference` will be returned,
+    /// and if the copies exceed the limits, `DecodeError::LimitExceeded` will be returned.
+    pub fn to_tree(&self, value: &Value) -> DecodeResult<TreeValue> {
+        self.to_tree_with(
+            value,
+            &mut Vec::new(),
+            &mut Vec::new(),
+            &mut Budget::default(),
+        )
+    }
+
+    /// Converts `value` to the reference-counted representation.
+    ///
+    /// Each node is converted once and shared by all of its occurrences.
+    /// If `value` contains circular references, `DecodeError::CircularReference` will be returned.
+    pub fn to_shared(&self, value: &Value) -> DecodeResult<shared::Value> {
+        shared::Nodes::default().share(self, value)
+    }
+
+    // Converts `value` to the tree representation,
+    // counting the copies of the nodes which have been `copied` before as reference expansions
+    pub(crate) fn to_tree_with(
+        &self,
+        value: &Value,
+        copied: &mut Vec<bool>,
+        avmplus_copied: &mut Vec<bool>,
+        budget: &mut Budget,
+    ) -> DecodeResult<TreeValue> {
+        let mut state = TreeState {
+            path: Vec::new(),
+            copied,
+            avmplus_copied,
+            budget,
+        };
+        self.to_tree_value(value, &mut state)
+    }
+
+    // Reserves a node for a container, so that its entries can refer to it before it is decoded
+    pub(crate) fn reserve(&mut self) -> NodeId {
+        self.nodes.push(None);
+        NodeId(self.nodes.len() - 1)
+    }
+
+    // Sets the node reserved by `reserve`
+    pub(crate) fn fill(&mut self, id: NodeId, node: Node) {
+        debug_assert!(self.nodes[id.0].is_none(), "the node is not reserved");
+        self.nodes[id.0] = Some(node);
+    }
+
+    // Returns the node identified by `id`,
+    // which is circularly referred if the node is reserved but not decoded yet
+    pub(crate) fn decoded_node(&self, id: NodeId) -> DecodeResult<&Node> {
+        self.nodes[id.0]
+            .as_ref()
+            .ok_or(DecodeError::CircularReference { index: id.0 })
+    }
+
+    // Removes the nodes added since `id` was reserved
+    pub(crate) fn truncate(&mut self, id: NodeId) {
+        self.nodes.truncate(id.0);
+    }
+
+    fn add_tree_pairs(&mut self, pairs: &[Pair<String, TreeValue>]) -> Vec<Pair<String, Value>> {
+        pairs
+            .iter()
+            .map(|p| Pair {
+                key: p.key.clone(),
+                value: self.add_tree(&p.value),
+            })
+            .collect()
+    }
+    fn to_tree_value(&self, value: &Value, state: &mut TreeState) -> DecodeResult<TreeValue> {
+        let id = match *value {
+            Value::Number(x) => return Ok(TreeValue::Number(x)),
+            Value::Boolean(x) => return Ok(TreeValue::Boolean(x)),
+            Value::String(ref x) => return Ok(TreeValue::String(x.clone())),
+            Value::Null => return Ok(TreeValue::Null),
+            Value::Undefined => return Ok(TreeValue::Undefined),
+            Value::Unsupported => return Ok(TreeValue::Unsupported),
+            Value::MovieClip => return Ok(TreeValue::MovieClip),
+            Value::RecordSet => return Ok(TreeValue::RecordSet),
+            Value::Date {
+                unix_time,
+                time_zone,
+            } => {
+                return Ok(TreeValue::Date {
+                    unix_time,
+                    time_zone,
+                })
+            }
+            Value::XmlDocument(ref x) => return Ok(TreeValue::XmlDocument(x.clone())),
+            Value::AvmPlus(ref x) => {
+                return self
+                    .avmplus
+                    .to_tree_with(x, state.avmplus_copied, state.budget)
+                    .map(TreeValue::AvmPlus)
+            }
+            Value::Node(id) => id,
+        };
+        if state.path.contains(&id) {
+            return Err(DecodeError::CircularReference { index: id.0 });
+        }
+        state.path.push(id);
+        let node = self.decoded_node(id)?;
+        state.copy(id, node)?;
+        let value = match *node {
+            Node::Object {
+                ref class_name,
+                ref entries,
+            } => TreeValue::Object {
+                class_name: class_name.clone(),
+                entries: self.to_tree_pairs(entries, state)?,
+            },
+            Node::EcmaArray { ref entries } => TreeValue::EcmaArray {
+                entries: self.to_tree_pairs(entries, state)?,
+            },
+            Node::Array { ref entries } => TreeValue::Array {
+                entries: entries
+                    .iter()
+                    .map(|v| self.to_tree_value(v, state))
+                    .collect::<DecodeResult<_>>()?,
+            },
+        };
+        state.path.pop();
+        Ok(value)
+    }
+    fn to_tree_pairs(
+        &self,
+        pairs: &[Pair<String, Value>],
+        state: &mut TreeState,
+    ) -> DecodeResult<Vec<Pair<String, TreeValue>>> {
+        pairs
+            .iter()
+            .map(|p| {
+                Ok(Pair {
+                    key: p.key.clone(),
+                    value: self.to_tree_value(&p.value, state)?,
+                })
+            })
+            .collect()
+    }
+}
+
+struct TreeState<'a> {
+    path: Vec<NodeId>,
+    copied: &'a mut Vec<bool>,
+    avmplus_copied: &'a mut Vec<bool>,
+    budget: &'a mut Budget,
+}
+impl<'a> TreeState<'a> {
+    fn copy(&mut self, id: NodeId, node: &Node) -> DecodeResult<()> {
+        if self.copied.len() <= id.0 {
+            self.copied.resize(id.0 + 1, false);
+        }
+        if mem::replace(&mut self.copied[id.0], true) {
+            let (entries, bytes) = copy_size(node);
+            self.budget.copy(1 + entries, bytes)?;
+        }
+        Ok(())
+    }
+}
+
+// Returns the number of the entries of `node` and the bytes allocated for copying it,
+// excluding the nodes which it refers to
+fn copy_size(node: &Node) -> (usize, usize) {
+    let pairs = |pairs: &[Pair<String, Value>]| {
+        pairs.len() * mem::size_of::<Pair<String, TreeValue>>()
+            + pairs
+                .iter()
+                .map(|p| p.key.len() + string_len(&p.value))
+                .sum::<usize>()
+    };
+    match *node {
+        Node::Object {
+            ref class_name,
+            ref entries,
+        } => (
+            entries.len(),
+            class_name.as_ref().map_or(0, String::len) + pairs(entries),
+        ),
+        Node::EcmaArray { ref entries } => (entries.len(), pairs(entries)),
+        Node::Array { ref entries } => (
+            entries.len(),
+            entries.len() * mem::size_of::<TreeValue>()
+                + entries.iter().map(string_len).sum::<usize>(),
+        ),
+    }
+}
+fn string_len(value: &Value) -> usize {
+    match *value {
+        Value::String(ref x) | Value::XmlDocument(ref x) => x.len(),
+        _ => 0,
+    }
+}
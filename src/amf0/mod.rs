@@ -14,22 +14,41 @@
 //! assert_eq!(number, decoded);
 //! ```
 use crate::amf3;
-use crate::{DecodeResult, Pair};
-use std::io;
-use std::time;
+use crate::io;
+use crate::util::{canonical_f64, ByteCounter};
+use crate::{DecodeResult, EncodeResult, Pair};
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::string::ToString;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::mem;
+use core::time;
 
-pub use self::decode::Decoder;
+pub use self::decode::{DecodedValue, Decoder, ExactValue, LenientValue};
 pub use self::encode::Encoder;
+pub use self::reader::{Event, Reader};
+pub use self::stream::StreamDecoder;
+pub use self::value_ref::{SliceDecoder, ValueRef};
+pub use self::writer::Writer;
 
+pub mod graph;
+pub mod shared;
+
+mod build;
 mod decode;
 mod encode;
+mod reader;
+mod stream;
+mod value_ref;
+mod writer;
 
 mod marker {
     pub const NUMBER: u8 = 0x00;
     pub const BOOLEAN: u8 = 0x01;
     pub const STRING: u8 = 0x02;
     pub const OBJECT: u8 = 0x03;
-    pub const MOVIECLIP: u8 = 0x04; // reserved, not supported
+    pub const MOVIECLIP: u8 = 0x04; // reserved
     pub const NULL: u8 = 0x05;
     pub const UNDEFINED: u8 = 0x06;
     pub const REFERENCE: u8 = 0x07;
@@ -39,7 +58,7 @@ mod marker {
     pub const DATE: u8 = 0x0B;
     pub const LONG_STRING: u8 = 0x0C;
     pub const UNSUPPORTED: u8 = 0x0D;
-    pub const RECORDSET: u8 = 0x0E; // reserved, not supported
+    pub const RECORDSET: u8 = 0x0E; // reserved
     pub const XML_DOCUMENT: u8 = 0x0F;
     pub const TYPED_OBJECT: u8 = 0x10;
     pub const AVMPLUS_OBJECT: u8 = 0x11;
@@ -99,6 +118,24 @@ pub enum Value {
     /// (http://download.macromedia.com/pub/labs/amf/amf0_spec_121207.pdf#page=6&zoom=auto,90,637).
     Undefined,
 
+    /// See [2.15 Unsupported Type]
+    /// (http://download.macromedia.com/pub/labs/amf/amf0_spec_121207.pdf).
+    ///
+    /// This is sent back for a value whose type cannot be serialized.
+    Unsupported,
+
+    /// See [2.6 movieclip Type]
+    /// (http://download.macromedia.com/pub/labs/amf/amf0_spec_121207.pdf).
+    ///
+    /// This type is reserved and has no content.
+    MovieClip,
+
+    /// See [2.16 RecordSet Type]
+    /// (http://download.macromedia.com/pub/labs/amf/amf0_spec_121207.pdf).
+    ///
+    /// This type is reserved and has no content.
+    RecordSet,
+
     /// See [2.10 ECMA Array Type]
     /// (http://download.macromedia.com/pub/labs/amf/amf0_spec_121207.pdf#page=6&zoom=auto,90,349).
     EcmaArray {
@@ -138,7 +175,10 @@ impl Value {
     ///
     /// Note that reference objects are copied in the decoding phase
     /// for the sake of simplicity of the resulting value representation.
-    /// And circular reference are unsupported (i.e., those are treated as errors).
+    /// Circular references are rejected because the tree `Value` cannot represent those;
+    /// decode them by [`Decoder::decode_graph`] instead.
+    ///
+    /// The location of an error is not available; use `Decoder::error_location` to get it.
     pub fn read_from<R>(reader: R) -> DecodeResult<Self>
     where
         R: io::Read,
@@ -147,13 +187,73 @@ impl Value {
     }
 
     /// Writes the AMF0 encoded bytes of this value to `writer`.
-    pub fn write_to<W>(&self, writer: W) -> io::Result<()>
+    pub fn write_to<W>(&self, writer: W) -> EncodeResult<()>
     where
         W: io::Write,
     {
         Encoder::new(writer).encode(self)
     }
 
+    /// Returns the number of the bytes which `write_to` writes.
+    ///
+    /// This is computed by encoding the value without storing the encoded bytes,
+    /// so the result always matches `write_to`, including the errors.
+    ///
+    /// # Examples
+    /// ```
+    /// use amf::amf0::Value;
+    ///
+    /// let value = Value::String("foo".to_string());
+    /// let mut buf = Vec::new();
+    /// value.write_to(&mut buf).unwrap();
+    /// assert_eq!(value.encoded_len().unwrap(), buf.len());
+    /// ```
+    pub fn encoded_len(&self) -> EncodeResult<usize> {
+        let mut encoder = Encoder::new(ByteCounter::default());
+        encoder.encode(self)?;
+        Ok(encoder.into_inner().count)
+    }
+
+    /// Normalizes this value into the canonical form, which `Encoder::encode_canonical` writes.
+    ///
+    /// - the members of objects and the entries of ECMA arrays are sorted by their keys,
+    /// - ECMA arrays keyed by all of the indices (i.e., `"0"`, `"1"`, ...) become strict arrays,
+    /// - negative zeros become zeros, and NaNs become `f64::NAN`,
+    /// - the AMF3 values embedded in `AvmPlus` are normalized by `amf3::Value::canonicalize`.
+    ///
+    /// # Examples
+    /// ```
+    /// use amf::amf0::{self, Value};
+    ///
+    /// let mut value = Value::EcmaArray {
+    ///     entries: vec![
+    ///         amf::Pair { key: "1".to_string(), value: amf0::number(2) },
+    ///         amf::Pair { key: "0".to_string(), value: amf0::number(1) },
+    ///     ],
+    /// };
+    /// value.canonicalize();
+    /// assert_eq!(value, amf0::array(vec![amf0::number(1), amf0::number(2)]));
+    /// ```
+    pub fn canonicalize(&mut self) {
+        match *self {
+            Value::Number(ref mut x) => *x = canonical_f64(*x),
+            Value::Object {
+                ref mut entries, ..
+            } => canonicalize_pairs(entries),
+            Value::EcmaArray { ref mut entries } => {
+                if let Some(dense) = dense_entries(entries) {
+                    *self = Value::Array { entries: dense };
+                    self.canonicalize();
+                } else {
+                    canonicalize_pairs(entries);
+                }
+            }
+            Value::Array { ref mut entries } => entries.iter_mut().for_each(Value::canonicalize),
+            Value::AvmPlus(ref mut x) => x.canonicalize(),
+            _ => {}
+        }
+    }
+
     /// Tries to convert the value as a `str` reference.
     pub fn try_as_str(&self) -> Option<&str> {
         match *self {
@@ -180,7 +280,7 @@ impl Value {
             Value::AvmPlus(x) => x
                 .try_into_values()
                 .map(|iter| iter.map(super::Value::Amf3))
-                .map(super::iter_boxed)
+                .map(crate::util::iter_boxed)
                 .map_err(Value::AvmPlus),
             _ => Err(self),
         }
@@ -202,13 +302,38 @@ impl Value {
             Value::AvmPlus(x) => x
                 .try_into_pairs()
                 .map(|ps| ps.map(|(k, v)| (k, super::Value::Amf3(v))))
-                .map(super::iter_boxed)
+                .map(crate::util::iter_boxed)
                 .map_err(Value::AvmPlus),
             _ => Err(self),
         }
     }
 }
 
+// Sorts the pairs by their keys, canonicalizing the values
+fn canonicalize_pairs(pairs: &mut [Pair<String, Value>]) {
+    pairs.sort_by(|a, b| a.key.cmp(&b.key));
+    for p in pairs {
+        p.value.canonicalize();
+    }
+}
+
+// Takes the values in the order of the keys if the keys are all of the indices
+fn dense_entries(entries: &mut [Pair<String, Value>]) -> Option<Vec<Value>> {
+    let mut indices = vec![None; entries.len()];
+    for (i, e) in entries.iter().enumerate() {
+        let index = e.key.parse::<usize>().ok()?;
+        if index.to_string() != e.key || indices.get(index)?.is_some() {
+            return None;
+        }
+        indices[index] = Some(i);
+    }
+    // The indices are all filled because the keys are distinct and less than the length
+    indices
+        .into_iter()
+        .map(|i| i.map(|i| mem::replace(&mut entries[i].value, Value::Undefined)))
+        .collect()
+}
+
 /// Makes a `String` value.
 pub fn string<T>(t: T) -> Value
 where
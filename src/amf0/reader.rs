@@ -0,0 +1,668 @@
+use super::marker;
+use super::Value;
+use crate::amf3::{self, ExternalizableRegistry};
+use crate::error::{DecodeError, Location, PathSegment};
+use crate::io::{self, ReadBytesExt};
+use crate::limits::Budget;
+use crate::util::Position;
+use crate::{DecodeResult, DecoderLimits};
+use alloc::string::String;
+use alloc::vec::Vec;
+use byteorder::BigEndian;
+use core::mem;
+use core::time;
+
+/// Event of `Reader`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    /// Number.
+    Number(f64),
+
+    /// Boolean.
+    Boolean(bool),
+
+    /// String.
+    String(String),
+
+    /// Start of an object.
+    ///
+    /// The `Key` and value events of the members are followed by `End`.
+    StartObject {
+        /// The class name of the object.
+        class_name: Option<String>,
+    },
+
+    /// Key of a member of an object or an entry of an ECMA array.
+    ///
+    /// The events of the value follow.
+    Key(String),
+
+    /// `null`.
+    Null,
+
+    /// `undefined`.
+    Undefined,
+
+    /// `unsupported`.
+    Unsupported,
+
+    /// `movieclip`, which is reserved.
+    MovieClip,
+
+    /// `recordset`, which is reserved.
+    RecordSet,
+
+    /// Reference to a previously read object or array.
+    ///
+    /// The objects and arrays are indexed in the order of their first occurrence.
+    Reference {
+        /// The index of the referenced value.
+        index: usize,
+    },
+
+    /// Start of an ECMA array.
+    ///
+    /// The `Key` and value events of the entries are followed by `End`.
+    StartEcmaArray {
+        /// The number of the entries declared in the header (it may be inaccurate).
+        len: usize,
+    },
+
+    /// Start of a strict array.
+    ///
+    /// The `len` value events of the entries are followed by `End`.
+    StartArray {
+        /// The number of the entries.
+        len: usize,
+    },
+
+    /// Date.
+    Date {
+        /// Unix timestamp with milliseconds precision.
+        unix_time: time::Duration,
+
+        /// Time zone offset.
+        time_zone: i16,
+    },
+
+    /// XML document.
+    XmlDocument(String),
+
+    /// Event of the AMF3 value embedded in `AvmPlus`.
+    ///
+    /// A single AMF3 value may consist of multiple events (e.g., `StartArray`, ..., `End`).
+    AvmPlus(amf3::Event),
+
+    /// End of an object or an array.
+    End,
+}
+
+#[derive(Debug, Clone)]
+enum Frame {
+    Pairs {
+        // The key of the entry being read
+        key: Option<String>,
+    },
+    Values {
+        remaining: usize,
+        len: usize,
+    },
+    AvmPlus,
+}
+
+// State of `Reader` before an event
+#[derive(Debug, Clone)]
+pub(crate) struct Checkpoint {
+    expects_value: bool,
+    pending_marker: Option<u8>,
+    complex_count: usize,
+    stack_len: usize,
+    top: Option<Frame>,
+    budget: Budget,
+    offset: u64,
+    amf3: amf3::reader::Checkpoint,
+}
+
+/// AMF0 pull parser.
+///
+/// Unlike `Decoder`, this reads values as a sequence of events without building the values in memory.
+/// The references are reported as `Event::Reference`.
+///
+/// The AMF3 values embedded in `AvmPlus` are also read as events,
+/// and the AMF3 reference tables are shared by those values as `Decoder` does.
+///
+/// # Examples
+/// ```
+/// use amf::amf0::{Event, Reader};
+///
+/// // "onMetaData", {duration: 3.0}
+/// let input = b"\x02\x00\x0aonMetaData\x03\x00\x08duration\x00\x40\x08\x00\x00\x00\x00\x00\x00\x00\x00\x09";
+/// let events = Reader::new(&input[..]).collect::<Result<Vec<_>, _>>().unwrap();
+/// assert_eq!(
+///     events,
+///     [
+///         Event::String("onMetaData".to_string()),
+///         Event::StartObject { class_name: None },
+///         Event::Key("duration".to_string()),
+///         Event::Number(3.0),
+///         Event::End,
+///     ]
+/// );
+/// ```
+#[derive(Debug)]
+pub struct Reader<R> {
+    inner: Position<R>,
+    stack: Vec<Frame>,
+    expects_value: bool,
+    pending_marker: Option<u8>,
+    complex_count: usize,
+    budget: Budget,
+    // The AMF3 reader is taken out while it reads an `AvmPlus` value
+    amf3: Option<amf3::Reader<io::Empty>>,
+    // The location of the last error detected in the input
+    location: Option<Location>,
+}
+impl<R> Reader<R> {
+    /// Unwraps this `Reader`, returning the underlying reader.
+    pub fn into_inner(self) -> R {
+        self.inner.inner
+    }
+
+    /// Returns a mutable reference to the registry of the externalizable class handlers
+    /// used to read the AMF3 values embedded in `AvmPlus`.
+    pub fn externalizables_mut(&mut self) -> &mut ExternalizableRegistry {
+        self.amf3_mut().externalizables_mut()
+    }
+    /// Returns the location of the event which failed to read in the last call of `next_event`.
+    ///
+    /// The path leads to the value which the event belongs to.
+    /// This is `None` if the last call succeeded.
+    pub fn error_location(&self) -> Option<&Location> {
+        self.location.as_ref()
+    }
+    pub(crate) fn clear_error_location(&mut self) {
+        self.location = None;
+    }
+    pub(crate) fn inner_mut(&mut self) -> &mut R {
+        &mut self.inner.inner
+    }
+    // Returns the number of the bytes which the last string lacked at the end of the input
+    pub(crate) fn take_missing(&mut self) -> usize {
+        let missing = self.inner.take_missing();
+        missing.max(self.amf3_mut().decoder_mut().take_missing())
+    }
+    pub(crate) fn budget_mut(&mut self) -> &mut Budget {
+        &mut self.budget
+    }
+    pub(crate) fn amf3_mut(&mut self) -> &mut amf3::Reader<io::Empty> {
+        self.amf3.as_mut().expect("the AMF3 reader is put back")
+    }
+    fn amf3(&self) -> &amf3::Reader<io::Empty> {
+        self.amf3.as_ref().expect("the AMF3 reader is put back")
+    }
+    pub(crate) fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            expects_value: self.expects_value,
+            pending_marker: self.pending_marker,
+            complex_count: self.complex_count,
+            stack_len: self.stack.len(),
+            top: self.stack.last().cloned(),
+            budget: self.budget,
+            offset: self.inner.offset,
+            amf3: self.amf3().checkpoint(),
+        }
+    }
+    // Restores the state saved before an event, except for the position of the underlying reader
+    pub(crate) fn rollback(&mut self, checkpoint: Checkpoint) {
+        // An event replaces the top frame at most, and may push a new frame onto it
+        self.stack.truncate(checkpoint.stack_len.saturating_sub(1));
+        self.stack.extend(checkpoint.top);
+        self.expects_value = checkpoint.expects_value;
+        self.pending_marker = checkpoint.pending_marker;
+        self.complex_count = checkpoint.complex_count;
+        self.budget = checkpoint.budget;
+        self.inner.offset = checkpoint.offset;
+        self.amf3_mut().rollback(checkpoint.amf3);
+    }
+}
+impl<R> Reader<R>
+where
+    R: io::Read,
+{
+    /// Makes a new instance.
+    pub fn new(inner: R) -> Self {
+        Self::with_limits(inner, DecoderLimits::default())
+    }
+
+    /// Makes a new instance with the specified limits.
+    ///
+    /// The limits also apply to the AMF3 values embedded in `AvmPlus`.
+    pub fn with_limits(inner: R, limits: DecoderLimits) -> Self {
+        Reader {
+            inner: Position::new(inner),
+            stack: Vec::new(),
+            expects_value: false,
+            pending_marker: None,
+            complex_count: 0,
+            budget: Budget::new(limits),
+            amf3: Some(amf3::Reader::with_limits(io::empty(), limits)),
+            location: None,
+        }
+    }
+
+    /// Reads the next event.
+    ///
+    /// If the underlying reader reaches EOF at a boundary of the top-level values, `None` will be returned.
+    pub fn next_event(&mut self) -> DecodeResult<Option<Event>> {
+        let offset = self.inner.offset;
+        self.location = None;
+        let result = self.read_event();
+        if result.is_err() {
+            Location::record(&mut self.location, offset);
+            for segment in self.path().into_iter().rev() {
+                Location::prepend(&mut self.location, segment);
+            }
+        }
+        result
+    }
+
+    /// Clear the reference table of this reader.
+    ///
+    /// The reference tables of the AMF3 values embedded in `AvmPlus` are also cleared.
+    pub fn clear_reference_table(&mut self) {
+        self.complex_count = 0;
+        self.amf3_mut().clear_reference_table();
+    }
+
+    fn read_event(&mut self) -> DecodeResult<Option<Event>> {
+        if mem::replace(&mut self.expects_value, false) {
+            let marker = match self.pending_marker.take() {
+                Some(marker) => marker,
+                None => self.inner.read_u8()?,
+            };
+            return self.read_value_with_marker(marker).map(Some);
+        }
+        let event = match self.stack.pop() {
+            None => {
+                let marker = match self.inner.read_u8() {
+                    Ok(marker) => marker,
+                    Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+                    Err(e) => return Err(e.into()),
+                };
+                self.budget.start_value();
+                self.read_value_with_marker(marker)?
+            }
+            Some(Frame::Pairs { .. }) => {
+                let len = self.inner.read_u16::<BigEndian>()? as usize;
+                let key = self.read_utf8(len)?;
+                if key.is_empty() {
+                    let marker = self.inner.read_u8()?;
+                    if marker == marker::OBJECT_END_MARKER {
+                        self.budget.leave();
+                        return Ok(Some(Event::End));
+                    }
+                    self.pending_marker = Some(marker);
+                }
+                self.stack.push(Frame::Pairs {
+                    key: Some(key.clone()),
+                });
+                self.expects_value = true;
+                Event::Key(key)
+            }
+            Some(Frame::Values { remaining, len }) => {
+                if remaining == 0 {
+                    self.budget.leave();
+                    return Ok(Some(Event::End));
+                }
+                self.stack.push(Frame::Values {
+                    remaining: remaining - 1,
+                    len,
+                });
+                let marker = self.inner.read_u8()?;
+                self.read_value_with_marker(marker)?
+            }
+            Some(Frame::AvmPlus) => self.read_avmplus()?,
+        };
+        Ok(Some(event))
+    }
+
+    fn read_value_with_marker(&mut self, marker: u8) -> DecodeResult<Event> {
+        match marker {
+            marker::NUMBER => {
+                let n = self.inner.read_f64::<BigEndian>()?;
+                Ok(Event::Number(n))
+            }
+            marker::BOOLEAN => {
+                let b = self.inner.read_u8()? != 0;
+                Ok(Event::Boolean(b))
+            }
+            marker::STRING => {
+                let len = self.inner.read_u16::<BigEndian>()? as usize;
+                self.read_utf8(len).map(Event::String)
+            }
+            marker::OBJECT => {
+                self.start_complex(Frame::Pairs { key: None })?;
+                Ok(Event::StartObject { class_name: None })
+            }
+            marker::MOVIECLIP => Ok(Event::MovieClip),
+            marker::NULL => Ok(Event::Null),
+            marker::UNDEFINED => Ok(Event::Undefined),
+            marker::REFERENCE => {
+                let index = self.inner.read_u16::<BigEndian>()? as usize;
+                if index >= self.complex_count {
+                    return Err(DecodeError::OutOfRangeReference { index });
+                }
+                Ok(Event::Reference { index })
+            }
+            marker::ECMA_ARRAY => {
+                let len = self.inner.read_u32::<BigEndian>()? as usize;
+                self.start_complex(Frame::Pairs { key: None })?;
+                Ok(Event::StartEcmaArray { len })
+            }
+            marker::OBJECT_END_MARKER => Err(DecodeError::UnexpectedObjectEnd),
+            marker::STRICT_ARRAY => {
+                let len = self.inner.read_u32::<BigEndian>()? as usize;
+                self.budget.collection::<Value>(len)?;
+                self.start_complex(Frame::Values {
+                    remaining: len,
+                    len,
+                })?;
+                Ok(Event::StartArray { len })
+            }
+            marker::DATE => {
+                let millis = self.inner.read_f64::<BigEndian>()?;
+                let time_zone = self.inner.read_i16::<BigEndian>()?;
+                if !(millis.is_finite() && millis.is_sign_positive()) {
+                    Err(DecodeError::InvalidDate { millis })
+                } else {
+                    Ok(Event::Date {
+                        unix_time: time::Duration::from_millis(millis as u64),
+                        time_zone,
+                    })
+                }
+            }
+            marker::LONG_STRING => {
+                let len = self.inner.read_u32::<BigEndian>()? as usize;
+                self.read_utf8(len).map(Event::String)
+            }
+            marker::UNSUPPORTED => Ok(Event::Unsupported),
+            marker::RECORDSET => Ok(Event::RecordSet),
+            marker::XML_DOCUMENT => {
+                let len = self.inner.read_u32::<BigEndian>()? as usize;
+                self.read_utf8(len).map(Event::XmlDocument)
+            }
+            marker::TYPED_OBJECT => {
+                let len = self.inner.read_u16::<BigEndian>()? as usize;
+                let class_name = self.read_utf8(len)?;
+                self.start_complex(Frame::Pairs { key: None })?;
+                Ok(Event::StartObject {
+                    class_name: Some(class_name),
+                })
+            }
+            marker::AVMPLUS_OBJECT => self.read_avmplus(),
+            _ => Err(DecodeError::Unknown { marker }),
+        }
+    }
+    fn read_avmplus(&mut self) -> DecodeResult<Event> {
+        // The AMF3 reference tables are shared by all `AvmPlus` values in a message
+        let amf3 = self.amf3.take().expect("the AMF3 reader is put back");
+        let offset = self.inner.offset;
+        let (mut reader, empty) = amf3.with_inner(&mut self.inner);
+        *reader.budget_mut() = self.budget;
+        reader.decoder_mut().set_offset(offset);
+        let result = reader.next_embedded_event();
+        let is_done = reader.is_at_value_boundary();
+        self.budget = *reader.budget_mut();
+        self.location = reader.decoder_mut().location.take();
+        self.amf3 = Some(reader.with_inner(empty).0);
+
+        let event = result?.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::UnexpectedEof, "failed to fill whole buffer")
+        })?;
+        if !is_done {
+            self.stack.push(Frame::AvmPlus);
+        }
+        Ok(Event::AvmPlus(event))
+    }
+    // Returns the path to the value being read from the top-level value
+    fn path(&self) -> Vec<PathSegment> {
+        self.stack
+            .iter()
+            .filter_map(|frame| match *frame {
+                Frame::Pairs { ref key } => key.clone().map(PathSegment::Key),
+                Frame::Values { remaining, len } => {
+                    (len - remaining).checked_sub(1).map(PathSegment::Index)
+                }
+                Frame::AvmPlus => None,
+            })
+            .collect()
+    }
+    fn start_complex(&mut self, frame: Frame) -> DecodeResult<()> {
+        self.budget.enter()?;
+        self.complex_count += 1;
+        self.stack.push(frame);
+        Ok(())
+    }
+    fn read_utf8(&mut self, len: usize) -> DecodeResult<String> {
+        self.budget.string(len)?;
+        let buf = self.inner.read_up_to(len)?;
+        if buf.len() < len {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        let utf8 = String::from_utf8(buf)?;
+        Ok(utf8)
+    }
+}
+impl<R> Iterator for Reader<R>
+where
+    R: io::Read,
+{
+    type Item = DecodeResult<Event>;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_event().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Decoder, Event, Reader, Value};
+    use crate::amf3;
+    use crate::error::DecodeError;
+    use crate::io;
+    use crate::Pair;
+    use std::iter::Peekable;
+
+    macro_rules! read_eq_decode {
+        ($($file:expr),*) => {$({
+            let input = include_bytes!(concat!("../testdata/", $file));
+            let mut events = Reader::new(&input[..]).map(Result::unwrap).peekable();
+            let value = build(&mut events, &mut Vec::new());
+            assert_eq!(events.next(), None);
+            assert_eq!(value, Decoder::new(&input[..]).decode().unwrap(), "{}", $file);
+        })*};
+    }
+
+    // Rebuilds a value from events for the comparison with `Decoder`
+    fn build<I>(events: &mut Peekable<I>, table: &mut Vec<Option<Value>>) -> Value
+    where
+        I: Iterator<Item = Event>,
+    {
+        let index = table.len();
+        let value = match events.next().unwrap() {
+            Event::Number(x) => return Value::Number(x),
+            Event::Boolean(x) => return Value::Boolean(x),
+            Event::String(x) => return Value::String(x),
+            Event::Null => return Value::Null,
+            Event::Undefined => return Value::Undefined,
+            Event::Unsupported => return Value::Unsupported,
+            Event::MovieClip => return Value::MovieClip,
+            Event::RecordSet => return Value::RecordSet,
+            Event::Date {
+                unix_time,
+                time_zone,
+            } => {
+                return Value::Date {
+                    unix_time,
+                    time_zone,
+                }
+            }
+            Event::XmlDocument(x) => return Value::XmlDocument(x),
+            Event::Reference { index } => return table[index].clone().unwrap(),
+            Event::Key(_) | Event::AvmPlus(_) | Event::End => panic!(),
+            Event::StartObject { class_name } => {
+                table.push(None);
+                let entries = build_pairs(events, table);
+                Value::Object {
+                    class_name,
+                    entries,
+                }
+            }
+            Event::StartEcmaArray { .. } => {
+                table.push(None);
+                let entries = build_pairs(events, table);
+                Value::EcmaArray { entries }
+            }
+            Event::StartArray { len } => {
+                table.push(None);
+                let entries = (0..len).map(|_| build(events, table)).collect();
+                assert_eq!(events.next(), Some(Event::End));
+                Value::Array { entries }
+            }
+        };
+        table[index] = Some(value.clone());
+        value
+    }
+    fn build_pairs<I>(
+        events: &mut Peekable<I>,
+        table: &mut Vec<Option<Value>>,
+    ) -> Vec<Pair<String, Value>>
+    where
+        I: Iterator<Item = Event>,
+    {
+        let mut pairs = Vec::new();
+        loop {
+            match events.next() {
+                Some(Event::Key(key)) => {
+                    let value = build(events, table);
+                    pairs.push(Pair { key, value });
+                }
+                Some(Event::End) => return pairs,
+                _ => panic!(),
+            }
+        }
+    }
+
+    #[test]
+    fn reads_values() {
+        read_eq_decode!(
+            "amf0-number.bin",
+            "amf0-boolean-true.bin",
+            "amf0-string.bin",
+            "amf0-null.bin",
+            "amf0-undefined.bin",
+            "amf0-unsupported.bin",
+            "amf0-movieclip.bin",
+            "amf0-recordset.bin",
+            "amf0-object.bin",
+            "amf0-typed-object.bin",
+            "amf0-ecma-ordinal-array.bin",
+            "amf0-hash.bin",
+            "amf0-strict-array.bin",
+            "amf0-date.bin",
+            "amf0-long-string.bin",
+            "amf0-xml-doc.bin",
+            "amf0-complex-encoded-string.bin",
+            "amf0-ref-test.bin"
+        );
+
+        // An empty key which is not followed by the object end marker
+        let input = b"\x03\x00\x00\x05\x00\x00\x09";
+        let events = Reader::new(&input[..])
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(
+            events,
+            [
+                Event::StartObject { class_name: None },
+                Event::Key("".to_string()),
+                Event::Null,
+                Event::End,
+            ]
+        );
+    }
+    #[test]
+    fn reads_avmplus() {
+        let mut input = Vec::new();
+        let value = Value::AvmPlus(amf3::Value::Array {
+            assoc_entries: vec![],
+            dense_entries: vec![amf3::Value::String("foo".to_string())],
+        });
+        value.write_to(&mut input).unwrap();
+        Value::AvmPlus(amf3::Value::String("foo".to_string()))
+            .write_to(&mut input)
+            .unwrap();
+        Value::Null.write_to(&mut input).unwrap();
+
+        let events = Reader::new(&input[..])
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(
+            events,
+            [
+                Event::AvmPlus(amf3::Event::StartArray { dense_len: 1 }),
+                Event::AvmPlus(amf3::Event::String("foo".to_string())),
+                Event::AvmPlus(amf3::Event::End),
+                Event::AvmPlus(amf3::Event::String("foo".to_string())),
+                Event::Null,
+            ]
+        );
+
+        // The AMF3 reference tables are shared by the sibling values
+        let input = b"\x11\x06\x07foo\x11\x06\x00";
+        let events = Reader::new(&input[..])
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(
+            events,
+            [
+                Event::AvmPlus(amf3::Event::String("foo".to_string())),
+                Event::AvmPlus(amf3::Event::String("foo".to_string())),
+            ]
+        );
+    }
+    #[test]
+    fn read_errors() {
+        let input = include_bytes!("../testdata/amf0-bad-reference.bin");
+        let result = Reader::new(&input[..]).collect::<Result<Vec<_>, _>>();
+        assert!(matches!(
+            result,
+            Err(DecodeError::OutOfRangeReference { .. })
+        ));
+
+        let input = include_bytes!("../testdata/amf0-bad-object-end.bin");
+        let result = Reader::new(&input[..]).collect::<Result<Vec<_>, _>>();
+        assert_eq!(result, Err(DecodeError::UnexpectedObjectEnd));
+
+        let input = include_bytes!("../testdata/amf0-object-partial.bin");
+        match Reader::new(&input[..]).collect::<Result<Vec<_>, _>>() {
+            Err(DecodeError::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof),
+            _ => panic!(),
+        }
+
+        let input = b"\x11";
+        match Reader::new(&input[..]).collect::<Result<Vec<_>, _>>() {
+            Err(DecodeError::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof),
+            _ => panic!(),
+        }
+    }
+    #[test]
+    fn locates_errors() {
+        // {a: [1.0, AvmPlus [<unknown marker>]]}
+        let input = b"\x03\x00\x01a\x0a\x00\x00\x00\x02\x00\x3f\xf0\x00\x00\x00\x00\x00\x00\x11\x09\x03\x01\x7b";
+        let mut reader = Reader::new(&input[..]);
+        let result = reader.by_ref().collect::<Result<Vec<_>, _>>();
+        assert_eq!(result, Err(DecodeError::Unknown { marker: 123 }));
+        let location = reader.error_location().unwrap();
+        assert_eq!(location.to_string(), "offset=22, path=$.a[1][0]");
+    }
+}
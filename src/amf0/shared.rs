@@ -0,0 +1,259 @@
+//! Reference-counted representation of AMF0 values.
+//!
+//! Unlike the tree [`Value`](super::Value), an object or an array referred multiple times
+//! is decoded into a single [`Node`] shared by [`Arc`]s,
+//! so the memory usage is proportional to the size of the input.
+//!
+//! # Examples
+//! ```
+//! use amf::amf0::{self, shared};
+//!
+//! // A strict array which contains the same empty object twice
+//! let input = b"\x0a\x00\x00\x00\x02\x03\x00\x00\x09\x07\x00\x01";
+//! let value = amf0::Decoder::new(&input[..]).decode_shared().unwrap();
+//! if let shared::Value::Node(ref node) = value {
+//!     if let shared::Node::Array { ref entries } = **node {
+//!         assert_eq!(entries[0], entries[1]);
+//!     }
+//! }
+//! assert_eq!(
+//!     value.into_owned(),
+//!     amf0::Value::read_from(&mut &input[..]).unwrap()
+//! );
+//! ```
+use super::graph::{self, Graph, NodeId};
+use super::Value as TreeValue;
+use crate::amf3;
+use crate::error::DecodeError;
+use crate::{DecodeResult, Pair};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::time;
+
+/// AMF0 value which shares the objects and arrays.
+#[derive(Debug, Clone, PartialEq, PartialOrd)]
+pub enum Value {
+    /// See [`Value::Number`](super::Value::Number).
+    Number(f64),
+
+    /// See [`Value::Boolean`](super::Value::Boolean).
+    Boolean(bool),
+
+    /// See [`Value::String`](super::Value::String).
+    String(String),
+
+    /// See [`Value::Null`](super::Value::Null).
+    Null,
+
+    /// See [`Value::Undefined`](super::Value::Undefined).
+    Undefined,
+
+    /// See [`Value::Unsupported`](super::Value::Unsupported).
+    Unsupported,
+
+    /// See [`Value::MovieClip`](super::Value::MovieClip).
+    MovieClip,
+
+    /// See [`Value::RecordSet`](super::Value::RecordSet).
+    RecordSet,
+
+    /// See [`Value::Date`](super::Value::Date).
+    Date {
+        /// Unix timestamp with milliseconds precision.
+        unix_time: time::Duration,
+
+        /// Time zone offset.
+        ///
+        /// Note that this is a reserved field and the value should be zero.
+        time_zone: i16,
+    },
+
+    /// See [`Value::XmlDocument`](super::Value::XmlDocument).
+    XmlDocument(String),
+
+    /// See [`Value::AvmPlus`](super::Value::AvmPlus).
+    AvmPlus(amf3::shared::Value),
+
+    /// An object or an array.
+    Node(Arc<Node>),
+}
+impl Value {
+    /// Converts this value into the tree representation.
+    ///
+    /// The nodes which are not shared with other values are moved without being copied.
+    pub fn into_owned(self) -> TreeValue {
+        match self {
+            Value::Number(x) => TreeValue::Number(x),
+            Value::Boolean(x) => TreeValue::Boolean(x),
+            Value::String(x) => TreeValue::String(x),
+            Value::Null => TreeValue::Null,
+            Value::Undefined => TreeValue::Undefined,
+            Value::Unsupported => TreeValue::Unsupported,
+            Value::MovieClip => TreeValue::MovieClip,
+            Value::RecordSet => TreeValue::RecordSet,
+            Value::Date {
+                unix_time,
+                time_zone,
+            } => TreeValue::Date {
+                unix_time,
+                time_zone,
+            },
+            Value::XmlDocument(x) => TreeValue::XmlDocument(x),
+            Value::AvmPlus(x) => TreeValue::AvmPlus(x.into_owned()),
+            Value::Node(x) => match Arc::try_unwrap(x).unwrap_or_else(|x| (*x).clone()) {
+                Node::Object {
+                    class_name,
+                    entries,
+                } => TreeValue::Object {
+                    class_name,
+                    entries: into_owned_pairs(entries),
+                },
+                Node::EcmaArray { entries } => TreeValue::EcmaArray {
+                    entries: into_owned_pairs(entries),
+                },
+                Node::Array { entries } => TreeValue::Array {
+                    entries: entries.into_iter().map(Value::into_owned).collect(),
+                },
+            },
+        }
+    }
+}
+impl From<Value> for TreeValue {
+    fn from(f: Value) -> Self {
+        f.into_owned()
+    }
+}
+
+/// Object or array which may be shared.
+#[derive(Debug, Clone, PartialEq, PartialOrd)]
+pub enum Node {
+    /// See [`Value::Object`](super::Value::Object).
+    Object {
+        /// The class name of the object.
+        /// `None` means it is an anonymous object.
+        class_name: Option<String>,
+
+        /// Properties of the object.
+        entries: Vec<Pair<String, Value>>,
+    },
+
+    /// See [`Value::EcmaArray`](super::Value::EcmaArray).
+    EcmaArray {
+        /// Entries of the associative array.
+        entries: Vec<Pair<String, Value>>,
+    },
+
+    /// See [`Value::Array`](super::Value::Array).
+    Array {
+        /// Entries of the array.
+        entries: Vec<Value>,
+    },
+}
+
+fn into_owned_pairs(pairs: Vec<Pair<String, Value>>) -> Vec<Pair<String, TreeValue>> {
+    pairs
+        .into_iter()
+        .map(|p| Pair {
+            key: p.key,
+            value: p.value.into_owned(),
+        })
+        .collect()
+}
+
+// The shared nodes converted from the nodes of a graph, indexed by `NodeId`
+#[derive(Debug, Default, Clone)]
+pub(crate) struct Nodes {
+    nodes: Vec<Option<Arc<Node>>>,
+    avmplus: amf3::shared::Nodes,
+}
+impl Nodes {
+    pub(crate) fn share(&mut self, graph: &Graph, value: &graph::Value) -> DecodeResult<Value> {
+        self.share_value(graph, value, &mut Vec::new())
+    }
+    pub(crate) fn clear(&mut self) {
+        self.nodes.clear();
+        self.avmplus.clear();
+    }
+
+    fn share_value(
+        &mut self,
+        graph: &Graph,
+        value: &graph::Value,
+        path: &mut Vec<NodeId>,
+    ) -> DecodeResult<Value> {
+        let id = match *value {
+            graph::Value::Number(x) => return Ok(Value::Number(x)),
+            graph::Value::Boolean(x) => return Ok(Value::Boolean(x)),
+            graph::Value::String(ref x) => return Ok(Value::String(x.clone())),
+            graph::Value::Null => return Ok(Value::Null),
+            graph::Value::Undefined => return Ok(Value::Undefined),
+            graph::Value::Unsupported => return Ok(Value::Unsupported),
+            graph::Value::MovieClip => return Ok(Value::MovieClip),
+            graph::Value::RecordSet => return Ok(Value::RecordSet),
+            graph::Value::Date {
+                unix_time,
+                time_zone,
+            } => {
+                return Ok(Value::Date {
+                    unix_time,
+                    time_zone,
+                })
+            }
+            graph::Value::XmlDocument(ref x) => return Ok(Value::XmlDocument(x.clone())),
+            graph::Value::AvmPlus(ref x) => {
+                return self.avmplus.share(graph.avmplus(), x).map(Value::AvmPlus)
+            }
+            graph::Value::Node(id) => id,
+        };
+        if let Some(Some(node)) = self.nodes.get(id.index()) {
+            return Ok(Value::Node(node.clone()));
+        }
+        if path.contains(&id) {
+            return Err(DecodeError::CircularReference { index: id.index() });
+        }
+        path.push(id);
+        let node = match *graph.decoded_node(id)? {
+            graph::Node::Object {
+                ref class_name,
+                ref entries,
+            } => Node::Object {
+                class_name: class_name.clone(),
+                entries: self.share_pairs(graph, entries, path)?,
+            },
+            graph::Node::EcmaArray { ref entries } => Node::EcmaArray {
+                entries: self.share_pairs(graph, entries, path)?,
+            },
+            graph::Node::Array { ref entries } => Node::Array {
+                entries: entries
+                    .iter()
+                    .map(|v| self.share_value(graph, v, path))
+                    .collect::<DecodeResult<_>>()?,
+            },
+        };
+        path.pop();
+
+        let node = Arc::new(node);
+        if self.nodes.len() <= id.index() {
+            self.nodes.resize(id.index() + 1, None);
+        }
+        self.nodes[id.index()] = Some(node.clone());
+        Ok(Value::Node(node))
+    }
+    fn share_pairs(
+        &mut self,
+        graph: &Graph,
+        pairs: &[Pair<String, graph::Value>],
+        path: &mut Vec<NodeId>,
+    ) -> DecodeResult<Vec<Pair<String, Value>>> {
+        pairs
+            .iter()
+            .map(|p| {
+                Ok(Pair {
+                    key: p.key.clone(),
+                    value: self.share_value(graph, &p.value, path)?,
+                })
+            })
+            .collect()
+    }
+}
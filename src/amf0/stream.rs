@@ -0,0 +1,464 @@
+use super::graph::{self, Graph, Node, NodeId};
+use super::reader::Reader;
+use super::{Event, Value};
+use crate::amf3::stream::{is_eof, Builder};
+use crate::amf3::ExternalizableRegistry;
+use crate::error::{DecodeError, Location};
+use crate::util::Feed;
+use crate::{DecodeResult, DecoderLimits, Pair};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::mem;
+
+// An object or an array whose entries are being read
+#[derive(Debug)]
+enum Partial {
+    Object {
+        id: NodeId,
+        class_name: Option<String>,
+        entries: Vec<Pair<String, graph::Value>>,
+        key: Option<String>,
+    },
+    EcmaArray {
+        id: NodeId,
+        entries: Vec<Pair<String, graph::Value>>,
+        key: Option<String>,
+    },
+    Array {
+        id: NodeId,
+        entries: Vec<graph::Value>,
+    },
+}
+impl Partial {
+    fn add(&mut self, value: graph::Value) {
+        match *self {
+            Partial::Object {
+                ref mut entries,
+                ref mut key,
+                ..
+            }
+            | Partial::EcmaArray {
+                ref mut entries,
+                ref mut key,
+                ..
+            } => entries.push(Pair {
+                key: key.take().unwrap_or_default(),
+                value,
+            }),
+            Partial::Array {
+                ref mut entries, ..
+            } => entries.push(value),
+        }
+    }
+    fn finish(self) -> (NodeId, Node) {
+        match self {
+            Partial::Object {
+                id,
+                class_name,
+                entries,
+                ..
+            } => (
+                id,
+                Node::Object {
+                    class_name,
+                    entries,
+                },
+            ),
+            Partial::EcmaArray { id, entries, .. } => (id, Node::EcmaArray { entries }),
+            Partial::Array { id, entries } => (id, Node::Array { entries }),
+        }
+    }
+}
+
+/// AMF0 decoder which decodes values from the bytes fed in pieces.
+///
+/// If the bytes fed so far end in the middle of a value, `decode` returns `DecodeError::Incomplete`.
+/// The events of the value which have been read are kept,
+/// so decoding resumes where it stopped when `decode` is called again after more bytes are fed.
+///
+/// After an error other than `Incomplete`, the decoder should be discarded.
+///
+/// # Examples
+/// ```
+/// use amf::amf0::{StreamDecoder, Value};
+/// use amf::error::DecodeError;
+///
+/// // "foo", 3.0
+/// let input = b"\x02\x00\x03foo\x00\x40\x08\x00\x00\x00\x00\x00\x00";
+/// let mut decoder = StreamDecoder::new();
+/// decoder.feed(&input[..2]);
+/// assert_eq!(decoder.decode().unwrap_err(), DecodeError::Incomplete { needed: 1 });
+/// decoder.feed(&input[2..]);
+/// assert_eq!(decoder.decode().unwrap(), Value::String("foo".to_string()));
+/// assert_eq!(decoder.decode().unwrap(), Value::Number(3.0));
+/// assert_eq!(decoder.decode().unwrap_err(), DecodeError::Incomplete { needed: 1 });
+/// ```
+#[derive(Debug)]
+pub struct StreamDecoder {
+    reader: Reader<Feed>,
+    // The number of the bytes which the incomplete value lacks at least,
+    // so that `decode` does not read it again until they are fed
+    pending: usize,
+    stack: Vec<Partial>,
+    avmplus: Builder,
+    complexes: Vec<NodeId>,
+    graph: Graph,
+    // The nodes which have been copied by `decode`
+    copied: Vec<bool>,
+    avmplus_copied: Vec<bool>,
+}
+impl StreamDecoder {
+    /// Makes a new instance.
+    pub fn new() -> Self {
+        Self::with_limits(DecoderLimits::default())
+    }
+
+    /// Makes a new instance with the specified limits.
+    ///
+    /// The limits also apply to the AMF3 values embedded in `AvmPlus`.
+    pub fn with_limits(limits: DecoderLimits) -> Self {
+        StreamDecoder {
+            reader: Reader::with_limits(Feed::default(), limits),
+            pending: 0,
+            stack: Vec::new(),
+            avmplus: Builder::default(),
+            complexes: Vec::new(),
+            graph: Graph::new(),
+            copied: Vec::new(),
+            avmplus_copied: Vec::new(),
+        }
+    }
+
+    /// Appends `bytes` to the input.
+    pub fn feed(&mut self, bytes: &[u8]) {
+        self.reader.inner_mut().extend(bytes);
+        self.pending = self.pending.saturating_sub(bytes.len());
+    }
+
+    /// Returns the number of the bytes fed but not consumed by the decoded values yet.
+    ///
+    /// The bytes of an incomplete value are counted until the value is decoded.
+    pub fn buffered_len(&mut self) -> usize {
+        self.reader.inner_mut().remaining()
+    }
+
+    /// Returns a mutable reference to the registry of the externalizable class handlers
+    /// used to decode the AMF3 values embedded in `AvmPlus`.
+    pub fn externalizables_mut(&mut self) -> &mut ExternalizableRegistry {
+        self.reader.externalizables_mut()
+    }
+
+    /// Returns the location of the value which failed to decode in the last call of `decode`.
+    ///
+    /// The offset is counted from the first byte fed.
+    /// This is `None` if the last call succeeded or returned `DecodeError::Incomplete`.
+    pub fn error_location(&self) -> Option<&Location> {
+        self.reader.error_location()
+    }
+
+    /// Decodes a AMF0 value from the bytes fed so far.
+    ///
+    /// Reference objects are copied as `Decoder::decode` does.
+    /// If the value is incomplete, `DecodeError::Incomplete` is returned
+    /// and this method can be called again after more bytes are fed.
+    /// The input is not read again until the `needed` bytes are fed.
+    pub fn decode(&mut self) -> DecodeResult<Value> {
+        if self.pending > 0 {
+            return Err(DecodeError::Incomplete {
+                needed: self.pending,
+            });
+        }
+        loop {
+            let checkpoint = self.reader.checkpoint();
+            let position = self.reader.inner_mut().position();
+            self.reader.inner_mut().take_shortage();
+            self.reader.take_missing();
+            let avmplus_index = self.reader.amf3_mut().decoder_mut().complex_count();
+            let event = match self.reader.next_event() {
+                Ok(Some(event)) => event,
+                Ok(None) => {
+                    self.reader.inner_mut().take_shortage();
+                    return Err(DecodeError::Incomplete { needed: 1 });
+                }
+                Err(e) => {
+                    let shortage = self.reader.inner_mut().take_shortage();
+                    let needed = shortage.max(self.reader.take_missing());
+                    if needed == 0 || !is_eof(&e) {
+                        return Err(e);
+                    }
+                    self.reader.inner_mut().rewind(position);
+                    self.reader.rollback(checkpoint);
+                    self.pending = needed;
+                    self.reader.clear_error_location();
+                    return Err(DecodeError::Incomplete { needed });
+                }
+            };
+            if let Some(value) = self.add(avmplus_index, event)? {
+                return self.copy_tree(&value);
+            }
+        }
+    }
+
+    /// Clear the reference table of this decoder.
+    ///
+    /// The reference tables of the AMF3 values embedded in `AvmPlus` are also cleared.
+    /// This should be called only at a boundary of the top-level values,
+    /// i.e., not while a value is incomplete.
+    pub fn clear_reference_table(&mut self) {
+        self.reader.clear_reference_table();
+        self.complexes.clear();
+        self.graph.clear();
+        self.copied.clear();
+        self.avmplus_copied.clear();
+    }
+
+    // Adds an event, returning the value if the event completes a top-level value
+    fn add(&mut self, avmplus_index: usize, event: Event) -> DecodeResult<Option<graph::Value>> {
+        let value = match event {
+            Event::Number(x) => graph::Value::Number(x),
+            Event::Boolean(x) => graph::Value::Boolean(x),
+            Event::String(x) => graph::Value::String(x),
+            Event::Null => graph::Value::Null,
+            Event::Undefined => graph::Value::Undefined,
+            Event::Unsupported => graph::Value::Unsupported,
+            Event::MovieClip => graph::Value::MovieClip,
+            Event::RecordSet => graph::Value::RecordSet,
+            Event::Date {
+                unix_time,
+                time_zone,
+            } => graph::Value::Date {
+                unix_time,
+                time_zone,
+            },
+            Event::XmlDocument(x) => graph::Value::XmlDocument(x),
+            Event::Reference { index } => match self.complexes.get(index) {
+                Some(&id) => graph::Value::Node(id),
+                None => return Err(DecodeError::OutOfRangeReference { index }),
+            },
+            Event::AvmPlus(event) => {
+                let decoder = self.reader.amf3_mut().decoder_mut();
+                match self.avmplus.add(decoder, avmplus_index, event)? {
+                    Some(value) => graph::Value::AvmPlus(value),
+                    None => return Ok(None),
+                }
+            }
+            Event::StartObject { class_name } => {
+                let id = self.reserve();
+                self.stack.push(Partial::Object {
+                    id,
+                    class_name,
+                    entries: Vec::new(),
+                    key: None,
+                });
+                return Ok(None);
+            }
+            Event::StartEcmaArray { .. } => {
+                let id = self.reserve();
+                self.stack.push(Partial::EcmaArray {
+                    id,
+                    entries: Vec::new(),
+                    key: None,
+                });
+                return Ok(None);
+            }
+            Event::StartArray { .. } => {
+                let id = self.reserve();
+                self.stack.push(Partial::Array {
+                    id,
+                    entries: Vec::new(),
+                });
+                return Ok(None);
+            }
+            Event::Key(x) => {
+                if let Some(Partial::Object { key, .. } | Partial::EcmaArray { key, .. }) =
+                    self.stack.last_mut()
+                {
+                    *key = Some(x);
+                }
+                return Ok(None);
+            }
+            Event::End => {
+                let (id, node) = self.stack.pop().expect("unbalanced events").finish();
+                self.graph.fill(id, node);
+                graph::Value::Node(id)
+            }
+        };
+        match self.stack.last_mut() {
+            None => Ok(Some(value)),
+            Some(partial) => {
+                partial.add(value);
+                Ok(None)
+            }
+        }
+    }
+    fn reserve(&mut self) -> NodeId {
+        let id = self.graph.reserve();
+        self.complexes.push(id);
+        id
+    }
+    fn copy_tree(&mut self, value: &graph::Value) -> DecodeResult<Value> {
+        // The AMF3 values are built in the graph of the AMF3 reader
+        let avmplus = self.reader.amf3_mut().decoder_mut().graph_mut();
+        mem::swap(self.graph.avmplus_mut(), avmplus);
+        let result = self.graph.to_tree_with(
+            value,
+            &mut self.copied,
+            &mut self.avmplus_copied,
+            self.reader.budget_mut(),
+        );
+        let avmplus = self.reader.amf3_mut().decoder_mut().graph_mut();
+        mem::swap(self.graph.avmplus_mut(), avmplus);
+        result
+    }
+}
+impl Default for StreamDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Decoder, StreamDecoder, Value};
+    use crate::error::DecodeError;
+
+    macro_rules! stream_eq_decode {
+        ($($file:expr),*) => {$({
+            let input = include_bytes!(concat!("../testdata/", $file));
+            let expected = Decoder::new(&input[..]).decode().unwrap();
+            assert_eq!(feed_bytewise(&input[..]), expected, "{}", $file);
+        })*};
+    }
+
+    // Feeds the input byte by byte, decoding a value after each byte
+    fn feed_bytewise(input: &[u8]) -> Value {
+        let mut decoder = StreamDecoder::new();
+        for (i, b) in input.iter().enumerate() {
+            match decoder.decode() {
+                Err(DecodeError::Incomplete { needed }) => {
+                    assert!(needed >= 1 && needed <= input.len() - i, "{}", needed)
+                }
+                other => panic!("unexpected result {:?} at {}", other, i),
+            }
+            decoder.feed(&[*b]);
+        }
+        let value = decoder.decode().unwrap();
+        assert_eq!(decoder.buffered_len(), 0);
+        value
+    }
+
+    #[test]
+    fn decodes_fed_bytes() {
+        stream_eq_decode!(
+            "amf0-number.bin",
+            "amf0-boolean-true.bin",
+            "amf0-string.bin",
+            "amf0-null.bin",
+            "amf0-undefined.bin",
+            "amf0-object.bin",
+            "amf0-typed-object.bin",
+            "amf0-ecma-ordinal-array.bin",
+            "amf0-hash.bin",
+            "amf0-strict-array.bin",
+            "amf0-date.bin",
+            "amf0-long-string.bin",
+            "amf0-xml-doc.bin",
+            "amf0-complex-encoded-string.bin",
+            "amf0-ref-test.bin",
+            "amf0-avmplus-object.bin"
+        );
+    }
+    #[test]
+    fn decodes_sequence() {
+        // AvmPlus("foo"), and an AMF3 reference to it
+        let input = b"\x11\x06\x07foo\x11\x06\x00\x05";
+        let mut decoder = StreamDecoder::new();
+        decoder.feed(&input[..5]);
+        assert_eq!(decoder.decode(), Err(DecodeError::Incomplete { needed: 1 }));
+        decoder.feed(&input[5..]);
+        let foo = Value::AvmPlus(crate::amf3::Value::String("foo".to_string()));
+        assert_eq!(decoder.decode(), Ok(foo.clone()));
+        assert_eq!(decoder.decode(), Ok(foo));
+        assert_eq!(decoder.decode(), Ok(Value::Null));
+        assert_eq!(decoder.buffered_len(), 0);
+
+        // [{}, <reference to 1>]
+        decoder.clear_reference_table();
+        decoder.feed(b"\x0a\x00\x00\x00\x02\x03\x00\x00\x09\x07\x00\x01");
+        assert_eq!(
+            decoder.decode(),
+            Ok(Value::Array {
+                entries: vec![
+                    Value::Object {
+                        class_name: None,
+                        entries: Vec::new()
+                    },
+                    Value::Object {
+                        class_name: None,
+                        entries: Vec::new()
+                    }
+                ]
+            })
+        );
+    }
+    #[test]
+    fn reports_incomplete_input() {
+        macro_rules! incomplete {
+            ($file:expr, $needed:expr) => {{
+                let mut decoder = StreamDecoder::new();
+                decoder.feed(include_bytes!(concat!("../testdata/", $file)));
+                assert_eq!(
+                    decoder.decode(),
+                    Err(DecodeError::Incomplete { needed: $needed }),
+                    "{}",
+                    $file
+                );
+            }};
+        }
+        incomplete!("amf0-empty.bin", 1);
+        incomplete!("amf0-number-partial.bin", 1);
+        incomplete!("amf0-boolean-partial.bin", 1);
+        incomplete!("amf0-date-partial.bin", 1);
+        incomplete!("amf0-strict-array-partial.bin", 1);
+
+        // A long string reports all the bytes which it lacks
+        let value = Value::String("a".repeat(100_000));
+        let mut input = Vec::new();
+        value.write_to(&mut input).unwrap();
+        let mut decoder = StreamDecoder::new();
+        decoder.feed(&input[..1000]);
+        assert_eq!(
+            decoder.decode(),
+            Err(DecodeError::Incomplete {
+                needed: input.len() - 1000
+            })
+        );
+        decoder.feed(&input[1000..50_000]);
+        assert_eq!(
+            decoder.decode(),
+            Err(DecodeError::Incomplete {
+                needed: input.len() - 50_000
+            })
+        );
+        decoder.feed(&input[50_000..]);
+        assert_eq!(decoder.decode(), Ok(value));
+
+        // Errors other than the end of the input are returned as they are
+        let mut decoder = StreamDecoder::new();
+        decoder.feed(include_bytes!("../testdata/amf0-unknown-marker.bin"));
+        assert_eq!(decoder.decode(), Err(DecodeError::Unknown { marker: 97 }));
+    }
+    #[test]
+    fn locates_errors() {
+        // {a: [1.0, AvmPlus [<unknown marker>]]}
+        let input = b"\x03\x00\x01a\x0a\x00\x00\x00\x02\x00\x3f\xf0\x00\x00\x00\x00\x00\x00\x11\x09\x03\x01\x7b";
+        let mut decoder = StreamDecoder::new();
+        decoder.feed(&input[..21]);
+        assert!(decoder.decode().is_err());
+        assert_eq!(decoder.error_location(), None);
+        decoder.feed(&input[21..]);
+        assert_eq!(decoder.decode(), Err(DecodeError::Unknown { marker: 123 }));
+        let location = decoder.error_location().unwrap();
+        assert_eq!(location.to_string(), "offset=22, path=$.a[1][0]");
+    }
+}
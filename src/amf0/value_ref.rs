@@ -0,0 +1,543 @@
+use super::marker;
+use super::Value;
+use crate::amf3::value_ref::{read_f64, read_slice, read_str, read_u8};
+use crate::amf3::{self, ExternalizableRegistry};
+use crate::error::{DecodeError, Location, PathSegment};
+use crate::limits::Budget;
+use crate::{DecodeResult, DecoderLimits, Pair};
+use alloc::borrow::ToOwned;
+use alloc::string::String;
+use alloc::string::ToString;
+use alloc::vec::Vec;
+use byteorder::{BigEndian, ByteOrder};
+use core::mem;
+use core::time;
+
+/// AMF0 value which borrows the strings from the input.
+///
+/// This is decoded from a byte slice by `SliceDecoder` without copying the strings.
+///
+/// The variants are the same as those of `Value`.
+///
+/// # Examples
+/// ```
+/// use amf::amf0::{Value, ValueRef};
+///
+/// let input = b"\x02\x00\x07connect";
+/// let value = ValueRef::read_from(&mut &input[..]).unwrap();
+/// assert_eq!(value, ValueRef::String("connect"));
+/// assert_eq!(value.into_owned(), Value::String("connect".to_string()));
+/// ```
+#[derive(Debug, Clone, PartialEq, PartialOrd)]
+pub enum ValueRef<'a> {
+    /// See `Value::Number`.
+    Number(f64),
+
+    /// See `Value::Boolean`.
+    Boolean(bool),
+
+    /// See `Value::String`.
+    String(&'a str),
+
+    /// See `Value::Object`.
+    Object {
+        /// The class name of the object.
+        /// `None` means it is an anonymous object.
+        class_name: Option<&'a str>,
+
+        /// Properties of the object.
+        entries: Vec<Pair<&'a str, ValueRef<'a>>>,
+    },
+
+    /// See `Value::Null`.
+    Null,
+
+    /// See `Value::Undefined`.
+    Undefined,
+
+    /// See `Value::Unsupported`.
+    Unsupported,
+
+    /// See `Value::MovieClip`.
+    MovieClip,
+
+    /// See `Value::RecordSet`.
+    RecordSet,
+
+    /// See `Value::EcmaArray`.
+    EcmaArray {
+        /// Entries of the associative array.
+        entries: Vec<Pair<&'a str, ValueRef<'a>>>,
+    },
+
+    /// See `Value::Array`.
+    Array {
+        /// Entries of the array.
+        entries: Vec<ValueRef<'a>>,
+    },
+
+    /// See `Value::Date`.
+    Date {
+        /// Unix timestamp with milliseconds precision.
+        unix_time: time::Duration,
+
+        /// Time zone offset.
+        time_zone: i16,
+    },
+
+    /// See `Value::XmlDocument`.
+    XmlDocument(&'a str),
+
+    /// See `Value::AvmPlus`.
+    AvmPlus(amf3::ValueRef<'a>),
+}
+impl<'a> ValueRef<'a> {
+    /// Reads an AMF0 encoded `ValueRef` from `input`, advancing it past the value.
+    ///
+    /// Like `Value::read_from`, reference objects are copied
+    /// and circular references are treated as errors.
+    pub fn read_from(input: &mut &'a [u8]) -> DecodeResult<Self> {
+        let mut decoder = SliceDecoder::new(input);
+        let value = decoder.decode()?;
+        *input = decoder.remaining();
+        Ok(value)
+    }
+
+    /// Converts this value into the owned `Value`.
+    pub fn into_owned(self) -> Value {
+        match self {
+            ValueRef::Number(x) => Value::Number(x),
+            ValueRef::Boolean(x) => Value::Boolean(x),
+            ValueRef::String(x) => Value::String(x.to_string()),
+            ValueRef::Object {
+                class_name,
+                entries,
+            } => Value::Object {
+                class_name: class_name.map(str::to_string),
+                entries: into_owned_pairs(entries),
+            },
+            ValueRef::Null => Value::Null,
+            ValueRef::Undefined => Value::Undefined,
+            ValueRef::Unsupported => Value::Unsupported,
+            ValueRef::MovieClip => Value::MovieClip,
+            ValueRef::RecordSet => Value::RecordSet,
+            ValueRef::EcmaArray { entries } => Value::EcmaArray {
+                entries: into_owned_pairs(entries),
+            },
+            ValueRef::Array { entries } => Value::Array {
+                entries: entries.into_iter().map(Self::into_owned).collect(),
+            },
+            ValueRef::Date {
+                unix_time,
+                time_zone,
+            } => Value::Date {
+                unix_time,
+                time_zone,
+            },
+            ValueRef::XmlDocument(x) => Value::XmlDocument(x.to_string()),
+            ValueRef::AvmPlus(x) => Value::AvmPlus(x.into_owned()),
+        }
+    }
+
+    /// Tries to convert the value as a `str` reference.
+    pub fn try_as_str(&self) -> Option<&str> {
+        match *self {
+            ValueRef::String(x) => Some(x),
+            ValueRef::XmlDocument(x) => Some(x),
+            ValueRef::AvmPlus(ref x) => x.try_as_str(),
+            _ => None,
+        }
+    }
+
+    /// Tries to convert the value as a `f64`.
+    pub fn try_as_f64(&self) -> Option<f64> {
+        match *self {
+            ValueRef::Number(x) => Some(x),
+            ValueRef::AvmPlus(ref x) => x.try_as_f64(),
+            _ => None,
+        }
+    }
+
+    /// Tries to convert the string, the XML document,
+    /// or the AMF3 string, XML or byte array into a `Bytes`.
+    ///
+    /// See `amf3::ValueRef::to_bytes`.
+    #[cfg(feature = "bytes")]
+    pub fn to_bytes(&self, input: &bytes::Bytes) -> Option<bytes::Bytes> {
+        match *self {
+            ValueRef::String(x) => Some(crate::util::share_bytes(input, x.as_bytes())),
+            ValueRef::XmlDocument(x) => Some(crate::util::share_bytes(input, x.as_bytes())),
+            ValueRef::AvmPlus(ref x) => x.to_bytes(input),
+            _ => None,
+        }
+    }
+}
+impl<'a> From<ValueRef<'a>> for Value {
+    fn from(f: ValueRef<'a>) -> Value {
+        f.into_owned()
+    }
+}
+
+fn into_owned_pairs(pairs: Vec<Pair<&str, ValueRef>>) -> Vec<Pair<String, Value>> {
+    pairs
+        .into_iter()
+        .map(|p| Pair {
+            key: p.key.to_string(),
+            value: p.value.into_owned(),
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Complex<'a> {
+    // The value is being decoded, so a reference to it is circular
+    Decoding,
+    // The encoded bytes of the value, which are decoded again when the value is referred
+    Decoded(&'a [u8]),
+}
+
+/// AMF0 decoder which decodes `ValueRef` values from a byte slice.
+///
+/// Unlike `Decoder`, the strings are borrowed from the input.
+/// A referred object or array is not kept in the reference table but decoded again from the input.
+///
+/// # Examples
+/// ```
+/// use amf::amf0::{SliceDecoder, ValueRef};
+///
+/// // "connect", 1.0, null
+/// let input = b"\x02\x00\x07connect\x00\x3f\xf0\x00\x00\x00\x00\x00\x00\x05";
+/// let mut decoder = SliceDecoder::new(&input[..]);
+/// assert_eq!(decoder.decode().unwrap(), ValueRef::String("connect"));
+/// assert_eq!(decoder.decode().unwrap(), ValueRef::Number(1.0));
+/// assert_eq!(decoder.decode().unwrap(), ValueRef::Null);
+/// assert!(decoder.remaining().is_empty());
+/// ```
+#[derive(Debug)]
+pub struct SliceDecoder<'a> {
+    input: &'a [u8],
+    // The address of the beginning of the input, from which the offsets of the errors are counted
+    origin: usize,
+    complexes: Vec<Complex<'a>>,
+    // Greater than zero while a referred value is decoded again
+    replaying: usize,
+    budget: Budget,
+    amf3: amf3::SliceDecoder<'a>,
+    // The location of the last error detected in the input
+    location: Option<Location>,
+}
+impl<'a> SliceDecoder<'a> {
+    /// Makes a new instance.
+    pub fn new(input: &'a [u8]) -> Self {
+        Self::with_limits(input, DecoderLimits::default())
+    }
+
+    /// Makes a new instance with the specified limits.
+    ///
+    /// Every value decoded again for a reference counts as a reference expansion.
+    pub fn with_limits(input: &'a [u8], limits: DecoderLimits) -> Self {
+        SliceDecoder {
+            input,
+            origin: input.as_ptr() as usize,
+            complexes: Vec::new(),
+            replaying: 0,
+            budget: Budget::new(limits),
+            // The empty prefix of the input makes the offsets of AMF3 values absolute
+            amf3: amf3::SliceDecoder::new(&input[..0]),
+            location: None,
+        }
+    }
+
+    /// Returns the limits of this decoder.
+    ///
+    /// The limits also apply to the AMF3 values embedded in `AvmPlus`.
+    pub fn limits(&self) -> &DecoderLimits {
+        &self.budget.limits
+    }
+
+    /// Returns a mutable reference to the limits of this decoder.
+    pub fn limits_mut(&mut self) -> &mut DecoderLimits {
+        &mut self.budget.limits
+    }
+
+    /// Returns the rest of the input which has not been decoded yet.
+    pub fn remaining(&self) -> &'a [u8] {
+        self.input
+    }
+
+    /// Returns the location of the value which failed to decode in the last call of `decode`.
+    ///
+    /// This is `None` if the last call succeeded.
+    /// The location of an error in the AMF3 values embedded in `AvmPlus` is also reported.
+    pub fn error_location(&self) -> Option<&Location> {
+        self.location.as_ref()
+    }
+
+    /// Returns a mutable reference to the registry of the externalizable class handlers
+    /// used to decode the AMF3 values embedded in `AvmPlus`.
+    pub fn externalizables_mut(&mut self) -> &mut ExternalizableRegistry {
+        self.amf3.externalizables_mut()
+    }
+
+    /// Enables or disables capturing the bodies of the externalizable objects which have no handler
+    /// in the AMF3 values embedded in `AvmPlus`.
+    ///
+    /// See `amf3::SliceDecoder::set_capture_unknown_externals` for the details.
+    pub fn set_capture_unknown_externals(&mut self, enabled: bool) {
+        self.amf3.set_capture_unknown_externals(enabled);
+    }
+
+    /// Decodes a AMF0 value.
+    pub fn decode(&mut self) -> DecodeResult<ValueRef<'a>> {
+        self.budget.start_value();
+        self.location = None;
+        self.decode_value()
+    }
+
+    /// Clear the reference table of this decoder.
+    ///
+    /// The reference tables of the AMF3 values embedded in `AvmPlus` are also cleared.
+    pub fn clear_reference_table(&mut self) {
+        self.complexes.clear();
+        self.amf3.clear_reference_table();
+        self.budget.clear_expansions();
+    }
+
+    fn decode_value(&mut self) -> DecodeResult<ValueRef<'a>> {
+        let offset = (self.input.as_ptr() as usize).wrapping_sub(self.origin) as u64;
+        let result = self.decode_marked_value();
+        if result.is_err() {
+            Location::record(&mut self.location, offset);
+        }
+        result
+    }
+    fn decode_marked_value(&mut self) -> DecodeResult<ValueRef<'a>> {
+        if self.replaying > 0 {
+            self.budget.expand(1)?;
+        }
+        let start = self.input;
+        let marker = read_u8(&mut self.input)?;
+        match marker {
+            marker::NUMBER => read_f64(&mut self.input).map(ValueRef::Number),
+            marker::BOOLEAN => Ok(ValueRef::Boolean(read_u8(&mut self.input)? != 0)),
+            marker::STRING => self.read_str_u16().map(ValueRef::String),
+            marker::OBJECT => self.decode_complex_type(start, |this| {
+                let entries = this.decode_pairs()?;
+                Ok(ValueRef::Object {
+                    class_name: None,
+                    entries,
+                })
+            }),
+            marker::MOVIECLIP => Ok(ValueRef::MovieClip),
+            marker::NULL => Ok(ValueRef::Null),
+            marker::UNDEFINED => Ok(ValueRef::Undefined),
+            marker::REFERENCE => {
+                let index = BigEndian::read_u16(read_slice(&mut self.input, 2)?) as usize;
+                self.decode_reference(index)
+            }
+            marker::ECMA_ARRAY => self.decode_complex_type(start, |this| {
+                let _count = read_slice(&mut this.input, 4)?;
+                let entries = this.decode_pairs()?;
+                Ok(ValueRef::EcmaArray { entries })
+            }),
+            marker::OBJECT_END_MARKER => Err(DecodeError::UnexpectedObjectEnd),
+            marker::STRICT_ARRAY => self.decode_complex_type(start, |this| {
+                let count = BigEndian::read_u32(read_slice(&mut this.input, 4)?) as usize;
+                this.budget.collection::<ValueRef>(count)?;
+                let entries = (0..count)
+                    .map(|i| {
+                        let result = this.decode_value();
+                        if result.is_err() {
+                            Location::prepend(&mut this.location, PathSegment::Index(i));
+                        }
+                        result
+                    })
+                    .collect::<DecodeResult<_>>()?;
+                Ok(ValueRef::Array { entries })
+            }),
+            marker::DATE => {
+                let millis = read_f64(&mut self.input)?;
+                let time_zone = BigEndian::read_i16(read_slice(&mut self.input, 2)?);
+                if !(millis.is_finite() && millis.is_sign_positive()) {
+                    Err(DecodeError::InvalidDate { millis })
+                } else {
+                    Ok(ValueRef::Date {
+                        unix_time: time::Duration::from_millis(millis as u64),
+                        time_zone,
+                    })
+                }
+            }
+            marker::LONG_STRING => self.read_str_u32().map(ValueRef::String),
+            marker::UNSUPPORTED => Ok(ValueRef::Unsupported),
+            marker::RECORDSET => Ok(ValueRef::RecordSet),
+            marker::XML_DOCUMENT => self.read_str_u32().map(ValueRef::XmlDocument),
+            marker::TYPED_OBJECT => self.decode_complex_type(start, |this| {
+                let class_name = this.read_str_u16()?;
+                let entries = this.decode_pairs()?;
+                Ok(ValueRef::Object {
+                    class_name: Some(class_name),
+                    entries,
+                })
+            }),
+            marker::AVMPLUS_OBJECT => {
+                // The AMF3 reference tables are shared by all `AvmPlus` values in a message
+                let replaying = self.replaying > 0;
+                self.amf3.budget = self.budget;
+                let result = self.amf3.decode_from(&mut self.input, replaying);
+                self.location = self.amf3.location.take();
+                self.budget = self.amf3.budget;
+                result.map(ValueRef::AvmPlus)
+            }
+            _ => Err(DecodeError::Unknown { marker }),
+        }
+    }
+    fn decode_reference(&mut self, index: usize) -> DecodeResult<ValueRef<'a>> {
+        match self.complexes.get(index) {
+            None => Err(DecodeError::OutOfRangeReference { index }),
+            Some(&Complex::Decoding) => Err(DecodeError::CircularReference { index }),
+            Some(&Complex::Decoded(start)) => {
+                let input = mem::replace(&mut self.input, start);
+                self.replaying += 1;
+                let result = self.decode_value();
+                self.replaying -= 1;
+                self.input = input;
+                result
+            }
+        }
+    }
+    fn decode_complex_type<F>(&mut self, start: &'a [u8], f: F) -> DecodeResult<ValueRef<'a>>
+    where
+        F: FnOnce(&mut Self) -> DecodeResult<ValueRef<'a>>,
+    {
+        self.budget.enter()?;
+        if self.replaying > 0 {
+            let result = f(self);
+            self.budget.leave();
+            return result;
+        }
+        let index = self.complexes.len();
+        self.complexes.push(Complex::Decoding);
+        let result = f(self);
+        self.budget.leave();
+        match result {
+            Ok(_) => self.complexes[index] = Complex::Decoded(start),
+            Err(_) => self.complexes.truncate(index),
+        }
+        result
+    }
+    fn decode_pairs(&mut self) -> DecodeResult<Vec<Pair<&'a str, ValueRef<'a>>>> {
+        let mut entries = Vec::new();
+        loop {
+            let key = self.read_str_u16()?;
+            match self.decode_value() {
+                Ok(value) => {
+                    self.budget
+                        .entry::<Pair<&str, ValueRef>>(entries.len() + 1)?;
+                    entries.push(Pair { key, value });
+                }
+                Err(DecodeError::UnexpectedObjectEnd) if key.is_empty() => {
+                    // The object end marker is not an error
+                    self.location = None;
+                    break;
+                }
+                Err(e) => {
+                    Location::prepend(&mut self.location, PathSegment::Key(key.to_owned()));
+                    return Err(e);
+                }
+            }
+        }
+        Ok(entries)
+    }
+    fn read_str_u16(&mut self) -> DecodeResult<&'a str> {
+        let len = BigEndian::read_u16(read_slice(&mut self.input, 2)?) as usize;
+        self.budget.string(len)?;
+        read_str(&mut self.input, len)
+    }
+    fn read_str_u32(&mut self) -> DecodeResult<&'a str> {
+        let len = BigEndian::read_u32(read_slice(&mut self.input, 4)?) as usize;
+        self.budget.string(len)?;
+        read_str(&mut self.input, len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Decoder, SliceDecoder, Value, ValueRef};
+    use crate::amf3;
+    use crate::error::DecodeError;
+    use crate::DecodeResult;
+    use std::fs;
+
+    fn same_result(x: &DecodeResult<Value>, y: &DecodeResult<Value>) -> bool {
+        match (x, y) {
+            (Err(DecodeError::Io(x)), Err(DecodeError::Io(y))) => x.kind() == y.kind(),
+            (Err(DecodeError::String(_)), Err(DecodeError::String(_))) => true,
+            // NaN is not equal to itself
+            (Ok(x), Ok(y)) => format!("{:?}", x) == format!("{:?}", y),
+            _ => x == y,
+        }
+    }
+
+    #[test]
+    fn decodes_same_values_as_decoder() {
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/src/testdata");
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if !path
+                .file_name()
+                .unwrap()
+                .to_str()
+                .unwrap()
+                .starts_with("amf0-")
+            {
+                continue;
+            }
+            let input = fs::read(&path).unwrap();
+
+            let mut decoder = Decoder::new(&input[..]);
+            let expected = decoder.decode();
+            let mut slice_decoder = SliceDecoder::new(&input[..]);
+            let actual = slice_decoder.decode().map(ValueRef::into_owned);
+            assert!(
+                same_result(&actual, &expected),
+                "{:?}: {:?} != {:?}",
+                path,
+                actual,
+                expected
+            );
+            // `Decoder` detects circular references after decoding, so those are not located
+            if !matches!(expected, Err(DecodeError::CircularReference { .. })) {
+                assert_eq!(
+                    slice_decoder.error_location(),
+                    decoder.error_location(),
+                    "{:?}",
+                    path
+                );
+            }
+            if expected.is_ok() {
+                assert_eq!(
+                    slice_decoder.remaining(),
+                    decoder.into_inner(),
+                    "{:?}",
+                    path
+                );
+            }
+        }
+    }
+    #[test]
+    fn shares_avmplus_reference_tables() {
+        let mut input = Vec::new();
+        let string = Value::AvmPlus(amf3::Value::String("foo".to_string()));
+        let mut encoder = super::super::Encoder::new(&mut input);
+        encoder.encode(&string).unwrap();
+        encoder.encode(&string).unwrap();
+        assert_eq!(input, b"\x11\x06\x07foo\x11\x06\x00");
+
+        let mut decoder = SliceDecoder::new(&input[..]);
+        for _ in 0..2 {
+            let value = decoder.decode().unwrap();
+            assert_eq!(value.try_as_str(), Some("foo"));
+        }
+        assert!(decoder.remaining().is_empty());
+    }
+}
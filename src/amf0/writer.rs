@@ -0,0 +1,348 @@
+use super::encode::collection_len;
+use super::marker;
+use super::{Encoder, Value};
+use crate::amf3::ExternalizableRegistry;
+use crate::error::EncodeError;
+use crate::io::{self, WriteBytesExt};
+use crate::{EncodeResult, EncoderOptions};
+use alloc::vec::Vec;
+use byteorder::BigEndian;
+use core::mem;
+use core::time;
+
+#[derive(Debug)]
+enum Frame {
+    Pairs,
+    Values { remaining: usize },
+}
+
+/// AMF0 push writer.
+///
+/// Unlike `Encoder`, this writes values incrementally without building the values in memory.
+/// The reference tables are shared by all values written by a writer,
+/// including the ones written by `write_value`.
+///
+/// # Examples
+/// ```
+/// use amf::amf0::{Value, Writer};
+///
+/// let mut writer = Writer::new(Vec::new());
+/// writer.write_string("_result").unwrap();
+/// writer.write_number(1.0).unwrap();
+/// writer.begin_object(None).unwrap();
+/// writer.key("code").unwrap();
+/// writer.write_string("NetConnection.Connect.Success").unwrap();
+/// writer.end().unwrap();
+///
+/// let buf = writer.into_inner();
+/// let mut input = &buf[..];
+/// assert_eq!(
+///     Value::read_from(&mut input).unwrap(),
+///     Value::String("_result".to_string())
+/// );
+/// ```
+#[derive(Debug)]
+pub struct Writer<W> {
+    encoder: Encoder<W>,
+    stack: Vec<Frame>,
+    expects_value: bool,
+}
+impl<W> Writer<W> {
+    /// Unwraps this `Writer`, returning the underlying writer.
+    pub fn into_inner(self) -> W {
+        self.encoder.into_inner()
+    }
+}
+impl<W> Writer<W>
+where
+    W: io::Write,
+{
+    /// Makes a new instance.
+    pub fn new(inner: W) -> Self {
+        Self::with_options(inner, EncoderOptions::default())
+    }
+
+    /// Makes a new instance with the specified options.
+    pub fn with_options(inner: W, options: EncoderOptions) -> Self {
+        Writer {
+            encoder: Encoder::with_options(inner, options),
+            stack: Vec::new(),
+            expects_value: false,
+        }
+    }
+
+    /// Returns a mutable reference to the registry of the externalizable class handlers
+    /// used to encode the AMF3 values embedded in `AvmPlus`.
+    pub fn externalizables_mut(&mut self) -> &mut ExternalizableRegistry {
+        self.encoder.externalizables_mut()
+    }
+
+    /// Writes a number.
+    pub fn write_number(&mut self, n: f64) -> EncodeResult<()> {
+        self.before_value()?;
+        self.encoder.encode_number(n)
+    }
+
+    /// Writes a boolean.
+    pub fn write_boolean(&mut self, b: bool) -> EncodeResult<()> {
+        self.before_value()?;
+        self.encoder.encode_boolean(b)
+    }
+
+    /// Writes a string.
+    pub fn write_string(&mut self, s: &str) -> EncodeResult<()> {
+        self.before_value()?;
+        self.encoder.encode_string(s)
+    }
+
+    /// Writes `null`.
+    pub fn write_null(&mut self) -> EncodeResult<()> {
+        self.before_value()?;
+        self.encoder.encode_null()
+    }
+
+    /// Writes `undefined`.
+    pub fn write_undefined(&mut self) -> EncodeResult<()> {
+        self.before_value()?;
+        self.encoder.encode_undefined()
+    }
+
+    /// Writes `unsupported`.
+    pub fn write_unsupported(&mut self) -> EncodeResult<()> {
+        self.before_value()?;
+        self.encoder.encode_marker(marker::UNSUPPORTED)
+    }
+
+    /// Writes a date.
+    pub fn write_date(&mut self, unix_time: time::Duration, time_zone: i16) -> EncodeResult<()> {
+        self.before_value()?;
+        self.encoder.encode_date(unix_time, time_zone)
+    }
+
+    /// Writes an XML document.
+    pub fn write_xml_document(&mut self, xml: &str) -> EncodeResult<()> {
+        self.before_value()?;
+        self.encoder.encode_xml_document(xml)
+    }
+
+    /// Writes a whole value.
+    ///
+    /// The AMF3 values embedded in `AvmPlus` can be written by this.
+    pub fn write_value(&mut self, value: &Value) -> EncodeResult<()> {
+        self.before_value()?;
+        self.encoder.encode(value)
+    }
+
+    /// Begins an object.
+    ///
+    /// The members are written by pairs of `key` and a value, then the object is closed by `end`.
+    pub fn begin_object(&mut self, class_name: Option<&str>) -> EncodeResult<()> {
+        self.before_complex()?;
+        if let Some(class_name) = class_name {
+            self.encoder.inner.write_u8(marker::TYPED_OBJECT)?;
+            self.encoder.write_str_u16(class_name)?;
+        } else {
+            self.encoder.inner.write_u8(marker::OBJECT)?;
+        }
+        self.stack.push(Frame::Pairs);
+        Ok(())
+    }
+
+    /// Begins an ECMA array.
+    ///
+    /// The entries are written by pairs of `key` and a value, then the array is closed by `end`.
+    /// `len` is written in the header as the number of the entries.
+    pub fn begin_ecma_array(&mut self, len: usize) -> EncodeResult<()> {
+        let len = collection_len(len)?;
+        self.before_complex()?;
+        self.encoder.inner.write_u8(marker::ECMA_ARRAY)?;
+        self.encoder.inner.write_u32::<BigEndian>(len)?;
+        self.stack.push(Frame::Pairs);
+        Ok(())
+    }
+
+    /// Begins a strict array.
+    ///
+    /// Exactly `len` values must be written before the array is closed by `end`.
+    pub fn begin_array(&mut self, len: usize) -> EncodeResult<()> {
+        let count = collection_len(len)?;
+        self.before_complex()?;
+        self.encoder.inner.write_u8(marker::STRICT_ARRAY)?;
+        self.encoder.inner.write_u32::<BigEndian>(count)?;
+        self.stack.push(Frame::Values { remaining: len });
+        Ok(())
+    }
+
+    /// Writes the key of a member of an object or an entry of an ECMA array.
+    ///
+    /// The value of the entry must follow.
+    pub fn key(&mut self, key: &str) -> EncodeResult<()> {
+        if self.expects_value {
+            return Err(invalid_state("a value is expected"));
+        }
+        if !matches!(self.stack.last(), Some(&Frame::Pairs)) {
+            return Err(invalid_state("a key is not allowed here"));
+        }
+        if key.is_empty() {
+            return Err(invalid_state("an empty key cannot be written"));
+        }
+        self.encoder.write_str_u16(key)?;
+        self.expects_value = true;
+        Ok(())
+    }
+
+    /// Closes the innermost object or array.
+    pub fn end(&mut self) -> EncodeResult<()> {
+        if self.expects_value {
+            return Err(invalid_state("a value is expected"));
+        }
+        match self.stack.pop() {
+            None => Err(invalid_state("no object or array to end")),
+            Some(Frame::Pairs) => {
+                self.encoder.inner.write_u16::<BigEndian>(0)?;
+                self.encoder.inner.write_u8(marker::OBJECT_END_MARKER)?;
+                Ok(())
+            }
+            Some(Frame::Values { remaining: 0 }) => Ok(()),
+            Some(frame) => {
+                self.stack.push(frame);
+                Err(invalid_state("too few array entries"))
+            }
+        }
+    }
+
+    /// Clear the reference table of this writer.
+    ///
+    /// The reference tables of the AMF3 values embedded in `AvmPlus` are also cleared.
+    pub fn clear_reference_table(&mut self) {
+        self.encoder.clear_reference_table();
+    }
+
+    fn before_complex(&mut self) -> EncodeResult<()> {
+        self.before_value()?;
+        self.encoder.complex_count += 1;
+        Ok(())
+    }
+    fn before_value(&mut self) -> EncodeResult<()> {
+        if mem::replace(&mut self.expects_value, false) {
+            return Ok(());
+        }
+        match self.stack.last_mut() {
+            None => Ok(()),
+            Some(&mut Frame::Pairs) => Err(invalid_state("a key is expected")),
+            Some(&mut Frame::Values { ref mut remaining }) => {
+                if *remaining == 0 {
+                    return Err(invalid_state("too many array entries"));
+                }
+                *remaining -= 1;
+                Ok(())
+            }
+        }
+    }
+}
+
+fn invalid_state(reason: &'static str) -> EncodeError {
+    EncodeError::InvalidWriterState { reason }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Encoder, Value, Writer};
+    use crate::amf3;
+    use crate::error::EncodeError;
+    use crate::Pair;
+    use std::time;
+
+    #[test]
+    fn writes_values() {
+        let mut writer = Writer::new(Vec::new());
+        writer.write_string("foo").unwrap();
+        writer.begin_object(Some("org.amf.ASClass")).unwrap();
+        writer.key("bar").unwrap();
+        writer.begin_array(2).unwrap();
+        writer.write_number(1.5).unwrap();
+        writer.begin_ecma_array(1).unwrap();
+        writer.key("0").unwrap();
+        writer.write_null().unwrap();
+        writer.end().unwrap();
+        writer.end().unwrap();
+        writer.key("baz").unwrap();
+        writer
+            .write_date(time::Duration::from_millis(1000), 0)
+            .unwrap();
+        writer.end().unwrap();
+        writer
+            .write_value(&Value::AvmPlus(amf3::Value::Integer(3)))
+            .unwrap();
+        writer.write_unsupported().unwrap();
+
+        let mut encoder = Encoder::new(Vec::new());
+        encoder.encode(&Value::String("foo".to_string())).unwrap();
+        let ecma_array = Value::EcmaArray {
+            entries: vec![pair("0", Value::Null)],
+        };
+        let object = Value::Object {
+            class_name: Some("org.amf.ASClass".to_string()),
+            entries: vec![
+                pair(
+                    "bar",
+                    Value::Array {
+                        entries: vec![Value::Number(1.5), ecma_array],
+                    },
+                ),
+                pair(
+                    "baz",
+                    Value::Date {
+                        unix_time: time::Duration::from_millis(1000),
+                        time_zone: 0,
+                    },
+                ),
+            ],
+        };
+        encoder.encode(&object).unwrap();
+        encoder
+            .encode(&Value::AvmPlus(amf3::Value::Integer(3)))
+            .unwrap();
+        encoder.encode(&Value::Unsupported).unwrap();
+        assert_eq!(writer.into_inner(), encoder.into_inner());
+    }
+    #[test]
+    fn write_errors() {
+        fn invalid_state(reason: &'static str) -> Result<(), EncodeError> {
+            Err(EncodeError::InvalidWriterState { reason })
+        }
+
+        let mut writer = Writer::new(Vec::new());
+        assert_eq!(
+            writer.key("foo"),
+            invalid_state("a key is not allowed here")
+        );
+        assert_eq!(writer.end(), invalid_state("no object or array to end"));
+
+        writer.begin_object(None).unwrap();
+        assert_eq!(writer.write_null(), invalid_state("a key is expected"));
+        assert_eq!(
+            writer.key(""),
+            invalid_state("an empty key cannot be written")
+        );
+        writer.key("foo").unwrap();
+        assert_eq!(writer.end(), invalid_state("a value is expected"));
+        writer.begin_array(1).unwrap();
+        assert_eq!(writer.end(), invalid_state("too few array entries"));
+        writer.write_null().unwrap();
+        assert_eq!(writer.write_null(), invalid_state("too many array entries"));
+        writer.end().unwrap();
+        writer.end().unwrap();
+        assert_eq!(
+            writer.into_inner(),
+            b"\x03\x00\x03foo\x0a\x00\x00\x00\x01\x05\x00\x00\x09"
+        );
+    }
+
+    fn pair(key: &str, value: Value) -> Pair<String, Value> {
+        Pair {
+            key: key.to_string(),
+            value,
+        }
+    }
+}
@@ -0,0 +1,421 @@
+use super::graph::{self, Graph, Node, NodeId};
+use super::{Decoder, Value};
+use crate::error::DecodeError;
+use crate::io;
+use crate::{DecodeResult, Pair};
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::time;
+
+// An entry of the object reference table of `Decoder`
+#[derive(Debug)]
+pub(crate) enum Slot {
+    // A complex value in the graph, which may be still being decoded
+    Node(NodeId),
+    // A complex value which `Reader` has read without adding it to the graph
+    Read,
+}
+impl Slot {
+    pub(crate) fn node(&self) -> Option<NodeId> {
+        match *self {
+            Slot::Node(id) => Some(id),
+            Slot::Read => None,
+        }
+    }
+}
+
+// A value which is not a complex value
+#[derive(Clone)]
+pub(crate) enum Scalar {
+    Undefined,
+    Null,
+    Boolean(bool),
+    Integer(i32),
+    Double(f64),
+    String(String),
+}
+
+// A complex value whose entries have been built as `V`
+pub(crate) enum Complex<V> {
+    XmlDocument(String),
+    Date {
+        unix_time: time::Duration,
+    },
+    Array {
+        assoc_entries: Vec<Pair<String, V>>,
+        dense_entries: Vec<V>,
+    },
+    Object {
+        class_name: Option<String>,
+        sealed_count: usize,
+        entries: Vec<Pair<String, V>>,
+    },
+    Xml(String),
+    ByteArray(Vec<u8>),
+    IntVector {
+        is_fixed: bool,
+        entries: Vec<i32>,
+    },
+    UintVector {
+        is_fixed: bool,
+        entries: Vec<u32>,
+    },
+    DoubleVector {
+        is_fixed: bool,
+        entries: Vec<f64>,
+    },
+    ObjectVector {
+        class_name: Option<String>,
+        is_fixed: bool,
+        entries: Vec<V>,
+    },
+    Dictionary {
+        is_weak: bool,
+        entries: Vec<Pair<V, V>>,
+    },
+    Externalizable {
+        class_name: String,
+        body: V,
+    },
+}
+
+// The representation which `Decoder` builds the decoded values in
+pub(crate) trait Build {
+    type Value;
+
+    fn scalar(scalar: Scalar) -> Self::Value;
+
+    // Returns the complex value at `index` of the object reference table
+    fn refer<R>(&mut self, decoder: &mut Decoder<R>, index: usize) -> DecodeResult<Self::Value>;
+
+    // Adds an entry to the object reference table, and builds the complex value decoded by `f` in it
+    fn complex<R, F>(&mut self, decoder: &mut Decoder<R>, f: F) -> DecodeResult<Self::Value>
+    where
+        F: FnOnce(&mut Decoder<R>, &mut Self) -> DecodeResult<Complex<Self::Value>>;
+
+    // Decodes the body of an externalizable object by the handler of `class_name`
+    fn external<R>(
+        &mut self,
+        decoder: &mut Decoder<R>,
+        class_name: &str,
+    ) -> DecodeResult<Self::Value>
+    where
+        R: io::Read;
+}
+
+// `Decoder::decode_graph` adds the complex values to the graph
+impl Build for Graph {
+    type Value = graph::Value;
+
+    fn scalar(scalar: Scalar) -> graph::Value {
+        match scalar {
+            Scalar::Undefined => graph::Value::Undefined,
+            Scalar::Null => graph::Value::Null,
+            Scalar::Boolean(x) => graph::Value::Boolean(x),
+            Scalar::Integer(x) => graph::Value::Integer(x),
+            Scalar::Double(x) => graph::Value::Double(x),
+            Scalar::String(x) => graph::Value::String(x),
+        }
+    }
+    fn refer<R>(&mut self, decoder: &mut Decoder<R>, index: usize) -> DecodeResult<graph::Value> {
+        match decoder.complexes.get(index) {
+            Some(&Slot::Node(id)) => Ok(graph::Value::Node(id)),
+            Some(Slot::Read) | None => Err(DecodeError::OutOfRangeReference { index }),
+        }
+    }
+    fn complex<R, F>(&mut self, decoder: &mut Decoder<R>, f: F) -> DecodeResult<graph::Value>
+    where
+        F: FnOnce(&mut Decoder<R>, &mut Self) -> DecodeResult<Complex<graph::Value>>,
+    {
+        let id = self.reserve();
+        decoder.complexes.push(Slot::Node(id));
+        match f(decoder, self) {
+            Ok(complex) => {
+                self.fill(id, complex.into());
+                Ok(graph::Value::Node(id))
+            }
+            Err(e) => {
+                // Drops the reserved node and the nodes decoded under it
+                self.truncate(id);
+                Err(e)
+            }
+        }
+    }
+    fn external<R>(
+        &mut self,
+        decoder: &mut Decoder<R>,
+        class_name: &str,
+    ) -> DecodeResult<graph::Value>
+    where
+        R: io::Read,
+    {
+        decoder.decode_external(self, class_name)
+    }
+}
+
+// `Decoder::decode` builds the complex values as trees.
+//
+// Each complex value is also added to `graph` once, and the references copy it out of `graph`
+// (as well as the complex values decoded as a graph, e.g., the bodies of externalizable objects).
+pub(crate) struct Tree<'a> {
+    pub(crate) graph: &'a mut Graph,
+    // The nodes of `graph` which have been copied
+    pub(crate) copied: &'a mut Vec<bool>,
+}
+// A value built by `Tree`, and its counterpart in the graph
+pub(crate) struct Built {
+    pub(crate) tree: Value,
+    pub(crate) node: graph::Value,
+}
+
+impl Build for Tree<'_> {
+    type Value = Built;
+
+    fn scalar(scalar: Scalar) -> Built {
+        let tree = match scalar.clone() {
+            Scalar::Undefined => Value::Undefined,
+            Scalar::Null => Value::Null,
+            Scalar::Boolean(x) => Value::Boolean(x),
+            Scalar::Integer(x) => Value::Integer(x),
+            Scalar::Double(x) => Value::Double(x),
+            Scalar::String(x) => Value::String(x),
+        };
+        Built {
+            tree,
+            node: Graph::scalar(scalar),
+        }
+    }
+    fn refer<R>(&mut self, decoder: &mut Decoder<R>, index: usize) -> DecodeResult<Built> {
+        match decoder.complexes.get(index).and_then(Slot::node) {
+            Some(id) if self.graph.decoded_node(id).is_ok() => {
+                let node = graph::Value::Node(id);
+                let tree = self
+                    .graph
+                    .to_tree_with(&node, self.copied, &mut decoder.budget)?;
+                Ok(Built { tree, node })
+            }
+            Some(_) => Err(DecodeError::CircularReference { index }),
+            None => Err(DecodeError::OutOfRangeReference { index }),
+        }
+    }
+    fn complex<R, F>(&mut self, decoder: &mut Decoder<R>, f: F) -> DecodeResult<Built>
+    where
+        F: FnOnce(&mut Decoder<R>, &mut Self) -> DecodeResult<Complex<Built>>,
+    {
+        let id = self.graph.reserve();
+        decoder.complexes.push(Slot::Node(id));
+        match f(decoder, self) {
+            Ok(complex) => {
+                let (tree, node) = complex.split();
+                self.graph.fill(id, node);
+                // The tree is the first copy of the node
+                if self.copied.len() <= id.index() {
+                    self.copied.resize(id.index() + 1, false);
+                }
+                self.copied[id.index()] = true;
+                Ok(Built {
+                    tree,
+                    node: graph::Value::Node(id),
+                })
+            }
+            Err(e) => {
+                // Drops the reserved node and the nodes decoded under it
+                self.graph.truncate(id);
+                self.copied.truncate(id.index());
+                Err(e)
+            }
+        }
+    }
+    fn external<R>(&mut self, decoder: &mut Decoder<R>, class_name: &str) -> DecodeResult<Built>
+    where
+        R: io::Read,
+    {
+        let node = decoder.decode_external(self.graph, class_name)?;
+        let tree = self
+            .graph
+            .to_tree_with(&node, self.copied, &mut decoder.budget)?;
+        Ok(Built { tree, node })
+    }
+}
+
+impl From<Complex<graph::Value>> for Node {
+    fn from(complex: Complex<graph::Value>) -> Self {
+        match complex {
+            Complex::XmlDocument(x) => Node::XmlDocument(x),
+            Complex::Date { unix_time } => Node::Date { unix_time },
+            Complex::Array {
+                assoc_entries,
+                dense_entries,
+            } => Node::Array {
+                assoc_entries,
+                dense_entries,
+            },
+            Complex::Object {
+                class_name,
+                sealed_count,
+                entries,
+            } => Node::Object {
+                class_name,
+                sealed_count,
+                entries,
+            },
+            Complex::Xml(x) => Node::Xml(x),
+            Complex::ByteArray(x) => Node::ByteArray(x),
+            Complex::IntVector { is_fixed, entries } => Node::IntVector { is_fixed, entries },
+            Complex::UintVector { is_fixed, entries } => Node::UintVector { is_fixed, entries },
+            Complex::DoubleVector { is_fixed, entries } => Node::DoubleVector { is_fixed, entries },
+            Complex::ObjectVector {
+                class_name,
+                is_fixed,
+                entries,
+            } => Node::ObjectVector {
+                class_name,
+                is_fixed,
+                entries,
+            },
+            Complex::Dictionary { is_weak, entries } => Node::Dictionary { is_weak, entries },
+            Complex::Externalizable { class_name, body } => {
+                Node::Externalizable { class_name, body }
+            }
+        }
+    }
+}
+impl Complex<Built> {
+    // Splits this into the tree and the node in the graph
+    fn split(self) -> (Value, Node) {
+        match self {
+            Complex::XmlDocument(x) => (Value::XmlDocument(x.clone()), Node::XmlDocument(x)),
+            Complex::Date { unix_time } => (Value::Date { unix_time }, Node::Date { unix_time }),
+            Complex::Array {
+                assoc_entries,
+                dense_entries,
+            } => {
+                let (tree_assoc_entries, assoc_entries) = split_pairs(assoc_entries);
+                let (tree_dense_entries, dense_entries) = split_values(dense_entries);
+                let tree = Value::Array {
+                    assoc_entries: tree_assoc_entries,
+                    dense_entries: tree_dense_entries,
+                };
+                let node = Node::Array {
+                    assoc_entries,
+                    dense_entries,
+                };
+                (tree, node)
+            }
+            Complex::Object {
+                class_name,
+                sealed_count,
+                entries,
+            } => {
+                let (tree_entries, entries) = split_pairs(entries);
+                let tree = Value::Object {
+                    class_name: class_name.clone(),
+                    sealed_count,
+                    entries: tree_entries,
+                };
+                let node = Node::Object {
+                    class_name,
+                    sealed_count,
+                    entries,
+                };
+                (tree, node)
+            }
+            Complex::Xml(x) => (Value::Xml(x.clone()), Node::Xml(x)),
+            Complex::ByteArray(x) => (Value::ByteArray(x.clone()), Node::ByteArray(x)),
+            Complex::IntVector { is_fixed, entries } => (
+                Value::IntVector {
+                    is_fixed,
+                    entries: entries.clone(),
+                },
+                Node::IntVector { is_fixed, entries },
+            ),
+            Complex::UintVector { is_fixed, entries } => (
+                Value::UintVector {
+                    is_fixed,
+                    entries: entries.clone(),
+                },
+                Node::UintVector { is_fixed, entries },
+            ),
+            Complex::DoubleVector { is_fixed, entries } => (
+                Value::DoubleVector {
+                    is_fixed,
+                    entries: entries.clone(),
+                },
+                Node::DoubleVector { is_fixed, entries },
+            ),
+            Complex::ObjectVector {
+                class_name,
+                is_fixed,
+                entries,
+            } => {
+                let (tree_entries, entries) = split_values(entries);
+                let tree = Value::ObjectVector {
+                    class_name: class_name.clone(),
+                    is_fixed,
+                    entries: tree_entries,
+                };
+                let node = Node::ObjectVector {
+                    class_name,
+                    is_fixed,
+                    entries,
+                };
+                (tree, node)
+            }
+            Complex::Dictionary { is_weak, entries } => {
+                let (tree_entries, entries) = entries
+                    .into_iter()
+                    .map(|p| {
+                        let tree = Pair {
+                            key: p.key.tree,
+                            value: p.value.tree,
+                        };
+                        let node = Pair {
+                            key: p.key.node,
+                            value: p.value.node,
+                        };
+                        (tree, node)
+                    })
+                    .unzip();
+                let tree = Value::Dictionary {
+                    is_weak,
+                    entries: tree_entries,
+                };
+                (tree, Node::Dictionary { is_weak, entries })
+            }
+            Complex::Externalizable { class_name, body } => {
+                let tree = Value::Externalizable {
+                    class_name: class_name.clone(),
+                    body: Box::new(body.tree),
+                };
+                let node = Node::Externalizable {
+                    class_name,
+                    body: body.node,
+                };
+                (tree, node)
+            }
+        }
+    }
+}
+
+fn split_values(values: Vec<Built>) -> (Vec<Value>, Vec<graph::Value>) {
+    values.into_iter().map(|v| (v.tree, v.node)).unzip()
+}
+type Pairs<V> = Vec<Pair<String, V>>;
+
+fn split_pairs(pairs: Pairs<Built>) -> (Pairs<Value>, Pairs<graph::Value>) {
+    pairs
+        .into_iter()
+        .map(|p| {
+            let tree = Pair {
+                key: p.key.clone(),
+                value: p.value.tree,
+            };
+            let node = Pair {
+                key: p.key,
+                value: p.value.node,
+            };
+            (tree, node)
+        })
+        .unzip()
+}
@@ -1,45 +1,158 @@
-use crate::error::DecodeError;
-use crate::{DecodeResult, Pair};
-use byteorder::{BigEndian, ReadBytesExt};
-use std::io;
-use std::time;
+use crate::error::{DecodeError, Location, PathSegment, Warning, WarningKind};
+use crate::io::{self, ReadBytesExt};
+use crate::limits::Budget;
+use crate::util::Position;
+use crate::wire::{Tape, WireDetail};
+use crate::{DecodeResult, DecoderLimits, Pair, WireFormat};
+use alloc::borrow::ToOwned;
+use alloc::string::String;
+use alloc::vec::Vec;
+use byteorder::BigEndian;
+use core::mem;
+use core::time;
 
+use super::build::{Build, Complex, Scalar, Slot, Tree};
+use super::graph::{self, Graph};
 use super::marker;
+use super::shared;
+use super::Trait;
 use super::Value;
+use super::{DataInput, ExternalizableRegistry};
 
-#[derive(Debug, Clone)]
-struct Trait {
-    class_name: Option<String>,
-    is_dynamic: bool,
-    fields: Vec<String>,
+/// AMF3 value decoded by `Decoder::decode_exact`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExactValue {
+    /// The decoded value.
+    pub value: Value,
+
+    /// The wire details of the value, which `Encoder::encode_exact` reproduces.
+    pub wire: WireFormat,
 }
 
 #[derive(Debug)]
-enum SizeOrIndex {
+pub(super) enum SizeOrIndex {
     Size(usize),
     Index(usize),
 }
 
 /// AMF3 decoder.
+///
+/// Strings, byte arrays and vectors are read in bulk,
+/// but the variable-length integers (U29) are read byte by byte,
+/// so make the decoder of an unbuffered reader such as `TcpStream` by `Decoder::buffered`.
 #[derive(Debug)]
 pub struct Decoder<R> {
-    inner: R,
-    traits: Vec<Trait>,
-    strings: Vec<String>,
-    complexes: Vec<Value>,
+    pub(super) inner: Position<R>,
+    pub(super) traits: Vec<Trait>,
+    pub(super) strings: Vec<String>,
+    pub(super) complexes: Vec<Slot>,
+    pub(super) graph: Graph,
+    shared: shared::Nodes,
+    // The nodes which have been copied by `decode`
+    copied: Vec<bool>,
+    pub(crate) budget: Budget,
+    // `Some` in the lenient mode of the enclosing AMF0 decoder
+    pub(crate) warnings: Option<Vec<Warning>>,
+    // `Some` while decoding in the exact mode
+    pub(crate) wire: Option<Tape>,
+    pub(super) externalizables: ExternalizableRegistry,
+    // The location of the last error detected in the input
+    pub(crate) location: Option<Location>,
 }
 impl<R> Decoder<R> {
     /// Unwraps this `Decoder`, returning the underlying reader.
+    ///
+    /// Note that the bytes in `buffer` are lost.
     pub fn into_inner(self) -> R {
-        self.inner
+        self.inner.inner
     }
     /// Returns an immutable reference to the underlying reader.
     pub fn inner(&mut self) -> &R {
-        &self.inner
+        &self.inner.inner
     }
     /// Returns a mutable reference to the underlying reader.
+    ///
+    /// Note that the bytes read through the reference are not counted
+    /// in the offsets of the errors.
     pub fn inner_mut(&mut self) -> &mut R {
-        &mut self.inner
+        &mut self.inner.inner
+    }
+
+    /// Returns the bytes which a buffered decoder has read from the underlying reader
+    /// but not decoded yet.
+    ///
+    /// This is empty unless the decoder is made by `buffered`.
+    pub fn buffer(&self) -> &[u8] {
+        self.inner.buffer()
+    }
+    /// Returns the object graph which holds the complex values decoded by `decode_graph`.
+    ///
+    /// `decode` also adds each complex value to the graph once, where the later references copy it from.
+    /// The graph is reset by `clear_reference_table`.
+    pub fn graph(&self) -> &Graph {
+        &self.graph
+    }
+    /// Returns a mutable reference to the registry of the externalizable class handlers.
+    pub fn externalizables_mut(&mut self) -> &mut ExternalizableRegistry {
+        &mut self.externalizables
+    }
+    /// Returns the limits of this decoder.
+    pub fn limits(&self) -> &DecoderLimits {
+        &self.budget.limits
+    }
+    /// Returns a mutable reference to the limits of this decoder.
+    pub fn limits_mut(&mut self) -> &mut DecoderLimits {
+        &mut self.budget.limits
+    }
+    /// Returns the location of the value which failed to decode in the last call of a decoding method.
+    ///
+    /// This is `None` if the last call succeeded, or failed after decoding the value
+    /// (e.g., `decode_shared` finds a circular reference in the decoded graph).
+    pub fn error_location(&self) -> Option<&Location> {
+        self.location.as_ref()
+    }
+    pub(crate) fn with_inner<S>(self, inner: S) -> (Decoder<S>, R) {
+        debug_assert!(
+            self.inner.read_ahead.is_none(),
+            "the bytes read ahead are lost"
+        );
+        let decoder = Decoder {
+            inner: Position {
+                offset: self.inner.offset,
+                missing: self.inner.missing,
+                ..Position::new(inner)
+            },
+            traits: self.traits,
+            strings: self.strings,
+            complexes: self.complexes,
+            graph: self.graph,
+            shared: self.shared,
+            copied: self.copied,
+            budget: self.budget,
+            warnings: self.warnings,
+            wire: self.wire,
+            externalizables: self.externalizables,
+            location: self.location,
+        };
+        (decoder, self.inner.inner)
+    }
+    pub(crate) fn take_missing(&mut self) -> usize {
+        self.inner.take_missing()
+    }
+    // Sets the offset of the next byte, e.g., when the decoder reads a part of an AMF0 message
+    pub(crate) fn set_offset(&mut self, offset: u64) {
+        self.inner.offset = offset;
+    }
+    pub(crate) fn graph_mut(&mut self) -> &mut Graph {
+        &mut self.graph
+    }
+    pub(crate) fn complex_count(&self) -> usize {
+        self.complexes.len()
+    }
+    // Copies a value decoded into the graph out as a tree
+    pub(crate) fn copy_tree(&mut self, value: &graph::Value) -> DecodeResult<Value> {
+        self.graph
+            .to_tree_with(value, &mut self.copied, &mut self.budget)
     }
 }
 impl<R> Decoder<R>
@@ -48,17 +161,119 @@ where
 {
     /// Makes a new instance.
     pub fn new(inner: R) -> Self {
+        Self::with_limits(inner, DecoderLimits::default())
+    }
+
+    /// Makes a new instance with the specified limits.
+    pub fn with_limits(inner: R, limits: DecoderLimits) -> Self {
         Decoder {
-            inner,
+            inner: Position::new(inner),
             traits: Vec::new(),
             strings: Vec::new(),
             complexes: Vec::new(),
+            graph: Graph::new(),
+            shared: shared::Nodes::default(),
+            copied: Vec::new(),
+            budget: Budget::new(limits),
+            warnings: None,
+            wire: None,
+            externalizables: ExternalizableRegistry::new(),
+            location: None,
         }
     }
 
+    /// Makes a new instance which reads ahead from `inner` through an internal buffer.
+    ///
+    /// This saves the read calls to an unbuffered `inner` (e.g., `File` or `TcpStream`),
+    /// which are otherwise made for each marker and each byte of the variable length integers.
+    /// The bytes read ahead but not decoded yet are returned by `buffer`.
+    ///
+    /// # Examples
+    /// ```
+    /// use amf::amf3::{Decoder, Value};
+    ///
+    /// let input = b"\x04\x01\x04\x02rest";
+    /// let mut decoder = Decoder::buffered(&input[..]);
+    /// assert_eq!(decoder.decode(), Ok(Value::Integer(1)));
+    /// assert_eq!(decoder.decode(), Ok(Value::Integer(2)));
+    ///
+    /// // The rest of the input has been read ahead
+    /// assert_eq!(decoder.buffer(), b"rest");
+    /// ```
+    pub fn buffered(inner: R) -> Self {
+        let mut decoder = Self::new(inner);
+        decoder.inner.enable_read_ahead();
+        decoder
+    }
+
     /// Decodes a AMF3 value.
+    ///
+    /// Note that reference objects are copied in the decoding phase
+    /// for the sake of simplicity of the resulting value representation.
+    /// Circular references are rejected because the tree `Value` cannot represent those.
+    /// The copies are limited by `DecoderLimits::max_reference_expansions`.
+    /// Use `decode_shared` to share the referred values instead of copying them,
+    /// or [`decode_graph`](Self::decode_graph) to preserve shared and circular references.
     pub fn decode(&mut self) -> DecodeResult<Value> {
-        self.decode_value()
+        self.budget.start_value();
+        self.location = None;
+        let mut graph = mem::take(&mut self.graph);
+        let mut copied = mem::take(&mut self.copied);
+        let result = self.decode_value(&mut Tree {
+            graph: &mut graph,
+            copied: &mut copied,
+        });
+        self.graph = graph;
+        self.copied = copied;
+        result.map(|built| built.tree)
+    }
+
+    /// Decodes a AMF3 value together with its wire details.
+    ///
+    /// `Encoder::encode_exact` reproduces the input byte for byte from the result,
+    /// which `Encoder::encode` does not necessarily do (see `WireFormat`).
+    ///
+    /// # Examples
+    /// ```
+    /// use amf::amf3::{Decoder, Encoder, Value};
+    ///
+    /// // 1 written in 2 bytes
+    /// let input = b"\x04\x80\x01";
+    /// let exact = Decoder::new(&input[..]).decode_exact().unwrap();
+    /// assert_eq!(exact.value, Value::Integer(1));
+    ///
+    /// let mut encoder = Encoder::new(Vec::new());
+    /// encoder.encode_exact(&exact.value, &exact.wire).unwrap();
+    /// assert_eq!(encoder.into_inner(), input);
+    /// ```
+    pub fn decode_exact(&mut self) -> DecodeResult<ExactValue> {
+        self.wire = Some(Tape::default());
+        let result = self.decode();
+        let wire = self.wire.take().unwrap_or_default().into_wire();
+        result.map(|value| ExactValue { value, wire })
+    }
+
+    /// Decodes a AMF3 value as an object graph.
+    ///
+    /// The complex values are added to the graph returned by `graph`.
+    /// Unlike `decode`, shared and circular references are preserved.
+    pub fn decode_graph(&mut self) -> DecodeResult<graph::Value> {
+        self.budget.start_value();
+        self.location = None;
+        let mut graph = mem::take(&mut self.graph);
+        let result = self.decode_value(&mut graph);
+        self.graph = graph;
+        result
+    }
+
+    /// Decodes a AMF3 value whose complex values are shared by reference counting.
+    ///
+    /// A complex value is decoded only once even if it is referred multiple times,
+    /// including the references from the values decoded by the previous calls.
+    /// Circular references are treated as errors.
+    pub fn decode_shared(&mut self) -> DecodeResult<shared::Value> {
+        let value = self.decode_graph()?;
+        self.shared.share(&self.graph, &value)
     }
 
     /// Clear the reference tables of this decoder.
@@ -71,137 +286,163 @@ where
         self.traits.clear();
         self.strings.clear();
         self.complexes.clear();
+        self.graph.clear();
+        self.shared.clear();
+        self.copied.clear();
+        self.budget.clear_expansions();
     }
 
-    fn decode_value(&mut self) -> DecodeResult<Value> {
+    pub(crate) fn decode_value<B: Build>(&mut self, b: &mut B) -> DecodeResult<B::Value> {
+        let offset = self.inner.offset;
+        let result = self.decode_marked_value(b);
+        if result.is_err() {
+            Location::record(&mut self.location, offset);
+        }
+        result
+    }
+    fn decode_marked_value<B: Build>(&mut self, b: &mut B) -> DecodeResult<B::Value> {
         let marker = self.inner.read_u8()?;
         match marker {
-            marker::UNDEFINED => Ok(Value::Undefined),
-            marker::NULL => Ok(Value::Null),
-            marker::FALSE => Ok(Value::Boolean(false)),
-            marker::TRUE => Ok(Value::Boolean(true)),
-            marker::INTEGER => self.decode_integer(),
-            marker::DOUBLE => self.decode_double(),
-            marker::STRING => self.decode_string(),
-            marker::XML_DOC => self.decode_xml_doc(),
-            marker::DATE => self.decode_date(),
-            marker::ARRAY => self.decode_array(),
-            marker::OBJECT => self.decode_object(),
-            marker::XML => self.decode_xml(),
-            marker::BYTE_ARRAY => self.decode_byte_array(),
-            marker::VECTOR_INT => self.decode_vector_int(),
-            marker::VECTOR_UINT => self.decode_vector_uint(),
-            marker::VECTOR_DOUBLE => self.decode_vector_double(),
-            marker::VECTOR_OBJECT => self.decode_vector_object(),
-            marker::DICTIONARY => self.decode_dictionary(),
+            marker::UNDEFINED => Ok(B::scalar(Scalar::Undefined)),
+            marker::NULL => Ok(B::scalar(Scalar::Null)),
+            marker::FALSE => Ok(B::scalar(Scalar::Boolean(false))),
+            marker::TRUE => Ok(B::scalar(Scalar::Boolean(true))),
+            marker::INTEGER => self.decode_integer().map(B::scalar),
+            marker::DOUBLE => self.decode_double().map(B::scalar),
+            marker::STRING => self.decode_string().map(B::scalar),
+            marker::XML_DOC => self.decode_xml_doc(b),
+            marker::DATE => self.decode_date(b),
+            marker::ARRAY => self.decode_array(b),
+            marker::OBJECT => self.decode_object(b),
+            marker::XML => self.decode_xml(b),
+            marker::BYTE_ARRAY => self.decode_byte_array(b),
+            marker::VECTOR_INT => self.decode_vector_int(b),
+            marker::VECTOR_UINT => self.decode_vector_uint(b),
+            marker::VECTOR_DOUBLE => self.decode_vector_double(b),
+            marker::VECTOR_OBJECT => self.decode_vector_object(b),
+            marker::DICTIONARY => self.decode_dictionary(b),
             _ => Err(DecodeError::Unknown { marker }),
         }
     }
 
-    fn decode_integer(&mut self) -> DecodeResult<Value> {
+    fn decode_integer(&mut self) -> DecodeResult<Scalar> {
         let n = self.decode_u29()? as i32;
         let n = if n >= (1 << 28) { n - (1 << 29) } else { n };
-        Ok(Value::Integer(n))
+        Ok(Scalar::Integer(n))
     }
-    fn decode_double(&mut self) -> DecodeResult<Value> {
+    fn decode_double(&mut self) -> DecodeResult<Scalar> {
         let n = self.inner.read_f64::<BigEndian>()?;
-        Ok(Value::Double(n))
+        Ok(Scalar::Double(n))
     }
-    fn decode_string(&mut self) -> DecodeResult<Value> {
+    fn decode_string(&mut self) -> DecodeResult<Scalar> {
         let s = self.decode_utf8()?;
-        Ok(Value::String(s))
+        Ok(Scalar::String(s))
     }
-    fn decode_xml_doc(&mut self) -> DecodeResult<Value> {
-        self.decode_complex_type(|this, len| this.read_utf8(len).map(Value::XmlDocument))
+    fn decode_xml_doc<B: Build>(&mut self, b: &mut B) -> DecodeResult<B::Value> {
+        self.decode_complex_type(b, |this, _, len| {
+            this.read_utf8(len).map(Complex::XmlDocument)
+        })
     }
-    fn decode_date(&mut self) -> DecodeResult<Value> {
-        self.decode_complex_type(|this, _| {
+    fn decode_date<B: Build>(&mut self, b: &mut B) -> DecodeResult<B::Value> {
+        self.decode_complex_type(b, |this, _, _| {
             let millis = this.inner.read_f64::<BigEndian>()?;
             if !(millis.is_finite() && millis.is_sign_positive()) {
                 Err(DecodeError::InvalidDate { millis })
             } else {
-                Ok(Value::Date {
+                Ok(Complex::Date {
                     unix_time: time::Duration::from_millis(millis as u64),
                 })
             }
         })
     }
-    fn decode_array(&mut self) -> DecodeResult<Value> {
-        self.decode_complex_type(|this, count| {
-            let assoc = this.decode_pairs()?;
+    fn decode_array<B: Build>(&mut self, b: &mut B) -> DecodeResult<B::Value> {
+        self.decode_complex_type(b, |this, b, count| {
+            this.budget.collection::<B::Value>(count)?;
+            let assoc = this.decode_pairs(b)?;
             let dense = (0..count)
-                .map(|_| this.decode_value())
+                .map(|i| this.decode_entry(b, i))
                 .collect::<DecodeResult<_>>()?;
-            Ok(Value::Array {
+            Ok(Complex::Array {
                 assoc_entries: assoc,
                 dense_entries: dense,
             })
         })
     }
-    fn decode_object(&mut self) -> DecodeResult<Value> {
-        self.decode_complex_type(|this, u28| {
+    fn decode_object<B: Build>(&mut self, b: &mut B) -> DecodeResult<B::Value> {
+        self.decode_complex_type(b, |this, b, u28| {
+            let site = this.site();
             let amf_trait = this.decode_trait(u28)?;
+            if amf_trait.is_externalizable {
+                let class_name = amf_trait.class_name.unwrap_or_default();
+                let body = b.external(this, &class_name)?;
+                return Ok(Complex::Externalizable { class_name, body });
+            }
             let mut entries = amf_trait
                 .fields
                 .iter()
                 .map(|k| {
                     Ok(Pair {
                         key: k.clone(),
-                        value: this.decode_value()?,
+                        value: this.decode_member(b, k)?,
                     })
                 })
                 .collect::<DecodeResult<Vec<_>>>()?;
             if amf_trait.is_dynamic {
-                entries.extend(this.decode_pairs()?);
+                let pairs = this.decode_pairs(b)?;
+                if pairs.is_empty() {
+                    this.record(site, WireDetail::DynamicObject);
+                }
+                entries.extend(pairs);
             }
-            Ok(Value::Object {
+            Ok(Complex::Object {
                 class_name: amf_trait.class_name,
                 sealed_count: amf_trait.fields.len(),
                 entries,
             })
         })
     }
-    fn decode_xml(&mut self) -> DecodeResult<Value> {
-        self.decode_complex_type(|this, len| this.read_utf8(len).map(Value::Xml))
+    fn decode_xml<B: Build>(&mut self, b: &mut B) -> DecodeResult<B::Value> {
+        self.decode_complex_type(b, |this, _, len| this.read_utf8(len).map(Complex::Xml))
     }
-    fn decode_byte_array(&mut self) -> DecodeResult<Value> {
-        self.decode_complex_type(|this, len| this.read_bytes(len).map(Value::ByteArray))
+    fn decode_byte_array<B: Build>(&mut self, b: &mut B) -> DecodeResult<B::Value> {
+        self.decode_complex_type(b, |this, _, len| {
+            this.budget.byte_array(len)?;
+            this.read_bytes(len).map(Complex::ByteArray)
+        })
     }
-    fn decode_vector_int(&mut self) -> DecodeResult<Value> {
-        self.decode_complex_type(|this, count| {
+    fn decode_vector_int<B: Build>(&mut self, b: &mut B) -> DecodeResult<B::Value> {
+        self.decode_complex_type(b, |this, _, count| {
             let is_fixed = this.inner.read_u8()? != 0;
-            let entries = (0..count)
-                .map(|_| this.inner.read_i32::<BigEndian>())
-                .collect::<Result<_, _>>()?;
-            Ok(Value::IntVector { is_fixed, entries })
+            this.budget.collection::<i32>(count)?;
+            let entries = this.inner.read_elements(count, i32::from_be_bytes)?;
+            Ok(Complex::IntVector { is_fixed, entries })
         })
     }
-    fn decode_vector_uint(&mut self) -> DecodeResult<Value> {
-        self.decode_complex_type(|this, count| {
+    fn decode_vector_uint<B: Build>(&mut self, b: &mut B) -> DecodeResult<B::Value> {
+        self.decode_complex_type(b, |this, _, count| {
             let is_fixed = this.inner.read_u8()? != 0;
-            let entries = (0..count)
-                .map(|_| this.inner.read_u32::<BigEndian>())
-                .collect::<Result<_, _>>()?;
-            Ok(Value::UintVector { is_fixed, entries })
+            this.budget.collection::<u32>(count)?;
+            let entries = this.inner.read_elements(count, u32::from_be_bytes)?;
+            Ok(Complex::UintVector { is_fixed, entries })
         })
     }
-    fn decode_vector_double(&mut self) -> DecodeResult<Value> {
-        self.decode_complex_type(|this, count| {
+    fn decode_vector_double<B: Build>(&mut self, b: &mut B) -> DecodeResult<B::Value> {
+        self.decode_complex_type(b, |this, _, count| {
             let is_fixed = this.inner.read_u8()? != 0;
-            let entries = (0..count)
-                .map(|_| this.inner.read_f64::<BigEndian>())
-                .collect::<Result<_, _>>()?;
-            Ok(Value::DoubleVector { is_fixed, entries })
+            this.budget.collection::<f64>(count)?;
+            let entries = this.inner.read_elements(count, f64::from_be_bytes)?;
+            Ok(Complex::DoubleVector { is_fixed, entries })
         })
     }
-    fn decode_vector_object(&mut self) -> DecodeResult<Value> {
-        self.decode_complex_type(|this, count| {
+    fn decode_vector_object<B: Build>(&mut self, b: &mut B) -> DecodeResult<B::Value> {
+        self.decode_complex_type(b, |this, b, count| {
             let is_fixed = this.inner.read_u8()? != 0;
             let class_name = this.decode_utf8()?;
+            this.budget.collection::<B::Value>(count)?;
             let entries = (0..count)
-                .map(|_| this.decode_value())
+                .map(|i| this.decode_entry(b, i))
                 .collect::<DecodeResult<_>>()?;
-            Ok(Value::ObjectVector {
+            Ok(Complex::ObjectVector {
                 class_name: if class_name == "*" {
                     None
                 } else {
@@ -212,21 +453,21 @@ where
             })
         })
     }
-    fn decode_dictionary(&mut self) -> DecodeResult<Value> {
-        self.decode_complex_type(|this, count| {
+    fn decode_dictionary<B: Build>(&mut self, b: &mut B) -> DecodeResult<B::Value> {
+        self.decode_complex_type(b, |this, b, count| {
             let is_weak = this.inner.read_u8()? == 1;
+            this.budget.collection::<Pair<B::Value, B::Value>>(count)?;
             let entries = (0..count)
-                .map(|_| {
+                .map(|i| {
                     Ok(Pair {
-                        key: this.decode_value()?,
-                        value: this.decode_value()?,
+                        key: this.decode_entry(b, i)?,
+                        value: this.decode_entry(b, i)?,
                     })
                 })
                 .collect::<DecodeResult<_>>()?;
-            Ok(Value::Dictionary { is_weak, entries })
+            Ok(Complex::Dictionary { is_weak, entries })
         })
     }
-
     /// Decode an AMF3 string.
     ///
     /// Use this if you need to decode an AMF3 string outside of value context.
@@ -234,14 +475,14 @@ where
     pub fn decode_utf8(&mut self) -> DecodeResult<String> {
         match self.decode_size_or_index()? {
             SizeOrIndex::Size(len) => {
-                let bytes = self.read_bytes(len)?;
-                let s = String::from_utf8(bytes)?;
+                let s = self.read_utf8(len)?;
                 if !s.is_empty() {
                     self.strings.push(s.clone());
                 }
                 Ok(s)
             }
             SizeOrIndex::Index(index) => {
+                self.record(self.site(), WireDetail::StringReference(index));
                 let s = self
                     .strings
                     .get(index)
@@ -250,20 +491,31 @@ where
             }
         }
     }
-    fn decode_u29(&mut self) -> DecodeResult<u32> {
+    pub(super) fn decode_u29(&mut self) -> DecodeResult<u32> {
+        let (n, width) = self.read_u29()?;
+        if let Some(wire) = &mut self.wire {
+            let site = wire.next_site();
+            if width > u29_width(n) {
+                wire.record(site, WireDetail::U29 { value: n, width });
+            }
+        }
+        Ok(n)
+    }
+    // Reads a U29, returning its value and width in bytes
+    fn read_u29(&mut self) -> DecodeResult<(u32, usize)> {
         let mut n = 0;
-        for _ in 0..3 {
+        for width in 1..4 {
             let b = self.inner.read_u8()? as u32;
             n = (n << 7) | (b & 0b0111_1111);
             if (b & 0b1000_0000) == 0 {
-                return Ok(n);
+                return Ok((n, width));
             }
         }
         let b = self.inner.read_u8()? as u32;
         n = (n << 8) | b;
-        Ok(n)
+        Ok((n, 4))
     }
-    fn decode_size_or_index(&mut self) -> DecodeResult<SizeOrIndex> {
+    pub(super) fn decode_size_or_index(&mut self) -> DecodeResult<SizeOrIndex> {
         let u29 = self.decode_u29()? as usize;
         let is_reference = (u29 & 0b01) == 0;
         let value = u29 >> 1;
@@ -273,57 +525,71 @@ where
             Ok(SizeOrIndex::Size(value))
         }
     }
-    fn decode_complex_type<F>(&mut self, f: F) -> DecodeResult<Value>
+    fn decode_complex_type<B, F>(&mut self, b: &mut B, f: F) -> DecodeResult<B::Value>
     where
-        F: FnOnce(&mut Self, usize) -> DecodeResult<Value>,
+        B: Build,
+        F: FnOnce(&mut Self, &mut B, usize) -> DecodeResult<Complex<B::Value>>,
     {
         match self.decode_size_or_index()? {
-            SizeOrIndex::Index(index) => self
-                .complexes
-                .get(index)
-                .ok_or(DecodeError::OutOfRangeReference { index })
-                .and_then(|v| {
-                    if *v == Value::Null {
-                        Err(DecodeError::CircularReference { index })
-                    } else {
-                        Ok(v.clone())
-                    }
-                }),
+            SizeOrIndex::Index(index) => {
+                self.record(self.site(), WireDetail::ObjectReference(index));
+                b.refer(self, index)
+            }
             SizeOrIndex::Size(u28) => {
+                self.budget.enter()?;
                 let index = self.complexes.len();
-                self.complexes.push(Value::Null);
-                let value = f(self, u28)?;
-                self.complexes[index] = value.clone();
-                Ok(value)
+                let result = b.complex(self, |this, b| f(this, b, u28));
+                self.budget.leave();
+                if result.is_err() {
+                    // Drops the entries of the value and those decoded under it
+                    self.complexes.truncate(index);
+                }
+                result
             }
         }
     }
-    fn decode_pairs(&mut self) -> DecodeResult<Vec<Pair<String, Value>>> {
+    fn decode_pairs<B: Build>(&mut self, b: &mut B) -> DecodeResult<Vec<Pair<String, B::Value>>> {
         let mut pairs = Vec::new();
         loop {
             let key = self.decode_utf8()?;
             if key.is_empty() {
                 return Ok(pairs);
             }
-            let value = self.decode_value()?;
+            self.budget
+                .entry::<Pair<String, B::Value>>(pairs.len() + 1)?;
+            let value = self.decode_member(b, &key)?;
             pairs.push(Pair { key, value });
         }
     }
-    fn decode_trait(&mut self, u28: usize) -> DecodeResult<Trait> {
+    fn decode_entry<B: Build>(&mut self, b: &mut B, index: usize) -> DecodeResult<B::Value> {
+        let result = self.decode_value(b);
+        if result.is_err() {
+            Location::prepend(&mut self.location, PathSegment::Index(index));
+        }
+        result
+    }
+    fn decode_member<B: Build>(&mut self, b: &mut B, key: &str) -> DecodeResult<B::Value> {
+        let result = self.decode_value(b);
+        if result.is_err() {
+            Location::prepend(&mut self.location, PathSegment::Key(key.to_owned()));
+        }
+        result
+    }
+    pub(super) fn decode_trait(&mut self, u28: usize) -> DecodeResult<Trait> {
         if (u28 & 0b1) == 0 {
-            let i = (u28 >> 1) as usize;
+            let i = u28 >> 1;
+            self.record(self.site(), WireDetail::TraitReference(i));
             let t = self
                 .traits
                 .get(i)
                 .ok_or(DecodeError::OutOfRangeReference { index: i })?;
             Ok(t.clone())
-        } else if (u28 & 0b10) != 0 {
-            let class_name = self.decode_utf8()?;
-            Err(DecodeError::ExternalizableType { name: class_name })
         } else {
-            let is_dynamic = (u28 & 0b100) != 0;
-            let field_num = u28 >> 3;
+            let is_externalizable = (u28 & 0b10) != 0;
+            let is_dynamic = !is_externalizable && (u28 & 0b100) != 0;
+            let field_num = if is_externalizable { 0 } else { u28 >> 3 };
             let class_name = self.decode_utf8()?;
+            self.budget.collection::<String>(field_num)?;
             let fields = (0..field_num)
                 .map(|_| self.decode_utf8())
                 .collect::<DecodeResult<_>>()?;
@@ -335,29 +601,109 @@ where
                     Some(class_name)
                 },
                 is_dynamic,
+                is_externalizable,
                 fields,
             };
             self.traits.push(t.clone());
             Ok(t)
         }
     }
-    fn read_bytes(&mut self, len: usize) -> DecodeResult<Vec<u8>> {
-        let mut buf = vec![0; len];
-        self.inner.read_exact(&mut buf)?;
+    pub(super) fn decode_external(
+        &mut self,
+        graph: &mut Graph,
+        class_name: &str,
+    ) -> DecodeResult<graph::Value> {
+        if let Some(handler) = self.externalizables.get(class_name) {
+            handler.read_external(&mut ExternalInput {
+                decoder: self,
+                graph,
+            })
+        } else {
+            // The length of the body is unknown, so the rest of the input cannot be skipped safely
+            Err(DecodeError::ExternalizableType {
+                name: class_name.to_owned(),
+            })
+        }
+    }
+    // The current site of the wire details in the exact mode
+    fn site(&self) -> u64 {
+        self.wire.as_ref().map_or(0, Tape::site)
+    }
+    fn record(&mut self, site: u64, detail: WireDetail) {
+        if let Some(wire) = &mut self.wire {
+            wire.record(site, detail);
+        }
+    }
+    pub(super) fn read_bytes(&mut self, len: usize) -> DecodeResult<Vec<u8>> {
+        let buf = self.inner.read_up_to(len)?;
+        if buf.len() < len {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
         Ok(buf)
     }
-    fn read_utf8(&mut self, len: usize) -> DecodeResult<String> {
-        self.read_bytes(len).and_then(|b| Ok(String::from_utf8(b)?))
+    pub(super) fn read_utf8(&mut self, len: usize) -> DecodeResult<String> {
+        self.budget.string(len)?;
+        let offset = self.inner.offset;
+        let bytes = self.read_bytes(len)?;
+        match (String::from_utf8(bytes), &mut self.warnings) {
+            (Ok(s), _) => Ok(s),
+            (Err(e), Some(warnings)) => {
+                warnings.push(Warning {
+                    offset,
+                    kind: WarningKind::InvalidUtf8,
+                });
+                Ok(String::from_utf8_lossy(e.as_bytes()).into_owned())
+            }
+            (Err(e), None) => Err(e.into()),
+        }
+    }
+}
+
+// The number of the bytes of the shortest encoding of a U29
+pub(super) fn u29_width(u29: u32) -> usize {
+    match u29 {
+        0..=0x7F => 1,
+        0x80..=0x3FFF => 2,
+        0x4000..=0x1F_FFFF => 3,
+        _ => 4,
+    }
+}
+
+struct ExternalInput<'a, R> {
+    decoder: &'a mut Decoder<R>,
+    graph: &'a mut Graph,
+}
+impl<'a, R> io::Read for ExternalInput<'a, R>
+where
+    R: io::Read,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.decoder.inner.read(buf)
+    }
+}
+impl<'a, R> DataInput for ExternalInput<'a, R>
+where
+    R: io::Read,
+{
+    fn read_value(&mut self) -> DecodeResult<graph::Value> {
+        self.decoder.decode_value(self.graph)
+    }
+    fn graph_mut(&mut self) -> &mut Graph {
+        self.graph
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::super::Value;
-    use crate::error::DecodeError;
-    use crate::Pair;
+    use super::super::{graph, shared};
+    use super::super::{DataInput, DataOutput, ExternalizableHandler, ExternalizableRegistry};
+    use super::super::{Decoder, Encoder, Reader, SliceDecoder, Value, ValueRef};
+    use crate::error::{DecodeError, Limit, Location, Path, PathSegment};
+    use crate::io::{self, ReadBytesExt, WriteBytesExt};
+    use crate::{DecodeResult, DecoderLimits, EncodeResult, Pair};
+    use byteorder::BigEndian;
     use std::f64;
-    use std::io;
+    use std::sync::Arc;
     use std::time;
 
     macro_rules! decode {
@@ -558,19 +904,144 @@ mod tests {
             "amf3-hash.bin",
             obj(&[("foo", s("bar")), ("answer", i(42))][..])
         );
+    }
+    #[test]
+    fn decodes_externalizable() {
+        #[derive(Debug)]
+        struct Pair64;
+        impl ExternalizableHandler for Pair64 {
+            fn read_external(&self, input: &mut dyn DataInput) -> DecodeResult<graph::Value> {
+                let entries = (0..2)
+                    .map(|_| Ok(graph::Value::Double(input.read_f64::<BigEndian>()?)))
+                    .collect::<DecodeResult<_>>()?;
+                let id = input.graph_mut().add(graph::Node::Array {
+                    assoc_entries: Vec::new(),
+                    dense_entries: entries,
+                });
+                Ok(graph::Value::Node(id))
+            }
+            fn write_external(
+                &self,
+                output: &mut dyn DataOutput,
+                body: &graph::Value,
+            ) -> EncodeResult<()> {
+                if let graph::Value::Node(id) = *body {
+                    if let graph::Node::Array {
+                        ref dense_entries, ..
+                    } = *output.graph().node(id)
+                    {
+                        for e in dense_entries.clone() {
+                            if let graph::Value::Double(x) = e {
+                                output.write_f64::<BigEndian>(x)?;
+                            }
+                        }
+                    }
+                }
+                Ok(())
+            }
+        }
 
+        let input = include_bytes!("../testdata/amf3-externalizable.bin");
+        let mut decoder = Decoder::new(&input[..]);
+        decoder
+            .externalizables_mut()
+            .register("ExternalizableTest", Pair64);
+        let ext = |a, b| Value::Externalizable {
+            class_name: "ExternalizableTest".to_string(),
+            body: Box::new(dense_array(&[Value::Double(a), Value::Double(b)][..])),
+        };
+        let value = decoder.decode().unwrap();
+        assert_eq!(value, dense_array(&[ext(5.0, 7.0), ext(13.0, 5.0)][..]));
+
+        let mut encoder = Encoder::new(Vec::new());
+        encoder
+            .externalizables_mut()
+            .register("ExternalizableTest", Pair64);
+        encoder.encode(&value).unwrap();
+        assert_eq!(encoder.into_inner(), &input[..]);
+
+        // An unknown class cannot be decoded, because the length of its body is unknown
+        let input = include_bytes!("../testdata/amf3-array-collection.bin");
+        let mut decoder = Decoder::new(&input[..]);
+        *decoder.externalizables_mut() = ExternalizableRegistry::empty();
         assert_eq!(
-            decode!("amf3-externalizable.bin"),
+            decoder.decode(),
             Err(DecodeError::ExternalizableType {
-                name: "ExternalizableTest".to_string()
+                name: "flex.messaging.io.ArrayCollection".to_string()
             })
         );
+
+        // The slice decoder can capture the rest of the input as the body of an unknown class
+        let mut decoder = SliceDecoder::new(&input[..]);
+        *decoder.externalizables_mut() = ExternalizableRegistry::empty();
         assert_eq!(
-            decode!("amf3-array-collection.bin"),
+            decoder.decode(),
             Err(DecodeError::ExternalizableType {
-                name: "flex.messaging.io.ArrayCollection".to_string(),
+                name: "flex.messaging.io.ArrayCollection".to_string()
             })
         );
+        let mut decoder = SliceDecoder::new(&input[..]);
+        *decoder.externalizables_mut() = ExternalizableRegistry::empty();
+        decoder.set_capture_unknown_externals(true);
+        let value = Value::Externalizable {
+            class_name: "flex.messaging.io.ArrayCollection".to_string(),
+            body: Box::new(Value::ByteArray(
+                b"\x09\x05\x01\x06\x07foo\x06\x07bar".to_vec(),
+            )),
+        };
+        assert_eq!(decoder.decode().unwrap().into_owned(), value);
+        assert!(decoder.remaining().is_empty());
+        let mut encoder = Encoder::new(Vec::new());
+        *encoder.externalizables_mut() = ExternalizableRegistry::empty();
+        encoder.encode(&value).unwrap();
+        assert_eq!(encoder.into_inner(), &input[..]);
+    }
+    #[test]
+    fn decodes_flex_classes() {
+        let collection = |entries: &[Value]| Value::Externalizable {
+            class_name: "flex.messaging.io.ArrayCollection".to_string(),
+            body: Box::new(dense_array(entries)),
+        };
+        decode_eq!(
+            "amf3-array-collection.bin",
+            collection(&[s("foo"), s("bar")][..])
+        );
+
+        let input = include_bytes!("../testdata/amf3-complex-array-collection.bin");
+        let value = Value::read_from(&mut &input[..]).unwrap();
+        let o = |foo| {
+            typed_obj(
+                "org.amf.ASClass",
+                &[("foo", s(foo)), ("baz", Value::Null)][..],
+            )
+        };
+        let objects = collection(&[o("bar"), o("asdf")][..]);
+        let expected = dense_array(
+            &[
+                collection(&[s("foo"), s("bar")][..]),
+                objects.clone(),
+                objects,
+            ][..],
+        );
+        assert_eq!(value, expected);
+        let mut buf = Vec::new();
+        value.write_to(&mut buf).unwrap();
+        assert_eq!(Value::read_from(&mut &buf[..]).unwrap(), expected);
+
+        let values = collection(&[i(1)][..]).try_into_values().unwrap();
+        assert_eq!(values.collect::<Vec<_>>(), [i(1)]);
+
+        let proxy = Value::Externalizable {
+            class_name: "flex.messaging.io.ManagedObjectProxy".to_string(),
+            body: Box::new(obj(&[("foo", s("bar"))][..])),
+        };
+        let mut buf = Vec::new();
+        proxy.write_to(&mut buf).unwrap();
+        assert_eq!(
+            buf,
+            &b"\x0a\x07\x49flex.messaging.io.ManagedObjectProxy\x00\x00\x00\x01\x06\x07foo\x0a\x0b\x01\x02\x06\x07bar\x01"[..]
+        );
+        assert_eq!(Value::read_from(&mut &buf[..]).unwrap(), proxy);
     }
     #[test]
     fn decodes_xml_doc() {
@@ -670,6 +1141,423 @@ mod tests {
         );
     }
     #[test]
+    fn decodes_vector_in_bulk() {
+        // Reader which counts the calls of `read`
+        struct Calls<'a>(&'a [u8], usize);
+        impl io::Read for Calls<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                self.1 += 1;
+                self.0.read(buf)
+            }
+        }
+
+        // The entries span multiple bulk reads
+        let value = Value::DoubleVector {
+            is_fixed: true,
+            entries: (0..20_000).map(f64::from).collect(),
+        };
+        let mut buf = Vec::new();
+        value.write_to(&mut buf).unwrap();
+        let mut decoder = Decoder::new(Calls(&buf, 0));
+        assert_eq!(decoder.decode().unwrap(), value);
+        assert!(decoder.inner().1 < 10);
+
+        let value = Value::IntVector {
+            is_fixed: false,
+            entries: vec![1, -2, 3],
+        };
+        let mut buf = Vec::new();
+        value.write_to(&mut buf).unwrap();
+        assert_eq!(Value::read_from(&mut &buf[..]).unwrap(), value);
+        match Value::read_from(&mut &buf[..buf.len() - 1]) {
+            Err(DecodeError::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof),
+            other => panic!("{:?}", other),
+        }
+    }
+    #[test]
+    fn decodes_buffered() {
+        // Reader which counts the calls of `read`
+        struct Calls<'a>(&'a [u8], usize);
+        impl io::Read for Calls<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                self.1 += 1;
+                self.0.read(buf)
+            }
+        }
+
+        let value = dense_array(&(0..10_000).map(|n| i(n * 1_000)).collect::<Vec<_>>());
+        let mut buf = Vec::new();
+        value.write_to(&mut buf).unwrap();
+        let mut decoder = Decoder::new(Calls(&buf, 0));
+        assert_eq!(decoder.decode().unwrap(), value);
+        assert!(decoder.inner().1 > 10_000);
+        let mut decoder = Decoder::buffered(Calls(&buf, 0));
+        assert_eq!(decoder.decode().unwrap(), value);
+        assert!(decoder.inner().1 < 10);
+        assert_eq!(decoder.buffer(), b"");
+
+        // The offsets are not affected by the bytes read ahead
+        let input = b"\x04\x01\x09\x03\x01\x06\x07ab";
+        let mut decoder = Decoder::buffered(&input[..]);
+        assert_eq!(decoder.decode(), Ok(i(1)));
+        assert!(decoder.decode().is_err());
+        assert_eq!(decoder.error_location().map(|l| l.offset), Some(5));
+    }
+    #[test]
+    fn decodes_graph() {
+        let input = include_bytes!("../testdata/amf3-graph-member.bin");
+        let mut decoder = Decoder::new(&input[..]);
+        let root = decoder.decode_graph().unwrap();
+        let id = match root {
+            graph::Value::Node(id) => id,
+            _ => panic!(),
+        };
+        assert_eq!(id.index(), 0);
+
+        // Each child refers to the root object as its parent
+        let g = decoder.graph();
+        let children = match *g.node(id) {
+            graph::Node::Object { ref entries, .. } => entries[0].value.clone(),
+            _ => panic!(),
+        };
+        let children = match children {
+            graph::Value::Node(x) => match *g.node(x) {
+                graph::Node::Array {
+                    ref dense_entries, ..
+                } => dense_entries.clone(),
+                _ => panic!(),
+            },
+            _ => panic!(),
+        };
+        assert_eq!(children.len(), 2);
+        for child in children {
+            match child {
+                graph::Value::Node(x) => match *g.node(x) {
+                    graph::Node::Object { ref entries, .. } => {
+                        let parent = entries.iter().find(|e| e.key == "parent").unwrap();
+                        assert_eq!(parent.value, root);
+                    }
+                    _ => panic!(),
+                },
+                _ => panic!(),
+            }
+        }
+
+        let mut buf = Vec::new();
+        Encoder::new(&mut buf).encode_graph(g, &root).unwrap();
+        assert_eq!(buf, &input[..]);
+    }
+    #[test]
+    fn decodes_shared() {
+        // An array which refers to another array 1000 times
+        let mut g = graph::Graph::new();
+        let inner = g.add(graph::Node::Array {
+            assoc_entries: Vec::new(),
+            dense_entries: (0..1000).map(graph::Value::Integer).collect(),
+        });
+        let outer = g.add(graph::Node::Array {
+            assoc_entries: Vec::new(),
+            dense_entries: vec![graph::Value::Node(inner); 1000],
+        });
+        let mut encoder = Encoder::new(Vec::new());
+        encoder
+            .encode_graph(&g, &graph::Value::Node(outer))
+            .unwrap();
+        encoder
+            .encode_graph(&g, &graph::Value::Node(inner))
+            .unwrap();
+        let input = encoder.into_inner();
+        assert!(input.len() < 5000);
+
+        let mut decoder = Decoder::new(&input[..]);
+        let value = decoder.decode_shared().unwrap();
+        let entries = match value {
+            shared::Value::Node(ref x) => match **x {
+                shared::Node::Array {
+                    ref dense_entries, ..
+                } => dense_entries.clone(),
+                _ => panic!(),
+            },
+            _ => panic!(),
+        };
+        let first = match entries[0] {
+            shared::Value::Node(ref x) => x.clone(),
+            _ => panic!(),
+        };
+        for entry in &entries {
+            match *entry {
+                shared::Value::Node(ref x) => assert!(Arc::ptr_eq(x, &first)),
+                _ => panic!(),
+            }
+        }
+        // The references from the following values are also shared
+        match decoder.decode_shared().unwrap() {
+            shared::Value::Node(ref x) => assert!(Arc::ptr_eq(x, &first)),
+            _ => panic!(),
+        }
+
+        let expected = Value::read_from(&mut &input[..]).unwrap();
+        assert_eq!(value.into_owned(), expected);
+        assert_eq!(
+            Decoder::new(&input[..])
+                .decode_shared()
+                .unwrap()
+                .into_owned(),
+            expected
+        );
+
+        let input = include_bytes!("../testdata/amf3-graph-member.bin");
+        assert_eq!(
+            Decoder::new(&input[..]).decode_shared(),
+            Err(DecodeError::CircularReference { index: 0 })
+        );
+    }
+    #[test]
+    fn decodes_with_limits() {
+        // Checks the decoders, and returns the result of `Reader`
+        fn check(input: &[u8], limits: DecoderLimits, limit: Limit) -> DecodeResult<()> {
+            let expected = Err(DecodeError::LimitExceeded { limit });
+            assert_eq!(Decoder::with_limits(input, limits).decode(), expected);
+            assert_eq!(
+                SliceDecoder::with_limits(input, limits)
+                    .decode()
+                    .map(ValueRef::into_owned),
+                expected
+            );
+            Reader::with_limits(input, limits)
+                .collect::<DecodeResult<Vec<_>>>()
+                .map(|_| ())
+        }
+        fn exceeded(limit: Limit) -> DecodeResult<()> {
+            Err(DecodeError::LimitExceeded { limit })
+        }
+        let unlimited = DecoderLimits::unlimited();
+
+        // The default limits bound the depth
+        let nested = |depth: usize| {
+            let mut input = b"\x09\x03\x01".repeat(depth - 1);
+            input.extend_from_slice(b"\x09\x01\x01");
+            input
+        };
+        let default = DecoderLimits::default();
+        assert!(Decoder::new(&nested(default.max_depth)[..])
+            .decode()
+            .is_ok());
+        assert_eq!(
+            check(&nested(default.max_depth + 1), default, Limit::Depth),
+            exceeded(Limit::Depth)
+        );
+
+        // [[]]
+        let input = b"\x09\x03\x01\x09\x01\x01";
+        let limits = DecoderLimits {
+            max_depth: 1,
+            ..unlimited
+        };
+        assert_eq!(check(input, limits, Limit::Depth), exceeded(Limit::Depth));
+        let limits = DecoderLimits {
+            max_depth: 2,
+            ..unlimited
+        };
+        assert!(Decoder::with_limits(&input[..], limits).decode().is_ok());
+
+        let limits = DecoderLimits {
+            max_string_len: 3,
+            ..unlimited
+        };
+        assert_eq!(
+            check(b"\x06\x09abcd", limits, Limit::StringLength),
+            exceeded(Limit::StringLength)
+        );
+        assert_eq!(
+            check(b"\x0b\x09abcd", limits, Limit::StringLength),
+            exceeded(Limit::StringLength)
+        );
+
+        let limits = DecoderLimits {
+            max_byte_array_len: 3,
+            ..unlimited
+        };
+        assert_eq!(
+            check(b"\x0c\x09abcd", limits, Limit::ByteArrayLength),
+            exceeded(Limit::ByteArrayLength)
+        );
+
+        // A bogus length is rejected before reading the entries
+        let limits = DecoderLimits {
+            max_collection_len: 3,
+            ..unlimited
+        };
+        assert_eq!(
+            check(b"\x09\x09\x01", limits, Limit::CollectionLength),
+            exceeded(Limit::CollectionLength)
+        );
+        assert_eq!(
+            check(b"\x0d\x09\x00", limits, Limit::CollectionLength),
+            exceeded(Limit::CollectionLength)
+        );
+        // `Reader` does not collect the entries of the associative part
+        assert_eq!(
+            check(
+                b"\x09\x01\x03a\x04\x01\x03b\x04\x02\x03c\x04\x03\x03d\x04\x04\x01",
+                limits,
+                Limit::CollectionLength,
+            ),
+            Ok(())
+        );
+
+        let limits = DecoderLimits {
+            max_allocation: 7,
+            ..unlimited
+        };
+        assert_eq!(
+            check(b"\x06\x11abcdefgh", limits, Limit::Allocation),
+            exceeded(Limit::Allocation)
+        );
+
+        // An array which contains a byte array in 100 nested arrays and `n` references to them
+        let copies = |n: u8| {
+            let mut input = vec![0x09, (n + 1) << 1 | 1, 0x01];
+            input.extend_from_slice(&b"\x09\x03\x01".repeat(100));
+            input.extend_from_slice(b"\x0c\x8f\x51");
+            input.extend_from_slice(&[0; 1000]);
+            input.extend_from_slice(&b"\x09\x02".repeat(n as usize));
+            input
+        };
+        let limits = DecoderLimits {
+            max_allocation: 32 * 1024,
+            ..default
+        };
+        assert!(Decoder::with_limits(&copies(1)[..], limits)
+            .decode()
+            .is_ok());
+        // The copies are counted in the allocation
+        assert_eq!(check(&copies(8), limits, Limit::Allocation), Ok(()));
+
+        // An array which contains the same array twice
+        let input = b"\x09\x05\x01\x09\x03\x01\x04\x01\x09\x02";
+        let limits = DecoderLimits {
+            max_reference_expansions: 1,
+            ..unlimited
+        };
+        // `Reader` does not copy the referred values
+        assert_eq!(check(input, limits, Limit::ReferenceExpansions), Ok(()));
+        let limits = DecoderLimits {
+            max_reference_expansions: 2,
+            ..unlimited
+        };
+        assert!(Decoder::with_limits(&input[..], limits).decode().is_ok());
+        let limits = DecoderLimits {
+            max_reference_expansions: 0,
+            ..unlimited
+        };
+        assert!(Decoder::with_limits(&input[..], limits)
+            .decode_shared()
+            .is_ok());
+    }
+    #[test]
+    fn locates_errors() {
+        // {orders: [1, <unknown marker>]}
+        let input = b"\x0a\x0b\x01\x0dorders\x09\x05\x01\x04\x01\x7b";
+        let expected = Location {
+            offset: 15,
+            path: Path {
+                segments: vec![
+                    PathSegment::Key("orders".to_string()),
+                    PathSegment::Index(1),
+                ],
+            },
+        };
+        let mut decoder = Decoder::new(&input[..]);
+        assert_eq!(decoder.decode(), Err(DecodeError::Unknown { marker: 123 }));
+        assert_eq!(decoder.error_location(), Some(&expected));
+        assert_eq!(expected.to_string(), "offset=15, path=$.orders[1]");
+        let mut decoder = SliceDecoder::new(&input[..]);
+        assert_eq!(
+            decoder.decode().map(ValueRef::into_owned),
+            Err(DecodeError::Unknown { marker: 123 })
+        );
+        assert_eq!(decoder.error_location(), Some(&expected));
+
+        // The offsets are counted from the beginning of the input
+        let input = b"\x04\x01\x09\x03\x01\x06\x07ab";
+        let mut decoder = Decoder::new(&input[..]);
+        decoder.decode().unwrap();
+        assert!(decoder.decode().is_err());
+        let location = decoder.error_location().unwrap();
+        assert_eq!(location.offset, 5);
+        assert_eq!(location.path.to_string(), "$[0]");
+    }
+    #[test]
+    fn drops_failed_nodes() {
+        // An empty array and a truncated one in an array
+        let input = b"\x09\x05\x01\x09\x01\x01\x09\x03";
+        let mut decoder = Decoder::new(&input[..]);
+        assert!(decoder.decode_graph().is_err());
+        assert!(decoder.graph().is_empty());
+
+        // The failed arrays are not in the reference table
+        *decoder.inner_mut() = &b"\x09\x00"[..];
+        assert_eq!(
+            decoder.decode_graph(),
+            Err(DecodeError::OutOfRangeReference { index: 0 })
+        );
+    }
+    #[test]
+    fn decodes_trees_with_one_node_per_value() {
+        // An empty array and three references to it
+        let input = b"\x09\x01\x01\x09\x00\x09\x00\x09\x00";
+        let empty = Value::Array {
+            assoc_entries: Vec::new(),
+            dense_entries: Vec::new(),
+        };
+        let mut decoder = Decoder::new(&input[..]);
+        assert_eq!(decoder.decode(), Ok(empty.clone()));
+        assert_eq!(decoder.decode(), Ok(empty));
+        assert_eq!(decoder.graph().len(), 1);
+
+        // `decode_graph` shares the node with `decode`
+        let node = decoder.decode_graph().unwrap();
+        assert_eq!(decoder.decode_graph(), Ok(node));
+        assert_eq!(decoder.graph().len(), 1);
+
+        // A byte array in 100 nested arrays is stored once, not once per level
+        let mut input = b"\x09\x03\x01".repeat(100);
+        input.extend_from_slice(b"\x0c\x65");
+        input.extend_from_slice(&[0; 50]);
+        let mut decoder = Decoder::new(&input[..]);
+        let mut value = decoder.decode().unwrap();
+        for _ in 0..100 {
+            match value {
+                Value::Array {
+                    mut dense_entries, ..
+                } => value = dense_entries.pop().unwrap(),
+                _ => panic!(),
+            }
+        }
+        assert_eq!(value, Value::ByteArray(vec![0; 50]));
+        assert_eq!(decoder.graph().len(), 101);
+    }
+    #[test]
+    fn copies_graph_with_limits() {
+        // Arrays each of which contains the previous one twice
+        let mut g = graph::Graph::new();
+        let mut value = graph::Value::Null;
+        for _ in 0..64 {
+            let id = g.add(graph::Node::Array {
+                assoc_entries: Vec::new(),
+                dense_entries: vec![value.clone(), value],
+            });
+            value = graph::Value::Node(id);
+        }
+        assert_eq!(
+            g.to_tree(&value),
+            Err(DecodeError::LimitExceeded {
+                limit: Limit::ReferenceExpansions
+            })
+        );
+    }
+    #[test]
     fn other_errors() {
         assert_eq!(
             decode!("amf3-graph-member.bin"),
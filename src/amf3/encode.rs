@@ -1,14 +1,56 @@
+use super::decode::u29_width;
+use super::graph::{self, Graph, Node, NodeId};
 use super::marker;
+use super::Trait;
 use super::Value;
-use crate::Pair;
-use byteorder::{BigEndian, WriteBytesExt};
-use std::io;
-use std::time;
+use super::{DataOutput, ExternalizableRegistry};
+use crate::error::EncodeError;
+use crate::io::{self, WriteBytesExt};
+use crate::util::{unix_millis, ByteCounter, Shapes};
+use crate::wire::{Tape, WireDetail};
+use crate::{EncodeResult, EncoderOptions, Map, ObjectPolicy, Pair, WireFormat};
+use alloc::borrow::ToOwned;
+use alloc::string::String;
+use alloc::string::ToString;
+use alloc::vec::Vec;
+use byteorder::BigEndian;
+use core::mem;
+use core::time;
 
 /// AMF3 encoder.
 #[derive(Debug)]
 pub struct Encoder<W> {
-    inner: W,
+    pub(super) inner: W,
+    traits: Map<Trait, usize>,
+    trait_count: usize,
+    strings: Map<String, usize>,
+    string_count: usize,
+    // The indices of the complex values by the identifiers of their structures
+    complexes: Map<usize, usize>,
+    shapes: Shapes,
+    // `true` for an encoder which writes the key of a complex value in `shapes`
+    writes_key: bool,
+    nodes: Map<NodeId, usize>,
+    pub(super) complex_count: usize,
+    object_references: bool,
+    // `Some` while encoding in the exact mode
+    pub(crate) wire: Option<Tape>,
+    // The indices of the complex values referred by the wire details being replayed,
+    // mapped to the keys of those values once encoded
+    pub(crate) referenced: Map<usize, Option<usize>>,
+    pub(crate) externalizables: ExternalizableRegistry,
+    options: EncoderOptions,
+    // `Some` while computing `encoded_len`
+    journal: Option<Journal>,
+}
+
+// The keys added to the reference tables while computing `encoded_len`,
+// which are removed afterwards
+#[derive(Debug, Default)]
+struct Journal {
+    traits: Vec<Trait>,
+    strings: Vec<String>,
+    complexes: Vec<usize>,
 }
 impl<W> Encoder<W> {
     /// Unwraps this `Encoder`, returning the underlying writer.
@@ -23,6 +65,90 @@ impl<W> Encoder<W> {
     pub fn inner_mut(&mut self) -> &mut W {
         &mut self.inner
     }
+    /// Returns a mutable reference to the registry of the externalizable class handlers.
+    pub fn externalizables_mut(&mut self) -> &mut ExternalizableRegistry {
+        &mut self.externalizables
+    }
+    /// Returns an immutable reference to the options of this encoder.
+    pub fn options(&self) -> &EncoderOptions {
+        &self.options
+    }
+    /// Returns a mutable reference to the options of this encoder.
+    pub fn options_mut(&mut self) -> &mut EncoderOptions {
+        &mut self.options
+    }
+    /// Returns the number of the bytes which `encode` writes for `value` in the current state.
+    ///
+    /// Unlike `encode`, this neither writes the value nor updates the reference tables,
+    /// so the length can be written before the value (e.g., as a length prefix).
+    /// The entries added to the reference tables while counting are removed afterwards.
+    pub fn encoded_len(&mut self, value: &Value) -> EncodeResult<usize> {
+        let mut counter = self.start_counting(ByteCounter::default());
+        let result = counter.encode(value);
+        let counter = self.finish_counting(counter);
+        result.map(|()| counter.count)
+    }
+    // Moves the reference tables into an encoder which records the entries added to them
+    pub(crate) fn start_counting<V>(&mut self, inner: V) -> Encoder<V> {
+        Encoder {
+            inner,
+            traits: mem::take(&mut self.traits),
+            trait_count: self.trait_count,
+            strings: mem::take(&mut self.strings),
+            string_count: self.string_count,
+            complexes: mem::take(&mut self.complexes),
+            shapes: mem::take(&mut self.shapes),
+            writes_key: false,
+            nodes: Map::new(),
+            complex_count: self.complex_count,
+            object_references: self.object_references,
+            wire: None,
+            referenced: Map::new(),
+            externalizables: self.externalizables.clone(),
+            options: self.options,
+            journal: Some(Journal::default()),
+        }
+    }
+    // Moves the reference tables back from `counter` without the entries added to them,
+    // returning the writer of `counter`
+    pub(crate) fn finish_counting<V>(&mut self, mut counter: Encoder<V>) -> V {
+        let journal = counter.journal.take().unwrap_or_default();
+        for t in journal.traits {
+            counter.traits.remove(&t);
+        }
+        for s in journal.strings {
+            counter.strings.remove(&s);
+        }
+        for key in journal.complexes {
+            counter.complexes.remove(&key);
+        }
+        self.traits = counter.traits;
+        self.strings = counter.strings;
+        self.complexes = counter.complexes;
+        self.shapes = counter.shapes;
+        counter.inner
+    }
+    pub(crate) fn with_inner<V>(self, inner: V) -> (Encoder<V>, W) {
+        let encoder = Encoder {
+            inner,
+            traits: self.traits,
+            trait_count: self.trait_count,
+            strings: self.strings,
+            string_count: self.string_count,
+            complexes: self.complexes,
+            shapes: self.shapes,
+            writes_key: self.writes_key,
+            nodes: self.nodes,
+            complex_count: self.complex_count,
+            object_references: self.object_references,
+            wire: self.wire,
+            referenced: self.referenced,
+            externalizables: self.externalizables,
+            options: self.options,
+            journal: self.journal,
+        };
+        (encoder, self.inner)
+    }
 }
 impl<W> Encoder<W>
 where
@@ -30,11 +156,85 @@ where
 {
     /// Makes a new instance.
     pub fn new(inner: W) -> Self {
-        Encoder { inner }
+        Self::with_options(inner, EncoderOptions::default())
+    }
+
+    /// Makes a new instance with the specified options.
+    pub fn with_options(inner: W, options: EncoderOptions) -> Self {
+        Encoder {
+            inner,
+            traits: Map::new(),
+            trait_count: 0,
+            strings: Map::new(),
+            string_count: 0,
+            complexes: Map::new(),
+            shapes: Shapes::default(),
+            writes_key: false,
+            nodes: Map::new(),
+            complex_count: 0,
+            object_references: false,
+            wire: None,
+            referenced: Map::new(),
+            externalizables: ExternalizableRegistry::new(),
+            options,
+            journal: None,
+        }
+    }
+
+    /// Enables or disables the emission of object references.
+    ///
+    /// If enabled, a complex value (e.g., object, array, byte array and date)
+    /// which is structurally equal to a previously encoded one is written as a reference to it.
+    ///
+    /// This is disabled by default because finding the repeated values
+    /// requires extra computation and memory proportional to the size of the encoded values.
+    pub fn set_object_references(&mut self, enabled: bool) {
+        self.object_references = enabled;
     }
 
     /// Encodes a AMF3 value.
-    pub fn encode(&mut self, value: &Value) -> io::Result<()> {
+    pub fn encode(&mut self, value: &Value) -> EncodeResult<()> {
+        let result = self.encode_value(value);
+        self.shapes.forget_addresses();
+        result
+    }
+    fn encode_value(&mut self, value: &Value) -> EncodeResult<()> {
+        if let Some(marker) = complex_marker(value) {
+            if self.writes_key && self.complex_count > 0 {
+                if let Some(id) = self.shapes.get(value) {
+                    self.inner.write_u8(Shapes::MEMBER)?;
+                    self.inner.write_all(&(id as u64).to_be_bytes())?;
+                    return Ok(());
+                }
+            }
+            if let Some(ref wire) = self.wire {
+                let reference = wire.peek(|d| match d {
+                    WireDetail::ObjectReference(index) => Some(index),
+                    _ => None,
+                });
+                if let Some(index) = reference {
+                    if self.is_replayed_reference(index, value)? {
+                        self.inner.write_u8(marker)?;
+                        return self.encode_reference(index);
+                    }
+                }
+                if self.referenced.contains_key(&self.complex_count) {
+                    let key = self.reference_key(value)?;
+                    self.referenced.insert(self.complex_count, Some(key));
+                }
+            } else if self.object_references {
+                let key = self.reference_key(value)?;
+                if let Some(&index) = self.complexes.get(&key) {
+                    self.inner.write_u8(marker)?;
+                    return self.encode_reference(index);
+                }
+                if let Some(ref mut journal) = self.journal {
+                    journal.complexes.push(key);
+                }
+                self.complexes.insert(key, self.complex_count);
+            }
+            self.complex_count += 1;
+        }
         match *value {
             Value::Undefined => self.encode_undefined(),
             Value::Null => self.encode_null(),
@@ -47,12 +247,12 @@ where
             Value::Array {
                 ref assoc_entries,
                 ref dense_entries,
-            } => self.encode_array(assoc_entries, dense_entries),
+            } => self.encode_array(assoc_entries, dense_entries, Self::encode_value),
             Value::Object {
                 ref class_name,
                 sealed_count,
                 ref entries,
-            } => self.encode_object(class_name, sealed_count, entries),
+            } => self.encode_object(class_name, sealed_count, entries, Self::encode_value),
             Value::Xml(ref x) => self.encode_xml(x),
             Value::ByteArray(ref x) => self.encode_byte_array(x),
             Value::IntVector {
@@ -71,23 +271,158 @@ where
                 ref class_name,
                 is_fixed,
                 ref entries,
-            } => self.encode_object_vector(class_name, is_fixed, entries),
+            } => self.encode_object_vector(class_name, is_fixed, entries, Self::encode_value),
             Value::Dictionary {
                 is_weak,
                 ref entries,
-            } => self.encode_dictionary(is_weak, entries),
+            } => self.encode_dictionary(is_weak, entries, Self::encode_value),
+            Value::Externalizable {
+                ref class_name,
+                ref body,
+            } => {
+                let mut graph = Graph::new();
+                let body = graph.add_tree(body);
+                let nodes = mem::take(&mut self.nodes);
+                let result = self.encode_externalizable(class_name, &graph, &body);
+                self.nodes = nodes;
+                result
+            }
         }
     }
 
-    fn encode_undefined(&mut self) -> io::Result<()> {
+    /// Encodes a AMF3 value reproducing its wire details recorded by `Decoder::decode_exact`.
+    ///
+    /// The options of this encoder and `set_object_references` are not applied,
+    /// and strings and traits are written as references only where those were in the decoded input.
+    /// See `WireFormat` for the details which are reproduced.
+    pub fn encode_exact(&mut self, value: &Value, wire: &WireFormat) -> EncodeResult<()> {
+        let tape = Tape::replay(wire);
+        self.referenced = tape.references(|d| match d {
+            WireDetail::ObjectReference(index) => Some(index),
+            _ => None,
+        });
+        self.wire = Some(tape);
+        let options = mem::take(&mut self.options);
+        let result = self.encode(value);
+        self.options = options;
+        self.wire = None;
+        self.referenced.clear();
+        result
+    }
+
+    /// Encodes a AMF3 value in the canonical form.
+    ///
+    /// The value is normalized by `Value::canonicalize`, and written without the options of this encoder
+    /// and `set_object_references`, so that equal values are written in identical bytes.
+    /// Strings and traits are written as references as usual,
+    /// thus this holds as long as the reference tables are in the same state
+    /// (e.g., the values are the first ones after `clear_reference_table`).
+    pub fn encode_canonical(&mut self, value: &Value) -> EncodeResult<()> {
+        let mut value = value.clone();
+        value.canonicalize();
+        let options = mem::take(&mut self.options);
+        let object_references = mem::replace(&mut self.object_references, false);
+        let result = self.encode(&value);
+        self.options = options;
+        self.object_references = object_references;
+        result
+    }
+
+    /// Encodes a AMF3 value in an object graph.
+    ///
+    /// Each node of `graph` is written in full only at its first occurrence,
+    /// and the subsequent occurrences are written as references to it.
+    /// So shared and circular references are preserved.
+    ///
+    /// Note that the nodes are identified by `NodeId` until `clear_reference_table` is called,
+    /// thus the values encoded in a message should belong to the same graph.
+    pub fn encode_graph(&mut self, graph: &Graph, value: &graph::Value) -> EncodeResult<()> {
+        let id = match *value {
+            graph::Value::Undefined => return self.encode_undefined(),
+            graph::Value::Null => return self.encode_null(),
+            graph::Value::Boolean(x) => return self.encode_boolean(x),
+            graph::Value::Integer(x) => return self.encode_integer(x),
+            graph::Value::Double(x) => return self.encode_double(x),
+            graph::Value::String(ref x) => return self.encode_string(x),
+            graph::Value::Node(id) => id,
+        };
+        let node = graph.node(id);
+        if let Some(&index) = self.nodes.get(&id) {
+            self.inner.write_u8(node_marker(node))?;
+            return self.encode_reference(index);
+        }
+        self.nodes.insert(id, self.complex_count);
+        self.complex_count += 1;
+
+        let f = |this: &mut Self, v: &graph::Value| this.encode_graph(graph, v);
+        match *node {
+            Node::XmlDocument(ref x) => self.encode_xml_document(x),
+            Node::Date { unix_time } => self.encode_date(unix_time),
+            Node::Array {
+                ref assoc_entries,
+                ref dense_entries,
+            } => self.encode_array(assoc_entries, dense_entries, f),
+            Node::Object {
+                ref class_name,
+                sealed_count,
+                ref entries,
+            } => self.encode_object(class_name, sealed_count, entries, f),
+            Node::Xml(ref x) => self.encode_xml(x),
+            Node::ByteArray(ref x) => self.encode_byte_array(x),
+            Node::IntVector {
+                is_fixed,
+                ref entries,
+            } => self.encode_int_vector(is_fixed, entries),
+            Node::UintVector {
+                is_fixed,
+                ref entries,
+            } => self.encode_uint_vector(is_fixed, entries),
+            Node::DoubleVector {
+                is_fixed,
+                ref entries,
+            } => self.encode_double_vector(is_fixed, entries),
+            Node::ObjectVector {
+                ref class_name,
+                is_fixed,
+                ref entries,
+            } => self.encode_object_vector(class_name, is_fixed, entries, f),
+            Node::Dictionary {
+                is_weak,
+                ref entries,
+            } => self.encode_dictionary(is_weak, entries, f),
+            Node::Externalizable {
+                ref class_name,
+                ref body,
+            } => self.encode_externalizable(class_name, graph, body),
+        }
+    }
+
+    /// Clear the reference tables of this encoder.
+    ///
+    /// > Similar to AFM 0, AMF 3 object reference tables, object trait reference tables
+    /// > and string reference tables must be reset each time a new context header or message is processed.
+    /// >
+    /// > [AMF 3 Specification: 4.1 NetConnection and AMF 3](https://www.adobe.com/content/dam/acom/en/devnet/pdf/amf-file-format-spec.pdf)
+    pub fn clear_reference_table(&mut self) {
+        self.traits.clear();
+        self.trait_count = 0;
+        self.strings.clear();
+        self.string_count = 0;
+        self.complexes.clear();
+        self.shapes.clear();
+        self.nodes.clear();
+        self.complex_count = 0;
+    }
+
+    pub(super) fn encode_undefined(&mut self) -> EncodeResult<()> {
         self.inner.write_u8(marker::UNDEFINED)?;
         Ok(())
     }
-    fn encode_null(&mut self) -> io::Result<()> {
+    pub(super) fn encode_null(&mut self) -> EncodeResult<()> {
         self.inner.write_u8(marker::NULL)?;
         Ok(())
     }
-    fn encode_boolean(&mut self, b: bool) -> io::Result<()> {
+    pub(super) fn encode_boolean(&mut self, b: bool) -> EncodeResult<()> {
         if b {
             self.inner.write_u8(marker::TRUE)?;
         } else {
@@ -95,7 +430,13 @@ where
         }
         Ok(())
     }
-    fn encode_integer(&mut self, i: i32) -> io::Result<()> {
+    pub(super) fn encode_integer(&mut self, i: i32) -> EncodeResult<()> {
+        if !(-(1 << 28)..(1 << 28)).contains(&i) {
+            if self.options.promote_integers {
+                return self.encode_double(i as f64);
+            }
+            return Err(EncodeError::IntegerOutOfRange { value: i as i64 });
+        }
         self.inner.write_u8(marker::INTEGER)?;
         let u29 = if i >= 0 {
             i as u32
@@ -105,66 +446,114 @@ where
         self.encode_u29(u29)?;
         Ok(())
     }
-    fn encode_double(&mut self, d: f64) -> io::Result<()> {
+    pub(super) fn encode_double(&mut self, d: f64) -> EncodeResult<()> {
+        let is_integral = d % 1.0 == 0.0 && !(d == 0.0 && d.is_sign_negative());
+        if self.options.integral_doubles
+            && is_integral
+            && (-(1 << 28) as f64..(1 << 28) as f64).contains(&d)
+        {
+            return self.encode_integer(d as i32);
+        }
         self.inner.write_u8(marker::DOUBLE)?;
         self.inner.write_f64::<BigEndian>(d)?;
         Ok(())
     }
-    fn encode_string(&mut self, s: &str) -> io::Result<()> {
+    pub(super) fn encode_string(&mut self, s: &str) -> EncodeResult<()> {
         self.inner.write_u8(marker::STRING)?;
         self.encode_utf8(s)?;
         Ok(())
     }
-    fn encode_xml_document(&mut self, xml: &str) -> io::Result<()> {
+    pub(super) fn encode_xml_document(&mut self, xml: &str) -> EncodeResult<()> {
+        let len = string_len(xml)?;
         self.inner.write_u8(marker::XML_DOC)?;
-        self.encode_utf8(xml)?;
+        self.encode_size(len)?;
+        self.inner.write_all(xml.as_bytes())?;
         Ok(())
     }
-    fn encode_date(&mut self, unix_time: time::Duration) -> io::Result<()> {
-        let millis = unix_time.as_secs() * 1000 + (unix_time.subsec_nanos() as u64) / 1_000_000;
+    pub(super) fn encode_date(&mut self, unix_time: time::Duration) -> EncodeResult<()> {
+        let millis = unix_millis(unix_time)?;
         self.inner.write_u8(marker::DATE)?;
         self.encode_size(0)?;
         self.inner.write_f64::<BigEndian>(millis as f64)?;
         Ok(())
     }
-    fn encode_array(&mut self, assoc: &[Pair<String, Value>], dense: &[Value]) -> io::Result<()> {
+    fn encode_array<T, F>(
+        &mut self,
+        assoc: &[Pair<String, T>],
+        dense: &[T],
+        mut f: F,
+    ) -> EncodeResult<()>
+    where
+        F: FnMut(&mut Self, &T) -> EncodeResult<()>,
+    {
         self.inner.write_u8(marker::ARRAY)?;
         self.encode_size(dense.len())?;
-        self.encode_pairs(assoc)?;
-        dense
-            .iter()
-            .map(|v| self.encode(v))
-            .collect::<io::Result<Vec<_>>>()?;
+        self.encode_pairs(assoc, &mut f)?;
+        for v in dense {
+            f(self, v)?;
+        }
         Ok(())
     }
-    fn encode_object(
+    fn encode_object<T, F>(
         &mut self,
         class_name: &Option<String>,
         sealed_count: usize,
-        entries: &[Pair<String, Value>],
-    ) -> io::Result<()> {
+        entries: &[Pair<String, T>],
+        mut f: F,
+    ) -> EncodeResult<()>
+    where
+        F: FnMut(&mut Self, &T) -> EncodeResult<()>,
+    {
+        if sealed_count > entries.len() {
+            return Err(EncodeError::InvalidSealedCount {
+                sealed_count,
+                len: entries.len(),
+            });
+        }
+        let sealed_count = match self.options.anonymous_objects {
+            _ if class_name.is_some() => sealed_count,
+            ObjectPolicy::AsIs => sealed_count,
+            ObjectPolicy::Dynamic => 0,
+            ObjectPolicy::Sealed => entries.len(),
+        };
+        let is_dynamic = sealed_count < entries.len()
+            || self.wire.as_ref().is_some_and(|wire| {
+                wire.peek(|d| (d == WireDetail::DynamicObject).then_some(()))
+                    .is_some()
+            });
         self.inner.write_u8(marker::OBJECT)?;
-        self.encode_trait(class_name, sealed_count, entries)?;
+        self.encode_trait(Trait {
+            class_name: class_name.clone(),
+            is_dynamic,
+            is_externalizable: false,
+            fields: entries
+                .iter()
+                .take(sealed_count)
+                .map(|e| e.key.clone())
+                .collect(),
+        })?;
         for e in entries.iter().take(sealed_count) {
-            self.encode(&e.value)?;
+            f(self, &e.value)?;
         }
-        if entries.len() > sealed_count {
-            self.encode_pairs(&entries[sealed_count..])?;
+        if is_dynamic {
+            self.encode_pairs(&entries[sealed_count..], &mut f)?;
         }
         Ok(())
     }
-    fn encode_xml(&mut self, xml: &str) -> io::Result<()> {
+    pub(super) fn encode_xml(&mut self, xml: &str) -> EncodeResult<()> {
+        let len = string_len(xml)?;
         self.inner.write_u8(marker::XML)?;
-        self.encode_utf8(xml)?;
+        self.encode_size(len)?;
+        self.inner.write_all(xml.as_bytes())?;
         Ok(())
     }
-    fn encode_byte_array(&mut self, bytes: &[u8]) -> io::Result<()> {
+    pub(super) fn encode_byte_array(&mut self, bytes: &[u8]) -> EncodeResult<()> {
         self.inner.write_u8(marker::BYTE_ARRAY)?;
         self.encode_size(bytes.len())?;
         self.inner.write_all(bytes)?;
         Ok(())
     }
-    fn encode_int_vector(&mut self, is_fixed: bool, vec: &[i32]) -> io::Result<()> {
+    fn encode_int_vector(&mut self, is_fixed: bool, vec: &[i32]) -> EncodeResult<()> {
         self.inner.write_u8(marker::VECTOR_INT)?;
         self.encode_size(vec.len())?;
         self.inner.write_u8(is_fixed as u8)?;
@@ -173,7 +562,7 @@ where
         }
         Ok(())
     }
-    fn encode_uint_vector(&mut self, is_fixed: bool, vec: &[u32]) -> io::Result<()> {
+    fn encode_uint_vector(&mut self, is_fixed: bool, vec: &[u32]) -> EncodeResult<()> {
         self.inner.write_u8(marker::VECTOR_UINT)?;
         self.encode_size(vec.len())?;
         self.inner.write_u8(is_fixed as u8)?;
@@ -182,7 +571,7 @@ where
         }
         Ok(())
     }
-    fn encode_double_vector(&mut self, is_fixed: bool, vec: &[f64]) -> io::Result<()> {
+    fn encode_double_vector(&mut self, is_fixed: bool, vec: &[f64]) -> EncodeResult<()> {
         self.inner.write_u8(marker::VECTOR_DOUBLE)?;
         self.encode_size(vec.len())?;
         self.inner.write_u8(is_fixed as u8)?;
@@ -191,114 +580,348 @@ where
         }
         Ok(())
     }
-    fn encode_object_vector(
+    fn encode_object_vector<T, F>(
         &mut self,
         class_name: &Option<String>,
         is_fixed: bool,
-        vec: &[Value],
-    ) -> io::Result<()> {
+        vec: &[T],
+        mut f: F,
+    ) -> EncodeResult<()>
+    where
+        F: FnMut(&mut Self, &T) -> EncodeResult<()>,
+    {
         self.inner.write_u8(marker::VECTOR_OBJECT)?;
         self.encode_size(vec.len())?;
         self.inner.write_u8(is_fixed as u8)?;
         self.encode_utf8(class_name.as_ref().map_or("*", |s| s))?;
         for x in vec {
-            self.encode(x)?;
+            f(self, x)?;
         }
         Ok(())
     }
-    fn encode_dictionary(
+    fn encode_dictionary<T, F>(
         &mut self,
         is_weak: bool,
-        entries: &[Pair<Value, Value>],
-    ) -> io::Result<()> {
+        entries: &[Pair<T, T>],
+        mut f: F,
+    ) -> EncodeResult<()>
+    where
+        F: FnMut(&mut Self, &T) -> EncodeResult<()>,
+    {
         self.inner.write_u8(marker::DICTIONARY)?;
         self.encode_size(entries.len())?;
         self.inner.write_u8(is_weak as u8)?;
         for e in entries {
-            self.encode(&e.key)?;
-            self.encode(&e.value)?;
+            f(self, &e.key)?;
+            f(self, &e.value)?;
         }
         Ok(())
     }
-    fn encode_trait(
+    fn encode_externalizable(
         &mut self,
-        class_name: &Option<String>,
-        sealed_count: usize,
-        entries: &[Pair<String, Value>],
-    ) -> io::Result<()> {
-        assert!(sealed_count <= entries.len());
+        class_name: &str,
+        graph: &Graph,
+        body: &graph::Value,
+    ) -> EncodeResult<()> {
+        let handler = self.externalizables.get(class_name);
+        let raw_body = match *body {
+            graph::Value::Node(id) => match *graph.node(id) {
+                Node::ByteArray(ref x) => Some(x),
+                _ => None,
+            },
+            _ => None,
+        };
+        if handler.is_none() && raw_body.is_none() {
+            return Err(EncodeError::ExternalizableType {
+                name: class_name.to_string(),
+            });
+        }
+
+        self.inner.write_u8(marker::OBJECT)?;
+        self.encode_trait(Trait {
+            class_name: Some(class_name.to_string()),
+            is_dynamic: false,
+            is_externalizable: true,
+            fields: Vec::new(),
+        })?;
+        if let Some(handler) = handler {
+            let mut output = ExternalOutput {
+                encoder: self,
+                graph,
+            };
+            handler.write_external(&mut output, body)
+        } else {
+            if let Some(bytes) = raw_body {
+                self.inner.write_all(bytes)?;
+            }
+            Ok(())
+        }
+    }
+    pub(super) fn encode_trait(&mut self, t: Trait) -> EncodeResult<()> {
+        let reference = match self.wire {
+            Some(ref wire) => wire
+                .peek(|d| match d {
+                    WireDetail::TraitReference(index) => Some(index),
+                    _ => None,
+                })
+                .filter(|index| self.traits.get(&t) == Some(index)),
+            None => self.traits.get(&t).copied(),
+        };
+        if let Some(index) = reference {
+            if index >= (1 << 27) {
+                return Err(EncodeError::OutOfRangeReference { index });
+            }
+            let is_reference = 0;
+            return self.encode_size((index << 1) | is_reference);
+        }
+        if t.fields.len() >= (1 << 25) {
+            return Err(EncodeError::CollectionTooLarge {
+                len: t.fields.len(),
+            });
+        }
+
         let not_reference = 1;
-        let is_externalizable = false as usize;
-        let is_dynamic = (sealed_count < entries.len()) as usize;
+        let is_externalizable = t.is_externalizable as usize;
+        let is_dynamic = t.is_dynamic as usize;
         let u28 =
-            (sealed_count << 3) | (is_dynamic << 2) | (is_externalizable << 1) | not_reference;
+            (t.fields.len() << 3) | (is_dynamic << 2) | (is_externalizable << 1) | not_reference;
         self.encode_size(u28)?;
 
-        let class_name = class_name.as_ref().map_or("", |s| s);
+        let class_name = t.class_name.as_ref().map_or("", |s| s);
         self.encode_utf8(class_name)?;
-        for e in entries.iter().take(sealed_count) {
-            self.encode_utf8(&e.key)?;
+        for field in &t.fields {
+            self.encode_utf8(field)?;
+        }
+        // A trait may be written in full more than once in the exact mode
+        if !self.traits.contains_key(&t) {
+            if let Some(ref mut journal) = self.journal {
+                journal.traits.push(t.clone());
+            }
+            self.traits.insert(t, self.trait_count);
         }
+        self.trait_count += 1;
         Ok(())
     }
-    fn encode_size(&mut self, size: usize) -> io::Result<()> {
-        assert!(size < (1 << 28));
+    pub(super) fn encode_size(&mut self, size: usize) -> EncodeResult<()> {
+        if size >= (1 << 28) {
+            return Err(EncodeError::CollectionTooLarge { len: size });
+        }
         let not_reference = 1;
         self.encode_u29(((size << 1) | not_reference) as u32)
     }
-    #[allow(clippy::zero_prefixed_literal, clippy::identity_op)]
-    fn encode_u29(&mut self, u29: u32) -> io::Result<()> {
-        if u29 < 0x80 {
-            self.inner.write_u8(u29 as u8)?;
-        } else if u29 < 0x4000 {
-            let b1 = ((u29 >> 0) & 0b0111_1111) as u8;
-            let b2 = ((u29 >> 7) | 0b1000_0000) as u8;
-            for b in &[b2, b1] {
-                self.inner.write_u8(*b)?;
-            }
-        } else if u29 < 0x20_0000 {
-            let b1 = ((u29 >> 00) & 0b0111_1111) as u8;
-            let b2 = ((u29 >> 07) | 0b1000_0000) as u8;
-            let b3 = ((u29 >> 14) | 0b1000_0000) as u8;
-            for b in &[b3, b2, b1] {
-                self.inner.write_u8(*b)?;
-            }
-        } else if u29 < 0x4000_0000 {
-            let b1 = ((u29 >> 00) & 0b1111_1111) as u8;
-            let b2 = ((u29 >> 08) | 0b1000_0000) as u8;
-            let b3 = ((u29 >> 15) | 0b1000_0000) as u8;
-            let b4 = ((u29 >> 22) | 0b1000_0000) as u8;
-            for b in &[b4, b3, b2, b1] {
-                self.inner.write_u8(*b)?;
-            }
+    fn encode_reference(&mut self, index: usize) -> EncodeResult<()> {
+        if index >= (1 << 28) {
+            return Err(EncodeError::OutOfRangeReference { index });
+        }
+        self.encode_u29((index << 1) as u32)
+    }
+    fn encode_u29(&mut self, u29: u32) -> EncodeResult<()> {
+        if u29 >= 0x2000_0000 {
+            return Err(EncodeError::IntegerOutOfRange { value: u29 as i64 });
+        }
+        let mut width = u29_width(u29);
+        if let Some(ref mut wire) = self.wire {
+            wire.next_site();
+            width = wire
+                .find(|d| match d {
+                    WireDetail::U29 { value, width } if value == u29 => Some(width),
+                    _ => None,
+                })
+                .unwrap_or(width);
+        }
+
+        // The last byte of a 4-byte U29 has 8 bits, and the others have 7 bits
+        let mut bytes = [0; 4];
+        let mut rest = u29;
+        if width == 4 {
+            bytes[3] = rest as u8;
+            rest >>= 8;
         } else {
-            panic!("Too large number: {}", u29);
+            bytes[width - 1] = (rest & 0b0111_1111) as u8;
+            rest >>= 7;
+        }
+        for b in bytes[..width - 1].iter_mut().rev() {
+            *b = (rest & 0b0111_1111) as u8 | 0b1000_0000;
+            rest >>= 7;
         }
+        self.inner.write_all(&bytes[..width])?;
         Ok(())
     }
     /// Encode an AMF3 string.
     ///
     /// Use this if you need to encode an AMF3 string outside of value context.
     /// An example of this is writing keys in Local Shared Object file.
-    pub fn encode_utf8(&mut self, s: &str) -> io::Result<()> {
-        self.encode_size(s.len())?;
+    ///
+    /// Non-empty strings which have already been written are encoded as references.
+    pub fn encode_utf8(&mut self, s: &str) -> EncodeResult<()> {
+        let reference = match self.wire {
+            Some(ref wire) => wire
+                .peek(|d| match d {
+                    WireDetail::StringReference(index) => Some(index),
+                    _ => None,
+                })
+                .filter(|index| self.strings.get(s) == Some(index)),
+            None => self.strings.get(s).copied(),
+        };
+        if let Some(index) = reference {
+            return self.encode_reference(index);
+        }
+        let len = string_len(s)?;
+        if !s.is_empty() {
+            // A string may be written in full more than once in the exact mode
+            if !self.strings.contains_key(s) {
+                if let Some(ref mut journal) = self.journal {
+                    journal.strings.push(s.to_owned());
+                }
+                self.strings.insert(s.to_owned(), self.string_count);
+            }
+            self.string_count += 1;
+        }
+        self.encode_size(len)?;
         self.inner.write_all(s.as_bytes())?;
         Ok(())
     }
-    fn encode_pairs(&mut self, pairs: &[Pair<String, Value>]) -> io::Result<()> {
+    // Checks if a reference in the wire details refers to a value which is structurally equal to `value`
+    fn is_replayed_reference(&mut self, index: usize, value: &Value) -> EncodeResult<bool> {
+        match self.referenced.get(&index) {
+            Some(&Some(key)) => Ok(key == self.reference_key(value)?),
+            _ => Ok(false),
+        }
+    }
+    // Returns the identifier of the structure of a complex value to find structurally equal values
+    //
+    // The key of the value is written by a new encoder after the identifiers of its complex members are found.
+    fn reference_key(&mut self, value: &Value) -> EncodeResult<usize> {
+        if let Some(id) = self.shapes.get(value) {
+            return Ok(id);
+        }
+        match *value {
+            Value::Array {
+                ref assoc_entries,
+                ref dense_entries,
+            } => {
+                let assoc_values = assoc_entries.iter().map(|p| &p.value);
+                self.member_keys(assoc_values.chain(dense_entries))?;
+            }
+            Value::Object { ref entries, .. } => {
+                self.member_keys(entries.iter().map(|p| &p.value))?;
+            }
+            Value::ObjectVector { ref entries, .. } => self.member_keys(entries)?,
+            Value::Dictionary { ref entries, .. } => {
+                self.member_keys(entries.iter().flat_map(|p| [&p.key, &p.value]))?;
+            }
+            _ => {}
+        }
+
+        let mut key = Vec::new();
+        let mut encoder = Encoder::with_options(&mut key, self.options);
+        encoder.externalizables = self.externalizables.clone();
+        encoder.writes_key = true;
+        encoder.shapes = mem::take(&mut self.shapes);
+        let result = encoder.encode_value(value);
+        self.shapes = mem::take(&mut encoder.shapes);
+        result?;
+        Ok(self.shapes.add(value, key))
+    }
+    fn member_keys<'a, I>(&mut self, members: I) -> EncodeResult<()>
+    where
+        I: IntoIterator<Item = &'a Value>,
+    {
+        for member in members {
+            if complex_marker(member).is_some() {
+                self.reference_key(member)?;
+            }
+        }
+        Ok(())
+    }
+    fn encode_pairs<T, F>(&mut self, pairs: &[Pair<String, T>], f: &mut F) -> EncodeResult<()>
+    where
+        F: FnMut(&mut Self, &T) -> EncodeResult<()>,
+    {
         for p in pairs {
             self.encode_utf8(&p.key)?;
-            self.encode(&p.value)?;
+            f(self, &p.value)?;
         }
         self.encode_utf8("")?;
         Ok(())
     }
 }
 
+fn string_len(s: &str) -> EncodeResult<usize> {
+    if s.len() >= (1 << 28) {
+        Err(EncodeError::StringTooLong { len: s.len() })
+    } else {
+        Ok(s.len())
+    }
+}
+
+fn complex_marker(value: &Value) -> Option<u8> {
+    match *value {
+        Value::XmlDocument(_) => Some(marker::XML_DOC),
+        Value::Date { .. } => Some(marker::DATE),
+        Value::Array { .. } => Some(marker::ARRAY),
+        Value::Object { .. } => Some(marker::OBJECT),
+        Value::Xml(_) => Some(marker::XML),
+        Value::ByteArray(_) => Some(marker::BYTE_ARRAY),
+        Value::IntVector { .. } => Some(marker::VECTOR_INT),
+        Value::UintVector { .. } => Some(marker::VECTOR_UINT),
+        Value::DoubleVector { .. } => Some(marker::VECTOR_DOUBLE),
+        Value::ObjectVector { .. } => Some(marker::VECTOR_OBJECT),
+        Value::Dictionary { .. } => Some(marker::DICTIONARY),
+        Value::Externalizable { .. } => Some(marker::OBJECT),
+        _ => None,
+    }
+}
+
+fn node_marker(node: &Node) -> u8 {
+    match *node {
+        Node::XmlDocument(_) => marker::XML_DOC,
+        Node::Date { .. } => marker::DATE,
+        Node::Array { .. } => marker::ARRAY,
+        Node::Object { .. } => marker::OBJECT,
+        Node::Xml(_) => marker::XML,
+        Node::ByteArray(_) => marker::BYTE_ARRAY,
+        Node::IntVector { .. } => marker::VECTOR_INT,
+        Node::UintVector { .. } => marker::VECTOR_UINT,
+        Node::DoubleVector { .. } => marker::VECTOR_DOUBLE,
+        Node::ObjectVector { .. } => marker::VECTOR_OBJECT,
+        Node::Dictionary { .. } => marker::DICTIONARY,
+        Node::Externalizable { .. } => marker::OBJECT,
+    }
+}
+
+struct ExternalOutput<'a, W> {
+    encoder: &'a mut Encoder<W>,
+    graph: &'a Graph,
+}
+impl<'a, W> io::Write for ExternalOutput<'a, W>
+where
+    W: io::Write,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.encoder.inner.write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.encoder.inner.flush()
+    }
+}
+impl<'a, W> DataOutput for ExternalOutput<'a, W>
+where
+    W: io::Write,
+{
+    fn write_value(&mut self, value: &graph::Value) -> EncodeResult<()> {
+        self.encoder.encode_graph(self.graph, value)
+    }
+    fn graph(&self) -> &Graph {
+        self.graph
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::super::Value;
-    use crate::Pair;
+    use super::super::{Decoder, Encoder, Value};
+    use crate::error::EncodeError;
+    use crate::{EncoderOptions, ObjectPolicy, Pair};
     use std::time;
 
     macro_rules! encode_eq {
@@ -309,6 +932,15 @@ mod tests {
             assert_eq!(buf, &expected[..]);
         }};
     }
+    macro_rules! encode_eq_with_references {
+        ($value:expr, $file:expr) => {{
+            let expected = include_bytes!(concat!("../testdata/", $file));
+            let mut encoder = Encoder::new(Vec::new());
+            encoder.set_object_references(true);
+            encoder.encode(&$value).unwrap();
+            assert_eq!(encoder.into_inner(), &expected[..]);
+        }};
+    }
     macro_rules! encode_and_decode {
         ($value:expr) => {{
             let v = $value;
@@ -356,6 +988,36 @@ mod tests {
             dense_array(&[i(5), s("Shift テスト"), s("UTF テスト"), i(5)][..]),
             "amf3-complex-encoded-string-array.bin"
         );
+        encode_eq!(
+            dense_array(
+                &[
+                    s("foo"),
+                    s("str"),
+                    s("foo"),
+                    s("str"),
+                    s("foo"),
+                    obj(&[("str", s("foo"))][..])
+                ][..]
+            ),
+            "amf3-string-ref.bin"
+        );
+        encode_eq!(
+            dense_array(&[s("this is a テスト"), s("this is a テスト")][..]),
+            "amf3-encoded-string-ref.bin"
+        );
+        encode_eq!(
+            dense_array(&[s(""), s("")][..]),
+            "amf3-empty-string-ref.bin"
+        );
+    }
+    #[test]
+    fn clears_string_reference_table() {
+        let mut buf = Vec::new();
+        let mut encoder = Encoder::new(&mut buf);
+        encoder.encode(&s("foo")).unwrap();
+        encoder.clear_reference_table();
+        encoder.encode(&s("foo")).unwrap();
+        assert_eq!(buf, b"\x06\x07foo\x06\x07foo");
     }
     #[test]
     fn encodes_array() {
@@ -384,6 +1046,81 @@ mod tests {
             obj(&[("foo", s("bar")), ("answer", i(42))][..]),
             "amf3-hash.bin"
         );
+
+        let o = [
+            typed_obj(
+                "org.amf.ASClass",
+                &[("foo", s("foo")), ("baz", Value::Null)],
+            ),
+            typed_obj(
+                "org.amf.ASClass",
+                &[("foo", s("bar")), ("baz", Value::Null)],
+            ),
+        ];
+        encode_eq!(dense_array(&o[..]), "amf3-trait-ref.bin");
+
+        encode_and_decode!(dense_array(
+            &[
+                obj(&[("foo", s("bar"))][..]),
+                typed_obj("org.amf.ASClass", &[("foo", s("bar"))][..]),
+                obj(&[("foo", s("baz"))][..]),
+                typed_obj("org.amf.ASClass", &[("baz", s("bar"))][..]),
+                typed_obj("org.amf.ASClass", &[("foo", s("qux"))][..]),
+            ][..]
+        ));
+    }
+    #[test]
+    fn encodes_reference() {
+        encode_eq_with_references!(
+            dense_array(
+                &[
+                    dense_array(&[i(1), i(2), i(3)][..]),
+                    dense_array(&[s("a"), s("b"), s("c")][..]),
+                    dense_array(&[i(1), i(2), i(3)][..]),
+                    dense_array(&[s("a"), s("b"), s("c")][..])
+                ][..]
+            ),
+            "amf3-array-ref.bin"
+        );
+
+        let d = Value::Date {
+            unix_time: time::Duration::from_secs(0),
+        };
+        encode_eq_with_references!(
+            dense_array(&[d.clone(), d.clone()][..]),
+            "amf3-date-ref.bin"
+        );
+
+        let b = Value::ByteArray(b"ASDF".to_vec());
+        encode_eq_with_references!(dense_array(&[b.clone(), b][..]), "amf3-byte-array-ref.bin");
+
+        let xml = Value::Xml("<parent><child prop=\"test\"/></parent>".to_string());
+        encode_eq_with_references!(dense_array(&[xml.clone(), xml][..]), "amf3-xml-ref.bin");
+
+        let mut encoder = Encoder::new(Vec::new());
+        encoder.set_object_references(true);
+        let o = obj(&[("foo", s("bar"))][..]);
+        let v = dense_array(&[o.clone(), dense_array(&[o.clone(), o][..])][..]);
+        encoder.encode(&v).unwrap();
+        assert_eq!(
+            encoder.inner(),
+            b"\x09\x05\x01\x0a\x0b\x01\x07foo\x06\x07bar\x01\x09\x05\x01\x0a\x02\x0a\x02"
+        );
+        assert_eq!(Value::read_from(&mut &encoder.inner()[..]).unwrap(), v);
+
+        // Deeply nested values which differ only in their innermost members
+        let nested = |leaf| (0..100).fold(leaf, |v, _| dense_array(&[v]));
+        let v = dense_array(&[nested(i(1)), nested(i(2)), nested(i(1))]);
+        let mut encoder = Encoder::new(Vec::new());
+        encoder.set_object_references(true);
+        encoder.encode(&v).unwrap();
+        assert!(encoder.inner().ends_with(b"\x04\x02\x09\x02"));
+        assert_eq!(Value::read_from(&mut &encoder.inner()[..]).unwrap(), v);
+
+        // Object references are disabled by default
+        let mut buf = Vec::new();
+        dense_array(&[d.clone(), d][..]).write_to(&mut buf).unwrap();
+        assert_eq!(buf.len(), 23);
     }
     #[test]
     fn encodes_xml_doc() {
@@ -391,6 +1128,12 @@ mod tests {
             Value::XmlDocument("<parent><child prop=\"test\" /></parent>".to_string()),
             "amf3-xml-doc.bin"
         );
+
+        // XML documents are not stored in the string reference table
+        let xml = "<parent><child prop=\"test\" /></parent>";
+        encode_and_decode!(dense_array(
+            &[s(xml), Value::XmlDocument(xml.to_string()), s(xml)][..]
+        ));
     }
     #[test]
     fn encodes_xml() {
@@ -468,11 +1211,283 @@ mod tests {
                 &[("foo", s("baz")), ("baz", Value::Null)][..],
             ),
         ];
-        encode_and_decode!(Value::ObjectVector {
-            class_name: Some("org.amf.ASClass".to_string()),
-            is_fixed: false,
-            entries: objects,
-        });
+        encode_eq!(
+            Value::ObjectVector {
+                class_name: Some("org.amf.ASClass".to_string()),
+                is_fixed: false,
+                entries: objects,
+            },
+            "amf3-vector-object.bin"
+        );
+    }
+    #[test]
+    fn encodes_externalizable() {
+        // Without a handler, only a raw body can be written
+        let value = Value::Externalizable {
+            class_name: "Foo".to_string(),
+            body: Box::new(Value::ByteArray(vec![1, 2, 3])),
+        };
+        let mut buf = Vec::new();
+        value.write_to(&mut buf).unwrap();
+        assert_eq!(buf, b"\x0a\x07\x07Foo\x01\x02\x03");
+
+        let value = Value::Externalizable {
+            class_name: "Foo".to_string(),
+            body: Box::new(i(1)),
+        };
+        assert_eq!(
+            value.write_to(&mut Vec::new()),
+            Err(EncodeError::ExternalizableType {
+                name: "Foo".to_string()
+            })
+        );
+    }
+    #[test]
+    fn encodes_with_options() {
+        macro_rules! options_eq {
+            ($options:expr, $value:expr, $expected:expr) => {{
+                let mut encoder = Encoder::with_options(Vec::new(), $options);
+                encoder.encode(&$value).unwrap();
+                assert_eq!(encoder.into_inner(), &$expected[..]);
+            }};
+        }
+        let promote = EncoderOptions {
+            promote_integers: true,
+            ..EncoderOptions::default()
+        };
+        options_eq!(promote, i(1 << 28), b"\x05\x41\xb0\x00\x00\x00\x00\x00\x00");
+        options_eq!(promote, i(3), b"\x04\x03");
+
+        let integral = EncoderOptions {
+            integral_doubles: true,
+            ..EncoderOptions::default()
+        };
+        options_eq!(integral, Value::Double(-3.0), b"\x04\xff\xff\xff\xfd");
+        options_eq!(
+            integral,
+            Value::Double(0.5),
+            b"\x05\x3f\xe0\x00\x00\x00\x00\x00\x00"
+        );
+        options_eq!(
+            integral,
+            Value::Double(-0.0),
+            b"\x05\x80\x00\x00\x00\x00\x00\x00\x00"
+        );
+        options_eq!(
+            integral,
+            Value::Double((1 << 28) as f64),
+            b"\x05\x41\xb0\x00\x00\x00\x00\x00\x00"
+        );
+
+        let sealed = EncoderOptions {
+            anonymous_objects: ObjectPolicy::Sealed,
+            ..EncoderOptions::default()
+        };
+        let object = Value::Object {
+            class_name: None,
+            sealed_count: 0,
+            entries: vec![pair("a", i(1))],
+        };
+        let array = Value::Array {
+            assoc_entries: vec![],
+            dense_entries: vec![object.clone(), object.clone()],
+        };
+        options_eq!(
+            sealed,
+            array,
+            b"\x09\x05\x01\x0a\x13\x01\x03a\x04\x01\x0a\x01\x04\x01"
+        );
+
+        let dynamic = EncoderOptions {
+            anonymous_objects: ObjectPolicy::Dynamic,
+            ..EncoderOptions::default()
+        };
+        let object = Value::Object {
+            class_name: None,
+            sealed_count: 1,
+            entries: vec![pair("a", i(1))],
+        };
+        options_eq!(dynamic, object, b"\x0a\x0b\x01\x03a\x04\x01\x01");
+        let typed = Value::Object {
+            class_name: Some("T".to_string()),
+            sealed_count: 1,
+            entries: vec![pair("a", i(1))],
+        };
+        options_eq!(dynamic, typed, b"\x0a\x13\x03T\x03a\x04\x01");
+    }
+    #[test]
+    fn encodes_exactly() {
+        macro_rules! exact_eq {
+            ($input:expr) => {{
+                let input = &$input[..];
+                let exact = Decoder::new(input).decode_exact().unwrap();
+                let mut encoder = Encoder::new(Vec::new());
+                encoder.encode_exact(&exact.value, &exact.wire).unwrap();
+                assert_eq!(encoder.into_inner(), input);
+            }};
+        }
+        exact_eq!(include_bytes!("../testdata/amf3-string-ref.bin"));
+        exact_eq!(include_bytes!("../testdata/amf3-object-ref.bin"));
+        exact_eq!(include_bytes!("../testdata/amf3-trait-ref.bin"));
+        exact_eq!(include_bytes!("../testdata/amf3-empty-array-ref.bin"));
+        exact_eq!(include_bytes!("../testdata/amf3-mixed-array.bin"));
+        exact_eq!(include_bytes!(
+            "../testdata/amf3-complex-array-collection.bin"
+        ));
+
+        // Non-minimal U29s
+        exact_eq!(b"\x04\x80\x01");
+        exact_eq!(b"\x04\x80\x80\x80\x01");
+        exact_eq!(b"\x06\x80\x07foo");
+
+        // A string written in full twice
+        exact_eq!(b"\x09\x05\x01\x06\x07foo\x06\x07foo");
+
+        // An empty object of a dynamic trait
+        exact_eq!(b"\x0a\x0b\x01\x01");
+
+        // A reference to a string which is no longer equal to the referring one is not reproduced
+        let input = b"\x09\x05\x01\x06\x07foo\x06\x00";
+        let mut exact = Decoder::new(&input[..]).decode_exact().unwrap();
+        assert_eq!(exact.value, dense_array(&[s("foo"), s("foo")]));
+        exact.value = dense_array(&[s("bar"), s("foo")]);
+        let mut encoder = Encoder::new(Vec::new());
+        encoder.encode_exact(&exact.value, &exact.wire).unwrap();
+        assert_eq!(encoder.into_inner(), b"\x09\x05\x01\x06\x07bar\x06\x07foo");
+    }
+    #[test]
+    fn encodes_canonically() {
+        macro_rules! canonical_eq {
+            ($value:expr, $expected:expr) => {{
+                let mut encoder = Encoder::new(Vec::new());
+                encoder.encode_canonical(&$value).unwrap();
+                assert_eq!(encoder.into_inner(), &$expected[..]);
+            }};
+        }
+        canonical_eq!(i(1), b"\x04\x01");
+        canonical_eq!(Value::Double(1.0), b"\x04\x01");
+        canonical_eq!(Value::Double(-0.0), b"\x04\x00");
+        canonical_eq!(i(1 << 28), b"\x05\x41\xb0\x00\x00\x00\x00\x00\x00");
+
+        // Anonymous objects have only dynamic members in the order of their keys
+        let expected = b"\x0a\x0b\x01\x03a\x04\x01\x03b\x01\x01";
+        canonical_eq!(obj(&[("a", i(1)), ("b", Value::Null)]), expected);
+        let value = Value::Object {
+            class_name: None,
+            sealed_count: 2,
+            entries: vec![pair("b", Value::Null), pair("a", Value::Double(1.0))],
+        };
+        canonical_eq!(value, expected);
+
+        // The sealed members of typed objects keep their order
+        let value = Value::Object {
+            class_name: Some("t".to_string()),
+            sealed_count: 1,
+            entries: vec![pair("b", Value::Null), pair("d", i(1)), pair("c", i(2))],
+        };
+        canonical_eq!(
+            value,
+            b"\x0a\x1b\x03t\x03b\x01\x03c\x04\x02\x03d\x04\x01\x01"
+        );
+
+        canonical_eq!(
+            dic(&[(s("b"), i(1)), (s("a"), i(2))]),
+            b"\x11\x05\x00\x06\x03a\x04\x02\x06\x03b\x04\x01"
+        );
+
+        // The options and object references are not applied
+        let options = EncoderOptions {
+            anonymous_objects: ObjectPolicy::Sealed,
+            ..EncoderOptions::default()
+        };
+        let mut encoder = Encoder::with_options(Vec::new(), options);
+        encoder.set_object_references(true);
+        let value = dense_array(&[obj(&[("a", i(1))]), obj(&[("a", i(1))])]);
+        encoder.encode_canonical(&value).unwrap();
+        assert_eq!(
+            encoder.into_inner(),
+            b"\x09\x05\x01\x0a\x0b\x01\x03a\x04\x01\x01\x0a\x01\x00\x04\x01\x01"
+        );
+    }
+    #[test]
+    fn computes_encoded_len() {
+        let files: [&[u8]; 6] = [
+            include_bytes!("../testdata/amf3-string-ref.bin"),
+            include_bytes!("../testdata/amf3-trait-ref.bin"),
+            include_bytes!("../testdata/amf3-integer-3byte.bin"),
+            include_bytes!("../testdata/amf3-dictionary.bin"),
+            include_bytes!("../testdata/amf3-vector-object.bin"),
+            include_bytes!("../testdata/amf3-byte-array.bin"),
+        ];
+        for input in files {
+            let value = Value::read_from(input).unwrap();
+            let mut buf = Vec::new();
+            value.write_to(&mut buf).unwrap();
+            assert_eq!(value.encoded_len(), Ok(buf.len()));
+        }
+
+        // The length depends on the reference tables, which are not updated
+        let mut encoder = Encoder::new(Vec::new());
+        assert_eq!(encoder.encoded_len(&s("foo")), Ok(5));
+        assert_eq!(encoder.encoded_len(&s("foo")), Ok(5));
+        encoder.encode(&s("foo")).unwrap();
+        assert_eq!(encoder.encoded_len(&s("foo")), Ok(2));
+        assert_eq!(encoder.into_inner().len(), 5);
+
+        // The tables are restored even if the value cannot be encoded
+        let mut encoder = Encoder::new(Vec::new());
+        encoder.set_object_references(true);
+        let object = obj(&[("bar", s("baz"))]);
+        let len = encoder.encoded_len(&object).unwrap();
+        let invalid = dense_array(&[object.clone(), i(1 << 28)]);
+        assert!(encoder.encoded_len(&invalid).is_err());
+        assert_eq!(encoder.encoded_len(&object), Ok(len));
+
+        assert_eq!(
+            i(1 << 28).encoded_len(),
+            Err(EncodeError::IntegerOutOfRange { value: 1 << 28 })
+        );
+    }
+    #[test]
+    fn encode_errors() {
+        assert_eq!(
+            i(1 << 28).write_to(&mut Vec::new()),
+            Err(EncodeError::IntegerOutOfRange { value: 1 << 28 })
+        );
+        assert_eq!(
+            i(-(1 << 28) - 1).write_to(&mut Vec::new()),
+            Err(EncodeError::IntegerOutOfRange {
+                value: -(1 << 28) - 1
+            })
+        );
+
+        let value = Value::Object {
+            class_name: None,
+            sealed_count: 2,
+            entries: vec![pair("foo", i(1))],
+        };
+        assert_eq!(
+            value.write_to(&mut Vec::new()),
+            Err(EncodeError::InvalidSealedCount {
+                sealed_count: 2,
+                len: 1
+            })
+        );
+
+        let value = Value::Date {
+            unix_time: time::Duration::from_secs(u64::MAX / 1000 + 1),
+        };
+        assert_eq!(
+            value.write_to(&mut Vec::new()),
+            Err(EncodeError::IntegerOutOfRange { value: i64::MAX })
+        );
+        let value = Value::Date {
+            unix_time: time::Duration::new(u64::MAX / 1000, 999_000_000),
+        };
+        assert_eq!(
+            value.write_to(&mut Vec::new()),
+            Err(EncodeError::IntegerOutOfRange { value: i64::MAX })
+        );
     }
 
     fn i(i: i32) -> Value {
@@ -519,4 +1534,30 @@ mod tests {
             entries: entries.iter().map(|e| pair(e.0, e.1.clone())).collect(),
         }
     }
+    #[cfg(feature = "bytes")]
+    #[test]
+    fn encodes_into_buf_mut() {
+        use crate::io::{BytesReader, BytesWriter, ErrorKind};
+        use bytes::{Buf, BytesMut};
+
+        let value = Value::ByteArray(vec![1, 2, 3]);
+        let mut buf = BytesMut::new();
+        value.write_to(BytesWriter::new(&mut buf)).unwrap();
+        assert_eq!(&buf[..], b"\x0c\x07\x01\x02\x03");
+
+        // The value split into two buffers
+        let frame = buf.freeze();
+        let (head, tail) = frame.split_at(2);
+        let mut input = head.chain(tail);
+        let mut decoder = Decoder::new(BytesReader::new(&mut input));
+        assert_eq!(decoder.decode().unwrap(), value);
+        assert!(!input.has_remaining());
+
+        // A buffer without enough capacity
+        let mut buf = [0; 4];
+        match value.write_to(BytesWriter::new(&mut buf[..])) {
+            Err(EncodeError::Io(e)) => assert_eq!(e.kind(), ErrorKind::WriteZero),
+            other => panic!("{:?}", other),
+        }
+    }
 }
@@ -0,0 +1,215 @@
+//! Support for the classes which implement `flash.utils.IExternalizable`.
+//!
+//! The body of an externalizable object is written by the class itself,
+//! so it can only be read and written by a handler which knows the format of the class.
+//!
+//! The handlers of the following Flex classes are registered by default:
+//!
+//! - `flex.messaging.io.ArrayCollection`, `mx.collections.ArrayCollection`,
+//!   `flex.messaging.io.ArrayList` and `mx.collections.ArrayList` ([`ProxyHandler`])
+//! - `flex.messaging.io.ObjectProxy` and `mx.utils.ObjectProxy` ([`ProxyHandler`])
+//! - `flex.messaging.io.ManagedObjectProxy` and `mx.data.ManagedObjectProxy` ([`ManagedObjectProxyHandler`])
+//!
+//! # Examples
+//! ```
+//! use amf::amf3::graph::{self, Graph};
+//! use amf::amf3::{DataInput, DataOutput, Decoder, ExternalizableHandler, Value};
+//! use amf::{DecodeResult, EncodeResult};
+//! use std::io::{self, Read, Write};
+//!
+//! // A class whose body is a single byte
+//! #[derive(Debug)]
+//! struct Flag;
+//! impl ExternalizableHandler for Flag {
+//!     fn read_external(&self, input: &mut dyn DataInput) -> DecodeResult<graph::Value> {
+//!         let mut buf = [0; 1];
+//!         input.read_exact(&mut buf)?;
+//!         Ok(graph::Value::Boolean(buf[0] != 0))
+//!     }
+//!     fn write_external(&self, output: &mut dyn DataOutput, body: &graph::Value) -> EncodeResult<()> {
+//!         output.write_all(&[(*body == graph::Value::Boolean(true)) as u8])?;
+//!         Ok(())
+//!     }
+//! }
+//!
+//! let input = b"\x0a\x07\x09Flag\x01";
+//! let mut decoder = Decoder::new(&input[..]);
+//! decoder.externalizables_mut().register("Flag", Flag);
+//! assert_eq!(
+//!     decoder.decode().unwrap(),
+//!     Value::Externalizable {
+//!         class_name: "Flag".to_string(),
+//!         body: Box::new(Value::Boolean(true)),
+//!     }
+//! );
+//! ```
+use super::graph::{self, Graph, Node};
+use crate::error::EncodeError;
+use crate::io::{self, ReadBytesExt, WriteBytesExt};
+use crate::{DecodeResult, EncodeResult, Map};
+use alloc::string::String;
+use alloc::string::ToString;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use byteorder::BigEndian;
+use core::fmt;
+#[cfg(feature = "std")]
+use std::sync::OnceLock;
+
+/// Reader of the body of an externalizable object.
+///
+/// This corresponds to `flash.utils.IDataInput`.
+/// Raw bytes are read through `io::Read`.
+pub trait DataInput: io::Read {
+    /// Reads an AMF3 value (i.e., `IDataInput.readObject`).
+    ///
+    /// The reference tables of the underlying decoder are shared with the enclosing message.
+    fn read_value(&mut self) -> DecodeResult<graph::Value>;
+
+    /// Returns the graph which holds the complex values read so far.
+    fn graph_mut(&mut self) -> &mut Graph;
+}
+
+/// Writer of the body of an externalizable object.
+///
+/// This corresponds to `flash.utils.IDataOutput`.
+/// Raw bytes are written through `io::Write`.
+pub trait DataOutput: io::Write {
+    /// Writes an AMF3 value (i.e., `IDataOutput.writeObject`).
+    ///
+    /// The reference tables of the underlying encoder are shared with the enclosing message.
+    fn write_value(&mut self, value: &graph::Value) -> EncodeResult<()>;
+
+    /// Returns the graph which holds the complex values being written.
+    fn graph(&self) -> &Graph;
+}
+
+/// Handler of the body of an externalizable class.
+///
+/// This corresponds to the `readExternal` and `writeExternal` methods of `flash.utils.IExternalizable`.
+pub trait ExternalizableHandler: fmt::Debug + Send + Sync {
+    /// Reads the body of an object of the class.
+    fn read_external(&self, input: &mut dyn DataInput) -> DecodeResult<graph::Value>;
+
+    /// Writes `body` as the body of an object of the class.
+    fn write_external(&self, output: &mut dyn DataOutput, body: &graph::Value) -> EncodeResult<()>;
+}
+
+/// Handler of the classes which wrap a single value (e.g., `ArrayCollection` and `ObjectProxy`).
+///
+/// The body is the wrapped value (i.e., the source array or the proxied object).
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ProxyHandler;
+impl ExternalizableHandler for ProxyHandler {
+    fn read_external(&self, input: &mut dyn DataInput) -> DecodeResult<graph::Value> {
+        input.read_value()
+    }
+    fn write_external(&self, output: &mut dyn DataOutput, body: &graph::Value) -> EncodeResult<()> {
+        output.write_value(body)
+    }
+}
+
+/// Handler of `ManagedObjectProxy`.
+///
+/// The body is the proxied object.
+/// The property names preceding the object are discarded when decoding,
+/// and taken from the members of the object when encoding.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ManagedObjectProxyHandler;
+impl ExternalizableHandler for ManagedObjectProxyHandler {
+    fn read_external(&self, input: &mut dyn DataInput) -> DecodeResult<graph::Value> {
+        let count = input.read_i32::<BigEndian>()?;
+        for _ in 0..count {
+            input.read_value()?;
+        }
+        input.read_value()
+    }
+    fn write_external(&self, output: &mut dyn DataOutput, body: &graph::Value) -> EncodeResult<()> {
+        let names = match *body {
+            graph::Value::Node(id) => match *output.graph().node(id) {
+                Node::Object { ref entries, .. } => entries.iter().map(|e| e.key.clone()).collect(),
+                _ => Vec::new(),
+            },
+            _ => Vec::new(),
+        };
+        if names.len() > i32::MAX as usize {
+            return Err(EncodeError::CollectionTooLarge { len: names.len() });
+        }
+        output.write_i32::<BigEndian>(names.len() as i32)?;
+        for name in names {
+            output.write_value(&graph::Value::String(name))?;
+        }
+        output.write_value(body)
+    }
+}
+
+/// Registry of the handlers of externalizable classes.
+///
+/// Cloning a registry is cheap.
+#[derive(Debug, Clone)]
+pub struct ExternalizableRegistry {
+    handlers: Arc<Map<String, Arc<dyn ExternalizableHandler>>>,
+}
+impl ExternalizableRegistry {
+    /// Makes a new registry which has the handlers of the built-in Flex classes.
+    pub fn new() -> Self {
+        // Without `std`, the built-in handlers are registered to each registry
+        #[cfg(feature = "std")]
+        {
+            static BUILT_IN: OnceLock<ExternalizableRegistry> = OnceLock::new();
+            BUILT_IN.get_or_init(Self::built_in).clone()
+        }
+        #[cfg(not(feature = "std"))]
+        {
+            Self::built_in()
+        }
+    }
+
+    /// Makes a new empty registry.
+    pub fn empty() -> Self {
+        ExternalizableRegistry {
+            handlers: Arc::new(Map::new()),
+        }
+    }
+
+    fn built_in() -> Self {
+        let mut this = Self::empty();
+        for class_name in &[
+            "flex.messaging.io.ArrayCollection",
+            "mx.collections.ArrayCollection",
+            "flex.messaging.io.ArrayList",
+            "mx.collections.ArrayList",
+            "flex.messaging.io.ObjectProxy",
+            "mx.utils.ObjectProxy",
+        ] {
+            this.register(class_name, ProxyHandler);
+        }
+        for class_name in &[
+            "flex.messaging.io.ManagedObjectProxy",
+            "mx.data.ManagedObjectProxy",
+        ] {
+            this.register(class_name, ManagedObjectProxyHandler);
+        }
+        this
+    }
+
+    /// Registers `handler` for the class named `class_name`.
+    ///
+    /// If a handler has already been registered for the class, it is replaced.
+    pub fn register<H>(&mut self, class_name: &str, handler: H)
+    where
+        H: ExternalizableHandler + 'static,
+    {
+        Arc::make_mut(&mut self.handlers).insert(class_name.to_string(), Arc::new(handler));
+    }
+
+    /// Returns the handler registered for the class named `class_name`.
+    pub fn get(&self, class_name: &str) -> Option<Arc<dyn ExternalizableHandler>> {
+        self.handlers.get(class_name).cloned()
+    }
+}
+impl Default for ExternalizableRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,593 @@
+//! Object graph representation of AMF3 values.
+//!
+//! Unlike the tree [`Value`](super::Value), complex values (e.g., objects and arrays)
+//! are stored in a [`Graph`] and addressed by [`NodeId`]s,
+//! so shared and circular references are preserved.
+//!
+//! # Examples
+//! ```
+//! use amf::amf3::{self, graph};
+//!
+//! // Builds an array which contains itself
+//! let mut g = graph::Graph::new();
+//! let id = g.add(graph::Node::Array {
+//!     assoc_entries: Vec::new(),
+//!     dense_entries: Vec::new(),
+//! });
+//! if let graph::Node::Array { dense_entries, .. } = g.node_mut(id) {
+//!     dense_entries.push(graph::Value::Node(id));
+//! }
+//!
+//! // Encodes and decodes the graph
+//! let mut buf = Vec::new();
+//! amf3::Encoder::new(&mut buf).encode_graph(&g, &graph::Value::Node(id)).unwrap();
+//! let mut decoder = amf3::Decoder::new(&buf[..]);
+//! let root = decoder.decode_graph().unwrap();
+//! assert_eq!(decoder.graph().node(id), g.node(id));
+//! assert_eq!(root, graph::Value::Node(id));
+//! ```
+use super::shared;
+use super::Value as TreeValue;
+use crate::error::DecodeError;
+use crate::limits::Budget;
+use crate::{DecodeResult, Pair};
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::mem;
+use core::time;
+
+/// Identifier of a node in a [`Graph`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct NodeId(usize);
+impl NodeId {
+    /// Returns the index of the node in the graph.
+    ///
+    /// For a graph decoded by [`Decoder`](super::Decoder),
+    /// this is equal to the index in the object reference table
+    /// unless the node was added by an [`ExternalizableHandler`](super::ExternalizableHandler).
+    pub fn index(self) -> usize {
+        self.0
+    }
+}
+
+/// AMF3 value in an object graph.
+///
+/// Complex values are represented as references to the nodes of a [`Graph`].
+#[derive(Debug, Clone, PartialEq, PartialOrd)]
+pub enum Value {
+    /// See [`Value::Undefined`](super::Value::Undefined).
+    Undefined,
+
+    /// See [`Value::Null`](super::Value::Null).
+    Null,
+
+    /// See [`Value::Boolean`](super::Value::Boolean).
+    Boolean(bool),
+
+    /// See [`Value::Integer`](super::Value::Integer).
+    Integer(i32),
+
+    /// See [`Value::Double`](super::Value::Double).
+    Double(f64),
+
+    /// See [`Value::String`](super::Value::String).
+    String(String),
+
+    /// A complex value stored in a graph.
+    Node(NodeId),
+}
+
+/// Complex AMF3 value in an object graph.
+#[derive(Debug, Clone, PartialEq, PartialOrd)]
+pub enum Node {
+    /// See [`Value::XmlDocument`](super::Value::XmlDocument).
+    XmlDocument(String),
+
+    /// See [`Value::Date`](super::Value::Date).
+    Date {
+        /// Unix timestamp with milliseconds precision.
+        unix_time: time::Duration,
+    },
+
+    /// See [`Value::Array`](super::Value::Array).
+    Array {
+        /// Entries of the associative part of the array.
+        assoc_entries: Vec<Pair<String, Value>>,
+
+        /// Entries of the dense part of the array.
+        dense_entries: Vec<Value>,
+    },
+
+    /// See [`Value::Object`](super::Value::Object).
+    Object {
+        /// The class name of the object.
+        /// `None` means it is an anonymous object.
+        class_name: Option<String>,
+
+        /// Sealed member count of the object.
+        ///
+        /// Sealed members are located in front of the `entries`.
+        sealed_count: usize,
+
+        /// Members of the object.
+        entries: Vec<Pair<String, Value>>,
+    },
+
+    /// See [`Value::Xml`](super::Value::Xml).
+    Xml(String),
+
+    /// See [`Value::ByteArray`](super::Value::ByteArray).
+    ByteArray(Vec<u8>),
+
+    /// See [`Value::IntVector`](super::Value::IntVector).
+    IntVector {
+        /// If `true`, this is a fixed-length vector.
+        is_fixed: bool,
+
+        /// The entries of the vector.
+        entries: Vec<i32>,
+    },
+
+    /// See [`Value::UintVector`](super::Value::UintVector).
+    UintVector {
+        /// If `true`, this is a fixed-length vector.
+        is_fixed: bool,
+
+        /// The entries of the vector.
+        entries: Vec<u32>,
+    },
+
+    /// See [`Value::DoubleVector`](super::Value::DoubleVector).
+    DoubleVector {
+        /// If `true`, this is a fixed-length vector.
+        is_fixed: bool,
+
+        /// The entries of the vector.
+        entries: Vec<f64>,
+    },
+
+    /// See [`Value::ObjectVector`](super::Value::ObjectVector).
+    ObjectVector {
+        /// The base type name of entries in the vector.
+        /// `None` means it is the ANY type.
+        class_name: Option<String>,
+
+        /// If `true`, this is a fixed-length vector.
+        is_fixed: bool,
+
+        /// The entries of the vector.
+        entries: Vec<Value>,
+    },
+
+    /// See [`Value::Dictionary`](super::Value::Dictionary).
+    Dictionary {
+        /// If `true`, the keys of `entries` are weakly referenced.
+        is_weak: bool,
+
+        /// The entries of the dictionary.
+        entries: Vec<Pair<Value, Value>>,
+    },
+
+    /// See [`Value::Externalizable`](super::Value::Externalizable).
+    Externalizable {
+        /// The class name of the object.
+        class_name: String,
+
+        /// The body of the object.
+        body: Value,
+    },
+}
+
+/// An arena of the complex values in an object graph.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Graph {
+    // `None` denotes a node reserved for a container which is being decoded
+    nodes: Vec<Option<Node>>,
+}
+impl Graph {
+    /// Makes a new empty graph.
+    pub fn new() -> Self {
+        Graph::default()
+    }
+
+    /// Adds `node` to this graph, returning its identifier.
+    pub fn add(&mut self, node: Node) -> NodeId {
+        self.nodes.push(Some(node));
+        NodeId(self.nodes.len() - 1)
+    }
+
+    /// Returns a reference to the node identified by `id`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `id` does not belong to this graph,
+    /// or refers to a container which a decoder is still decoding.
+    pub fn node(&self, id: NodeId) -> &Node {
+        self.nodes[id.0]
+            .as_ref()
+            .expect("the node is being decoded")
+    }
+
+    /// Returns a mutable reference to the node identified by `id`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `id` does not belong to this graph,
+    /// or refers to a container which a decoder is still decoding.
+    pub fn node_mut(&mut self, id: NodeId) -> &mut Node {
+        self.nodes[id.0]
+            .as_mut()
+            .expect("the node is being decoded")
+    }
+
+    /// Returns the number of the nodes in this graph.
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Returns `true` if this graph has no nodes.
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// Removes all the nodes of this graph.
+    pub fn clear(&mut self) {
+        self.nodes.clear();
+    }
+
+    /// Adds the complex values contained in the tree `value` to this graph.
+    ///
+    /// Each complex value becomes a distinct node.
+    pub fn add_tree(&mut self, value: &TreeValue) -> Value {
+        let node = match *value {
+            TreeValue::Undefined => return Value::Undefined,
+            TreeValue::Null => return Value::Null,
+            TreeValue::Boolean(x) => return Value::Boolean(x),
+            TreeValue::Integer(x) => return Value::Integer(x),
+            TreeValue::Double(x) => return Value::Double(x),
+            TreeValue::String(ref x) => return Value::String(x.clone()),
+            TreeValue::XmlDocument(ref x) => Node::XmlDocument(x.clone()),
+            TreeValue::Date { unix_time } => Node::Date { unix_time },
+            TreeValue::Array {
+                ref assoc_entries,
+                ref dense_entries,
+            } => Node::Array {
+                assoc_entries: self.add_tree_pairs(assoc_entries),
+                dense_entries: dense_entries.iter().map(|v| self.add_tree(v)).collect(),
+            },
+            TreeValue::Object {
+                ref class_name,
+                sealed_count,
+                ref entries,
+            } => Node::Object {
+                class_name: class_name.clone(),
+                sealed_count,
+                entries: self.add_tree_pairs(entries),
+            },
+            TreeValue::Xml(ref x) => Node::Xml(x.clone()),
+            TreeValue::ByteArray(ref x) => Node::ByteArray(x.clone()),
+            TreeValue::IntVector {
+                is_fixed,
+                ref entries,
+            } => Node::IntVector {
+                is_fixed,
+                entries: entries.clone(),
+            },
+            TreeValue::UintVector {
+                is_fixed,
+                ref entries,
+            } => Node::UintVector {
+                is_fixed,
+                entries: entries.clone(),
+            },
+            TreeValue::DoubleVector {
+                is_fixed,
+                ref entries,
+            } => Node::DoubleVector {
+                is_fixed,
+                entries: entries.clone(),
+            },
+            TreeValue::ObjectVector {
+                ref class_name,
+                is_fixed,
+                ref entries,
+            } => Node::ObjectVector {
+                class_name: class_name.clone(),
+                is_fixed,
+                entries: entries.iter().map(|v| self.add_tree(v)).collect(),
+            },
+            TreeValue::Dictionary {
+                is_weak,
+                ref entries,
+            } => Node::Dictionary {
+                is_weak,
+                entries: entries
+                    .iter()
+                    .map(|e| Pair {
+                        key: self.add_tree(&e.key),
+                        value: self.add_tree(&e.value),
+                    })
+                    .collect(),
+            },
+            TreeValue::Externalizable {
+                ref class_name,
+                ref body,
+            } => Node::Externalizable {
+                class_name: class_name.clone(),
+                body: self.add_tree(body),
+            },
+        };
+        Value::Node(self.add(node))
+    }
+
+    /// Converts `value` to the tree representation.
+    ///
+    /// Shared nodes are copied for each occurrence,
+    /// and the copies are limited by `DecoderLimits::default().max_reference_expansions`
+    /// and `DecoderLimits::default().max_allocation`.
+    /// If `value` contains circular references, `DecodeError::CircularReference` will be returned,
+    /// and if the copies exceed the limits, `DecodeError::LimitExceeded` will be returned.
+    pub fn to_tree(&self, value: &Value) -> DecodeResult<TreeValue> {
+        self.to_tree_with(value, &mut Vec::new(), &mut Budget::default())
+    }
+
+    /// Converts `value` to the reference-counted representation.
+    ///
+    /// Each node is converted once and shared by all of its occurrences.
+    /// If `value` contains circular references, `DecodeError::CircularReference` will be returned.
+    pub fn to_shared(&self, value: &Value) -> DecodeResult<shared::Value> {
+        shared::Nodes::default().share(self, value)
+    }
+
+    // Converts `value` to the tree representation,
+    // counting the copies of the nodes which have been `copied` before as reference expansions
+    pub(crate) fn to_tree_with(
+        &self,
+        value: &Value,
+        copied: &mut Vec<bool>,
+        budget: &mut Budget,
+    ) -> DecodeResult<TreeValue> {
+        let mut state = TreeState {
+            path: Vec::new(),
+            copied,
+            budget,
+        };
+        self.to_tree_value(value, &mut state)
+    }
+
+    // Reserves a node for a container, so that its entries can refer to it before it is decoded
+    pub(crate) fn reserve(&mut self) -> NodeId {
+        self.nodes.push(None);
+        NodeId(self.nodes.len() - 1)
+    }
+
+    // Sets the node reserved by `reserve`
+    pub(crate) fn fill(&mut self, id: NodeId, node: Node) {
+        debug_assert!(self.nodes[id.0].is_none(), "the node is not reserved");
+        self.nodes[id.0] = Some(node);
+    }
+
+    // Returns the node identified by `id`,
+    // which is circularly referred if the node is reserved but not decoded yet
+    pub(crate) fn decoded_node(&self, id: NodeId) -> DecodeResult<&Node> {
+        self.nodes[id.0]
+            .as_ref()
+            .ok_or(DecodeError::CircularReference { index: id.0 })
+    }
+
+    // Removes the nodes added since `id` was reserved
+    pub(crate) fn truncate(&mut self, id: NodeId) {
+        self.nodes.truncate(id.0);
+    }
+
+    fn add_tree_pairs(&mut self, pairs: &[Pair<String, TreeValue>]) -> Vec<Pair<String, Value>> {
+        pairs
+            .iter()
+            .map(|p| Pair {
+                key: p.key.clone(),
+                value: self.add_tree(&p.value),
+            })
+            .collect()
+    }
+    fn to_tree_value(&self, value: &Value, state: &mut TreeState) -> DecodeResult<TreeValue> {
+        let id = match *value {
+            Value::Undefined => return Ok(TreeValue::Undefined),
+            Value::Null => return Ok(TreeValue::Null),
+            Value::Boolean(x) => return Ok(TreeValue::Boolean(x)),
+            Value::Integer(x) => return Ok(TreeValue::Integer(x)),
+            Value::Double(x) => return Ok(TreeValue::Double(x)),
+            Value::String(ref x) => return Ok(TreeValue::String(x.clone())),
+            Value::Node(id) => id,
+        };
+        if state.path.contains(&id) {
+            return Err(DecodeError::CircularReference { index: id.0 });
+        }
+        state.path.push(id);
+        let node = self.decoded_node(id)?;
+        state.copy(id, node)?;
+        let value = match *node {
+            Node::XmlDocument(ref x) => TreeValue::XmlDocument(x.clone()),
+            Node::Date { unix_time } => TreeValue::Date { unix_time },
+            Node::Array {
+                ref assoc_entries,
+                ref dense_entries,
+            } => TreeValue::Array {
+                assoc_entries: self.to_tree_pairs(assoc_entries, state)?,
+                dense_entries: self.to_tree_values(dense_entries, state)?,
+            },
+            Node::Object {
+                ref class_name,
+                sealed_count,
+                ref entries,
+            } => TreeValue::Object {
+                class_name: class_name.clone(),
+                sealed_count,
+                entries: self.to_tree_pairs(entries, state)?,
+            },
+            Node::Xml(ref x) => TreeValue::Xml(x.clone()),
+            Node::ByteArray(ref x) => TreeValue::ByteArray(x.clone()),
+            Node::IntVector {
+                is_fixed,
+                ref entries,
+            } => TreeValue::IntVector {
+                is_fixed,
+                entries: entries.clone(),
+            },
+            Node::UintVector {
+                is_fixed,
+                ref entries,
+            } => TreeValue::UintVector {
+                is_fixed,
+                entries: entries.clone(),
+            },
+            Node::DoubleVector {
+                is_fixed,
+                ref entries,
+            } => TreeValue::DoubleVector {
+                is_fixed,
+                entries: entries.clone(),
+            },
+            Node::ObjectVector {
+                ref class_name,
+                is_fixed,
+                ref entries,
+            } => TreeValue::ObjectVector {
+                class_name: class_name.clone(),
+                is_fixed,
+                entries: self.to_tree_values(entries, state)?,
+            },
+            Node::Dictionary {
+                is_weak,
+                ref entries,
+            } => TreeValue::Dictionary {
+                is_weak,
+                entries: entries
+                    .iter()
+                    .map(|e| {
+                        Ok(Pair {
+                            key: self.to_tree_value(&e.key, state)?,
+                            value: self.to_tree_value(&e.value, state)?,
+                        })
+                    })
+                    .collect::<DecodeResult<_>>()?,
+            },
+            Node::Externalizable {
+                ref class_name,
+                ref body,
+            } => TreeValue::Externalizable {
+                class_name: class_name.clone(),
+                body: Box::new(self.to_tree_value(body, state)?),
+            },
+        };
+        state.path.pop();
+        Ok(value)
+    }
+    fn to_tree_values(
+        &self,
+        values: &[Value],
+        state: &mut TreeState,
+    ) -> DecodeResult<Vec<TreeValue>> {
+        values
+            .iter()
+            .map(|v| self.to_tree_value(v, state))
+            .collect()
+    }
+    fn to_tree_pairs(
+        &self,
+        pairs: &[Pair<String, Value>],
+        state: &mut TreeState,
+    ) -> DecodeResult<Vec<Pair<String, TreeValue>>> {
+        pairs
+            .iter()
+            .map(|p| {
+                Ok(Pair {
+                    key: p.key.clone(),
+                    value: self.to_tree_value(&p.value, state)?,
+                })
+            })
+            .collect()
+    }
+}
+
+struct TreeState<'a> {
+    path: Vec<NodeId>,
+    copied: &'a mut Vec<bool>,
+    budget: &'a mut Budget,
+}
+impl<'a> TreeState<'a> {
+    fn copy(&mut self, id: NodeId, node: &Node) -> DecodeResult<()> {
+        if self.copied.len() <= id.0 {
+            self.copied.resize(id.0 + 1, false);
+        }
+        if mem::replace(&mut self.copied[id.0], true) {
+            let (entries, bytes) = copy_size(node);
+            self.budget.copy(1 + entries, bytes)?;
+        }
+        Ok(())
+    }
+}
+
+// Returns the number of the entries of `node` and the bytes allocated for copying it,
+// excluding the nodes which it refers to
+fn copy_size(node: &Node) -> (usize, usize) {
+    let values = |values: &[Value]| {
+        values.len() * mem::size_of::<TreeValue>() + values.iter().map(string_len).sum::<usize>()
+    };
+    let pairs = |pairs: &[Pair<String, Value>]| {
+        pairs.len() * mem::size_of::<Pair<String, TreeValue>>()
+            + pairs
+                .iter()
+                .map(|p| p.key.len() + string_len(&p.value))
+                .sum::<usize>()
+    };
+    match *node {
+        Node::XmlDocument(ref x) | Node::Xml(ref x) => (0, x.len()),
+        Node::Date { .. } => (0, 0),
+        Node::Array {
+            ref assoc_entries,
+            ref dense_entries,
+        } => (
+            assoc_entries.len() + dense_entries.len(),
+            pairs(assoc_entries) + values(dense_entries),
+        ),
+        Node::Object {
+            ref class_name,
+            ref entries,
+            ..
+        } => (
+            entries.len(),
+            class_name.as_ref().map_or(0, String::len) + pairs(entries),
+        ),
+        Node::ByteArray(ref x) => (0, x.len()),
+        Node::IntVector { ref entries, .. } => (entries.len(), mem::size_of_val(&entries[..])),
+        Node::UintVector { ref entries, .. } => (entries.len(), mem::size_of_val(&entries[..])),
+        Node::DoubleVector { ref entries, .. } => (entries.len(), mem::size_of_val(&entries[..])),
+        Node::ObjectVector {
+            ref class_name,
+            ref entries,
+            ..
+        } => (
+            entries.len(),
+            class_name.as_ref().map_or(0, String::len) + values(entries),
+        ),
+        Node::Dictionary { ref entries, .. } => (
+            entries.len() * 2,
+            entries.len() * mem::size_of::<Pair<TreeValue, TreeValue>>()
+                + entries
+                    .iter()
+                    .map(|p| string_len(&p.key) + string_len(&p.value))
+                    .sum::<usize>(),
+        ),
+        Node::Externalizable {
+            ref class_name,
+            ref body,
+        } => (0, class_name.len() + string_len(body)),
+    }
+}
+fn string_len(value: &Value) -> usize {
+    match *value {
+        Value::String(ref x) => x.len(),
+        _ => 0,
+    }
+}
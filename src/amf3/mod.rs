@@ -13,15 +13,36 @@
 //! let decoded = Value::read_from(&mut &buf[..]).unwrap();
 //! assert_eq!(integer, decoded);
 //! ```
-use crate::{DecodeResult, Pair};
-use std::io;
-use std::time;
-
-pub use self::decode::Decoder;
+use crate::io;
+use crate::util::{canonical_f64, ByteCounter};
+use crate::{DecodeResult, EncodeResult, Pair};
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::time;
+
+pub use self::decode::{Decoder, ExactValue};
 pub use self::encode::Encoder;
-
+pub use self::externalizable::{
+    DataInput, DataOutput, ExternalizableHandler, ExternalizableRegistry,
+    ManagedObjectProxyHandler, ProxyHandler,
+};
+pub use self::reader::{Event, Reader};
+pub use self::stream::StreamDecoder;
+pub use self::value_ref::{SliceDecoder, ValueRef};
+pub use self::writer::Writer;
+
+pub mod externalizable;
+pub mod graph;
+pub mod shared;
+
+pub(crate) mod build;
 mod decode;
 mod encode;
+pub(crate) mod reader;
+pub(crate) mod stream;
+pub(crate) mod value_ref;
+mod writer;
 
 mod marker {
     pub const UNDEFINED: u8 = 0x00;
@@ -44,6 +65,14 @@ mod marker {
     pub const DICTIONARY: u8 = 0x11;
 }
 
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+struct Trait {
+    class_name: Option<String>,
+    is_dynamic: bool,
+    is_externalizable: bool,
+    fields: Vec<String>,
+}
+
 /// AMF3 value.
 ///
 /// # Examples
@@ -186,13 +215,33 @@ pub enum Value {
         /// The entries of the dictionary.
         entries: Vec<Pair<Value, Value>>,
     },
+
+    /// An object of a class which implements `flash.utils.IExternalizable`.
+    ///
+    /// See [3.12 Object Type]
+    /// (https://www.adobe.com/content/dam/acom/en/devnet/pdf/amf-file-format-spec.pdf#page=9&zoom=auto,88,275).
+    Externalizable {
+        /// The class name of the object.
+        class_name: String,
+
+        /// The body of the object.
+        ///
+        /// This is the value read and written by the [`ExternalizableHandler`] registered for `class_name`.
+        /// If no handler is registered, decoding fails with `DecodeError::ExternalizableType`
+        /// because the length of the body is unknown,
+        /// and a `ByteArray` body is written as is.
+        body: Box<Value>,
+    },
 }
 impl Value {
     /// Reads an AMF3 encoded `Value` from `reader`.
     ///
     /// Note that reference objects are copied in the decoding phase
     /// for the sake of simplicity of the resulting value representation.
-    /// And circular reference are unsupported (i.e., those are treated as errors).
+    /// Circular references are rejected because the tree `Value` cannot represent those;
+    /// decode them by [`Decoder::decode_graph`] instead.
+    ///
+    /// The location of an error is not available; use `Decoder::error_location` to get it.
     pub fn read_from<R>(reader: R) -> DecodeResult<Self>
     where
         R: io::Read,
@@ -201,13 +250,126 @@ impl Value {
     }
 
     /// Writes the AMF3 encoded bytes of this value to `writer`.
-    pub fn write_to<W>(&self, writer: W) -> io::Result<()>
+    pub fn write_to<W>(&self, writer: W) -> EncodeResult<()>
     where
         W: io::Write,
     {
         Encoder::new(writer).encode(self)
     }
 
+    /// Returns the number of the bytes which `write_to` writes.
+    ///
+    /// This is computed by encoding the value without storing the encoded bytes,
+    /// so the result always matches `write_to`, including the errors.
+    ///
+    /// # Examples
+    /// ```
+    /// use amf::amf3::Value;
+    ///
+    /// let value = Value::String("foo".to_string());
+    /// let mut buf = Vec::new();
+    /// value.write_to(&mut buf).unwrap();
+    /// assert_eq!(value.encoded_len().unwrap(), buf.len());
+    /// ```
+    pub fn encoded_len(&self) -> EncodeResult<usize> {
+        let mut encoder = Encoder::new(ByteCounter::default());
+        encoder.encode(self)?;
+        Ok(encoder.into_inner().count)
+    }
+
+    /// Normalizes this value into the canonical form, which `Encoder::encode_canonical` writes.
+    ///
+    /// - doubles which have whole values representable in 29 bits become integers,
+    ///   and integers which are not representable in 29 bits become doubles,
+    /// - negative zeros become zeros, and NaNs become `f64::NAN`,
+    /// - all members of anonymous objects become dynamic members,
+    /// - dynamic members of objects and associative entries of arrays are sorted by their keys,
+    /// - entries of dictionaries are sorted by the encoded bytes of their keys.
+    ///
+    /// # Examples
+    /// ```
+    /// use amf::amf3::Value;
+    /// use amf::Pair;
+    ///
+    /// let mut value = Value::Object {
+    ///     class_name: None,
+    ///     sealed_count: 2,
+    ///     entries: vec![
+    ///         Pair { key: "b".to_string(), value: Value::Double(2.0) },
+    ///         Pair { key: "a".to_string(), value: Value::Integer(1) },
+    ///     ],
+    /// };
+    /// value.canonicalize();
+    /// assert_eq!(
+    ///     value,
+    ///     Value::Object {
+    ///         class_name: None,
+    ///         sealed_count: 0,
+    ///         entries: vec![
+    ///             Pair { key: "a".to_string(), value: Value::Integer(1) },
+    ///             Pair { key: "b".to_string(), value: Value::Integer(2) },
+    ///         ],
+    ///     }
+    /// );
+    /// ```
+    pub fn canonicalize(&mut self) {
+        match *self {
+            Value::Integer(x) if !(-(1 << 28)..(1 << 28)).contains(&x) => {
+                *self = Value::Double(x as f64);
+            }
+            Value::Double(x) => {
+                let x = canonical_f64(x);
+                *self = if x % 1.0 == 0.0 && (-(1 << 28) as f64..(1 << 28) as f64).contains(&x) {
+                    Value::Integer(x as i32)
+                } else {
+                    Value::Double(x)
+                };
+            }
+            Value::Array {
+                ref mut assoc_entries,
+                ref mut dense_entries,
+            } => {
+                canonicalize_pairs(assoc_entries);
+                dense_entries.iter_mut().for_each(Value::canonicalize);
+            }
+            Value::Object {
+                ref class_name,
+                ref mut sealed_count,
+                ref mut entries,
+            } => {
+                if class_name.is_none() {
+                    *sealed_count = 0;
+                }
+                let sealed = (*sealed_count).min(entries.len());
+                entries[..sealed]
+                    .iter_mut()
+                    .for_each(|e| e.value.canonicalize());
+                canonicalize_pairs(&mut entries[sealed..]);
+            }
+            Value::DoubleVector {
+                ref mut entries, ..
+            } => entries.iter_mut().for_each(|x| *x = canonical_f64(*x)),
+            Value::ObjectVector {
+                ref mut entries, ..
+            } => entries.iter_mut().for_each(Value::canonicalize),
+            Value::Dictionary {
+                ref mut entries, ..
+            } => {
+                for e in entries.iter_mut() {
+                    e.key.canonicalize();
+                    e.value.canonicalize();
+                }
+                // A key which cannot be encoded is sorted first, though it fails to be encoded anyway
+                entries.sort_by_cached_key(|e| {
+                    let mut key = Vec::new();
+                    e.key.write_to(&mut key).map(|_| key).unwrap_or_default()
+                });
+            }
+            Value::Externalizable { ref mut body, .. } => body.canonicalize(),
+            _ => {}
+        }
+    }
+
     /// Tries to convert the value as a `str` reference.
     pub fn try_as_str(&self) -> Option<&str> {
         match *self {
@@ -228,6 +390,8 @@ impl Value {
     }
 
     /// Tries to convert the value as an iterator of the contained values.
+    ///
+    /// The values of an `Externalizable` are those of its body.
     pub fn try_into_values(self) -> Result<Box<dyn Iterator<Item = Value>>, Self> {
         match self {
             Value::Array { dense_entries, .. } => Ok(Box::new(dense_entries.into_iter())),
@@ -241,11 +405,20 @@ impl Value {
                 Ok(Box::new(entries.into_iter().map(Value::Double)))
             }
             Value::ObjectVector { entries, .. } => Ok(Box::new(entries.into_iter())),
+            Value::Externalizable { class_name, body } => {
+                body.try_into_values()
+                    .map_err(|body| Value::Externalizable {
+                        class_name,
+                        body: Box::new(body),
+                    })
+            }
             _ => Err(self),
         }
     }
 
     /// Tries to convert the value as an iterator of the contained pairs.
+    ///
+    /// The pairs of an `Externalizable` are those of its body.
     pub fn try_into_pairs(self) -> Result<Box<dyn Iterator<Item = (String, Value)>>, Self> {
         match self {
             Value::Array { assoc_entries, .. } => Ok(Box::new(
@@ -254,7 +427,21 @@ impl Value {
             Value::Object { entries, .. } => {
                 Ok(Box::new(entries.into_iter().map(|p| (p.key, p.value))))
             }
+            Value::Externalizable { class_name, body } => {
+                body.try_into_pairs().map_err(|body| Value::Externalizable {
+                    class_name,
+                    body: Box::new(body),
+                })
+            }
             _ => Err(self),
         }
     }
 }
+
+// Sorts the pairs by their keys, canonicalizing the values
+fn canonicalize_pairs(pairs: &mut [Pair<String, Value>]) {
+    pairs.sort_by(|a, b| a.key.cmp(&b.key));
+    for p in pairs {
+        p.value.canonicalize();
+    }
+}
@@ -0,0 +1,900 @@
+use super::build::Slot;
+use super::decode::SizeOrIndex;
+use super::graph::Node;
+use super::marker;
+use super::{Decoder, ExternalizableRegistry, Value};
+use crate::error::{DecodeError, Location, PathSegment};
+use crate::io::{self, ReadBytesExt};
+use crate::limits::Budget;
+use crate::{DecodeResult, DecoderLimits};
+use alloc::rc::Rc;
+use alloc::string::String;
+use alloc::vec::Vec;
+use byteorder::BigEndian;
+use core::mem;
+use core::time;
+
+const BYTES_CHUNK_SIZE: usize = 8192;
+
+/// Event of `Reader`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    /// `undefined`.
+    Undefined,
+
+    /// `null`.
+    Null,
+
+    /// Boolean.
+    Boolean(bool),
+
+    /// Integer.
+    Integer(i32),
+
+    /// Double.
+    Double(f64),
+
+    /// String.
+    String(String),
+
+    /// XML document.
+    XmlDocument(String),
+
+    /// Date.
+    Date {
+        /// Unix timestamp with milliseconds precision.
+        unix_time: time::Duration,
+    },
+
+    /// Start of an array.
+    ///
+    /// The `Key` and value events of the associative part are followed by
+    /// the `dense_len` value events of the dense part, then `End`.
+    StartArray {
+        /// The number of the entries of the dense part.
+        dense_len: usize,
+    },
+
+    /// Start of an object.
+    ///
+    /// The `Key` and value events of the members are followed by `End`.
+    /// The sealed members come first.
+    StartObject {
+        /// The class name of the object.
+        class_name: Option<String>,
+
+        /// The number of the sealed members.
+        sealed_count: usize,
+    },
+
+    /// Key of a member of an object or an associative entry of an array.
+    ///
+    /// The events of the value follow.
+    Key(String),
+
+    /// XML.
+    Xml(String),
+
+    /// Start of a byte array.
+    ///
+    /// The contents are split into `Bytes` events, which are followed by `End`.
+    StartByteArray {
+        /// The number of the bytes.
+        len: usize,
+    },
+
+    /// Chunk of the contents of a byte array.
+    Bytes(Vec<u8>),
+
+    /// `Vector.<int>`.
+    IntVector {
+        /// If `true`, this is a fixed-length vector.
+        is_fixed: bool,
+
+        /// The entries of the vector.
+        entries: Vec<i32>,
+    },
+
+    /// `Vector.<uint>`.
+    UintVector {
+        /// If `true`, this is a fixed-length vector.
+        is_fixed: bool,
+
+        /// The entries of the vector.
+        entries: Vec<u32>,
+    },
+
+    /// `Vector.<Number>`.
+    DoubleVector {
+        /// If `true`, this is a fixed-length vector.
+        is_fixed: bool,
+
+        /// The entries of the vector.
+        entries: Vec<f64>,
+    },
+
+    /// Start of a `Vector.<Object>`.
+    ///
+    /// The `len` value events of the entries are followed by `End`.
+    StartObjectVector {
+        /// The base type name of the entries of the vector.
+        class_name: Option<String>,
+
+        /// If `true`, this is a fixed-length vector.
+        is_fixed: bool,
+
+        /// The number of the entries.
+        len: usize,
+    },
+
+    /// Start of a dictionary.
+    ///
+    /// The value events of the `len` key and value pairs are followed by `End`.
+    StartDictionary {
+        /// If `true`, the keys are weakly referenced.
+        is_weak: bool,
+
+        /// The number of the entries.
+        len: usize,
+    },
+
+    /// Externalizable object.
+    ///
+    /// The body is read as a whole by the registered handler.
+    Externalizable {
+        /// The class name of the object.
+        class_name: String,
+
+        /// The body of the object.
+        body: Value,
+    },
+
+    /// Reference to a previously read complex value.
+    ///
+    /// The complex values (i.e., all values except for `undefined`, `null`, booleans, integers,
+    /// doubles and strings) are indexed in the order of their first occurrence.
+    Reference {
+        /// The index of the referenced value.
+        index: usize,
+    },
+
+    /// End of an array, an object, a byte array, a vector or a dictionary.
+    End,
+}
+
+#[derive(Debug, Clone)]
+enum Frame {
+    Assoc {
+        dense_len: usize,
+        // The key of the entry being read
+        key: Option<String>,
+    },
+    Values {
+        remaining: usize,
+        len: usize,
+        // The number of the values per entry (2 for the keys and values of a dictionary)
+        width: usize,
+    },
+    Object {
+        // Shared so that a checkpoint can copy the frame cheaply
+        sealed: Rc<[String]>,
+        next: usize,
+        is_dynamic: bool,
+        // The key of the member being read
+        key: Option<String>,
+    },
+    Bytes {
+        remaining: usize,
+    },
+}
+
+// State of `Reader` before an event
+#[derive(Debug, Clone)]
+pub(crate) struct Checkpoint {
+    expects_value: bool,
+    stack_len: usize,
+    top: Option<Frame>,
+    strings: usize,
+    traits: usize,
+    complexes: usize,
+    budget: Budget,
+    offset: u64,
+}
+
+/// AMF3 pull parser.
+///
+/// Unlike `Decoder`, this reads values as a sequence of events without building the values in memory.
+/// The string and trait references are resolved as `Decoder` does,
+/// while the object references are reported as `Event::Reference`.
+///
+/// Note that the body of an externalizable object cannot refer to the values read as events.
+///
+/// # Examples
+/// ```
+/// use amf::amf3::{Event, Reader};
+///
+/// // [1, "foo"]
+/// let input = b"\x09\x05\x01\x04\x01\x06\x07foo";
+/// let events = Reader::new(&input[..]).collect::<Result<Vec<_>, _>>().unwrap();
+/// assert_eq!(
+///     events,
+///     [
+///         Event::StartArray { dense_len: 2 },
+///         Event::Integer(1),
+///         Event::String("foo".to_string()),
+///         Event::End,
+///     ]
+/// );
+/// ```
+#[derive(Debug)]
+pub struct Reader<R> {
+    decoder: Decoder<R>,
+    stack: Vec<Frame>,
+    expects_value: bool,
+}
+impl<R> Reader<R> {
+    /// Unwraps this `Reader`, returning the underlying reader.
+    pub fn into_inner(self) -> R {
+        self.decoder.into_inner()
+    }
+    /// Returns a mutable reference to the registry of the externalizable class handlers.
+    pub fn externalizables_mut(&mut self) -> &mut ExternalizableRegistry {
+        self.decoder.externalizables_mut()
+    }
+    /// Returns the location of the event which failed to read in the last call of `next_event`.
+    ///
+    /// The path leads to the value which the event belongs to.
+    /// This is `None` if the last call succeeded.
+    pub fn error_location(&self) -> Option<&Location> {
+        self.decoder.error_location()
+    }
+    pub(crate) fn with_inner<S>(self, inner: S) -> (Reader<S>, R) {
+        let (decoder, inner) = self.decoder.with_inner(inner);
+        let reader = Reader {
+            decoder,
+            stack: self.stack,
+            expects_value: self.expects_value,
+        };
+        (reader, inner)
+    }
+    pub(crate) fn budget_mut(&mut self) -> &mut Budget {
+        &mut self.decoder.budget
+    }
+    pub(crate) fn is_at_value_boundary(&self) -> bool {
+        self.stack.is_empty() && !self.expects_value
+    }
+    pub(crate) fn decoder_mut(&mut self) -> &mut Decoder<R> {
+        &mut self.decoder
+    }
+    pub(crate) fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            expects_value: self.expects_value,
+            stack_len: self.stack.len(),
+            top: self.stack.last().cloned(),
+            strings: self.decoder.strings.len(),
+            traits: self.decoder.traits.len(),
+            complexes: self.decoder.complexes.len(),
+            budget: self.decoder.budget,
+            offset: self.decoder.inner.offset,
+        }
+    }
+    // Restores the state saved before an event, except for the position of the underlying reader
+    pub(crate) fn rollback(&mut self, checkpoint: Checkpoint) {
+        // An event replaces the top frame at most, and may push a new frame onto it
+        self.stack.truncate(checkpoint.stack_len.saturating_sub(1));
+        self.stack.extend(checkpoint.top);
+        self.expects_value = checkpoint.expects_value;
+        self.decoder.strings.truncate(checkpoint.strings);
+        self.decoder.traits.truncate(checkpoint.traits);
+        self.decoder.complexes.truncate(checkpoint.complexes);
+        self.decoder.budget = checkpoint.budget;
+        self.decoder.inner.offset = checkpoint.offset;
+    }
+}
+impl<R> Reader<R>
+where
+    R: io::Read,
+{
+    /// Makes a new instance.
+    pub fn new(inner: R) -> Self {
+        Self::with_limits(inner, DecoderLimits::default())
+    }
+
+    /// Makes a new instance with the specified limits.
+    pub fn with_limits(inner: R, limits: DecoderLimits) -> Self {
+        Reader {
+            decoder: Decoder::with_limits(inner, limits),
+            stack: Vec::new(),
+            expects_value: false,
+        }
+    }
+
+    /// Reads the next event.
+    ///
+    /// If the underlying reader reaches EOF at a boundary of the top-level values, `None` will be returned.
+    pub fn next_event(&mut self) -> DecodeResult<Option<Event>> {
+        self.next_event_with(true)
+    }
+
+    // Reads the next event of an AMF3 value embedded in an AMF0 value,
+    // whose resources are counted as a part of the enclosing value.
+    pub(crate) fn next_embedded_event(&mut self) -> DecodeResult<Option<Event>> {
+        self.next_event_with(false)
+    }
+
+    /// Clear the reference tables of this reader.
+    pub fn clear_reference_table(&mut self) {
+        self.decoder.clear_reference_table();
+    }
+
+    fn next_event_with(&mut self, is_top_level: bool) -> DecodeResult<Option<Event>> {
+        let offset = self.decoder.inner.offset;
+        self.decoder.location = None;
+        let result = self.read_event(is_top_level);
+        if result.is_err() {
+            Location::record(&mut self.decoder.location, offset);
+            for segment in self.path().into_iter().rev() {
+                Location::prepend(&mut self.decoder.location, segment);
+            }
+        }
+        result
+    }
+    fn read_event(&mut self, is_top_level: bool) -> DecodeResult<Option<Event>> {
+        if mem::replace(&mut self.expects_value, false) {
+            return self.read_value().map(Some);
+        }
+        let frame = match self.stack.pop() {
+            None => {
+                let marker = match self.decoder.inner.read_u8() {
+                    Ok(marker) => marker,
+                    Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+                    Err(e) => return Err(e.into()),
+                };
+                if is_top_level {
+                    self.decoder.budget.start_value();
+                }
+                return self.read_value_with_marker(marker).map(Some);
+            }
+            Some(frame) => frame,
+        };
+        self.read_in(frame).map(Some)
+    }
+
+    fn read_in(&mut self, frame: Frame) -> DecodeResult<Event> {
+        match frame {
+            Frame::Assoc { dense_len, .. } => {
+                let key = self.decoder.decode_utf8()?;
+                if key.is_empty() {
+                    return self.read_in(Frame::Values {
+                        remaining: dense_len,
+                        len: dense_len,
+                        width: 1,
+                    });
+                }
+                self.stack.push(Frame::Assoc {
+                    dense_len,
+                    key: Some(key.clone()),
+                });
+                self.expects_value = true;
+                Ok(Event::Key(key))
+            }
+            Frame::Values {
+                remaining,
+                len,
+                width,
+            } => {
+                if remaining == 0 {
+                    self.decoder.budget.leave();
+                    return Ok(Event::End);
+                }
+                self.stack.push(Frame::Values {
+                    remaining: remaining - 1,
+                    len,
+                    width,
+                });
+                self.read_value()
+            }
+            Frame::Object {
+                sealed,
+                next,
+                is_dynamic,
+                ..
+            } => {
+                let key = match sealed.get(next) {
+                    Some(key) => key.clone(),
+                    None if is_dynamic => {
+                        let key = self.decoder.decode_utf8()?;
+                        if key.is_empty() {
+                            self.decoder.budget.leave();
+                            return Ok(Event::End);
+                        }
+                        key
+                    }
+                    None => {
+                        self.decoder.budget.leave();
+                        return Ok(Event::End);
+                    }
+                };
+                self.stack.push(Frame::Object {
+                    sealed,
+                    next: next + 1,
+                    is_dynamic,
+                    key: Some(key.clone()),
+                });
+                self.expects_value = true;
+                Ok(Event::Key(key))
+            }
+            Frame::Bytes { remaining } => {
+                if remaining == 0 {
+                    return Ok(Event::End);
+                }
+                let len = remaining.min(BYTES_CHUNK_SIZE);
+                let bytes = self.decoder.read_bytes(len)?;
+                self.stack.push(Frame::Bytes {
+                    remaining: remaining - len,
+                });
+                Ok(Event::Bytes(bytes))
+            }
+        }
+    }
+    // Returns the path to the value being read from the top-level value
+    fn path(&self) -> Vec<PathSegment> {
+        self.stack
+            .iter()
+            .filter_map(|frame| match *frame {
+                Frame::Assoc { ref key, .. } | Frame::Object { ref key, .. } => {
+                    key.clone().map(PathSegment::Key)
+                }
+                Frame::Values {
+                    remaining,
+                    len,
+                    width,
+                } => (len - remaining)
+                    .checked_sub(1)
+                    .map(|i| PathSegment::Index(i / width)),
+                Frame::Bytes { .. } => None,
+            })
+            .collect()
+    }
+    fn push_container(&mut self, frame: Frame) -> DecodeResult<()> {
+        self.decoder.budget.enter()?;
+        self.stack.push(frame);
+        Ok(())
+    }
+    fn read_value(&mut self) -> DecodeResult<Event> {
+        // The event may have read the end of the associative entries before the value
+        let offset = self.decoder.inner.offset;
+        let result = self
+            .decoder
+            .inner
+            .read_u8()
+            .map_err(DecodeError::from)
+            .and_then(|marker| self.read_value_with_marker(marker));
+        if result.is_err() {
+            Location::record(&mut self.decoder.location, offset);
+        }
+        result
+    }
+    fn read_value_with_marker(&mut self, marker: u8) -> DecodeResult<Event> {
+        match marker {
+            marker::UNDEFINED => return Ok(Event::Undefined),
+            marker::NULL => return Ok(Event::Null),
+            marker::FALSE => return Ok(Event::Boolean(false)),
+            marker::TRUE => return Ok(Event::Boolean(true)),
+            marker::INTEGER => {
+                let n = self.decoder.decode_u29()? as i32;
+                let n = if n >= (1 << 28) { n - (1 << 29) } else { n };
+                return Ok(Event::Integer(n));
+            }
+            marker::DOUBLE => {
+                let n = self.decoder.inner.read_f64::<BigEndian>()?;
+                return Ok(Event::Double(n));
+            }
+            marker::STRING => return self.decoder.decode_utf8().map(Event::String),
+            marker::XML_DOC
+            | marker::DATE
+            | marker::ARRAY
+            | marker::OBJECT
+            | marker::XML
+            | marker::BYTE_ARRAY
+            | marker::VECTOR_INT
+            | marker::VECTOR_UINT
+            | marker::VECTOR_DOUBLE
+            | marker::VECTOR_OBJECT
+            | marker::DICTIONARY => {}
+            _ => return Err(DecodeError::Unknown { marker }),
+        }
+
+        let u28 = match self.decoder.decode_size_or_index()? {
+            SizeOrIndex::Index(index) => {
+                if index >= self.decoder.complexes.len() {
+                    return Err(DecodeError::OutOfRangeReference { index });
+                }
+                return Ok(Event::Reference { index });
+            }
+            SizeOrIndex::Size(u28) => u28,
+        };
+        let index = self.decoder.complexes.len();
+        self.decoder.complexes.push(Slot::Read);
+        match marker {
+            marker::XML_DOC => self.decoder.read_utf8(u28).map(Event::XmlDocument),
+            marker::DATE => {
+                let millis = self.decoder.inner.read_f64::<BigEndian>()?;
+                if !(millis.is_finite() && millis.is_sign_positive()) {
+                    Err(DecodeError::InvalidDate { millis })
+                } else {
+                    Ok(Event::Date {
+                        unix_time: time::Duration::from_millis(millis as u64),
+                    })
+                }
+            }
+            marker::ARRAY => {
+                self.decoder.budget.collection::<Value>(u28)?;
+                self.push_container(Frame::Assoc {
+                    dense_len: u28,
+                    key: None,
+                })?;
+                Ok(Event::StartArray { dense_len: u28 })
+            }
+            marker::OBJECT => {
+                let amf_trait = self.decoder.decode_trait(u28)?;
+                if amf_trait.is_externalizable {
+                    let class_name = amf_trait.class_name.unwrap_or_default();
+                    let mut graph = mem::take(&mut self.decoder.graph);
+                    let result = self.decoder.decode_external(&mut graph, &class_name);
+                    self.decoder.graph = graph;
+                    let body = result?;
+                    let body_tree = self.decoder.graph.to_tree(&body)?;
+                    let id = self.decoder.graph.add(Node::Externalizable {
+                        class_name: class_name.clone(),
+                        body,
+                    });
+                    self.decoder.complexes[index] = Slot::Node(id);
+                    return Ok(Event::Externalizable {
+                        class_name,
+                        body: body_tree,
+                    });
+                }
+                let sealed_count = amf_trait.fields.len();
+                self.push_container(Frame::Object {
+                    sealed: amf_trait.fields.into(),
+                    next: 0,
+                    is_dynamic: amf_trait.is_dynamic,
+                    key: None,
+                })?;
+                Ok(Event::StartObject {
+                    class_name: amf_trait.class_name,
+                    sealed_count,
+                })
+            }
+            marker::XML => self.decoder.read_utf8(u28).map(Event::Xml),
+            marker::BYTE_ARRAY => {
+                self.decoder.budget.byte_array(u28)?;
+                self.stack.push(Frame::Bytes { remaining: u28 });
+                Ok(Event::StartByteArray { len: u28 })
+            }
+            marker::VECTOR_INT => {
+                let is_fixed = self.decoder.inner.read_u8()? != 0;
+                self.decoder.budget.collection::<i32>(u28)?;
+                let entries = self.decoder.inner.read_elements(u28, i32::from_be_bytes)?;
+                Ok(Event::IntVector { is_fixed, entries })
+            }
+            marker::VECTOR_UINT => {
+                let is_fixed = self.decoder.inner.read_u8()? != 0;
+                self.decoder.budget.collection::<u32>(u28)?;
+                let entries = self.decoder.inner.read_elements(u28, u32::from_be_bytes)?;
+                Ok(Event::UintVector { is_fixed, entries })
+            }
+            marker::VECTOR_DOUBLE => {
+                let is_fixed = self.decoder.inner.read_u8()? != 0;
+                self.decoder.budget.collection::<f64>(u28)?;
+                let entries = self.decoder.inner.read_elements(u28, f64::from_be_bytes)?;
+                Ok(Event::DoubleVector { is_fixed, entries })
+            }
+            marker::VECTOR_OBJECT => {
+                let is_fixed = self.decoder.inner.read_u8()? != 0;
+                let class_name = self.decoder.decode_utf8()?;
+                self.decoder.budget.collection::<Value>(u28)?;
+                self.push_container(Frame::Values {
+                    remaining: u28,
+                    len: u28,
+                    width: 1,
+                })?;
+                Ok(Event::StartObjectVector {
+                    class_name: if class_name == "*" {
+                        None
+                    } else {
+                        Some(class_name)
+                    },
+                    is_fixed,
+                    len: u28,
+                })
+            }
+            _ => {
+                let is_weak = self.decoder.inner.read_u8()? == 1;
+                self.decoder.budget.collection::<(Value, Value)>(u28)?;
+                self.push_container(Frame::Values {
+                    remaining: u28 * 2,
+                    len: u28 * 2,
+                    width: 2,
+                })?;
+                Ok(Event::StartDictionary { is_weak, len: u28 })
+            }
+        }
+    }
+}
+impl<R> Iterator for Reader<R>
+where
+    R: io::Read,
+{
+    type Item = DecodeResult<Event>;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_event().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Decoder, Event, Reader, Value};
+    use crate::error::DecodeError;
+    use crate::io;
+    use crate::Pair;
+    use std::iter::Peekable;
+
+    macro_rules! read_eq_decode {
+        ($($file:expr),*) => {$({
+            let input = include_bytes!(concat!("../testdata/", $file));
+            let mut events = Reader::new(&input[..]).map(Result::unwrap).peekable();
+            let value = build(&mut events, &mut Vec::new());
+            assert_eq!(events.next(), None);
+            assert_eq!(value, Decoder::new(&input[..]).decode().unwrap(), "{}", $file);
+        })*};
+    }
+
+    // Rebuilds a value from events for the comparison with `Decoder`
+    fn build<I>(events: &mut Peekable<I>, table: &mut Vec<Option<Value>>) -> Value
+    where
+        I: Iterator<Item = Event>,
+    {
+        let index = table.len();
+        let value = match events.next().unwrap() {
+            Event::Undefined => return Value::Undefined,
+            Event::Null => return Value::Null,
+            Event::Boolean(x) => return Value::Boolean(x),
+            Event::Integer(x) => return Value::Integer(x),
+            Event::Double(x) => return Value::Double(x),
+            Event::String(x) => return Value::String(x),
+            Event::Reference { index } => return table[index].clone().unwrap(),
+            Event::Key(_) | Event::Bytes(_) | Event::End => panic!(),
+            Event::XmlDocument(x) => Value::XmlDocument(x),
+            Event::Date { unix_time } => Value::Date { unix_time },
+            Event::Xml(x) => Value::Xml(x),
+            Event::IntVector { is_fixed, entries } => Value::IntVector { is_fixed, entries },
+            Event::UintVector { is_fixed, entries } => Value::UintVector { is_fixed, entries },
+            Event::DoubleVector { is_fixed, entries } => Value::DoubleVector { is_fixed, entries },
+            Event::Externalizable { class_name, body } => Value::Externalizable {
+                class_name,
+                body: Box::new(body),
+            },
+            Event::StartArray { dense_len } => {
+                table.push(None);
+                let assoc_entries = build_pairs(events, table);
+                let dense_entries = (0..dense_len).map(|_| build(events, table)).collect();
+                assert_eq!(events.next(), Some(Event::End));
+                Value::Array {
+                    assoc_entries,
+                    dense_entries,
+                }
+            }
+            Event::StartObject {
+                class_name,
+                sealed_count,
+            } => {
+                table.push(None);
+                let entries = build_pairs(events, table);
+                assert_eq!(events.next(), Some(Event::End));
+                Value::Object {
+                    class_name,
+                    sealed_count,
+                    entries,
+                }
+            }
+            Event::StartByteArray { len } => {
+                table.push(None);
+                let mut bytes = Vec::new();
+                while let Some(Event::Bytes(chunk)) = events.next() {
+                    bytes.extend(chunk);
+                }
+                assert_eq!(bytes.len(), len);
+                Value::ByteArray(bytes)
+            }
+            Event::StartObjectVector {
+                class_name,
+                is_fixed,
+                len,
+            } => {
+                table.push(None);
+                let entries = (0..len).map(|_| build(events, table)).collect();
+                assert_eq!(events.next(), Some(Event::End));
+                Value::ObjectVector {
+                    class_name,
+                    is_fixed,
+                    entries,
+                }
+            }
+            Event::StartDictionary { is_weak, len } => {
+                table.push(None);
+                let entries = (0..len)
+                    .map(|_| Pair {
+                        key: build(events, table),
+                        value: build(events, table),
+                    })
+                    .collect();
+                assert_eq!(events.next(), Some(Event::End));
+                Value::Dictionary { is_weak, entries }
+            }
+        };
+        if table.len() == index {
+            table.push(None);
+        }
+        table[index] = Some(value.clone());
+        value
+    }
+    fn build_pairs<I>(
+        events: &mut Peekable<I>,
+        table: &mut Vec<Option<Value>>,
+    ) -> Vec<Pair<String, Value>>
+    where
+        I: Iterator<Item = Event>,
+    {
+        let mut pairs = Vec::new();
+        while let Some(&Event::Key(_)) = events.peek() {
+            let key = match events.next() {
+                Some(Event::Key(key)) => key,
+                _ => unreachable!(),
+            };
+            let value = build(events, table);
+            pairs.push(Pair { key, value });
+        }
+        pairs
+    }
+
+    #[test]
+    fn reads_values() {
+        read_eq_decode!(
+            "amf3-undefined.bin",
+            "amf3-null.bin",
+            "amf3-true.bin",
+            "amf3-min.bin",
+            "amf3-bignum.bin",
+            "amf3-string.bin",
+            "amf3-xml-doc.bin",
+            "amf3-date.bin",
+            "amf3-xml.bin",
+            "amf3-primitive-array.bin",
+            "amf3-mixed-array.bin",
+            "amf3-associative-array.bin",
+            "amf3-dynamic-object.bin",
+            "amf3-typed-object.bin",
+            "amf3-hash.bin",
+            "amf3-byte-array.bin",
+            "amf3-vector-int.bin",
+            "amf3-vector-uint.bin",
+            "amf3-vector-double.bin",
+            "amf3-vector-object.bin",
+            "amf3-dictionary.bin",
+            "amf3-empty-dictionary.bin"
+        );
+    }
+    #[test]
+    fn reads_references() {
+        read_eq_decode!(
+            "amf3-string-ref.bin",
+            "amf3-encoded-string-ref.bin",
+            "amf3-trait-ref.bin",
+            "amf3-object-ref.bin",
+            "amf3-array-ref.bin",
+            "amf3-empty-array-ref.bin",
+            "amf3-date-ref.bin",
+            "amf3-xml-ref.bin",
+            "amf3-byte-array-ref.bin"
+        );
+
+        // Circular references can be read as events
+        let input = include_bytes!("../testdata/amf3-graph-member.bin");
+        let events = Reader::new(&input[..])
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert!(events.contains(&Event::Reference { index: 0 }));
+
+        // [[], <reference to 1>]
+        let input = b"\x09\x05\x01\x09\x01\x01\x09\x02";
+        let events = Reader::new(&input[..])
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(
+            events,
+            [
+                Event::StartArray { dense_len: 2 },
+                Event::StartArray { dense_len: 0 },
+                Event::End,
+                Event::Reference { index: 1 },
+                Event::End,
+            ]
+        );
+
+        let input = include_bytes!("../testdata/amf3-bad-object-ref.bin");
+        let result = Reader::new(&input[..]).collect::<Result<Vec<_>, _>>();
+        assert_eq!(result, Err(DecodeError::OutOfRangeReference { index: 10 }));
+    }
+    #[test]
+    fn reads_byte_array_chunks() {
+        let mut input = Vec::new();
+        Value::ByteArray(vec![1; 10000])
+            .write_to(&mut input)
+            .unwrap();
+        let events = Reader::new(&input[..])
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(
+            events,
+            [
+                Event::StartByteArray { len: 10000 },
+                Event::Bytes(vec![1; 8192]),
+                Event::Bytes(vec![1; 1808]),
+                Event::End,
+            ]
+        );
+    }
+    #[test]
+    fn reads_externalizable() {
+        let input = include_bytes!("../testdata/amf3-array-collection.bin");
+        let events = Reader::new(&input[..])
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(
+            events,
+            [Event::Externalizable {
+                class_name: "flex.messaging.io.ArrayCollection".to_string(),
+                body: Value::Array {
+                    assoc_entries: vec![],
+                    dense_entries: vec![
+                        Value::String("foo".to_string()),
+                        Value::String("bar".to_string())
+                    ],
+                },
+            }]
+        );
+    }
+    #[test]
+    fn reads_sequence() {
+        let mut input = Vec::new();
+        Value::Integer(1).write_to(&mut input).unwrap();
+        Value::String("foo".to_string())
+            .write_to(&mut input)
+            .unwrap();
+        let events = Reader::new(&input[..])
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(
+            events,
+            [Event::Integer(1), Event::String("foo".to_string())]
+        );
+
+        let input = include_bytes!("../testdata/amf3-dictionary-partial.bin");
+        match Reader::new(&input[..]).collect::<Result<Vec<_>, _>>() {
+            Err(DecodeError::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof),
+            _ => panic!(),
+        }
+    }
+    #[test]
+    fn locates_errors() {
+        // {orders: [1, <unknown marker>]}
+        let input = b"\x0a\x0b\x01\x0dorders\x09\x05\x01\x04\x01\x7b";
+        let mut reader = Reader::new(&input[..]);
+        let result = reader.by_ref().collect::<Result<Vec<_>, _>>();
+        assert_eq!(result, Err(DecodeError::Unknown { marker: 123 }));
+        let location = reader.error_location().unwrap();
+        assert_eq!(location.to_string(), "offset=15, path=$.orders[1]");
+    }
+}
@@ -0,0 +1,392 @@
+//! Reference-counted representation of AMF3 values.
+//!
+//! Unlike the tree [`Value`](super::Value), a complex value referred multiple times
+//! is decoded into a single [`Node`] shared by [`Arc`]s,
+//! so the memory usage is proportional to the size of the input.
+//!
+//! # Examples
+//! ```
+//! use amf::amf3::{self, shared};
+//!
+//! // An array which contains the same array twice
+//! let input = b"\x09\x05\x01\x09\x03\x01\x04\x01\x09\x02";
+//! let value = amf3::Decoder::new(&input[..]).decode_shared().unwrap();
+//! if let shared::Value::Node(ref node) = value {
+//!     if let shared::Node::Array { ref dense_entries, .. } = **node {
+//!         assert_eq!(dense_entries[0], dense_entries[1]);
+//!     }
+//! }
+//! assert_eq!(
+//!     value.into_owned(),
+//!     amf3::Value::read_from(&mut &input[..]).unwrap()
+//! );
+//! ```
+use super::graph::{self, Graph, NodeId};
+use super::Value as TreeValue;
+use crate::error::DecodeError;
+use crate::{DecodeResult, Pair};
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::time;
+
+/// AMF3 value which shares the complex values.
+#[derive(Debug, Clone, PartialEq, PartialOrd)]
+pub enum Value {
+    /// See [`Value::Undefined`](super::Value::Undefined).
+    Undefined,
+
+    /// See [`Value::Null`](super::Value::Null).
+    Null,
+
+    /// See [`Value::Boolean`](super::Value::Boolean).
+    Boolean(bool),
+
+    /// See [`Value::Integer`](super::Value::Integer).
+    Integer(i32),
+
+    /// See [`Value::Double`](super::Value::Double).
+    Double(f64),
+
+    /// See [`Value::String`](super::Value::String).
+    String(String),
+
+    /// A complex value.
+    Node(Arc<Node>),
+}
+impl Value {
+    /// Converts this value into the tree representation.
+    ///
+    /// The nodes which are not shared with other values are moved without being copied.
+    pub fn into_owned(self) -> TreeValue {
+        match self {
+            Value::Undefined => TreeValue::Undefined,
+            Value::Null => TreeValue::Null,
+            Value::Boolean(x) => TreeValue::Boolean(x),
+            Value::Integer(x) => TreeValue::Integer(x),
+            Value::Double(x) => TreeValue::Double(x),
+            Value::String(x) => TreeValue::String(x),
+            Value::Node(x) => Arc::try_unwrap(x)
+                .unwrap_or_else(|x| (*x).clone())
+                .into_owned(),
+        }
+    }
+}
+impl From<Value> for TreeValue {
+    fn from(f: Value) -> Self {
+        f.into_owned()
+    }
+}
+
+/// Complex AMF3 value which may be shared.
+#[derive(Debug, Clone, PartialEq, PartialOrd)]
+pub enum Node {
+    /// See [`Value::XmlDocument`](super::Value::XmlDocument).
+    XmlDocument(String),
+
+    /// See [`Value::Date`](super::Value::Date).
+    Date {
+        /// Unix timestamp with milliseconds precision.
+        unix_time: time::Duration,
+    },
+
+    /// See [`Value::Array`](super::Value::Array).
+    Array {
+        /// Entries of the associative part of the array.
+        assoc_entries: Vec<Pair<String, Value>>,
+
+        /// Entries of the dense part of the array.
+        dense_entries: Vec<Value>,
+    },
+
+    /// See [`Value::Object`](super::Value::Object).
+    Object {
+        /// The class name of the object.
+        /// `None` means it is an anonymous object.
+        class_name: Option<String>,
+
+        /// Sealed member count of the object.
+        ///
+        /// Sealed members are located in front of the `entries`.
+        sealed_count: usize,
+
+        /// Members of the object.
+        entries: Vec<Pair<String, Value>>,
+    },
+
+    /// See [`Value::Xml`](super::Value::Xml).
+    Xml(String),
+
+    /// See [`Value::ByteArray`](super::Value::ByteArray).
+    ByteArray(Vec<u8>),
+
+    /// See [`Value::IntVector`](super::Value::IntVector).
+    IntVector {
+        /// If `true`, this is a fixed-length vector.
+        is_fixed: bool,
+
+        /// The entries of the vector.
+        entries: Vec<i32>,
+    },
+
+    /// See [`Value::UintVector`](super::Value::UintVector).
+    UintVector {
+        /// If `true`, this is a fixed-length vector.
+        is_fixed: bool,
+
+        /// The entries of the vector.
+        entries: Vec<u32>,
+    },
+
+    /// See [`Value::DoubleVector`](super::Value::DoubleVector).
+    DoubleVector {
+        /// If `true`, this is a fixed-length vector.
+        is_fixed: bool,
+
+        /// The entries of the vector.
+        entries: Vec<f64>,
+    },
+
+    /// See [`Value::ObjectVector`](super::Value::ObjectVector).
+    ObjectVector {
+        /// The base type name of entries in the vector.
+        /// `None` means it is the ANY type.
+        class_name: Option<String>,
+
+        /// If `true`, this is a fixed-length vector.
+        is_fixed: bool,
+
+        /// The entries of the vector.
+        entries: Vec<Value>,
+    },
+
+    /// See [`Value::Dictionary`](super::Value::Dictionary).
+    Dictionary {
+        /// If `true`, the keys of `entries` are weakly referenced.
+        is_weak: bool,
+
+        /// The entries of the dictionary.
+        entries: Vec<Pair<Value, Value>>,
+    },
+
+    /// See [`Value::Externalizable`](super::Value::Externalizable).
+    Externalizable {
+        /// The class name of the object.
+        class_name: String,
+
+        /// The body of the object.
+        body: Value,
+    },
+}
+impl Node {
+    fn into_owned(self) -> TreeValue {
+        match self {
+            Node::XmlDocument(x) => TreeValue::XmlDocument(x),
+            Node::Date { unix_time } => TreeValue::Date { unix_time },
+            Node::Array {
+                assoc_entries,
+                dense_entries,
+            } => TreeValue::Array {
+                assoc_entries: into_owned_pairs(assoc_entries),
+                dense_entries: dense_entries.into_iter().map(Value::into_owned).collect(),
+            },
+            Node::Object {
+                class_name,
+                sealed_count,
+                entries,
+            } => TreeValue::Object {
+                class_name,
+                sealed_count,
+                entries: into_owned_pairs(entries),
+            },
+            Node::Xml(x) => TreeValue::Xml(x),
+            Node::ByteArray(x) => TreeValue::ByteArray(x),
+            Node::IntVector { is_fixed, entries } => TreeValue::IntVector { is_fixed, entries },
+            Node::UintVector { is_fixed, entries } => TreeValue::UintVector { is_fixed, entries },
+            Node::DoubleVector { is_fixed, entries } => {
+                TreeValue::DoubleVector { is_fixed, entries }
+            }
+            Node::ObjectVector {
+                class_name,
+                is_fixed,
+                entries,
+            } => TreeValue::ObjectVector {
+                class_name,
+                is_fixed,
+                entries: entries.into_iter().map(Value::into_owned).collect(),
+            },
+            Node::Dictionary { is_weak, entries } => TreeValue::Dictionary {
+                is_weak,
+                entries: entries
+                    .into_iter()
+                    .map(|p| Pair {
+                        key: p.key.into_owned(),
+                        value: p.value.into_owned(),
+                    })
+                    .collect(),
+            },
+            Node::Externalizable { class_name, body } => TreeValue::Externalizable {
+                class_name,
+                body: Box::new(body.into_owned()),
+            },
+        }
+    }
+}
+
+fn into_owned_pairs(pairs: Vec<Pair<String, Value>>) -> Vec<Pair<String, TreeValue>> {
+    pairs
+        .into_iter()
+        .map(|p| Pair {
+            key: p.key,
+            value: p.value.into_owned(),
+        })
+        .collect()
+}
+
+// The shared nodes converted from the nodes of a graph, indexed by `NodeId`
+#[derive(Debug, Default, Clone)]
+pub(crate) struct Nodes {
+    nodes: Vec<Option<Arc<Node>>>,
+}
+impl Nodes {
+    pub(crate) fn share(&mut self, graph: &Graph, value: &graph::Value) -> DecodeResult<Value> {
+        self.share_value(graph, value, &mut Vec::new())
+    }
+    pub(crate) fn clear(&mut self) {
+        self.nodes.clear();
+    }
+
+    fn share_value(
+        &mut self,
+        graph: &Graph,
+        value: &graph::Value,
+        path: &mut Vec<NodeId>,
+    ) -> DecodeResult<Value> {
+        let id = match *value {
+            graph::Value::Undefined => return Ok(Value::Undefined),
+            graph::Value::Null => return Ok(Value::Null),
+            graph::Value::Boolean(x) => return Ok(Value::Boolean(x)),
+            graph::Value::Integer(x) => return Ok(Value::Integer(x)),
+            graph::Value::Double(x) => return Ok(Value::Double(x)),
+            graph::Value::String(ref x) => return Ok(Value::String(x.clone())),
+            graph::Value::Node(id) => id,
+        };
+        if let Some(Some(node)) = self.nodes.get(id.index()) {
+            return Ok(Value::Node(node.clone()));
+        }
+        if path.contains(&id) {
+            return Err(DecodeError::CircularReference { index: id.index() });
+        }
+        path.push(id);
+        let node = match *graph.decoded_node(id)? {
+            graph::Node::XmlDocument(ref x) => Node::XmlDocument(x.clone()),
+            graph::Node::Date { unix_time } => Node::Date { unix_time },
+            graph::Node::Array {
+                ref assoc_entries,
+                ref dense_entries,
+            } => Node::Array {
+                assoc_entries: self.share_pairs(graph, assoc_entries, path)?,
+                dense_entries: self.share_values(graph, dense_entries, path)?,
+            },
+            graph::Node::Object {
+                ref class_name,
+                sealed_count,
+                ref entries,
+            } => Node::Object {
+                class_name: class_name.clone(),
+                sealed_count,
+                entries: self.share_pairs(graph, entries, path)?,
+            },
+            graph::Node::Xml(ref x) => Node::Xml(x.clone()),
+            graph::Node::ByteArray(ref x) => Node::ByteArray(x.clone()),
+            graph::Node::IntVector {
+                is_fixed,
+                ref entries,
+            } => Node::IntVector {
+                is_fixed,
+                entries: entries.clone(),
+            },
+            graph::Node::UintVector {
+                is_fixed,
+                ref entries,
+            } => Node::UintVector {
+                is_fixed,
+                entries: entries.clone(),
+            },
+            graph::Node::DoubleVector {
+                is_fixed,
+                ref entries,
+            } => Node::DoubleVector {
+                is_fixed,
+                entries: entries.clone(),
+            },
+            graph::Node::ObjectVector {
+                ref class_name,
+                is_fixed,
+                ref entries,
+            } => Node::ObjectVector {
+                class_name: class_name.clone(),
+                is_fixed,
+                entries: self.share_values(graph, entries, path)?,
+            },
+            graph::Node::Dictionary {
+                is_weak,
+                ref entries,
+            } => Node::Dictionary {
+                is_weak,
+                entries: entries
+                    .iter()
+                    .map(|e| {
+                        Ok(Pair {
+                            key: self.share_value(graph, &e.key, path)?,
+                            value: self.share_value(graph, &e.value, path)?,
+                        })
+                    })
+                    .collect::<DecodeResult<_>>()?,
+            },
+            graph::Node::Externalizable {
+                ref class_name,
+                ref body,
+            } => Node::Externalizable {
+                class_name: class_name.clone(),
+                body: self.share_value(graph, body, path)?,
+            },
+        };
+        path.pop();
+
+        let node = Arc::new(node);
+        if self.nodes.len() <= id.index() {
+            self.nodes.resize(id.index() + 1, None);
+        }
+        self.nodes[id.index()] = Some(node.clone());
+        Ok(Value::Node(node))
+    }
+    fn share_values(
+        &mut self,
+        graph: &Graph,
+        values: &[graph::Value],
+        path: &mut Vec<NodeId>,
+    ) -> DecodeResult<Vec<Value>> {
+        values
+            .iter()
+            .map(|v| self.share_value(graph, v, path))
+            .collect()
+    }
+    fn share_pairs(
+        &mut self,
+        graph: &Graph,
+        pairs: &[Pair<String, graph::Value>],
+        path: &mut Vec<NodeId>,
+    ) -> DecodeResult<Vec<Pair<String, Value>>> {
+        pairs
+            .iter()
+            .map(|p| {
+                Ok(Pair {
+                    key: p.key.clone(),
+                    value: self.share_value(graph, &p.value, path)?,
+                })
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,577 @@
+use super::build::Slot;
+use super::graph::{self, Node, NodeId};
+use super::reader::Reader;
+use super::{Decoder, Event, ExternalizableRegistry, Value};
+use crate::error::{DecodeError, Location};
+use crate::io;
+use crate::util::Feed;
+use crate::{DecodeResult, DecoderLimits, Pair};
+use alloc::string::String;
+use alloc::vec::Vec;
+
+// A complex value whose entries are being read
+#[derive(Debug)]
+enum Partial {
+    Array {
+        id: NodeId,
+        assoc_entries: Vec<Pair<String, graph::Value>>,
+        dense_entries: Vec<graph::Value>,
+        key: Option<String>,
+    },
+    Object {
+        id: NodeId,
+        class_name: Option<String>,
+        sealed_count: usize,
+        entries: Vec<Pair<String, graph::Value>>,
+        key: Option<String>,
+    },
+    ByteArray {
+        id: NodeId,
+        bytes: Vec<u8>,
+    },
+    ObjectVector {
+        id: NodeId,
+        class_name: Option<String>,
+        is_fixed: bool,
+        entries: Vec<graph::Value>,
+    },
+    Dictionary {
+        id: NodeId,
+        is_weak: bool,
+        entries: Vec<Pair<graph::Value, graph::Value>>,
+        key: Option<graph::Value>,
+    },
+}
+impl Partial {
+    fn add(&mut self, value: graph::Value) {
+        match *self {
+            Partial::Array {
+                ref mut assoc_entries,
+                ref mut dense_entries,
+                ref mut key,
+                ..
+            } => match key.take() {
+                Some(key) => assoc_entries.push(Pair { key, value }),
+                None => dense_entries.push(value),
+            },
+            Partial::Object {
+                ref mut entries,
+                ref mut key,
+                ..
+            } => entries.push(Pair {
+                key: key.take().unwrap_or_default(),
+                value,
+            }),
+            Partial::ByteArray { .. } => {}
+            Partial::ObjectVector {
+                ref mut entries, ..
+            } => entries.push(value),
+            Partial::Dictionary {
+                ref mut entries,
+                ref mut key,
+                ..
+            } => match key.take() {
+                Some(key) => entries.push(Pair { key, value }),
+                None => *key = Some(value),
+            },
+        }
+    }
+    fn finish(self) -> (NodeId, Node) {
+        match self {
+            Partial::Array {
+                id,
+                assoc_entries,
+                dense_entries,
+                ..
+            } => (
+                id,
+                Node::Array {
+                    assoc_entries,
+                    dense_entries,
+                },
+            ),
+            Partial::Object {
+                id,
+                class_name,
+                sealed_count,
+                entries,
+                ..
+            } => (
+                id,
+                Node::Object {
+                    class_name,
+                    sealed_count,
+                    entries,
+                },
+            ),
+            Partial::ByteArray { id, bytes } => (id, Node::ByteArray(bytes)),
+            Partial::ObjectVector {
+                id,
+                class_name,
+                is_fixed,
+                entries,
+            } => (
+                id,
+                Node::ObjectVector {
+                    class_name,
+                    is_fixed,
+                    entries,
+                },
+            ),
+            Partial::Dictionary {
+                id,
+                is_weak,
+                entries,
+                ..
+            } => (id, Node::Dictionary { is_weak, entries }),
+        }
+    }
+}
+
+// Builds the values read by `Reader` in the graph of its decoder, one event at a time
+#[derive(Debug, Default)]
+pub(crate) struct Builder {
+    stack: Vec<Partial>,
+}
+impl Builder {
+    // Adds an event, returning the value if the event completes a top-level value.
+    //
+    // `index` is the number of the complex values which had been read before the event.
+    pub(crate) fn add<R>(
+        &mut self,
+        decoder: &mut Decoder<R>,
+        index: usize,
+        event: Event,
+    ) -> DecodeResult<Option<graph::Value>> {
+        let value = match event {
+            Event::Undefined => graph::Value::Undefined,
+            Event::Null => graph::Value::Null,
+            Event::Boolean(x) => graph::Value::Boolean(x),
+            Event::Integer(x) => graph::Value::Integer(x),
+            Event::Double(x) => graph::Value::Double(x),
+            Event::String(x) => graph::Value::String(x),
+            Event::XmlDocument(x) => Self::complex(decoder, index, Node::XmlDocument(x)),
+            Event::Date { unix_time } => Self::complex(decoder, index, Node::Date { unix_time }),
+            Event::Xml(x) => Self::complex(decoder, index, Node::Xml(x)),
+            Event::IntVector { is_fixed, entries } => {
+                Self::complex(decoder, index, Node::IntVector { is_fixed, entries })
+            }
+            Event::UintVector { is_fixed, entries } => {
+                Self::complex(decoder, index, Node::UintVector { is_fixed, entries })
+            }
+            Event::DoubleVector { is_fixed, entries } => {
+                Self::complex(decoder, index, Node::DoubleVector { is_fixed, entries })
+            }
+            // The reader has added the externalizable object to the graph
+            Event::Externalizable { .. } => Self::node(decoder, index)?,
+            Event::Reference { index } => Self::node(decoder, index)?,
+            Event::StartArray { .. } => {
+                let id = Self::reserve(decoder, index);
+                self.stack.push(Partial::Array {
+                    id,
+                    assoc_entries: Vec::new(),
+                    dense_entries: Vec::new(),
+                    key: None,
+                });
+                return Ok(None);
+            }
+            Event::StartObject {
+                class_name,
+                sealed_count,
+            } => {
+                let id = Self::reserve(decoder, index);
+                self.stack.push(Partial::Object {
+                    id,
+                    class_name,
+                    sealed_count,
+                    entries: Vec::new(),
+                    key: None,
+                });
+                return Ok(None);
+            }
+            Event::StartByteArray { .. } => {
+                let id = Self::reserve(decoder, index);
+                self.stack.push(Partial::ByteArray {
+                    id,
+                    bytes: Vec::new(),
+                });
+                return Ok(None);
+            }
+            Event::StartObjectVector {
+                class_name,
+                is_fixed,
+                ..
+            } => {
+                let id = Self::reserve(decoder, index);
+                self.stack.push(Partial::ObjectVector {
+                    id,
+                    class_name,
+                    is_fixed,
+                    entries: Vec::new(),
+                });
+                return Ok(None);
+            }
+            Event::StartDictionary { is_weak, .. } => {
+                let id = Self::reserve(decoder, index);
+                self.stack.push(Partial::Dictionary {
+                    id,
+                    is_weak,
+                    entries: Vec::new(),
+                    key: None,
+                });
+                return Ok(None);
+            }
+            Event::Key(x) => {
+                if let Some(Partial::Array { key, .. } | Partial::Object { key, .. }) =
+                    self.stack.last_mut()
+                {
+                    *key = Some(x);
+                }
+                return Ok(None);
+            }
+            Event::Bytes(x) => {
+                if let Some(Partial::ByteArray { bytes, .. }) = self.stack.last_mut() {
+                    bytes.extend_from_slice(&x);
+                }
+                return Ok(None);
+            }
+            Event::End => {
+                let (id, node) = self.stack.pop().expect("unbalanced events").finish();
+                decoder.graph.fill(id, node);
+                graph::Value::Node(id)
+            }
+        };
+        match self.stack.last_mut() {
+            None => Ok(Some(value)),
+            Some(partial) => {
+                partial.add(value);
+                Ok(None)
+            }
+        }
+    }
+    fn complex<R>(decoder: &mut Decoder<R>, index: usize, node: Node) -> graph::Value {
+        let id = decoder.graph.add(node);
+        decoder.complexes[index] = Slot::Node(id);
+        graph::Value::Node(id)
+    }
+    fn node<R>(decoder: &Decoder<R>, index: usize) -> DecodeResult<graph::Value> {
+        match decoder.complexes.get(index).and_then(Slot::node) {
+            Some(id) => Ok(graph::Value::Node(id)),
+            None => Err(DecodeError::OutOfRangeReference { index }),
+        }
+    }
+    // Reserves the node of a container, so that its entries can refer to it
+    fn reserve<R>(decoder: &mut Decoder<R>, index: usize) -> NodeId {
+        let id = decoder.graph.reserve();
+        decoder.complexes[index] = Slot::Node(id);
+        id
+    }
+}
+
+/// AMF3 decoder which decodes values from the bytes fed in pieces.
+///
+/// If the bytes fed so far end in the middle of a value, `decode` returns `DecodeError::Incomplete`.
+/// The events of the value which have been read are kept,
+/// so decoding resumes where it stopped when `decode` is called again after more bytes are fed.
+///
+/// After an error other than `Incomplete`, the decoder should be discarded.
+///
+/// # Examples
+/// ```
+/// use amf::amf3::{StreamDecoder, Value};
+/// use amf::error::DecodeError;
+///
+/// // [1, "foo"]
+/// let input = b"\x09\x05\x01\x04\x01\x06\x07foo";
+/// let mut decoder = StreamDecoder::new();
+/// decoder.feed(&input[..6]);
+/// assert_eq!(decoder.decode().unwrap_err(), DecodeError::Incomplete { needed: 1 });
+/// decoder.feed(&input[6..]);
+/// assert_eq!(
+///     decoder.decode().unwrap(),
+///     Value::Array {
+///         assoc_entries: Vec::new(),
+///         dense_entries: vec![Value::Integer(1), Value::String("foo".to_string())],
+///     }
+/// );
+/// ```
+#[derive(Debug)]
+pub struct StreamDecoder {
+    reader: Reader<Feed>,
+    // The number of the bytes which the incomplete value lacks at least,
+    // so that `decode` does not read it again until they are fed
+    pending: usize,
+    builder: Builder,
+}
+impl StreamDecoder {
+    /// Makes a new instance.
+    pub fn new() -> Self {
+        Self::with_limits(DecoderLimits::default())
+    }
+
+    /// Makes a new instance with the specified limits.
+    pub fn with_limits(limits: DecoderLimits) -> Self {
+        StreamDecoder {
+            reader: Reader::with_limits(Feed::default(), limits),
+            pending: 0,
+            builder: Builder::default(),
+        }
+    }
+
+    /// Appends `bytes` to the input.
+    pub fn feed(&mut self, bytes: &[u8]) {
+        self.reader.decoder_mut().inner_mut().extend(bytes);
+        self.pending = self.pending.saturating_sub(bytes.len());
+    }
+
+    /// Returns the number of the bytes fed but not consumed by the decoded values yet.
+    ///
+    /// The bytes of an incomplete value are counted until the value is decoded.
+    pub fn buffered_len(&mut self) -> usize {
+        self.reader.decoder_mut().inner_mut().remaining()
+    }
+
+    /// Returns a mutable reference to the registry of the externalizable class handlers.
+    pub fn externalizables_mut(&mut self) -> &mut ExternalizableRegistry {
+        self.reader.externalizables_mut()
+    }
+
+    /// Returns the location of the value which failed to decode in the last call of `decode`.
+    ///
+    /// The offset is counted from the first byte fed.
+    /// This is `None` if the last call succeeded or returned `DecodeError::Incomplete`.
+    pub fn error_location(&self) -> Option<&Location> {
+        self.reader.error_location()
+    }
+
+    /// Decodes a AMF3 value from the bytes fed so far.
+    ///
+    /// Reference objects are copied as `Decoder::decode` does.
+    /// If the value is incomplete, `DecodeError::Incomplete` is returned
+    /// and this method can be called again after more bytes are fed.
+    /// The input is not read again until the `needed` bytes are fed.
+    pub fn decode(&mut self) -> DecodeResult<Value> {
+        if self.pending > 0 {
+            return Err(DecodeError::Incomplete {
+                needed: self.pending,
+            });
+        }
+        loop {
+            let checkpoint = self.reader.checkpoint();
+            let decoder = self.reader.decoder_mut();
+            let position = decoder.inner_mut().position();
+            decoder.inner_mut().take_shortage();
+            decoder.take_missing();
+            let index = decoder.complex_count();
+            let event = match self.reader.next_event() {
+                Ok(Some(event)) => event,
+                Ok(None) => {
+                    self.reader.decoder_mut().inner_mut().take_shortage();
+                    return Err(DecodeError::Incomplete { needed: 1 });
+                }
+                Err(e) => {
+                    let decoder = self.reader.decoder_mut();
+                    let needed = decoder.take_missing();
+                    let feed = decoder.inner_mut();
+                    let needed = needed.max(feed.take_shortage());
+                    if needed == 0 || !is_eof(&e) {
+                        return Err(e);
+                    }
+                    feed.rewind(position);
+                    self.reader.rollback(checkpoint);
+                    self.pending = needed;
+                    self.reader.decoder_mut().location = None;
+                    return Err(DecodeError::Incomplete { needed });
+                }
+            };
+            let decoder = self.reader.decoder_mut();
+            if let Some(value) = self.builder.add(decoder, index, event)? {
+                return decoder.copy_tree(&value);
+            }
+        }
+    }
+
+    /// Clear the reference tables of this decoder.
+    ///
+    /// This should be called only at a boundary of the top-level values,
+    /// i.e., not while a value is incomplete.
+    pub fn clear_reference_table(&mut self) {
+        self.reader.clear_reference_table();
+    }
+}
+impl Default for StreamDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub(crate) fn is_eof(e: &DecodeError) -> bool {
+    match *e {
+        DecodeError::Io(ref e) => e.kind() == io::ErrorKind::UnexpectedEof,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Decoder, StreamDecoder, Value};
+    use crate::error::DecodeError;
+
+    macro_rules! stream_eq_decode {
+        ($($file:expr),*) => {$({
+            let input = include_bytes!(concat!("../testdata/", $file));
+            let expected = Decoder::new(&input[..]).decode().unwrap();
+            assert_eq!(feed_bytewise(&input[..]), expected, "{}", $file);
+        })*};
+    }
+
+    // Feeds the input byte by byte, decoding a value after each byte
+    fn feed_bytewise(input: &[u8]) -> Value {
+        let mut decoder = StreamDecoder::new();
+        for (i, b) in input.iter().enumerate() {
+            match decoder.decode() {
+                Err(DecodeError::Incomplete { needed }) => {
+                    assert!(needed >= 1 && needed <= input.len() - i, "{}", needed)
+                }
+                other => panic!("unexpected result {:?} at {}", other, i),
+            }
+            decoder.feed(&[*b]);
+        }
+        let value = decoder.decode().unwrap();
+        assert_eq!(decoder.buffered_len(), 0);
+        value
+    }
+
+    #[test]
+    fn decodes_fed_bytes() {
+        stream_eq_decode!(
+            "amf3-undefined.bin",
+            "amf3-true.bin",
+            "amf3-min.bin",
+            "amf3-bignum.bin",
+            "amf3-string.bin",
+            "amf3-xml-doc.bin",
+            "amf3-date.bin",
+            "amf3-xml.bin",
+            "amf3-mixed-array.bin",
+            "amf3-associative-array.bin",
+            "amf3-dynamic-object.bin",
+            "amf3-typed-object.bin",
+            "amf3-hash.bin",
+            "amf3-byte-array.bin",
+            "amf3-vector-int.bin",
+            "amf3-vector-uint.bin",
+            "amf3-vector-double.bin",
+            "amf3-vector-object.bin",
+            "amf3-dictionary.bin",
+            "amf3-empty-dictionary.bin",
+            "amf3-array-collection.bin",
+            "amf3-string-ref.bin",
+            "amf3-trait-ref.bin",
+            "amf3-object-ref.bin",
+            "amf3-array-ref.bin",
+            "amf3-date-ref.bin",
+            "amf3-byte-array-ref.bin"
+        );
+
+        let mut input = Vec::new();
+        let value = Value::ByteArray(vec![1; 10000]);
+        value.write_to(&mut input).unwrap();
+        let mut decoder = StreamDecoder::new();
+        for chunk in input.chunks(1000) {
+            assert!(decoder.decode().is_err());
+            decoder.feed(chunk);
+        }
+        assert_eq!(decoder.decode().unwrap(), value);
+    }
+    #[test]
+    fn decodes_sequence() {
+        // "foo", and a reference to it
+        let input = b"\x06\x07foo\x06\x00\x04";
+        let mut decoder = StreamDecoder::new();
+        decoder.feed(&input[..4]);
+        assert_eq!(decoder.decode(), Err(DecodeError::Incomplete { needed: 1 }));
+        decoder.feed(&input[4..]);
+        assert_eq!(decoder.decode(), Ok(Value::String("foo".to_string())));
+        assert_eq!(decoder.decode(), Ok(Value::String("foo".to_string())));
+        assert_eq!(decoder.buffered_len(), 1);
+        assert_eq!(decoder.decode(), Err(DecodeError::Incomplete { needed: 1 }));
+
+        decoder.clear_reference_table();
+        decoder.feed(b"\x01\x06\x00");
+        assert_eq!(decoder.decode(), Ok(Value::Integer(1)));
+        assert_eq!(
+            decoder.decode(),
+            Err(DecodeError::OutOfRangeReference { index: 0 })
+        );
+    }
+    #[test]
+    fn reports_incomplete_input() {
+        macro_rules! incomplete {
+            ($file:expr, $needed:expr) => {{
+                let mut decoder = StreamDecoder::new();
+                decoder.feed(include_bytes!(concat!("../testdata/", $file)));
+                assert_eq!(
+                    decoder.decode(),
+                    Err(DecodeError::Incomplete { needed: $needed }),
+                    "{}",
+                    $file
+                );
+            }};
+        }
+        incomplete!("amf3-empty.bin", 1);
+        incomplete!("amf3-u29-partial.bin", 1);
+        incomplete!("amf3-double-partial.bin", 1);
+        incomplete!("amf3-string-partial.bin", 1);
+        incomplete!("amf3-vector-int-partial.bin", 1);
+        incomplete!("amf3-dictionary-partial.bin", 1);
+
+        // A long string or vector reports all the bytes which it lacks
+        for value in [
+            Value::String("a".repeat(100_000)),
+            Value::IntVector {
+                is_fixed: false,
+                entries: (0..25_000).collect(),
+            },
+        ] {
+            let mut input = Vec::new();
+            value.write_to(&mut input).unwrap();
+            let mut decoder = StreamDecoder::new();
+            decoder.feed(&input[..1000]);
+            assert_eq!(
+                decoder.decode(),
+                Err(DecodeError::Incomplete {
+                    needed: input.len() - 1000
+                })
+            );
+            decoder.feed(&input[1000..50_000]);
+            assert_eq!(
+                decoder.decode(),
+                Err(DecodeError::Incomplete {
+                    needed: input.len() - 50_000
+                })
+            );
+            decoder.feed(&input[50_000..]);
+            assert_eq!(decoder.decode(), Ok(value));
+        }
+
+        // Errors other than the end of the input are returned as they are
+        let mut decoder = StreamDecoder::new();
+        decoder.feed(include_bytes!("../testdata/amf3-unknown-marker.bin"));
+        assert_eq!(decoder.decode(), Err(DecodeError::Unknown { marker: 123 }));
+    }
+    #[test]
+    fn locates_errors() {
+        // 1, {orders: [1, <unknown marker>]}
+        let input = b"\x04\x01\x0a\x0b\x01\x0dorders\x09\x05\x01\x04\x01\x7b";
+        let mut decoder = StreamDecoder::new();
+        decoder.feed(&input[..12]);
+        assert_eq!(decoder.decode(), Ok(Value::Integer(1)));
+        assert!(decoder.decode().is_err());
+        assert_eq!(decoder.error_location(), None);
+        decoder.feed(&input[12..]);
+        assert_eq!(decoder.decode(), Err(DecodeError::Unknown { marker: 123 }));
+        let location = decoder.error_location().unwrap();
+        assert_eq!(location.to_string(), "offset=17, path=$.orders[1]");
+    }
+}
@@ -0,0 +1,1038 @@
+use super::build::Slot;
+use super::decode::SizeOrIndex;
+use super::graph::{self, NodeId};
+use super::marker;
+use super::{Decoder, ExternalizableRegistry, Trait, Value};
+use crate::error::{DecodeError, Location, PathSegment};
+use crate::io;
+use crate::limits::Budget;
+use crate::{DecodeResult, DecoderLimits, Pair};
+use alloc::borrow::Cow;
+use alloc::borrow::ToOwned;
+use alloc::boxed::Box;
+use alloc::rc::Rc;
+use alloc::string::String;
+use alloc::string::ToString;
+use alloc::vec::Vec;
+use byteorder::{BigEndian, ByteOrder};
+use core::mem;
+use core::str;
+use core::time;
+
+/// AMF3 value which borrows the strings and the byte arrays from the input.
+///
+/// This is decoded from a byte slice by `SliceDecoder` without copying the strings and the byte arrays.
+/// The strings are `Cow` because the ones read by an `ExternalizableHandler` are owned
+/// and may be referred from the following values.
+///
+/// The variants are the same as those of `Value`.
+///
+/// # Examples
+/// ```
+/// use amf::amf3::{Value, ValueRef};
+/// use std::borrow::Cow;
+///
+/// let input = b"\x06\x07foo";
+/// let value = ValueRef::read_from(&mut &input[..]).unwrap();
+/// assert_eq!(value, ValueRef::String(Cow::Borrowed("foo")));
+/// assert_eq!(value.into_owned(), Value::String("foo".to_string()));
+/// ```
+#[derive(Debug, Clone, PartialEq, PartialOrd)]
+pub enum ValueRef<'a> {
+    /// See `Value::Undefined`.
+    Undefined,
+
+    /// See `Value::Null`.
+    Null,
+
+    /// See `Value::Boolean`.
+    Boolean(bool),
+
+    /// See `Value::Integer`.
+    Integer(i32),
+
+    /// See `Value::Double`.
+    Double(f64),
+
+    /// See `Value::String`.
+    String(Cow<'a, str>),
+
+    /// See `Value::XmlDocument`.
+    XmlDocument(Cow<'a, str>),
+
+    /// See `Value::Date`.
+    Date {
+        /// Unix timestamp with milliseconds precision.
+        unix_time: time::Duration,
+    },
+
+    /// See `Value::Array`.
+    Array {
+        /// Entries of the associative part of the array.
+        assoc_entries: Vec<Pair<Cow<'a, str>, ValueRef<'a>>>,
+
+        /// Entries of the dense part of the array.
+        dense_entries: Vec<ValueRef<'a>>,
+    },
+
+    /// See `Value::Object`.
+    Object {
+        /// The class name of the object.
+        /// `None` means it is an anonymous object.
+        class_name: Option<Cow<'a, str>>,
+
+        /// Sealed member count of the object.
+        sealed_count: usize,
+
+        /// Members of the object.
+        entries: Vec<Pair<Cow<'a, str>, ValueRef<'a>>>,
+    },
+
+    /// See `Value::Xml`.
+    Xml(Cow<'a, str>),
+
+    /// See `Value::ByteArray`.
+    ByteArray(Cow<'a, [u8]>),
+
+    /// See `Value::IntVector`.
+    IntVector {
+        /// If `true`, this is a fixed-length vector.
+        is_fixed: bool,
+
+        /// The entries of the vector.
+        entries: Vec<i32>,
+    },
+
+    /// See `Value::UintVector`.
+    UintVector {
+        /// If `true`, this is a fixed-length vector.
+        is_fixed: bool,
+
+        /// The entries of the vector.
+        entries: Vec<u32>,
+    },
+
+    /// See `Value::DoubleVector`.
+    DoubleVector {
+        /// If `true`, this is a fixed-length vector.
+        is_fixed: bool,
+
+        /// The entries of the vector.
+        entries: Vec<f64>,
+    },
+
+    /// See `Value::ObjectVector`.
+    ObjectVector {
+        /// The base type name of entries in the vector.
+        /// `None` means it is the ANY type.
+        class_name: Option<Cow<'a, str>>,
+
+        /// If `true`, this is a fixed-length vector.
+        is_fixed: bool,
+
+        /// The entries of the vector.
+        entries: Vec<ValueRef<'a>>,
+    },
+
+    /// See `Value::Dictionary`.
+    Dictionary {
+        /// If `true`, the keys of `entries` are weakly referenced.
+        is_weak: bool,
+
+        /// The entries of the dictionary.
+        entries: Vec<Pair<ValueRef<'a>, ValueRef<'a>>>,
+    },
+
+    /// See `Value::Externalizable`.
+    Externalizable {
+        /// The class name of the object.
+        class_name: Cow<'a, str>,
+
+        /// The body of the object.
+        ///
+        /// If no handler is registered and `SliceDecoder::set_capture_unknown_externals` is enabled,
+        /// this is a `ByteArray` which borrows the rest of the input.
+        body: Box<ValueRef<'a>>,
+    },
+}
+impl<'a> ValueRef<'a> {
+    /// Reads an AMF3 encoded `ValueRef` from `input`, advancing it past the value.
+    ///
+    /// Like `Value::read_from`, reference objects are copied
+    /// and circular references are treated as errors.
+    pub fn read_from(input: &mut &'a [u8]) -> DecodeResult<Self> {
+        let mut decoder = SliceDecoder::new(input);
+        let value = decoder.decode()?;
+        *input = decoder.remaining();
+        Ok(value)
+    }
+
+    /// Converts this value into the owned `Value`.
+    pub fn into_owned(self) -> Value {
+        match self {
+            ValueRef::Undefined => Value::Undefined,
+            ValueRef::Null => Value::Null,
+            ValueRef::Boolean(x) => Value::Boolean(x),
+            ValueRef::Integer(x) => Value::Integer(x),
+            ValueRef::Double(x) => Value::Double(x),
+            ValueRef::String(x) => Value::String(x.into_owned()),
+            ValueRef::XmlDocument(x) => Value::XmlDocument(x.into_owned()),
+            ValueRef::Date { unix_time } => Value::Date { unix_time },
+            ValueRef::Array {
+                assoc_entries,
+                dense_entries,
+            } => Value::Array {
+                assoc_entries: into_owned_pairs(assoc_entries),
+                dense_entries: dense_entries.into_iter().map(Self::into_owned).collect(),
+            },
+            ValueRef::Object {
+                class_name,
+                sealed_count,
+                entries,
+            } => Value::Object {
+                class_name: class_name.map(Cow::into_owned),
+                sealed_count,
+                entries: into_owned_pairs(entries),
+            },
+            ValueRef::Xml(x) => Value::Xml(x.into_owned()),
+            ValueRef::ByteArray(x) => Value::ByteArray(x.into_owned()),
+            ValueRef::IntVector { is_fixed, entries } => Value::IntVector { is_fixed, entries },
+            ValueRef::UintVector { is_fixed, entries } => Value::UintVector { is_fixed, entries },
+            ValueRef::DoubleVector { is_fixed, entries } => {
+                Value::DoubleVector { is_fixed, entries }
+            }
+            ValueRef::ObjectVector {
+                class_name,
+                is_fixed,
+                entries,
+            } => Value::ObjectVector {
+                class_name: class_name.map(Cow::into_owned),
+                is_fixed,
+                entries: entries.into_iter().map(Self::into_owned).collect(),
+            },
+            ValueRef::Dictionary { is_weak, entries } => Value::Dictionary {
+                is_weak,
+                entries: entries
+                    .into_iter()
+                    .map(|p| Pair {
+                        key: p.key.into_owned(),
+                        value: p.value.into_owned(),
+                    })
+                    .collect(),
+            },
+            ValueRef::Externalizable { class_name, body } => Value::Externalizable {
+                class_name: class_name.into_owned(),
+                body: Box::new(body.into_owned()),
+            },
+        }
+    }
+
+    /// Tries to convert the value as a `str` reference.
+    pub fn try_as_str(&self) -> Option<&str> {
+        match *self {
+            ValueRef::String(ref x) => Some(x),
+            ValueRef::XmlDocument(ref x) => Some(x),
+            ValueRef::Xml(ref x) => Some(x),
+            _ => None,
+        }
+    }
+
+    /// Tries to convert the value as a `f64`.
+    pub fn try_as_f64(&self) -> Option<f64> {
+        match *self {
+            ValueRef::Integer(x) => Some(x as f64),
+            ValueRef::Double(x) => Some(x),
+            _ => None,
+        }
+    }
+
+    /// Tries to convert the string, the XML or the byte array into a `Bytes`.
+    ///
+    /// `input` is the bytes from which this value has been decoded.
+    /// The borrowed bytes are sliced from `input` without copying,
+    /// and the owned ones (e.g., read by an `ExternalizableHandler`) are copied.
+    ///
+    /// # Examples
+    /// ```
+    /// use amf::amf3::ValueRef;
+    /// use bytes::Bytes;
+    ///
+    /// let input = Bytes::from_static(b"\x0c\x07foo");
+    /// let value = ValueRef::read_from(&mut &input[..]).unwrap();
+    /// let bytes = value.to_bytes(&input).unwrap();
+    /// assert_eq!(bytes, &b"foo"[..]);
+    /// assert_eq!(bytes.as_ptr(), input[2..].as_ptr());
+    /// ```
+    #[cfg(feature = "bytes")]
+    pub fn to_bytes(&self, input: &bytes::Bytes) -> Option<bytes::Bytes> {
+        match *self {
+            ValueRef::String(ref x) => Some(crate::util::share_bytes(input, x.as_bytes())),
+            ValueRef::XmlDocument(ref x) => Some(crate::util::share_bytes(input, x.as_bytes())),
+            ValueRef::Xml(ref x) => Some(crate::util::share_bytes(input, x.as_bytes())),
+            ValueRef::ByteArray(ref x) => Some(crate::util::share_bytes(input, x)),
+            _ => None,
+        }
+    }
+}
+impl<'a> From<ValueRef<'a>> for Value {
+    fn from(f: ValueRef<'a>) -> Value {
+        f.into_owned()
+    }
+}
+impl<'a> From<Value> for ValueRef<'a> {
+    fn from(f: Value) -> ValueRef<'a> {
+        match f {
+            Value::Undefined => ValueRef::Undefined,
+            Value::Null => ValueRef::Null,
+            Value::Boolean(x) => ValueRef::Boolean(x),
+            Value::Integer(x) => ValueRef::Integer(x),
+            Value::Double(x) => ValueRef::Double(x),
+            Value::String(x) => ValueRef::String(Cow::Owned(x)),
+            Value::XmlDocument(x) => ValueRef::XmlDocument(Cow::Owned(x)),
+            Value::Date { unix_time } => ValueRef::Date { unix_time },
+            Value::Array {
+                assoc_entries,
+                dense_entries,
+            } => ValueRef::Array {
+                assoc_entries: from_owned_pairs(assoc_entries),
+                dense_entries: dense_entries.into_iter().map(ValueRef::from).collect(),
+            },
+            Value::Object {
+                class_name,
+                sealed_count,
+                entries,
+            } => ValueRef::Object {
+                class_name: class_name.map(Cow::Owned),
+                sealed_count,
+                entries: from_owned_pairs(entries),
+            },
+            Value::Xml(x) => ValueRef::Xml(Cow::Owned(x)),
+            Value::ByteArray(x) => ValueRef::ByteArray(Cow::Owned(x)),
+            Value::IntVector { is_fixed, entries } => ValueRef::IntVector { is_fixed, entries },
+            Value::UintVector { is_fixed, entries } => ValueRef::UintVector { is_fixed, entries },
+            Value::DoubleVector { is_fixed, entries } => {
+                ValueRef::DoubleVector { is_fixed, entries }
+            }
+            Value::ObjectVector {
+                class_name,
+                is_fixed,
+                entries,
+            } => ValueRef::ObjectVector {
+                class_name: class_name.map(Cow::Owned),
+                is_fixed,
+                entries: entries.into_iter().map(ValueRef::from).collect(),
+            },
+            Value::Dictionary { is_weak, entries } => ValueRef::Dictionary {
+                is_weak,
+                entries: entries
+                    .into_iter()
+                    .map(|p| Pair {
+                        key: p.key.into(),
+                        value: p.value.into(),
+                    })
+                    .collect(),
+            },
+            Value::Externalizable { class_name, body } => ValueRef::Externalizable {
+                class_name: Cow::Owned(class_name),
+                body: Box::new((*body).into()),
+            },
+        }
+    }
+}
+
+fn into_owned_pairs(pairs: Vec<Pair<Cow<str>, ValueRef>>) -> Vec<Pair<String, Value>> {
+    pairs
+        .into_iter()
+        .map(|p| Pair {
+            key: p.key.into_owned(),
+            value: p.value.into_owned(),
+        })
+        .collect()
+}
+fn from_owned_pairs<'a>(pairs: Vec<Pair<String, Value>>) -> Vec<Pair<Cow<'a, str>, ValueRef<'a>>> {
+    pairs
+        .into_iter()
+        .map(|p| Pair {
+            key: Cow::Owned(p.key),
+            value: p.value.into(),
+        })
+        .collect()
+}
+
+#[derive(Debug)]
+struct TraitRef<'a> {
+    class_name: Option<Cow<'a, str>>,
+    is_dynamic: bool,
+    is_externalizable: bool,
+    fields: Vec<Cow<'a, str>>,
+}
+impl<'a> TraitRef<'a> {
+    fn to_owned_trait(&self) -> Trait {
+        Trait {
+            class_name: self.class_name.as_ref().map(|s| s.to_string()),
+            is_dynamic: self.is_dynamic,
+            is_externalizable: self.is_externalizable,
+            fields: self.fields.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+}
+impl<'a> From<Trait> for TraitRef<'a> {
+    fn from(f: Trait) -> Self {
+        TraitRef {
+            class_name: f.class_name.map(Cow::Owned),
+            is_dynamic: f.is_dynamic,
+            is_externalizable: f.is_externalizable,
+            fields: f.fields.into_iter().map(Cow::Owned).collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Complex<'a> {
+    // The value is being decoded, so a reference to it is circular
+    Decoding,
+    // The encoded bytes of the value, which are decoded again when the value is referred
+    Decoded(&'a [u8]),
+    // A value read by an `ExternalizableHandler`
+    External(NodeId),
+}
+
+/// AMF3 decoder which decodes `ValueRef` values from a byte slice.
+///
+/// Unlike `Decoder`, the strings and the byte arrays are borrowed from the input.
+/// A referred complex value is not kept in the reference table but decoded again from the input,
+/// so the values which are never referred cost nothing more than decoding them.
+///
+/// The values read by an `ExternalizableHandler` are owned,
+/// and those cannot refer to the complex values outside of the externalizable object.
+///
+/// # Examples
+/// ```
+/// use amf::amf3::{SliceDecoder, ValueRef};
+/// use std::borrow::Cow;
+///
+/// // "foo", and a reference to it
+/// let input = b"\x06\x07foo\x06\x00";
+/// let mut decoder = SliceDecoder::new(&input[..]);
+/// assert_eq!(decoder.decode().unwrap(), ValueRef::String(Cow::Borrowed("foo")));
+/// assert_eq!(decoder.decode().unwrap(), ValueRef::String(Cow::Borrowed("foo")));
+/// assert!(decoder.remaining().is_empty());
+/// ```
+#[derive(Debug)]
+pub struct SliceDecoder<'a> {
+    input: &'a [u8],
+    // The address of the beginning of the input, from which the offsets of the errors are counted
+    origin: usize,
+    traits: Vec<Rc<TraitRef<'a>>>,
+    strings: Vec<Cow<'a, str>>,
+    complexes: Vec<Complex<'a>>,
+    // Greater than zero while a referred value is decoded again
+    replaying: usize,
+    // Decodes the bodies of externalizable objects, with mirrors of the reference tables,
+    // and is taken out while it decodes one
+    external: Option<Decoder<io::Empty>>,
+    capture_unknown_externals: bool,
+    // The location of the last error detected in the input
+    pub(crate) location: Option<Location>,
+    pub(crate) budget: Budget,
+}
+impl<'a> SliceDecoder<'a> {
+    /// Makes a new instance.
+    pub fn new(input: &'a [u8]) -> Self {
+        Self::with_limits(input, DecoderLimits::default())
+    }
+
+    /// Makes a new instance with the specified limits.
+    ///
+    /// Every value decoded again for a reference counts as a reference expansion.
+    pub fn with_limits(input: &'a [u8], limits: DecoderLimits) -> Self {
+        SliceDecoder {
+            input,
+            origin: input.as_ptr() as usize,
+            traits: Vec::new(),
+            strings: Vec::new(),
+            complexes: Vec::new(),
+            replaying: 0,
+            external: Some(Decoder::new(io::empty())),
+            capture_unknown_externals: false,
+            location: None,
+            budget: Budget::new(limits),
+        }
+    }
+
+    /// Returns the limits of this decoder.
+    pub fn limits(&self) -> &DecoderLimits {
+        &self.budget.limits
+    }
+
+    /// Returns a mutable reference to the limits of this decoder.
+    pub fn limits_mut(&mut self) -> &mut DecoderLimits {
+        &mut self.budget.limits
+    }
+
+    /// Returns the rest of the input which has not been decoded yet.
+    pub fn remaining(&self) -> &'a [u8] {
+        self.input
+    }
+
+    /// Returns the location of the value which failed to decode in the last call of `decode`.
+    ///
+    /// This is `None` if the last call succeeded.
+    pub fn error_location(&self) -> Option<&Location> {
+        self.location.as_ref()
+    }
+
+    /// Returns a mutable reference to the registry of the externalizable class handlers.
+    pub fn externalizables_mut(&mut self) -> &mut ExternalizableRegistry {
+        self.external_mut().externalizables_mut()
+    }
+
+    /// Enables or disables capturing the bodies of the externalizable objects which have no handler.
+    ///
+    /// If enabled, the body of such an object is decoded as a `ValueRef::ByteArray`
+    /// which borrows all the rest of the input, because the length of the body is unknown.
+    /// If disabled, which is the default, `DecodeError::ExternalizableType` is returned.
+    pub fn set_capture_unknown_externals(&mut self, enabled: bool) {
+        self.capture_unknown_externals = enabled;
+    }
+
+    /// Decodes a AMF3 value.
+    pub fn decode(&mut self) -> DecodeResult<ValueRef<'a>> {
+        self.budget.start_value();
+        self.location = None;
+        self.decode_value()
+    }
+
+    /// Clear the reference tables of this decoder.
+    pub fn clear_reference_table(&mut self) {
+        self.traits.clear();
+        self.strings.clear();
+        self.complexes.clear();
+        self.external_mut().clear_reference_table();
+        self.budget.clear_expansions();
+    }
+
+    // Decodes a value from `input` which is a part of the same message,
+    // e.g., the `AvmPlus` values in an AMF0 message share the reference tables.
+    pub(crate) fn decode_from(
+        &mut self,
+        input: &mut &'a [u8],
+        replaying: bool,
+    ) -> DecodeResult<ValueRef<'a>> {
+        self.input = *input;
+        if replaying {
+            self.replaying += 1;
+        }
+        let result = self.decode_value();
+        if replaying {
+            self.replaying -= 1;
+        }
+        *input = mem::take(&mut self.input);
+        result
+    }
+
+    fn decode_value(&mut self) -> DecodeResult<ValueRef<'a>> {
+        let offset = self.offset();
+        let result = self.decode_marked_value();
+        if result.is_err() {
+            Location::record(&mut self.location, offset);
+        }
+        result
+    }
+    fn decode_marked_value(&mut self) -> DecodeResult<ValueRef<'a>> {
+        if self.replaying > 0 {
+            self.budget.expand(1)?;
+        }
+        let start = self.input;
+        let marker = read_u8(&mut self.input)?;
+        match marker {
+            marker::UNDEFINED => Ok(ValueRef::Undefined),
+            marker::NULL => Ok(ValueRef::Null),
+            marker::FALSE => Ok(ValueRef::Boolean(false)),
+            marker::TRUE => Ok(ValueRef::Boolean(true)),
+            marker::INTEGER => {
+                let n = self.decode_u29()? as i32;
+                let n = if n >= (1 << 28) { n - (1 << 29) } else { n };
+                Ok(ValueRef::Integer(n))
+            }
+            marker::DOUBLE => read_f64(&mut self.input).map(ValueRef::Double),
+            marker::STRING => self.decode_utf8().map(ValueRef::String),
+            marker::XML_DOC => self.decode_complex_type(start, |this, len| {
+                this.budget.string(len)?;
+                read_str(&mut this.input, len).map(|s| ValueRef::XmlDocument(Cow::Borrowed(s)))
+            }),
+            marker::DATE => self.decode_complex_type(start, |this, _| {
+                let millis = read_f64(&mut this.input)?;
+                if !(millis.is_finite() && millis.is_sign_positive()) {
+                    Err(DecodeError::InvalidDate { millis })
+                } else {
+                    Ok(ValueRef::Date {
+                        unix_time: time::Duration::from_millis(millis as u64),
+                    })
+                }
+            }),
+            marker::ARRAY => self.decode_complex_type(start, |this, count| {
+                this.budget.collection::<ValueRef>(count)?;
+                let assoc_entries = this.decode_pairs()?;
+                let dense_entries = (0..count)
+                    .map(|i| this.decode_entry(i))
+                    .collect::<DecodeResult<_>>()?;
+                Ok(ValueRef::Array {
+                    assoc_entries,
+                    dense_entries,
+                })
+            }),
+            marker::OBJECT => self.decode_complex_type(start, Self::decode_object),
+            marker::XML => self.decode_complex_type(start, |this, len| {
+                this.budget.string(len)?;
+                read_str(&mut this.input, len).map(|s| ValueRef::Xml(Cow::Borrowed(s)))
+            }),
+            marker::BYTE_ARRAY => self.decode_complex_type(start, |this, len| {
+                this.budget.byte_array(len)?;
+                read_slice(&mut this.input, len).map(|b| ValueRef::ByteArray(Cow::Borrowed(b)))
+            }),
+            marker::VECTOR_INT => self.decode_complex_type(start, |this, count| {
+                let is_fixed = read_u8(&mut this.input)? != 0;
+                this.budget.collection::<i32>(count)?;
+                let entries = read_numbers(&mut this.input, count, BigEndian::read_i32)?;
+                Ok(ValueRef::IntVector { is_fixed, entries })
+            }),
+            marker::VECTOR_UINT => self.decode_complex_type(start, |this, count| {
+                let is_fixed = read_u8(&mut this.input)? != 0;
+                this.budget.collection::<u32>(count)?;
+                let entries = read_numbers(&mut this.input, count, BigEndian::read_u32)?;
+                Ok(ValueRef::UintVector { is_fixed, entries })
+            }),
+            marker::VECTOR_DOUBLE => self.decode_complex_type(start, |this, count| {
+                let is_fixed = read_u8(&mut this.input)? != 0;
+                this.budget.collection::<f64>(count)?;
+                let entries = read_numbers(&mut this.input, count, BigEndian::read_f64)?;
+                Ok(ValueRef::DoubleVector { is_fixed, entries })
+            }),
+            marker::VECTOR_OBJECT => self.decode_complex_type(start, |this, count| {
+                let is_fixed = read_u8(&mut this.input)? != 0;
+                let class_name = this.decode_utf8()?;
+                this.budget.collection::<ValueRef>(count)?;
+                let entries = (0..count)
+                    .map(|i| this.decode_entry(i))
+                    .collect::<DecodeResult<_>>()?;
+                Ok(ValueRef::ObjectVector {
+                    class_name: if class_name == "*" {
+                        None
+                    } else {
+                        Some(class_name)
+                    },
+                    is_fixed,
+                    entries,
+                })
+            }),
+            marker::DICTIONARY => self.decode_complex_type(start, |this, count| {
+                let is_weak = read_u8(&mut this.input)? == 1;
+                this.budget.collection::<Pair<ValueRef, ValueRef>>(count)?;
+                let entries = (0..count)
+                    .map(|i| {
+                        Ok(Pair {
+                            key: this.decode_entry(i)?,
+                            value: this.decode_entry(i)?,
+                        })
+                    })
+                    .collect::<DecodeResult<_>>()?;
+                Ok(ValueRef::Dictionary { is_weak, entries })
+            }),
+            _ => Err(DecodeError::Unknown { marker }),
+        }
+    }
+    fn decode_object(&mut self, u28: usize) -> DecodeResult<ValueRef<'a>> {
+        let amf_trait = self.decode_trait(u28)?;
+        if amf_trait.is_externalizable {
+            let class_name = amf_trait.class_name.clone().unwrap_or_default();
+            let body = self.decode_external(&class_name)?;
+            return Ok(ValueRef::Externalizable {
+                class_name,
+                body: Box::new(body),
+            });
+        }
+        let mut entries = amf_trait
+            .fields
+            .iter()
+            .map(|k| {
+                Ok(Pair {
+                    key: k.clone(),
+                    value: self.decode_member(k)?,
+                })
+            })
+            .collect::<DecodeResult<Vec<_>>>()?;
+        if amf_trait.is_dynamic {
+            entries.extend(self.decode_pairs()?);
+        }
+        Ok(ValueRef::Object {
+            class_name: amf_trait.class_name.clone(),
+            sealed_count: amf_trait.fields.len(),
+            entries,
+        })
+    }
+    fn decode_utf8(&mut self) -> DecodeResult<Cow<'a, str>> {
+        match self.decode_size_or_index()? {
+            SizeOrIndex::Size(len) => {
+                self.budget.string(len)?;
+                let s = read_str(&mut self.input, len)?;
+                if !s.is_empty() && self.replaying == 0 {
+                    self.strings.push(Cow::Borrowed(s));
+                }
+                Ok(Cow::Borrowed(s))
+            }
+            SizeOrIndex::Index(index) => self
+                .strings
+                .get(index)
+                .cloned()
+                .ok_or(DecodeError::OutOfRangeReference { index }),
+        }
+    }
+    fn decode_u29(&mut self) -> DecodeResult<u32> {
+        let mut n = 0;
+        for _ in 0..3 {
+            let b = read_u8(&mut self.input)? as u32;
+            n = (n << 7) | (b & 0b0111_1111);
+            if (b & 0b1000_0000) == 0 {
+                return Ok(n);
+            }
+        }
+        let b = read_u8(&mut self.input)? as u32;
+        n = (n << 8) | b;
+        Ok(n)
+    }
+    fn decode_size_or_index(&mut self) -> DecodeResult<SizeOrIndex> {
+        let u29 = self.decode_u29()? as usize;
+        let is_reference = (u29 & 0b01) == 0;
+        let value = u29 >> 1;
+        if is_reference {
+            Ok(SizeOrIndex::Index(value))
+        } else {
+            Ok(SizeOrIndex::Size(value))
+        }
+    }
+    fn decode_complex_type<F>(&mut self, start: &'a [u8], f: F) -> DecodeResult<ValueRef<'a>>
+    where
+        F: FnOnce(&mut Self, usize) -> DecodeResult<ValueRef<'a>>,
+    {
+        match self.decode_size_or_index()? {
+            SizeOrIndex::Index(index) => self.decode_reference(index),
+            SizeOrIndex::Size(u28) if self.replaying > 0 => self.decode_nested(u28, f),
+            SizeOrIndex::Size(u28) => {
+                let index = self.complexes.len();
+                self.complexes.push(Complex::Decoding);
+                let result = self.decode_nested(u28, f);
+                match result {
+                    Ok(_) => self.complexes[index] = Complex::Decoded(start),
+                    Err(_) => self.complexes.truncate(index),
+                }
+                result
+            }
+        }
+    }
+    fn decode_nested<F>(&mut self, u28: usize, f: F) -> DecodeResult<ValueRef<'a>>
+    where
+        F: FnOnce(&mut Self, usize) -> DecodeResult<ValueRef<'a>>,
+    {
+        self.budget.enter()?;
+        let result = f(self, u28);
+        self.budget.leave();
+        result
+    }
+    fn decode_reference(&mut self, index: usize) -> DecodeResult<ValueRef<'a>> {
+        match self.complexes.get(index) {
+            None => Err(DecodeError::OutOfRangeReference { index }),
+            Some(&Complex::Decoding) => Err(DecodeError::CircularReference { index }),
+            Some(&Complex::Decoded(mut start)) => {
+                let input = self.input;
+                let result = self.decode_from(&mut start, true);
+                self.input = input;
+                result
+            }
+            Some(&Complex::External(id)) => {
+                let value = self.external().graph.to_tree(&graph::Value::Node(id))?;
+                Ok(value.into())
+            }
+        }
+    }
+    fn decode_pairs(&mut self) -> DecodeResult<Vec<Pair<Cow<'a, str>, ValueRef<'a>>>> {
+        let mut pairs = Vec::new();
+        loop {
+            let key = self.decode_utf8()?;
+            if key.is_empty() {
+                return Ok(pairs);
+            }
+            self.budget
+                .entry::<Pair<Cow<str>, ValueRef>>(pairs.len() + 1)?;
+            let value = self.decode_member(&key)?;
+            pairs.push(Pair { key, value });
+        }
+    }
+    fn decode_entry(&mut self, index: usize) -> DecodeResult<ValueRef<'a>> {
+        let result = self.decode_value();
+        if result.is_err() {
+            Location::prepend(&mut self.location, PathSegment::Index(index));
+        }
+        result
+    }
+    fn decode_member(&mut self, key: &str) -> DecodeResult<ValueRef<'a>> {
+        let result = self.decode_value();
+        if result.is_err() {
+            Location::prepend(&mut self.location, PathSegment::Key(key.to_owned()));
+        }
+        result
+    }
+    fn offset(&self) -> u64 {
+        (self.input.as_ptr() as usize).wrapping_sub(self.origin) as u64
+    }
+    fn decode_trait(&mut self, u28: usize) -> DecodeResult<Rc<TraitRef<'a>>> {
+        if (u28 & 0b1) == 0 {
+            let i = u28 >> 1;
+            self.traits
+                .get(i)
+                .cloned()
+                .ok_or(DecodeError::OutOfRangeReference { index: i })
+        } else {
+            let is_externalizable = (u28 & 0b10) != 0;
+            let is_dynamic = !is_externalizable && (u28 & 0b100) != 0;
+            let field_num = if is_externalizable { 0 } else { u28 >> 3 };
+            let class_name = self.decode_utf8()?;
+            self.budget.collection::<Cow<str>>(field_num)?;
+            let fields = (0..field_num)
+                .map(|_| self.decode_utf8())
+                .collect::<DecodeResult<_>>()?;
+
+            let t = Rc::new(TraitRef {
+                class_name: if class_name.is_empty() {
+                    None
+                } else {
+                    Some(class_name)
+                },
+                is_dynamic,
+                is_externalizable,
+                fields,
+            });
+            if self.replaying == 0 {
+                self.traits.push(t.clone());
+            }
+            Ok(t)
+        }
+    }
+    fn external(&self) -> &Decoder<io::Empty> {
+        self.external.as_ref().expect("the decoder is put back")
+    }
+    fn external_mut(&mut self) -> &mut Decoder<io::Empty> {
+        self.external.as_mut().expect("the decoder is put back")
+    }
+    fn decode_external(&mut self, class_name: &str) -> DecodeResult<ValueRef<'a>> {
+        if self.external().externalizables.get(class_name).is_none() {
+            if !self.capture_unknown_externals {
+                return Err(DecodeError::ExternalizableType {
+                    name: class_name.to_string(),
+                });
+            }
+            let len = self.input.len();
+            self.budget.byte_array(len)?;
+            let body = read_slice(&mut self.input, len)?;
+            return Ok(ValueRef::ByteArray(Cow::Borrowed(body)));
+        }
+
+        // The handler reads the body through `Decoder`,
+        // so the reference tables are copied to it and the new entries are copied back
+        let external = self.external.take().expect("the decoder is put back");
+        let (mut decoder, empty) = external.with_inner(self.input);
+        decoder.set_offset(self.offset());
+        for s in &self.strings[decoder.strings.len()..] {
+            decoder.strings.push(s.to_string());
+        }
+        for t in &self.traits[decoder.traits.len()..] {
+            decoder.traits.push(t.to_owned_trait());
+        }
+        decoder
+            .complexes
+            .resize_with(self.complexes.len(), || Slot::Read);
+        let strings = decoder.strings.len();
+        let traits = decoder.traits.len();
+        let complexes = decoder.complexes.len();
+
+        decoder.budget = self.budget;
+        let mut graph = mem::take(&mut decoder.graph);
+        let result = decoder
+            .decode_external(&mut graph, class_name)
+            .and_then(|body| graph.to_tree(&body));
+        decoder.graph = graph;
+        self.location = decoder.location.take();
+        self.budget = decoder.budget;
+
+        if self.replaying > 0 {
+            decoder.strings.truncate(strings);
+            decoder.traits.truncate(traits);
+            decoder.complexes.truncate(complexes);
+        } else {
+            self.strings
+                .extend(decoder.strings[strings..].iter().cloned().map(Cow::Owned));
+            self.traits.extend(
+                decoder.traits[traits..]
+                    .iter()
+                    .cloned()
+                    .map(|t| Rc::new(t.into())),
+            );
+            // The complex values read by the handler are always added to the graph
+            self.complexes.extend(
+                decoder.complexes[complexes..]
+                    .iter()
+                    .filter_map(Slot::node)
+                    .map(Complex::External),
+            );
+        }
+        let (external, input) = decoder.with_inner(empty);
+        self.external = Some(external);
+        self.input = input;
+        result.map(ValueRef::from)
+    }
+}
+
+fn unexpected_eof() -> DecodeError {
+    DecodeError::Io(io::ErrorKind::UnexpectedEof.into())
+}
+
+pub(crate) fn read_u8(input: &mut &[u8]) -> DecodeResult<u8> {
+    let (&b, rest) = input.split_first().ok_or_else(unexpected_eof)?;
+    *input = rest;
+    Ok(b)
+}
+pub(crate) fn read_slice<'a>(input: &mut &'a [u8], len: usize) -> DecodeResult<&'a [u8]> {
+    if input.len() < len {
+        return Err(unexpected_eof());
+    }
+    let (bytes, rest) = input.split_at(len);
+    *input = rest;
+    Ok(bytes)
+}
+pub(crate) fn read_str<'a>(input: &mut &'a [u8], len: usize) -> DecodeResult<&'a str> {
+    let bytes = read_slice(input, len)?;
+    match str::from_utf8(bytes) {
+        Ok(s) => Ok(s),
+        Err(_) => Err(String::from_utf8(bytes.to_vec()).unwrap_err().into()),
+    }
+}
+pub(crate) fn read_f64(input: &mut &[u8]) -> DecodeResult<f64> {
+    read_slice(input, 8).map(BigEndian::read_f64)
+}
+fn read_numbers<T>(input: &mut &[u8], count: usize, f: fn(&[u8]) -> T) -> DecodeResult<Vec<T>> {
+    let size = mem::size_of::<T>();
+    let len = count.checked_mul(size).ok_or_else(unexpected_eof)?;
+    let bytes = read_slice(input, len)?;
+    Ok(bytes.chunks_exact(size).map(f).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Decoder, SliceDecoder, Value, ValueRef};
+    use crate::error::DecodeError;
+    use crate::DecodeResult;
+    use std::borrow::Cow;
+    use std::fs;
+
+    fn same_result(x: &DecodeResult<Value>, y: &DecodeResult<Value>) -> bool {
+        match (x, y) {
+            (Err(DecodeError::Io(x)), Err(DecodeError::Io(y))) => x.kind() == y.kind(),
+            (Err(DecodeError::String(_)), Err(DecodeError::String(_))) => true,
+            // NaN is not equal to itself
+            (Ok(x), Ok(y)) => format!("{:?}", x) == format!("{:?}", y),
+            _ => x == y,
+        }
+    }
+
+    #[test]
+    fn decodes_same_values_as_decoder() {
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/src/testdata");
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if !path
+                .file_name()
+                .unwrap()
+                .to_str()
+                .unwrap()
+                .starts_with("amf3-")
+            {
+                continue;
+            }
+            let input = fs::read(&path).unwrap();
+
+            let mut decoder = Decoder::new(&input[..]);
+            let expected = decoder.decode();
+            let mut slice_decoder = SliceDecoder::new(&input[..]);
+            let actual = slice_decoder.decode().map(ValueRef::into_owned);
+            assert!(
+                same_result(&actual, &expected),
+                "{:?}: {:?} != {:?}",
+                path,
+                actual,
+                expected
+            );
+            // `Decoder` detects circular references after decoding, so those are not located
+            if !matches!(expected, Err(DecodeError::CircularReference { .. })) {
+                assert_eq!(
+                    slice_decoder.error_location(),
+                    decoder.error_location(),
+                    "{:?}",
+                    path
+                );
+            }
+            if expected.is_ok() {
+                assert_eq!(
+                    slice_decoder.remaining(),
+                    decoder.into_inner(),
+                    "{:?}",
+                    path
+                );
+            }
+        }
+    }
+    #[test]
+    fn borrows_strings() {
+        let input = include_bytes!("../testdata/amf3-string-ref.bin");
+        let value = ValueRef::read_from(&mut &input[..]).unwrap();
+        let ValueRef::Array { dense_entries, .. } = value else {
+            panic!("{:?}", value);
+        };
+        assert!(matches!(
+            dense_entries[2],
+            ValueRef::String(Cow::Borrowed("foo"))
+        ));
+        let ValueRef::Object { ref entries, .. } = dense_entries[5] else {
+            panic!("{:?}", dense_entries[5]);
+        };
+        assert!(matches!(entries[0].key, Cow::Borrowed("str")));
+
+        let input = include_bytes!("../testdata/amf3-byte-array.bin");
+        let value = ValueRef::read_from(&mut &input[..]).unwrap();
+        assert!(matches!(value, ValueRef::ByteArray(Cow::Borrowed(_))));
+    }
+    #[test]
+    fn decodes_references_to_externalizable_contents() {
+        let input = include_bytes!("../testdata/amf3-complex-array-collection.bin");
+        let expected = Value::read_from(&mut &input[..]).unwrap();
+        let value = ValueRef::read_from(&mut &input[..]).unwrap();
+        assert_eq!(Value::from(value.clone()), expected);
+        assert_eq!(ValueRef::from(expected), value);
+    }
+    #[cfg(feature = "bytes")]
+    #[test]
+    fn shares_bytes() {
+        let input = bytes::Bytes::from_static(b"\x0c\x07foo");
+        let value = ValueRef::read_from(&mut &input[..]).unwrap();
+        let shared = value.to_bytes(&input).unwrap();
+        assert_eq!(shared, &b"foo"[..]);
+        assert_eq!(shared.as_ptr(), input[2..].as_ptr());
+
+        // The owned bytes are copied
+        let value = ValueRef::String(Cow::Owned("bar".to_string()));
+        let copied = value.to_bytes(&input).unwrap();
+        assert_eq!(copied, &b"bar"[..]);
+        assert!(!input.as_ptr_range().contains(&copied.as_ptr()));
+
+        assert_eq!(ValueRef::Null.to_bytes(&input), None);
+    }
+}
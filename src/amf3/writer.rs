@@ -0,0 +1,350 @@
+use super::marker;
+use super::Trait;
+use super::{Encoder, ExternalizableRegistry, Value};
+use crate::error::EncodeError;
+use crate::io::{self, WriteBytesExt};
+use crate::{EncodeResult, EncoderOptions};
+use alloc::string::ToString;
+use alloc::vec::Vec;
+use core::mem;
+use core::time;
+
+#[derive(Debug)]
+enum Frame {
+    Object,
+    Array { is_assoc: bool, remaining: usize },
+}
+
+/// AMF3 push writer.
+///
+/// Unlike `Encoder`, this writes values incrementally without building the values in memory.
+/// The reference tables are shared by all values written by a writer,
+/// including the ones written by `write_value`.
+///
+/// # Examples
+/// ```
+/// use amf::amf3::{Value, Writer};
+///
+/// let mut writer = Writer::new(Vec::new());
+/// writer.begin_array(2).unwrap();
+/// for name in &["foo", "bar"] {
+///     writer.begin_object(None).unwrap();
+///     writer.key("name").unwrap();
+///     writer.write_string(name).unwrap();
+///     writer.end().unwrap();
+/// }
+/// writer.end().unwrap();
+///
+/// let buf = writer.into_inner();
+/// let value = Value::read_from(&mut &buf[..]).unwrap();
+/// assert_eq!(value.try_into_values().unwrap().count(), 2);
+/// ```
+#[derive(Debug)]
+pub struct Writer<W> {
+    encoder: Encoder<W>,
+    stack: Vec<Frame>,
+    expects_value: bool,
+}
+impl<W> Writer<W> {
+    /// Unwraps this `Writer`, returning the underlying writer.
+    pub fn into_inner(self) -> W {
+        self.encoder.into_inner()
+    }
+
+    /// Returns a mutable reference to the registry of the externalizable class handlers.
+    pub fn externalizables_mut(&mut self) -> &mut ExternalizableRegistry {
+        self.encoder.externalizables_mut()
+    }
+}
+impl<W> Writer<W>
+where
+    W: io::Write,
+{
+    /// Makes a new instance.
+    pub fn new(inner: W) -> Self {
+        Self::with_options(inner, EncoderOptions::default())
+    }
+
+    /// Makes a new instance with the specified options.
+    pub fn with_options(inner: W, options: EncoderOptions) -> Self {
+        Writer {
+            encoder: Encoder::with_options(inner, options),
+            stack: Vec::new(),
+            expects_value: false,
+        }
+    }
+
+    /// Writes `undefined`.
+    pub fn write_undefined(&mut self) -> EncodeResult<()> {
+        self.before_value()?;
+        self.encoder.encode_undefined()
+    }
+
+    /// Writes `null`.
+    pub fn write_null(&mut self) -> EncodeResult<()> {
+        self.before_value()?;
+        self.encoder.encode_null()
+    }
+
+    /// Writes a boolean.
+    pub fn write_boolean(&mut self, b: bool) -> EncodeResult<()> {
+        self.before_value()?;
+        self.encoder.encode_boolean(b)
+    }
+
+    /// Writes an integer.
+    pub fn write_integer(&mut self, i: i32) -> EncodeResult<()> {
+        self.before_value()?;
+        self.encoder.encode_integer(i)
+    }
+
+    /// Writes a double.
+    pub fn write_double(&mut self, d: f64) -> EncodeResult<()> {
+        self.before_value()?;
+        self.encoder.encode_double(d)
+    }
+
+    /// Writes a string.
+    pub fn write_string(&mut self, s: &str) -> EncodeResult<()> {
+        self.before_value()?;
+        self.encoder.encode_string(s)
+    }
+
+    /// Writes an XML document.
+    pub fn write_xml_document(&mut self, xml: &str) -> EncodeResult<()> {
+        self.before_complex()?;
+        self.encoder.encode_xml_document(xml)
+    }
+
+    /// Writes a date.
+    pub fn write_date(&mut self, unix_time: time::Duration) -> EncodeResult<()> {
+        self.before_complex()?;
+        self.encoder.encode_date(unix_time)
+    }
+
+    /// Writes an XML.
+    pub fn write_xml(&mut self, xml: &str) -> EncodeResult<()> {
+        self.before_complex()?;
+        self.encoder.encode_xml(xml)
+    }
+
+    /// Writes a byte array.
+    pub fn write_byte_array(&mut self, bytes: &[u8]) -> EncodeResult<()> {
+        self.before_complex()?;
+        self.encoder.encode_byte_array(bytes)
+    }
+
+    /// Writes a whole value.
+    pub fn write_value(&mut self, value: &Value) -> EncodeResult<()> {
+        self.before_value()?;
+        self.encoder.encode(value)
+    }
+
+    /// Begins a dynamic object.
+    ///
+    /// The members are written by pairs of `key` and a value, then the object is closed by `end`.
+    pub fn begin_object(&mut self, class_name: Option<&str>) -> EncodeResult<()> {
+        self.before_complex()?;
+        self.encoder.inner.write_u8(marker::OBJECT)?;
+        self.encoder.encode_trait(Trait {
+            class_name: class_name.map(|s| s.to_string()),
+            is_dynamic: true,
+            is_externalizable: false,
+            fields: Vec::new(),
+        })?;
+        self.stack.push(Frame::Object);
+        Ok(())
+    }
+
+    /// Begins an array which has `dense_len` dense entries.
+    ///
+    /// The associative entries are written by pairs of `key` and a value,
+    /// and followed by exactly `dense_len` values. Then the array is closed by `end`.
+    pub fn begin_array(&mut self, dense_len: usize) -> EncodeResult<()> {
+        self.before_complex()?;
+        self.encoder.inner.write_u8(marker::ARRAY)?;
+        self.encoder.encode_size(dense_len)?;
+        self.stack.push(Frame::Array {
+            is_assoc: true,
+            remaining: dense_len,
+        });
+        Ok(())
+    }
+
+    /// Writes the key of a member of an object or an associative entry of an array.
+    ///
+    /// The value of the entry must follow.
+    pub fn key(&mut self, key: &str) -> EncodeResult<()> {
+        if self.expects_value {
+            return Err(invalid_state("a value is expected"));
+        }
+        match self.stack.last() {
+            Some(&Frame::Object) | Some(&Frame::Array { is_assoc: true, .. }) => {}
+            _ => return Err(invalid_state("a key is not allowed here")),
+        }
+        if key.is_empty() {
+            return Err(invalid_state("an empty key cannot be written"));
+        }
+        self.encoder.encode_utf8(key)?;
+        self.expects_value = true;
+        Ok(())
+    }
+
+    /// Closes the innermost object or array.
+    pub fn end(&mut self) -> EncodeResult<()> {
+        if self.expects_value {
+            return Err(invalid_state("a value is expected"));
+        }
+        match self.stack.pop() {
+            None => Err(invalid_state("no object or array to end")),
+            Some(Frame::Object) => self.encoder.encode_utf8(""),
+            Some(Frame::Array {
+                is_assoc,
+                remaining: 0,
+            }) => {
+                if is_assoc {
+                    self.encoder.encode_utf8("")?;
+                }
+                Ok(())
+            }
+            Some(frame) => {
+                self.stack.push(frame);
+                Err(invalid_state("too few array entries"))
+            }
+        }
+    }
+
+    /// Clear the reference tables of this writer.
+    pub fn clear_reference_table(&mut self) {
+        self.encoder.clear_reference_table();
+    }
+
+    fn before_complex(&mut self) -> EncodeResult<()> {
+        self.before_value()?;
+        self.encoder.complex_count += 1;
+        Ok(())
+    }
+    fn before_value(&mut self) -> EncodeResult<()> {
+        if mem::replace(&mut self.expects_value, false) {
+            return Ok(());
+        }
+        match self.stack.last_mut() {
+            None => Ok(()),
+            Some(&mut Frame::Object) => Err(invalid_state("a key is expected")),
+            Some(&mut Frame::Array {
+                ref mut is_assoc,
+                ref mut remaining,
+            }) => {
+                if *remaining == 0 {
+                    return Err(invalid_state("too many array entries"));
+                }
+                if *is_assoc {
+                    self.encoder.encode_utf8("")?;
+                    *is_assoc = false;
+                }
+                *remaining -= 1;
+                Ok(())
+            }
+        }
+    }
+}
+
+fn invalid_state(reason: &'static str) -> EncodeError {
+    EncodeError::InvalidWriterState { reason }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Encoder, Value, Writer};
+    use crate::error::EncodeError;
+    use crate::Pair;
+
+    #[test]
+    fn writes_values() {
+        let mut writer = Writer::new(Vec::new());
+        writer.begin_array(2).unwrap();
+        writer.key("foo").unwrap();
+        writer.write_string("bar").unwrap();
+        for i in 0..2 {
+            writer.begin_object(Some("org.amf.ASClass")).unwrap();
+            writer.key("foo").unwrap();
+            writer.write_integer(i).unwrap();
+            writer.key("bar").unwrap();
+            writer.write_byte_array(b"baz").unwrap();
+            writer.end().unwrap();
+        }
+        writer.end().unwrap();
+        writer.write_value(&Value::Double(1.5)).unwrap();
+
+        let object = |i| Value::Object {
+            class_name: Some("org.amf.ASClass".to_string()),
+            sealed_count: 0,
+            entries: vec![
+                pair("foo", Value::Integer(i)),
+                pair("bar", Value::ByteArray(b"baz".to_vec())),
+            ],
+        };
+        let array = Value::Array {
+            assoc_entries: vec![pair("foo", Value::String("bar".to_string()))],
+            dense_entries: vec![object(0), object(1)],
+        };
+        let mut encoder = Encoder::new(Vec::new());
+        encoder.encode(&array).unwrap();
+        encoder.encode(&Value::Double(1.5)).unwrap();
+        assert_eq!(writer.into_inner(), encoder.into_inner());
+    }
+    #[test]
+    fn writes_dense_array() {
+        let mut writer = Writer::new(Vec::new());
+        writer.begin_array(2).unwrap();
+        writer.write_null().unwrap();
+        writer.write_undefined().unwrap();
+        writer.end().unwrap();
+        writer.begin_array(0).unwrap();
+        writer.end().unwrap();
+        assert_eq!(writer.into_inner(), b"\x09\x05\x01\x01\x00\x09\x01\x01");
+    }
+    #[test]
+    fn write_errors() {
+        fn invalid_state(reason: &'static str) -> Result<(), EncodeError> {
+            Err(EncodeError::InvalidWriterState { reason })
+        }
+
+        let mut writer = Writer::new(Vec::new());
+        assert_eq!(
+            writer.key("foo"),
+            invalid_state("a key is not allowed here")
+        );
+        assert_eq!(writer.end(), invalid_state("no object or array to end"));
+
+        writer.begin_object(None).unwrap();
+        assert_eq!(writer.write_null(), invalid_state("a key is expected"));
+        assert_eq!(
+            writer.key(""),
+            invalid_state("an empty key cannot be written")
+        );
+        writer.key("foo").unwrap();
+        assert_eq!(writer.end(), invalid_state("a value is expected"));
+        writer.begin_array(1).unwrap();
+        assert_eq!(writer.end(), invalid_state("too few array entries"));
+        writer.write_null().unwrap();
+        assert_eq!(
+            writer.key("bar"),
+            invalid_state("a key is not allowed here")
+        );
+        assert_eq!(writer.write_null(), invalid_state("too many array entries"));
+        writer.end().unwrap();
+        writer.end().unwrap();
+        assert_eq!(
+            writer.into_inner(),
+            b"\x0a\x0b\x01\x07foo\x09\x03\x01\x01\x01"
+        );
+    }
+
+    fn pair(key: &str, value: Value) -> Pair<String, Value> {
+        Pair {
+            key: key.to_string(),
+            value,
+        }
+    }
+}
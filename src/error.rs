@@ -1,10 +1,17 @@
 //! AMF error.
-use std::error;
-use std::fmt;
-use std::io;
-use std::string;
+use crate::io;
+use alloc::string;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::error;
+use core::fmt;
 
 /// AMF Decoding Error.
+///
+/// The error does not carry the location in the input where it was detected.
+/// The decoders report it by `error_location` instead,
+/// i.e., `Decoder`, `SliceDecoder`, `StreamDecoder` and `Reader` of each format.
+/// The `read_from` functions drop the decoder and with it the location.
 #[derive(Debug)]
 pub enum DecodeError {
     /// I/O error.
@@ -31,7 +38,8 @@ pub enum DecodeError {
     /// Circular reference.
     ///
     /// Note that circular references are allowed in the specification,
-    /// but limited by the current implementation.
+    /// but cannot be represented by the tree values.
+    /// Use `decode_graph` of the decoders to decode such values.
     CircularReference {
         /// Circular reference index.
         index: usize,
@@ -56,10 +64,27 @@ pub enum DecodeError {
     },
 
     /// Unsupported externalizable type.
+    ///
+    /// This may be returned by an `ExternalizableHandler` which cannot read the body of an object.
     ExternalizableType {
         /// The name of the externalizable type.
         name: String,
     },
+
+    /// Input which exceeds a limit of `DecoderLimits`.
+    LimitExceeded {
+        /// The exceeded limit.
+        limit: Limit,
+    },
+
+    /// Input which ends in the middle of a value.
+    ///
+    /// This is returned only by the stream decoders,
+    /// which can resume decoding after more bytes are fed.
+    Incomplete {
+        /// The number of the bytes needed to proceed, at least.
+        needed: usize,
+    },
 }
 impl error::Error for DecodeError {
     fn source(&self) -> Option<&(dyn error::Error + 'static)> {
@@ -91,6 +116,14 @@ impl fmt::Display for DecodeError {
             ExternalizableType { ref name } => {
                 write!(f, "Externalizable type {:?} is unsupported", name)
             }
+            LimitExceeded { limit } => write!(f, "Exceeded the limit of {}", limit),
+            Incomplete { needed } => {
+                write!(
+                    f,
+                    "Incomplete input: at least {} more bytes are needed",
+                    needed
+                )
+            }
         }
     }
 }
@@ -105,7 +138,9 @@ impl PartialEq for DecodeError {
             (&OutOfRangeReference { index: x }, &OutOfRangeReference { index: y }) => x == y,
             (&NonZeroTimeZone { offset: x }, &NonZeroTimeZone { offset: y }) => x == y,
             (&InvalidDate { millis: x }, &InvalidDate { millis: y }) => x == y,
-            (&ExternalizableType { name: ref x }, &ExternalizableType { name: ref y }) => x == y,
+            (ExternalizableType { name: x }, ExternalizableType { name: y }) => x == y,
+            (&LimitExceeded { limit: x }, &LimitExceeded { limit: y }) => x == y,
+            (&Incomplete { needed: x }, &Incomplete { needed: y }) => x == y,
             _ => false,
         }
     }
@@ -120,3 +155,309 @@ impl From<string::FromUtf8Error> for DecodeError {
         DecodeError::String(f)
     }
 }
+
+/// Location of the value which failed to decode.
+///
+/// The decoders keep the location of the last error detected in the input,
+/// which is returned by `error_location` of the decoders.
+///
+/// # Examples
+/// ```
+/// use amf::amf0::Decoder;
+/// use amf::error::DecodeError;
+///
+/// // An object whose member "foo" has an unknown marker 0x20
+/// let input = b"\x03\x00\x03foo\x20";
+/// let mut decoder = Decoder::new(&input[..]);
+/// assert_eq!(decoder.decode(), Err(DecodeError::Unknown { marker: 0x20 }));
+///
+/// let location = decoder.error_location().unwrap();
+/// assert_eq!(location.offset, 6);
+/// assert_eq!(location.path.to_string(), "$.foo");
+/// ```
+#[derive(Debug, Default, Clone, PartialEq, Eq, Hash)]
+pub struct Location {
+    /// The byte offset of the marker of the value which failed to decode.
+    ///
+    /// The offset is relative to the position of the input when the decoder was made.
+    pub offset: u64,
+
+    /// The logical path to the value from the top-level value.
+    pub path: Path,
+}
+impl Location {
+    // Records `offset` unless the location of an inner value has been recorded
+    pub(crate) fn record(location: &mut Option<Location>, offset: u64) {
+        if location.is_none() {
+            *location = Some(Location {
+                offset,
+                path: Path::default(),
+            });
+        }
+    }
+
+    // Prepends the segment which leads to the value which failed to decode
+    pub(crate) fn prepend(location: &mut Option<Location>, segment: PathSegment) {
+        if let Some(location) = location {
+            location.path.segments.insert(0, segment);
+        }
+    }
+}
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "offset={}, path={}", self.offset, self.path)
+    }
+}
+
+/// Logical path from a top-level value to a value in it.
+///
+/// The path is displayed in the form like `$[1].entries["orders"][42].price`,
+/// where `$` denotes the top-level value.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Hash)]
+pub struct Path {
+    /// The segments of the path.
+    pub segments: Vec<PathSegment>,
+}
+impl fmt::Display for Path {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "$")?;
+        for segment in &self.segments {
+            match *segment {
+                PathSegment::Key(ref key) if is_identifier(key) => write!(f, ".{}", key)?,
+                PathSegment::Key(ref key) => write!(f, "[{:?}]", key)?,
+                PathSegment::Index(index) => write!(f, "[{}]", index)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A segment of `Path`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum PathSegment {
+    /// The key of a member of an object or an associative array.
+    Key(string::String),
+
+    /// The index of an entry of an array, a vector or a dictionary,
+    /// or of a top-level value in a sequence.
+    Index(usize),
+}
+
+fn is_identifier(s: &str) -> bool {
+    let mut chars = s.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_' || c == '$')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '$')
+}
+
+/// A limit of `DecoderLimits`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Limit {
+    /// `DecoderLimits::max_depth`.
+    Depth,
+
+    /// `DecoderLimits::max_string_len`.
+    StringLength,
+
+    /// `DecoderLimits::max_byte_array_len`.
+    ByteArrayLength,
+
+    /// `DecoderLimits::max_collection_len`.
+    CollectionLength,
+
+    /// `DecoderLimits::max_allocation`.
+    Allocation,
+
+    /// `DecoderLimits::max_reference_expansions`.
+    ReferenceExpansions,
+}
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Limit::*;
+        match *self {
+            Depth => write!(f, "nesting depth"),
+            StringLength => write!(f, "string length"),
+            ByteArrayLength => write!(f, "byte array length"),
+            CollectionLength => write!(f, "collection length"),
+            Allocation => write!(f, "total allocation"),
+            ReferenceExpansions => write!(f, "reference expansions"),
+        }
+    }
+}
+
+/// A problem in the input which has been worked around by a lenient decoder.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Warning {
+    /// The byte offset of the problem.
+    ///
+    /// The offset is relative to the position of the input when the decoder was made.
+    pub offset: u64,
+
+    /// The kind of the problem.
+    pub kind: WarningKind,
+}
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} (offset={})", self.kind, self.offset)
+    }
+}
+
+/// The kind of a `Warning`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum WarningKind {
+    /// A string which is not valid UTF-8.
+    ///
+    /// The invalid sequences have been replaced with `U+FFFD`.
+    InvalidUtf8,
+
+    /// A marker which cannot start a value, and has been skipped.
+    SkippedMarker {
+        /// The skipped marker.
+        marker: u8,
+    },
+
+    /// An ECMA array whose declared length differs from the number of its entries.
+    EcmaArrayLength {
+        /// The declared length.
+        declared: usize,
+
+        /// The number of the entries.
+        actual: usize,
+    },
+
+    /// Input which ends in the middle of a value.
+    ///
+    /// The truncated strings, objects and arrays keep the contents decoded before the end.
+    Truncated,
+}
+impl fmt::Display for WarningKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::WarningKind::*;
+        match *self {
+            InvalidUtf8 => write!(f, "Invalid UTF-8 string"),
+            SkippedMarker { marker } => write!(f, "Skipped marker {}", marker),
+            EcmaArrayLength { declared, actual } => write!(
+                f,
+                "ECMA array declares {} entries but has {}",
+                declared, actual
+            ),
+            Truncated => write!(f, "Truncated input"),
+        }
+    }
+}
+
+/// AMF Encoding Error.
+#[derive(Debug)]
+pub enum EncodeError {
+    /// I/O error.
+    Io(io::Error),
+
+    /// Integer which cannot be represented in the encoded format (e.g., beyond 29 bits in AMF3).
+    IntegerOutOfRange {
+        /// Out-of-range integer.
+        value: i64,
+    },
+
+    /// Too long string.
+    StringTooLong {
+        /// The length of the string in bytes.
+        len: usize,
+    },
+
+    /// Too large collection (e.g., array, object and byte array).
+    CollectionTooLarge {
+        /// The number of the entries of the collection.
+        len: usize,
+    },
+
+    /// Sealed member count which exceeds the number of the members of an object (only AMF3).
+    InvalidSealedCount {
+        /// Sealed member count.
+        sealed_count: usize,
+
+        /// The number of the members.
+        len: usize,
+    },
+
+    /// Reference index which cannot be represented in the encoded format.
+    OutOfRangeReference {
+        /// Out-of-range index.
+        index: usize,
+    },
+
+    /// Externalizable type which has no registered handler (only AMF3).
+    ExternalizableType {
+        /// The name of the externalizable type.
+        name: String,
+    },
+
+    /// Method of a writer which is called in an invalid state
+    /// (e.g., `key` outside of an object, or `end` before all array entries are written).
+    InvalidWriterState {
+        /// The description of the violation.
+        reason: &'static str,
+    },
+}
+impl error::Error for EncodeError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            EncodeError::Io(ref x) => x.source(),
+            _ => None,
+        }
+    }
+}
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::EncodeError::*;
+        match *self {
+            Io(ref x) => write!(f, "I/O Error: {}", x),
+            IntegerOutOfRange { value } => write!(f, "Integer {} is out-of-range", value),
+            StringTooLong { len } => write!(f, "Too long string: length={}", len),
+            CollectionTooLarge { len } => write!(f, "Too large collection: length={}", len),
+            InvalidSealedCount { sealed_count, len } => write!(
+                f,
+                "Sealed count {} exceeds the number of members {}",
+                sealed_count, len
+            ),
+            OutOfRangeReference { index } => write!(f, "Reference index {} is out-of-range", index),
+            ExternalizableType { ref name } => {
+                write!(
+                    f,
+                    "No handler is registered for externalizable type {:?}",
+                    name
+                )
+            }
+            InvalidWriterState { reason } => write!(f, "Invalid writer state: {}", reason),
+        }
+    }
+}
+impl PartialEq for EncodeError {
+    fn eq(&self, other: &Self) -> bool {
+        use self::EncodeError::*;
+        match (self, other) {
+            (&IntegerOutOfRange { value: x }, &IntegerOutOfRange { value: y }) => x == y,
+            (&StringTooLong { len: x }, &StringTooLong { len: y }) => x == y,
+            (&CollectionTooLarge { len: x }, &CollectionTooLarge { len: y }) => x == y,
+            (
+                &InvalidSealedCount {
+                    sealed_count: x0,
+                    len: x1,
+                },
+                &InvalidSealedCount {
+                    sealed_count: y0,
+                    len: y1,
+                },
+            ) => x0 == y0 && x1 == y1,
+            (&OutOfRangeReference { index: x }, &OutOfRangeReference { index: y }) => x == y,
+            (ExternalizableType { name: x }, ExternalizableType { name: y }) => x == y,
+            (&InvalidWriterState { reason: x }, &InvalidWriterState { reason: y }) => x == y,
+            _ => false,
+        }
+    }
+}
+impl From<io::Error> for EncodeError {
+    fn from(f: io::Error) -> Self {
+        EncodeError::Io(f)
+    }
+}
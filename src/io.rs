@@ -0,0 +1,429 @@
+//! I/O traits which the decoders read from and the encoders write to.
+//!
+//! With the `std` feature (enabled by default), these are the items of `std::io`.
+//! Without it, this module provides minimal replacements,
+//! which are implemented for `&[u8]` and `Vec<u8>`.
+//!
+//! # Examples
+//! ```
+//! use amf::amf0::Value;
+//! use amf::io::Read;
+//!
+//! let mut input = &b"\x01\x01rest"[..];
+//! assert_eq!(Value::read_from(&mut input).unwrap(), Value::Boolean(true));
+//!
+//! let mut rest = [0; 4];
+//! input.read_exact(&mut rest).unwrap();
+//! assert_eq!(&rest, b"rest");
+//! ```
+//!
+//! With the `bytes` feature, `BytesReader` and `BytesWriter` adapt
+//! `bytes::Buf` and `bytes::BufMut` to the traits,
+//! which copy the bytes from and into the buffers.
+#[cfg(feature = "std")]
+pub use std::io::{empty, sink, Empty, Error, ErrorKind, Read, Result, Sink, Take, Write};
+
+#[cfg(feature = "std")]
+pub(crate) use byteorder::{ReadBytesExt, WriteBytesExt};
+
+#[cfg(not(feature = "std"))]
+pub use self::no_std::{empty, sink, Empty, Error, ErrorKind, Read, Result, Sink, Take, Write};
+
+#[cfg(not(feature = "std"))]
+pub(crate) use self::no_std::{ReadBytesExt, WriteBytesExt};
+
+/// Reader which reads the bytes from a `bytes::Buf`, advancing it.
+///
+/// The bytes are copied out of the buffer, since the values decoded by `Decoder` own their contents.
+/// To share the strings and the byte arrays with a `Bytes` instead,
+/// decode it by `SliceDecoder` and convert them by `ValueRef::to_bytes`.
+///
+/// # Examples
+/// ```
+/// use amf::amf3::{Decoder, SliceDecoder, Value};
+/// use amf::io::BytesReader;
+/// use bytes::{Buf, Bytes};
+///
+/// let mut frame = Bytes::from_static(b"\x04\x01\x02");
+/// let mut decoder = Decoder::new(BytesReader::new(&mut frame));
+/// assert_eq!(decoder.decode().unwrap(), Value::Integer(1));
+/// assert_eq!(frame.remaining(), 1);
+///
+/// // A byte array sliced from the frame without copying
+/// let frame = Bytes::from_static(b"\x0c\x07foo");
+/// let value = SliceDecoder::new(&frame[..]).decode().unwrap();
+/// let bytes = value.to_bytes(&frame).unwrap();
+/// assert_eq!(bytes.as_ptr(), frame[2..].as_ptr());
+/// ```
+#[cfg(feature = "bytes")]
+#[derive(Debug)]
+pub struct BytesReader<B> {
+    inner: B,
+}
+#[cfg(feature = "bytes")]
+impl<B: bytes::Buf> BytesReader<B> {
+    /// Makes a new instance.
+    pub fn new(inner: B) -> Self {
+        BytesReader { inner }
+    }
+
+    /// Returns an immutable reference to the underlying buffer.
+    pub fn get_ref(&self) -> &B {
+        &self.inner
+    }
+
+    /// Returns a mutable reference to the underlying buffer.
+    pub fn get_mut(&mut self) -> &mut B {
+        &mut self.inner
+    }
+
+    /// Unwraps this `BytesReader`, returning the underlying buffer.
+    pub fn into_inner(self) -> B {
+        self.inner
+    }
+}
+#[cfg(feature = "bytes")]
+impl<B: bytes::Buf> Read for BytesReader<B> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let size = buf.len().min(self.inner.remaining());
+        self.inner.copy_to_slice(&mut buf[..size]);
+        Ok(size)
+    }
+}
+
+/// Writer which writes the bytes into a `bytes::BufMut`.
+///
+/// If the buffer has no more capacity, the writes fail with `ErrorKind::WriteZero`.
+///
+/// # Examples
+/// ```
+/// use amf::amf0::{Encoder, Value};
+/// use amf::io::BytesWriter;
+/// use bytes::BytesMut;
+///
+/// let mut frame = BytesMut::new();
+/// let mut encoder = Encoder::new(BytesWriter::new(&mut frame));
+/// encoder.encode(&Value::Boolean(true)).unwrap();
+/// assert_eq!(&frame[..], b"\x01\x01");
+/// ```
+#[cfg(feature = "bytes")]
+#[derive(Debug)]
+pub struct BytesWriter<B> {
+    inner: B,
+}
+#[cfg(feature = "bytes")]
+impl<B: bytes::BufMut> BytesWriter<B> {
+    /// Makes a new instance.
+    pub fn new(inner: B) -> Self {
+        BytesWriter { inner }
+    }
+
+    /// Returns an immutable reference to the underlying buffer.
+    pub fn get_ref(&self) -> &B {
+        &self.inner
+    }
+
+    /// Returns a mutable reference to the underlying buffer.
+    pub fn get_mut(&mut self) -> &mut B {
+        &mut self.inner
+    }
+
+    /// Unwraps this `BytesWriter`, returning the underlying buffer.
+    pub fn into_inner(self) -> B {
+        self.inner
+    }
+}
+#[cfg(feature = "bytes")]
+impl<B: bytes::BufMut> Write for BytesWriter<B> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let size = buf.len().min(self.inner.remaining_mut());
+        self.inner.put_slice(&buf[..size]);
+        Ok(size)
+    }
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(not(feature = "std"))]
+mod no_std {
+    use alloc::vec::Vec;
+    use byteorder::ByteOrder;
+    use core::fmt;
+
+    /// A list specifying general categories of I/O error.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    #[non_exhaustive]
+    pub enum ErrorKind {
+        /// The reader reached the end of the input before the expected bytes.
+        UnexpectedEof,
+
+        /// The writer did not accept any more bytes.
+        WriteZero,
+
+        /// The input was invalid.
+        InvalidData,
+
+        /// Any other error.
+        Other,
+    }
+    impl ErrorKind {
+        fn as_str(self) -> &'static str {
+            match self {
+                ErrorKind::UnexpectedEof => "unexpected end of file",
+                ErrorKind::WriteZero => "write zero",
+                ErrorKind::InvalidData => "invalid data",
+                ErrorKind::Other => "other error",
+            }
+        }
+    }
+
+    /// I/O error.
+    #[derive(Debug)]
+    pub struct Error {
+        kind: ErrorKind,
+        message: &'static str,
+    }
+    impl Error {
+        /// Makes a new error with the specified kind and message.
+        pub fn new(kind: ErrorKind, message: &'static str) -> Self {
+            Error { kind, message }
+        }
+
+        /// Returns the kind of this error.
+        pub fn kind(&self) -> ErrorKind {
+            self.kind
+        }
+    }
+    impl From<ErrorKind> for Error {
+        fn from(kind: ErrorKind) -> Self {
+            Error::new(kind, kind.as_str())
+        }
+    }
+    impl fmt::Display for Error {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str(self.message)
+        }
+    }
+    impl core::error::Error for Error {}
+
+    /// I/O result.
+    pub type Result<T> = core::result::Result<T, Error>;
+
+    /// Source of bytes.
+    pub trait Read {
+        /// Reads some bytes into `buf`, returning the number of the bytes read.
+        ///
+        /// `Ok(0)` denotes the end of the input unless `buf` is empty.
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize>;
+
+        /// Reads the exact number of the bytes required to fill `buf`.
+        fn read_exact(&mut self, mut buf: &mut [u8]) -> Result<()> {
+            while !buf.is_empty() {
+                match self.read(buf)? {
+                    0 => return Err(ErrorKind::UnexpectedEof.into()),
+                    n => buf = &mut buf[n..],
+                }
+            }
+            Ok(())
+        }
+
+        /// Reads all the bytes until the end of the input, appending them to `buf`.
+        fn read_to_end(&mut self, buf: &mut Vec<u8>) -> Result<usize> {
+            let start = buf.len();
+            let mut chunk = [0; 4096];
+            loop {
+                match self.read(&mut chunk)? {
+                    0 => return Ok(buf.len() - start),
+                    n => buf.extend_from_slice(&chunk[..n]),
+                }
+            }
+        }
+
+        /// Makes an adapter which reads at most `limit` bytes from this reader.
+        fn take(self, limit: u64) -> Take<Self>
+        where
+            Self: Sized,
+        {
+            Take { inner: self, limit }
+        }
+
+        /// Makes a "by reference" adapter for this reader.
+        fn by_ref(&mut self) -> &mut Self
+        where
+            Self: Sized,
+        {
+            self
+        }
+    }
+    impl<R: Read + ?Sized> Read for &mut R {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            (**self).read(buf)
+        }
+    }
+    impl Read for &[u8] {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            let size = buf.len().min(self.len());
+            let (head, tail) = self.split_at(size);
+            buf[..size].copy_from_slice(head);
+            *self = tail;
+            Ok(size)
+        }
+    }
+
+    /// Reader which reads at most a limited number of the bytes from the underlying reader.
+    #[derive(Debug)]
+    pub struct Take<R> {
+        inner: R,
+        limit: u64,
+    }
+    impl<R: Read> Read for Take<R> {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            if self.limit == 0 {
+                return Ok(0);
+            }
+            let max = buf
+                .len()
+                .min(usize::try_from(self.limit).unwrap_or(usize::MAX));
+            let size = self.inner.read(&mut buf[..max])?;
+            self.limit -= size as u64;
+            Ok(size)
+        }
+    }
+
+    /// Sink of bytes.
+    pub trait Write {
+        /// Writes some bytes of `buf`, returning the number of the bytes written.
+        fn write(&mut self, buf: &[u8]) -> Result<usize>;
+
+        /// Flushes the bytes buffered by this writer.
+        fn flush(&mut self) -> Result<()>;
+
+        /// Writes all the bytes of `buf`.
+        fn write_all(&mut self, mut buf: &[u8]) -> Result<()> {
+            while !buf.is_empty() {
+                match self.write(buf)? {
+                    0 => return Err(ErrorKind::WriteZero.into()),
+                    n => buf = &buf[n..],
+                }
+            }
+            Ok(())
+        }
+    }
+    impl<W: Write + ?Sized> Write for &mut W {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            (**self).write(buf)
+        }
+        fn flush(&mut self) -> Result<()> {
+            (**self).flush()
+        }
+    }
+    impl Write for Vec<u8> {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            self.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Reader which is always at the end of the input.
+    #[derive(Debug, Default, Clone, Copy)]
+    pub struct Empty;
+    impl Read for Empty {
+        fn read(&mut self, _buf: &mut [u8]) -> Result<usize> {
+            Ok(0)
+        }
+    }
+
+    /// Makes a reader which is always at the end of the input.
+    pub fn empty() -> Empty {
+        Empty
+    }
+
+    /// Writer which discards all the bytes written to it.
+    #[derive(Debug, Default, Clone, Copy)]
+    pub struct Sink;
+    impl Write for Sink {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Makes a writer which discards all the bytes written to it.
+    pub fn sink() -> Sink {
+        Sink
+    }
+
+    // Replacement of `byteorder::ReadBytesExt`, which requires `std`
+    pub trait ReadBytesExt: Read {
+        fn read_u8(&mut self) -> Result<u8> {
+            let mut buf = [0; 1];
+            self.read_exact(&mut buf)?;
+            Ok(buf[0])
+        }
+        fn read_u16<T: ByteOrder>(&mut self) -> Result<u16> {
+            let mut buf = [0; 2];
+            self.read_exact(&mut buf)?;
+            Ok(T::read_u16(&buf))
+        }
+        fn read_i16<T: ByteOrder>(&mut self) -> Result<i16> {
+            let mut buf = [0; 2];
+            self.read_exact(&mut buf)?;
+            Ok(T::read_i16(&buf))
+        }
+        fn read_u32<T: ByteOrder>(&mut self) -> Result<u32> {
+            let mut buf = [0; 4];
+            self.read_exact(&mut buf)?;
+            Ok(T::read_u32(&buf))
+        }
+        fn read_i32<T: ByteOrder>(&mut self) -> Result<i32> {
+            let mut buf = [0; 4];
+            self.read_exact(&mut buf)?;
+            Ok(T::read_i32(&buf))
+        }
+        fn read_f64<T: ByteOrder>(&mut self) -> Result<f64> {
+            let mut buf = [0; 8];
+            self.read_exact(&mut buf)?;
+            Ok(T::read_f64(&buf))
+        }
+    }
+    impl<R: Read + ?Sized> ReadBytesExt for R {}
+
+    // Replacement of `byteorder::WriteBytesExt`, which requires `std`
+    pub trait WriteBytesExt: Write {
+        fn write_u8(&mut self, n: u8) -> Result<()> {
+            self.write_all(&[n])
+        }
+        fn write_u16<T: ByteOrder>(&mut self, n: u16) -> Result<()> {
+            let mut buf = [0; 2];
+            T::write_u16(&mut buf, n);
+            self.write_all(&buf)
+        }
+        fn write_i16<T: ByteOrder>(&mut self, n: i16) -> Result<()> {
+            let mut buf = [0; 2];
+            T::write_i16(&mut buf, n);
+            self.write_all(&buf)
+        }
+        fn write_u32<T: ByteOrder>(&mut self, n: u32) -> Result<()> {
+            let mut buf = [0; 4];
+            T::write_u32(&mut buf, n);
+            self.write_all(&buf)
+        }
+        fn write_i32<T: ByteOrder>(&mut self, n: i32) -> Result<()> {
+            let mut buf = [0; 4];
+            T::write_i32(&mut buf, n);
+            self.write_all(&buf)
+        }
+        fn write_f64<T: ByteOrder>(&mut self, n: f64) -> Result<()> {
+            let mut buf = [0; 8];
+            T::write_f64(&mut buf, n);
+            self.write_all(&buf)
+        }
+    }
+    impl<W: Write + ?Sized> WriteBytesExt for W {}
+}
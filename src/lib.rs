@@ -17,22 +17,52 @@
 //! # References
 //! - [AMF0 Specification](http://download.macromedia.com/pub/labs/amf/amf0_spec_121207.pdf)
 //! - [AMF3 Specification](https://www.adobe.com/content/dam/acom/en/devnet/pdf/amf-file-format-spec.pdf)
+//!
+//! # Features
+//! - `std` (enabled by default): Uses `std::io` for the readers and the writers.
+//!   Without it, the crate depends only on `core` and `alloc`,
+//!   and provides the minimal replacements in the `io` module.
+//! - `bytes`: Adds the adapters which read from `bytes::Buf` and write into `bytes::BufMut`
+//!   (`io::BytesReader` and `io::BytesWriter`), which copy the bytes,
+//!   and `ValueRef::to_bytes`, which shares the strings and the byte arrays decoded by `SliceDecoder`
+//!   with the input `Bytes` without copying them.
 #![warn(missing_docs)]
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+extern crate alloc;
 extern crate byteorder;
 
-use std::io;
+use crate::util::iter_boxed;
+use alloc::boxed::Box;
+use alloc::string::String;
 
 pub use amf0::Value as Amf0Value;
 pub use amf3::Value as Amf3Value;
+pub use limits::DecoderLimits;
+pub use options::{ArrayPolicy, EncoderOptions, LongStringPolicy, ObjectPolicy};
+pub use wire::WireFormat;
 
 pub mod amf0;
 pub mod amf3;
 pub mod error;
+pub mod io;
+
+mod limits;
+mod options;
+mod util;
+mod wire;
+
+// The map of the reference tables, which is a hash map with `std`
+#[cfg(feature = "std")]
+pub(crate) type Map<K, V> = std::collections::HashMap<K, V>;
+#[cfg(not(feature = "std"))]
+pub(crate) type Map<K, V> = alloc::collections::BTreeMap<K, V>;
 
 /// AMF decoding result.
 pub type DecodeResult<T> = Result<T, error::DecodeError>;
+/// AMF decoding result with the number of bytes consumed.
 pub type DecodePart<T> = Result<(T, u32), error::DecodeError>;
-
+/// AMF encoding result.
+pub type EncodeResult<T> = Result<T, error::EncodeError>;
 
 /// Format version.
 #[derive(Debug, Clone, Copy, PartialOrd, Ord, PartialEq, Eq, Hash)]
@@ -58,7 +88,10 @@ impl Value {
     ///
     /// Note that reference objects are copied in the decoding phase
     /// for the sake of simplicity of the resulting value representation.
-    /// And circular reference are unsupported (i.e., those are treated as errors).
+    /// Circular references are rejected because the tree values cannot represent those;
+    /// decode them by `decode_graph` of [`amf0::Decoder`] or [`amf3::Decoder`] instead.
+    ///
+    /// The location of an error is not available; use the `Decoder` of each format to get it.
     pub fn read_from<R>(reader: R, version: Version) -> DecodeResult<Self>
     where
         R: io::Read,
@@ -70,7 +103,7 @@ impl Value {
     }
 
     /// Writes the AMF encoded bytes of this value to `writer`.
-    pub fn write_to<W>(&self, writer: W) -> io::Result<()>
+    pub fn write_to<W>(&self, writer: W) -> EncodeResult<()>
     where
         W: io::Write,
     {
@@ -80,6 +113,14 @@ impl Value {
         }
     }
 
+    /// Returns the number of the bytes which `write_to` writes.
+    pub fn encoded_len(&self) -> EncodeResult<usize> {
+        match *self {
+            Value::Amf0(ref x) => x.encoded_len(),
+            Value::Amf3(ref x) => x.encoded_len(),
+        }
+    }
+
     /// Tries to convert the value as a `str` reference.
     pub fn try_as_str(&self) -> Option<&str> {
         match *self {
@@ -140,10 +181,3 @@ pub struct Pair<K, V> {
     /// The value of the pair.
     pub value: V,
 }
-
-fn iter_boxed<I, T>(iter: I) -> Box<dyn Iterator<Item = T>>
-where
-    I: Iterator<Item = T> + 'static,
-{
-    Box::new(iter)
-}
@@ -0,0 +1,174 @@
+use crate::error::{DecodeError, Limit};
+use crate::DecodeResult;
+use core::mem;
+
+/// Limits of the resources used by the AMF0 and AMF3 decoders against hostile input.
+///
+/// A violation results in `DecodeError::LimitExceeded`.
+/// The default limits (which `Value::read_from` also uses) bound the nesting depth,
+/// the allocation per top-level value and the reference expansions;
+/// use `DecoderLimits::unlimited()` to trust the input entirely.
+///
+/// # Examples
+/// ```
+/// use amf::amf3::Decoder;
+/// use amf::error::{DecodeError, Limit};
+/// use amf::DecoderLimits;
+///
+/// let limits = DecoderLimits {
+///     max_string_len: 3,
+///     ..DecoderLimits::default()
+/// };
+/// let mut decoder = Decoder::with_limits(&b"\x06\x09abcd"[..], limits);
+/// assert_eq!(
+///     decoder.decode(),
+///     Err(DecodeError::LimitExceeded { limit: Limit::StringLength })
+/// );
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct DecoderLimits {
+    /// Maximum nesting depth of complex values (e.g., objects and arrays).
+    ///
+    /// A complex value at the top level has depth 1.
+    pub max_depth: usize,
+
+    /// Maximum length of a string in bytes.
+    ///
+    /// This also applies to keys, class names and XML.
+    pub max_string_len: usize,
+
+    /// Maximum length of a byte array.
+    pub max_byte_array_len: usize,
+
+    /// Maximum number of the entries of a collection (e.g., array, object, vector and dictionary).
+    pub max_collection_len: usize,
+
+    /// Maximum number of the bytes allocated for the strings, byte arrays and collections
+    /// of a top-level value.
+    ///
+    /// The copies of referred values (e.g., by `decode`) are also counted.
+    pub max_allocation: usize,
+
+    /// Maximum number of the values copied from referred values.
+    ///
+    /// This applies to the methods which copy referred values (e.g., `decode`),
+    /// and is counted until the reference tables are cleared.
+    pub max_reference_expansions: usize,
+}
+impl DecoderLimits {
+    /// Makes limits which restrict nothing.
+    pub fn unlimited() -> Self {
+        DecoderLimits {
+            max_depth: usize::MAX,
+            max_string_len: usize::MAX,
+            max_byte_array_len: usize::MAX,
+            max_collection_len: usize::MAX,
+            max_allocation: usize::MAX,
+            max_reference_expansions: usize::MAX,
+        }
+    }
+}
+impl Default for DecoderLimits {
+    /// Makes the limits with `max_depth` 256, `max_allocation` 64 MiB
+    /// and `max_reference_expansions` 1,048,576.
+    ///
+    /// The lengths are bounded only by `max_allocation`.
+    fn default() -> Self {
+        DecoderLimits {
+            max_depth: 256,
+            max_allocation: 64 * 1024 * 1024,
+            max_reference_expansions: 1024 * 1024,
+            ..Self::unlimited()
+        }
+    }
+}
+
+// Resources used by a decoder, which are checked against the limits
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct Budget {
+    pub(crate) limits: DecoderLimits,
+    depth: usize,
+    allocated: usize,
+    expansions: usize,
+}
+impl Budget {
+    pub(crate) fn new(limits: DecoderLimits) -> Self {
+        Budget {
+            limits,
+            ..Budget::default()
+        }
+    }
+
+    // Called before decoding a top-level value
+    pub(crate) fn start_value(&mut self) {
+        self.depth = 0;
+        self.allocated = 0;
+    }
+
+    // Called when the reference tables are cleared
+    pub(crate) fn clear_expansions(&mut self) {
+        self.expansions = 0;
+    }
+
+    pub(crate) fn enter(&mut self) -> DecodeResult<()> {
+        if self.depth >= self.limits.max_depth {
+            return Err(exceeded(Limit::Depth));
+        }
+        self.depth += 1;
+        Ok(())
+    }
+    pub(crate) fn leave(&mut self) {
+        self.depth -= 1;
+    }
+
+    pub(crate) fn string(&mut self, len: usize) -> DecodeResult<()> {
+        if len > self.limits.max_string_len {
+            return Err(exceeded(Limit::StringLength));
+        }
+        self.allocate(len)
+    }
+    pub(crate) fn byte_array(&mut self, len: usize) -> DecodeResult<()> {
+        if len > self.limits.max_byte_array_len {
+            return Err(exceeded(Limit::ByteArrayLength));
+        }
+        self.allocate(len)
+    }
+    pub(crate) fn collection<T>(&mut self, len: usize) -> DecodeResult<()> {
+        if len > self.limits.max_collection_len {
+            return Err(exceeded(Limit::CollectionLength));
+        }
+        self.allocate(len.saturating_mul(mem::size_of::<T>()))
+    }
+    // Called before adding the `len`-th entry to a collection whose length is unknown in advance
+    pub(crate) fn entry<T>(&mut self, len: usize) -> DecodeResult<()> {
+        if len > self.limits.max_collection_len {
+            return Err(exceeded(Limit::CollectionLength));
+        }
+        self.allocate(mem::size_of::<T>())
+    }
+    // Called when a referred value is copied again,
+    // with the number of the values and the bytes allocated for the copy
+    pub(crate) fn copy(&mut self, count: usize, bytes: usize) -> DecodeResult<()> {
+        self.expand(count)?;
+        self.allocate(bytes)
+    }
+    pub(crate) fn expand(&mut self, count: usize) -> DecodeResult<()> {
+        self.expansions = self.expansions.saturating_add(count);
+        if self.expansions > self.limits.max_reference_expansions {
+            return Err(exceeded(Limit::ReferenceExpansions));
+        }
+        Ok(())
+    }
+
+    fn allocate(&mut self, bytes: usize) -> DecodeResult<()> {
+        self.allocated = self.allocated.saturating_add(bytes);
+        if self.allocated > self.limits.max_allocation {
+            return Err(exceeded(Limit::Allocation));
+        }
+        Ok(())
+    }
+}
+
+fn exceeded(limit: Limit) -> DecodeError {
+    DecodeError::LimitExceeded { limit }
+}
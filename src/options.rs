@@ -0,0 +1,82 @@
+/// Options of the AMF0 and AMF3 encoders.
+///
+/// The default options produce the same encoding as `Value::write_to`.
+///
+/// # Examples
+/// ```
+/// use amf::amf3::{Encoder, Value};
+/// use amf::EncoderOptions;
+///
+/// let options = EncoderOptions {
+///     promote_integers: true,
+///     ..EncoderOptions::default()
+/// };
+/// let mut encoder = Encoder::with_options(Vec::new(), options);
+/// encoder.encode(&Value::Integer(1 << 28)).unwrap();
+/// assert_eq!(encoder.into_inner(), b"\x05\x41\xb0\x00\x00\x00\x00\x00\x00");
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct EncoderOptions {
+    /// If `true`, an AMF3 integer which does not fit in 29 bits is written as a double
+    /// instead of returning `EncodeError::IntegerOutOfRange`.
+    pub promote_integers: bool,
+
+    /// If `true`, an AMF3 double which has a whole value representable in 29 bits is written as an integer.
+    ///
+    /// Negative zero is always written as a double.
+    pub integral_doubles: bool,
+
+    /// Encoding of AMF0 strings.
+    pub long_strings: LongStringPolicy,
+
+    /// Encoding of AMF0 arrays.
+    pub arrays: ArrayPolicy,
+
+    /// Encoding of anonymous (i.e., untyped) AMF3 objects.
+    pub anonymous_objects: ObjectPolicy,
+}
+
+/// Encoding of AMF0 strings.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum LongStringPolicy {
+    /// Strings are written as `LONG_STRING` only when those are longer than 65535 bytes.
+    #[default]
+    WhenNeeded,
+
+    /// Strings are always written as `LONG_STRING`.
+    ///
+    /// Note that object keys and class names are not affected.
+    Always,
+}
+
+/// Encoding of AMF0 arrays.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum ArrayPolicy {
+    /// ECMA arrays and strict arrays are written as they are.
+    #[default]
+    AsIs,
+
+    /// Strict arrays are written as ECMA arrays keyed by the indices (i.e., `"0"`, `"1"`, ...).
+    Ecma,
+
+    /// ECMA arrays whose keys are the indices in order are written as strict arrays.
+    ///
+    /// The other ECMA arrays are written as they are.
+    Strict,
+}
+
+/// Encoding of anonymous AMF3 objects.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum ObjectPolicy {
+    /// Objects are written according to their `sealed_count`.
+    #[default]
+    AsIs,
+
+    /// All members are written as dynamic members.
+    Dynamic,
+
+    /// All members are written as sealed members.
+    ///
+    /// The traits of the objects which have the same member names are shared by references.
+    Sealed,
+}
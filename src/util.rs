@@ -0,0 +1,270 @@
+use crate::error::EncodeError;
+use crate::io::{self, Read};
+use crate::{EncodeResult, Map};
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::mem;
+use core::time;
+
+// The maximum number of the bytes which a bulk read allocates in advance
+const BULK_READ_SIZE: usize = 64 * 1024;
+
+// Returns `part` as a `Bytes` which shares the memory of `input` if `part` is a subslice of it
+#[cfg(feature = "bytes")]
+pub(crate) fn share_bytes(input: &bytes::Bytes, part: &[u8]) -> bytes::Bytes {
+    let range = input.as_ptr_range();
+    let part_range = part.as_ptr_range();
+    if range.start <= part_range.start && part_range.end <= range.end {
+        input.slice_ref(part)
+    } else {
+        bytes::Bytes::copy_from_slice(part)
+    }
+}
+
+// The size of the buffer of a buffered decoder
+const READ_AHEAD_SIZE: usize = 8 * 1024;
+
+// Bytes read from the underlying reader ahead of the decoding
+pub(crate) struct ReadAhead {
+    buf: Box<[u8]>,
+    pos: usize,
+    filled: usize,
+}
+impl ReadAhead {
+    fn new() -> Self {
+        ReadAhead {
+            buf: vec![0; READ_AHEAD_SIZE].into_boxed_slice(),
+            pos: 0,
+            filled: 0,
+        }
+    }
+    fn unread(&self) -> &[u8] {
+        &self.buf[self.pos..self.filled]
+    }
+}
+impl core::fmt::Debug for ReadAhead {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.debug_struct("ReadAhead")
+            .field("capacity", &self.buf.len())
+            .field("unread", &(self.filled - self.pos))
+            .finish()
+    }
+}
+
+// Reader which counts the bytes read from the underlying reader
+#[derive(Debug)]
+pub(crate) struct Position<R> {
+    pub(crate) inner: R,
+    pub(crate) offset: u64,
+    // The number of the bytes which the last bulk read lacked at the end of the input
+    pub(crate) missing: usize,
+    // `Some` if the reads of the underlying reader are buffered
+    pub(crate) read_ahead: Option<ReadAhead>,
+}
+impl<R> Position<R> {
+    pub(crate) fn new(inner: R) -> Self {
+        Position {
+            inner,
+            offset: 0,
+            missing: 0,
+            read_ahead: None,
+        }
+    }
+    pub(crate) fn enable_read_ahead(&mut self) {
+        if self.read_ahead.is_none() {
+            self.read_ahead = Some(ReadAhead::new());
+        }
+    }
+    pub(crate) fn take_missing(&mut self) -> usize {
+        mem::take(&mut self.missing)
+    }
+    // Returns the bytes which have been read from the underlying reader but not decoded yet
+    pub(crate) fn buffer(&self) -> &[u8] {
+        self.read_ahead.as_ref().map_or(&[], ReadAhead::unread)
+    }
+}
+impl<R> Position<R>
+where
+    R: io::Read,
+{
+    // Reads `len` bytes, or fewer at the end of the input, in bulk
+    pub(crate) fn read_up_to(&mut self, len: usize) -> io::Result<Vec<u8>> {
+        // The buffer beyond `BULK_READ_SIZE` grows as the bytes are read,
+        // so a bogus length does not allocate a large buffer
+        let mut buf = Vec::with_capacity(len.min(BULK_READ_SIZE));
+        io::Read::by_ref(self)
+            .take(len as u64)
+            .read_to_end(&mut buf)?;
+        self.missing = len - buf.len();
+        Ok(buf)
+    }
+    // Reads `count` big-endian elements of `N` bytes with one `read_exact` per `BULK_READ_SIZE` bytes
+    pub(crate) fn read_elements<T, const N: usize>(
+        &mut self,
+        count: usize,
+        from_be_bytes: fn([u8; N]) -> T,
+    ) -> io::Result<Vec<T>> {
+        let start = self.offset;
+        let mut remaining = count.saturating_mul(N);
+        let mut chunk = vec![0; remaining.min(BULK_READ_SIZE / N * N)];
+        let mut entries = Vec::with_capacity(chunk.len() / N);
+        while remaining > 0 {
+            let len = remaining.min(chunk.len());
+            let bytes = &mut chunk[..len];
+            if let Err(e) = self.read_exact(bytes) {
+                let consumed = (self.offset - start) as usize;
+                self.missing = count.saturating_mul(N) - consumed;
+                return Err(e);
+            }
+            entries.extend(
+                bytes
+                    .chunks_exact(N)
+                    .map(|b| from_be_bytes(b.try_into().expect("a chunk of N bytes"))),
+            );
+            remaining -= bytes.len();
+        }
+        Ok(entries)
+    }
+}
+impl<R> io::Read for Position<R>
+where
+    R: io::Read,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let size = match self.read_ahead {
+            None => self.inner.read(buf)?,
+            // A read as large as the buffer bypasses it
+            Some(ref ra) if ra.pos == ra.filled && buf.len() >= ra.buf.len() => {
+                self.inner.read(buf)?
+            }
+            Some(ref mut ra) => {
+                if ra.pos == ra.filled {
+                    ra.filled = self.inner.read(&mut ra.buf)?;
+                    ra.pos = 0;
+                }
+                let size = (&ra.buf[ra.pos..ra.filled]).read(buf)?;
+                ra.pos += size;
+                size
+            }
+        };
+        self.offset += size as u64;
+        Ok(size)
+    }
+}
+
+// Identifiers of the structures of the complex values, which are equal for structurally equal values
+//
+// The key of a complex value is its encoding whose complex members are written
+// as `Shapes::MEMBER` followed by their identifiers,
+// so the key of each value is built once from the identifiers of its members.
+#[derive(Debug, Default)]
+pub(crate) struct Shapes {
+    ids: Map<Vec<u8>, usize>,
+    // The identifiers of the complex values in the value being encoded, by their addresses
+    addresses: Map<usize, usize>,
+}
+impl Shapes {
+    // The marker of a member in a key, which is not used by AMF0 nor AMF3
+    pub(crate) const MEMBER: u8 = 0xFF;
+
+    pub(crate) fn get<T>(&self, value: &T) -> Option<usize> {
+        self.addresses.get(&(value as *const T as usize)).copied()
+    }
+    pub(crate) fn add<T>(&mut self, value: &T, key: Vec<u8>) -> usize {
+        let next = self.ids.len();
+        let id = *self.ids.entry(key).or_insert(next);
+        self.addresses.insert(value as *const T as usize, id);
+        id
+    }
+    // Called after encoding a top-level value, whose addresses may be reused by later values
+    pub(crate) fn forget_addresses(&mut self) {
+        self.addresses.clear();
+    }
+    pub(crate) fn clear(&mut self) {
+        self.ids.clear();
+        self.addresses.clear();
+    }
+}
+
+// Writer which counts the bytes written to it without storing them
+#[derive(Debug, Default)]
+pub(crate) struct ByteCounter {
+    pub(crate) count: usize,
+}
+impl io::Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.count += buf.len();
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Buffer of the bytes fed to a stream decoder, which can be read again from a saved position
+#[derive(Debug, Default)]
+pub(crate) struct Feed {
+    buf: Vec<u8>,
+    pos: usize,
+    // The number of the bytes which the last read beyond the end of the buffer lacked at least
+    shortage: usize,
+}
+impl Feed {
+    pub(crate) fn extend(&mut self, bytes: &[u8]) {
+        self.buf.drain(..self.pos);
+        self.pos = 0;
+        self.buf.extend_from_slice(bytes);
+    }
+    pub(crate) fn position(&self) -> usize {
+        self.pos
+    }
+    pub(crate) fn rewind(&mut self, pos: usize) {
+        self.pos = pos;
+    }
+    pub(crate) fn remaining(&self) -> usize {
+        self.buf.len() - self.pos
+    }
+    pub(crate) fn take_shortage(&mut self) -> usize {
+        mem::take(&mut self.shortage)
+    }
+}
+impl io::Read for Feed {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let size = (&self.buf[self.pos..]).read(buf)?;
+        self.pos += size;
+        if size < buf.len() {
+            self.shortage = buf.len() - size;
+        }
+        Ok(size)
+    }
+}
+
+// Converts a date to the milliseconds since the Unix epoch, failing if those do not fit in `u64`
+pub(crate) fn unix_millis(unix_time: time::Duration) -> EncodeResult<u64> {
+    unix_time
+        .as_secs()
+        .checked_mul(1000)
+        .and_then(|millis| millis.checked_add(u64::from(unix_time.subsec_millis())))
+        .ok_or(EncodeError::IntegerOutOfRange {
+            value: i64::try_from(unix_time.as_millis()).unwrap_or(i64::MAX),
+        })
+}
+
+// Normalizes a negative zero to zero and a NaN to `f64::NAN`, which are written in the same bytes
+pub(crate) fn canonical_f64(x: f64) -> f64 {
+    if x.is_nan() {
+        f64::NAN
+    } else if x == 0.0 {
+        0.0
+    } else {
+        x
+    }
+}
+
+pub(crate) fn iter_boxed<I, T>(iter: I) -> Box<dyn Iterator<Item = T>>
+where
+    I: Iterator<Item = T> + 'static,
+{
+    Box::new(iter)
+}
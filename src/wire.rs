@@ -0,0 +1,132 @@
+use crate::Map;
+use alloc::vec::Vec;
+
+/// Wire details of an encoded value which the decoded value does not represent.
+///
+/// These are recorded by `decode_exact` of the AMF0 and AMF3 decoders,
+/// and reproduced by `encode_exact` of the encoders:
+///
+/// - AMF0 strings shorter than 65536 bytes which are written as `LONG_STRING`,
+/// - the declared lengths of AMF0 ECMA arrays which differ from their entries,
+/// - AMF0 and AMF3 references (including those to AMF3 strings and traits),
+/// - AMF3 objects of dynamic traits which have no dynamic members,
+/// - AMF3 integers, lengths and references which are written in more bytes than needed.
+///
+/// The details are identified by their positions in the encoded value.
+/// If the value passed to `encode_exact` has been modified,
+/// the details which no longer fit the value are ignored (e.g., a reference to
+/// an object which is no longer equal to the referring one is written in full).
+///
+/// # Examples
+/// ```
+/// use amf::amf0::{Decoder, Encoder, Value};
+///
+/// // "a" written as `LONG_STRING`
+/// let input = b"\x0c\x00\x00\x00\x01a";
+/// let exact = Decoder::new(&input[..]).decode_exact().unwrap();
+/// assert_eq!(exact.value, Value::String("a".to_string()));
+///
+/// let mut encoder = Encoder::new(Vec::new());
+/// encoder.encode_exact(&exact.value, &exact.wire).unwrap();
+/// assert_eq!(encoder.into_inner(), input);
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WireFormat {
+    details: Vec<(u64, WireDetail)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum WireDetail {
+    // An AMF0 string shorter than 65536 bytes written as `LONG_STRING`
+    LongString,
+    // The declared length of an AMF0 ECMA array which differs from its entries
+    EcmaArrayLength(u32),
+    // An AMF0 reference
+    Reference(usize),
+    // An AMF3 reference to a complex value
+    ObjectReference(usize),
+    // An AMF3 reference to a string
+    StringReference(usize),
+    // An AMF3 reference to a trait
+    TraitReference(usize),
+    // An AMF3 object of a dynamic trait which has no dynamic members
+    DynamicObject,
+    // An AMF3 U29 written in more bytes than needed
+    U29 { value: u32, width: usize },
+}
+
+// Wire details which are recorded by a decoder or replayed by an encoder.
+//
+// The details are attached to sites, which are the AMF0 values and the AMF3 U29s numbered in order.
+#[derive(Debug, Default)]
+pub(crate) struct Tape {
+    site: u64,
+    // The index of the first detail which is not before the current site
+    pos: usize,
+    details: Vec<(u64, WireDetail)>,
+}
+impl Tape {
+    pub(crate) fn replay(wire: &WireFormat) -> Self {
+        Tape {
+            site: 0,
+            pos: 0,
+            details: wire.details.clone(),
+        }
+    }
+    pub(crate) fn into_wire(mut self) -> WireFormat {
+        // The details of a complex value may be recorded after those of its entries
+        self.details.sort_by_key(|&(site, _)| site);
+        WireFormat {
+            details: self.details,
+        }
+    }
+    // Moves to the next site, returning it
+    pub(crate) fn next_site(&mut self) -> u64 {
+        self.site += 1;
+        while self.details.get(self.pos).is_some_and(|d| d.0 < self.site) {
+            self.pos += 1;
+        }
+        self.site
+    }
+    pub(crate) fn site(&self) -> u64 {
+        self.site
+    }
+    pub(crate) fn record(&mut self, site: u64, detail: WireDetail) {
+        self.details.push((site, detail));
+    }
+    // Finds a detail of the current site
+    pub(crate) fn find<T, F>(&self, f: F) -> Option<T>
+    where
+        F: FnMut(WireDetail) -> Option<T>,
+    {
+        self.find_at(self.site, f)
+    }
+    // Finds a detail of the next site, i.e., the one which `next_site` moves to
+    pub(crate) fn peek<T, F>(&self, f: F) -> Option<T>
+    where
+        F: FnMut(WireDetail) -> Option<T>,
+    {
+        self.find_at(self.site + 1, f)
+    }
+    // Collects the indices referred by the details, which are mapped to the keys of the referred values
+    pub(crate) fn references<F>(&self, mut f: F) -> Map<usize, Option<usize>>
+    where
+        F: FnMut(WireDetail) -> Option<usize>,
+    {
+        self.details
+            .iter()
+            .filter_map(|d| f(d.1))
+            .map(|index| (index, None))
+            .collect()
+    }
+    fn find_at<T, F>(&self, site: u64, mut f: F) -> Option<T>
+    where
+        F: FnMut(WireDetail) -> Option<T>,
+    {
+        self.details[self.pos..]
+            .iter()
+            .take_while(|d| d.0 <= site)
+            .filter(|d| d.0 == site)
+            .find_map(|d| f(d.1))
+    }
+}